@@ -0,0 +1,46 @@
+//! Throughput of request parsing, for spotting regressions in the code
+//! that has to keep up with floods of `RRQ`s during mass boots.
+//!
+//! Run with `cargo bench`; criterion compares each run with the last.
+
+extern crate allenap_libtftp;
+extern crate criterion;
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use allenap_libtftp::options::Options;
+use allenap_libtftp::packet::Packet;
+
+
+const PLAIN: &[u8] = b"\0\x01pxelinux.0\0octet\0";
+const OPTIONS: &[u8] = b"\0\x01pxelinux.0\0octet\0blksize\x001468\0\
+                         tsize\x000\0windowsize\x004\0timeout\x003\0";
+const UNUSUAL: &[u8] = b"\0\x01pxelinux.0\0octet\0BLKSIZE\x001468\0\
+                         TSize\x000\0vendor\0value\0";
+
+
+fn parse_requests(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rrq");
+    for (name, buf) in [
+        ("plain", PLAIN), ("options", OPTIONS), ("unusual", UNUSUAL),
+    ] {
+        group.throughput(Throughput::Elements(1));
+        group.bench_function(name, |b| b.iter(
+            || Packet::parse(black_box(buf)).unwrap()));
+    }
+    group.finish();
+}
+
+
+fn parse_options(c: &mut Criterion) {
+    // Skip the opcode, filename, and mode.
+    let buf = &OPTIONS[19..];
+    c.bench_function("options", |b| b.iter(
+        || Options::parse(black_box(buf)).unwrap()));
+}
+
+
+criterion_group!(benches, parse_requests, parse_options);
+criterion_main!(benches);
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Seek};
+use std::net;
+use std::path;
+use std::sync::Arc;
+
+use flate2::read::DeflateDecoder;
+use tar;
+use zip;
+
+use super::{Access, Handler, spawn_transfer};
+use super::events::EventSink;
+use super::logging::Logger;
+use super::metrics::Metrics;
+use super::options::Options;
+use super::packet::{Filename, Packet, TransferMode};
+use super::rrq;
+
+
+/// How a member's bytes are stored in the archive.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+enum Encoding {
+    Stored,
+    /// Compressed with deflate, into this many bytes.
+    Deflated(u64),
+}
+
+
+/// Where to find a member in the archive.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+struct Member {
+    offset: u64,
+    size: u64,
+    encoding: Encoding,
+}
+
+
+impl Member {
+
+    /// The number of bytes stored for the member, compressed or not.
+    fn stored(&self) -> u64 {
+        match self.encoding {
+            Encoding::Stored => self.size,
+            Encoding::Deflated(compressed) => compressed,
+        }
+    }
+
+}
+
+
+/// A `Handler` that serves the files in a tar or zip archive, such as a
+/// netboot bundle, without unpacking it.
+///
+/// The archive is read once, when mounted, to find where each file is;
+/// each download then reads just that file, decompressing it on the fly
+/// if need be, with `tsize` answered from the size recorded in the
+/// archive. Tar archives must be uncompressed; zip members may be
+/// stored or deflated. Directories, links, and other special members
+/// are left out, as are members compressed other ways.
+///
+/// Members are found by their names in the archive, less any leading
+/// `./` or `/`, which are also ignored in requests. Write requests are
+/// refused. The archive should not be changed while mounted.
+pub struct ArchiveHandler {
+    path: path::PathBuf,
+    members: HashMap<Vec<u8>, Member>,
+    config: Arc<rrq::Config>,
+    logger: Logger,
+}
+
+
+impl ArchiveHandler {
+
+    /// Mount the archive at `path`, telling a zip archive from a tar
+    /// archive by its signature.
+    pub fn mount<P: AsRef<path::Path>>(path: P, logger: Logger)
+                                       -> io::Result<ArchiveHandler> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = fs::File::open(&path)?;
+        let mut magic = [0u8; 4];
+        let is_zip = match file.read_exact(&mut magic) {
+            Ok(()) => &magic == b"PK\x03\x04" || &magic == b"PK\x05\x06",
+            Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof =>
+                false,
+            Err(error) => return Err(error),
+        };
+        file.rewind()?;
+        let members = match is_zip {
+            true => index_zip(file, &logger)?,
+            false => index_tar(file, &logger)?,
+        };
+        info!(
+            logger, "Mounted {} files from {}", members.len(), path.display());
+        let config = Arc::new(rrq::Config::new());
+        Ok(ArchiveHandler{path, members, config, logger})
+    }
+
+    /// Serve with `config`, rather than the default. Its `metrics` and
+    /// `events` are also those of the handler.
+    pub fn with_config(self, config: Arc<rrq::Config>) -> ArchiveHandler {
+        ArchiveHandler{config, ..self}
+    }
+
+    /// The path of the archive.
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+
+    /// The names of the files served.
+    pub fn names(&self) -> impl Iterator<Item = Filename> + '_ {
+        self.members.keys().map(|name| Filename::new(name.clone()))
+    }
+
+    /// The size of the file served for `filename`, if there is one.
+    pub fn size(&self, filename: &Filename) -> Option<u64> {
+        self.members.get(normalize(filename.as_bytes())).map(|m| m.size)
+    }
+
+    /// A reader of the member served for `filename`, and its size.
+    fn open(&self, filename: &Filename)
+            -> io::Result<(Box<dyn io::Read + Send>, u64)> {
+        let member = match self.members.get(normalize(filename.as_bytes())) {
+            Some(member) => *member,
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        let mut file = fs::File::open(&self.path)?;
+        file.seek(io::SeekFrom::Start(member.offset))?;
+        let stored = file.take(member.stored());
+        let reader: Box<dyn io::Read + Send> = match member.encoding {
+            Encoding::Stored => Box::new(stored),
+            Encoding::Deflated(_) => Box::new(DeflateDecoder::new(stored)),
+        };
+        Ok((reader, member.size))
+    }
+
+}
+
+
+impl std::fmt::Debug for ArchiveHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ArchiveHandler")
+            .field("path", &self.path)
+            .field("members", &self.members.len())
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+
+impl Handler for ArchiveHandler {
+
+    fn access(&self) -> Access {
+        Access::ReadOnly
+    }
+
+    fn metrics(&self) -> Arc<dyn Metrics> {
+        self.config.metrics.clone()
+    }
+
+    fn events(&self) -> Option<Arc<dyn EventSink>> {
+        self.config.events.clone()
+    }
+
+    fn handle_rrq(
+        &self, _local: net::SocketAddr, remote: net::SocketAddr,
+        filename: Filename, txmode: TransferMode, options: Options)
+        -> Option<Packet<'_>>
+    {
+        let (data, size) = match self.open(&filename) {
+            Ok(opened) => opened,
+            Err(error) => {
+                warn!(
+                    self.logger, "Could not open {:?} in {}: {}",
+                    filename.to_string_lossy(), self.path.display(), error);
+                return Some(Packet::from(&error));
+            },
+        };
+        let config = self.config.clone();
+        let logger = self.logger.clone();
+        spawn_transfer(remote, &self.logger, move || {
+            // Failures are logged by serve_reader.
+            let _ = rrq::serve_reader(
+                remote, filename, data, Some(size), txmode, options, &config,
+                &logger);
+        });
+        None
+    }
+
+}
+
+
+/// A member's name, or a requested filename, without any leading `./`
+/// or `/`.
+fn normalize(mut name: &[u8]) -> &[u8] {
+    loop {
+        if let Some(rest) = name.strip_prefix(b"./") {
+            name = rest;
+        }
+        else if let Some(rest) = name.strip_prefix(b"/") {
+            name = rest;
+        }
+        else {
+            return name;
+        }
+    }
+}
+
+
+fn index_tar(file: fs::File, logger: &Logger)
+             -> io::Result<HashMap<Vec<u8>, Member>> {
+    let mut archive = tar::Archive::new(file);
+    let mut members = HashMap::new();
+    for entry in archive.entries_with_seek()? {
+        let entry = entry?;
+        let name = normalize(&entry.path_bytes()).to_vec();
+        match entry.header().entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                members.insert(name, Member{
+                    offset: entry.raw_file_position(),
+                    size: entry.size(),
+                    encoding: Encoding::Stored,
+                });
+            },
+            tar::EntryType::Directory => {},
+            kind => debug!(
+                logger, "Leaving out {:?}: {:?} members are not served",
+                String::from_utf8_lossy(&name), kind),
+        }
+    }
+    Ok(members)
+}
+
+
+fn index_zip(file: fs::File, logger: &Logger)
+             -> io::Result<HashMap<Vec<u8>, Member>> {
+    let mut archive = zip::ZipArchive::new(file).map_err(io::Error::other)?;
+    let mut members = HashMap::new();
+    for index in 0..archive.len() {
+        let file = archive.by_index_raw(index).map_err(io::Error::other)?;
+        if file.is_dir() {
+            continue;
+        }
+        let name = normalize(file.name_raw()).to_vec();
+        let encoding = match file.compression() {
+            zip::CompressionMethod::Stored => Encoding::Stored,
+            zip::CompressionMethod::Deflated =>
+                Encoding::Deflated(file.compressed_size()),
+            method => {
+                warn!(
+                    logger, "Leaving out {:?}: compressed with {}",
+                    String::from_utf8_lossy(&name), method);
+                continue;
+            },
+        };
+        if file.encrypted() {
+            warn!(
+                logger, "Leaving out {:?}: encrypted",
+                String::from_utf8_lossy(&name));
+            continue;
+        }
+        members.insert(name, Member{
+            offset: file.data_start(),
+            size: file.size(),
+            encoding,
+        });
+    }
+    Ok(members)
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::fs;
+    use std::io::{self, Read, Write};
+    use std::net;
+
+    use tar;
+    use zip;
+
+    use super::{ArchiveHandler, normalize};
+    use super::super::Handler;
+    use super::super::logging;
+    use super::super::options::Options;
+    use super::super::packet::{ErrorCode, Filename, Packet, TransferMode};
+    use super::super::testing::Scratch;
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|n| (n % 251) as u8).collect()
+    }
+
+    fn read(handler: &ArchiveHandler, name: &str) -> Vec<u8> {
+        let (mut reader, size) = handler.open(&Filename::new(name)).unwrap();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(size, data.len() as u64);
+        data
+    }
+
+    #[test]
+    fn test_indexes_files_in_tar() {
+        let scratch = Scratch::new("archive-index");
+        let path = scratch.join("test.tar");
+        {
+            let mut builder = tar::Builder::new(
+                fs::File::create(&path).unwrap());
+            for &(name, len) in &[("./pxelinux.0", 1300), ("ldlinux.c32", 0)] {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(len as u64);
+                header.set_cksum();
+                builder.append_data(
+                    &mut header, name, &content(len)[..]).unwrap();
+            }
+            builder.finish().unwrap();
+        }
+        let handler = ArchiveHandler::mount(&path, logging::discard())
+            .unwrap();
+        assert_eq!(2, handler.names().count());
+        assert_eq!(Some(1300), handler.size(&Filename::new("pxelinux.0")));
+        assert_eq!(Some(0), handler.size(&Filename::new("/ldlinux.c32")));
+        assert_eq!(None, handler.size(&Filename::new("other")));
+    }
+
+    #[test]
+    fn test_reads_members_of_tar() {
+        let scratch = Scratch::new("archive-tar");
+        let path = scratch.join("read.tar");
+        {
+            let mut builder = tar::Builder::new(
+                fs::File::create(&path).unwrap());
+            let mut header = tar::Header::new_gnu();
+            header.set_size(1300);
+            header.set_cksum();
+            builder.append_data(
+                &mut header, "boot/pxelinux.0", &content(1300)[..]).unwrap();
+            builder.finish().unwrap();
+        }
+        let handler = ArchiveHandler::mount(&path, logging::discard())
+            .unwrap();
+        assert_eq!(content(1300), read(&handler, "boot/pxelinux.0"));
+        let error = handler.open(&Filename::new("boot")).err().unwrap();
+        assert_eq!(io::ErrorKind::NotFound, error.kind());
+    }
+
+    #[test]
+    fn test_reads_stored_and_deflated_members_of_zip() {
+        let scratch = Scratch::new("archive-zip");
+        let path = scratch.join("read.zip");
+        {
+            let mut writer = zip::ZipWriter::new(
+                fs::File::create(&path).unwrap());
+            let stored = zip::write::SimpleFileOptions::default()
+                .compression_method(zip::CompressionMethod::Stored);
+            let deflated = zip::write::SimpleFileOptions::default()
+                .compression_method(zip::CompressionMethod::Deflated);
+            writer.add_directory("boot/", stored).unwrap();
+            writer.start_file("boot/vmlinuz", stored).unwrap();
+            writer.write_all(&content(2000)).unwrap();
+            writer.start_file("boot/initrd", deflated).unwrap();
+            writer.write_all(&vec![7u8; 5000]).unwrap();
+            writer.finish().unwrap();
+        }
+        let handler = ArchiveHandler::mount(&path, logging::discard())
+            .unwrap();
+        assert_eq!(2, handler.names().count());
+        assert_eq!(Some(5000), handler.size(&Filename::new("boot/initrd")));
+        assert_eq!(content(2000), read(&handler, "boot/vmlinuz"));
+        assert_eq!(vec![7u8; 5000], read(&handler, "boot/initrd"));
+    }
+
+    #[test]
+    fn test_handler_replies_to_missing_files_with_errors() {
+        let scratch = Scratch::new("archive-missing");
+        let path = scratch.join("empty.tar");
+        tar::Builder::new(fs::File::create(&path).unwrap()).finish().unwrap();
+        let handler = ArchiveHandler::mount(&path, logging::discard())
+            .unwrap();
+        let addr: net::SocketAddr = "127.0.0.1:69".parse().unwrap();
+        match handler.handle_rrq(
+            addr, addr, Filename::new("pxelinux.0"), TransferMode::Octet,
+            Options::new())
+        {
+            Some(Packet::Error(code, _)) =>
+                assert_eq!(ErrorCode::FileNotFound, code),
+            reply => panic!("Unexpected reply: {:?}", reply),
+        };
+    }
+
+    #[test]
+    fn test_normalize_strips_leading_dot_and_slash() {
+        assert_eq!(b"a/b", normalize(b"./a/b"));
+        assert_eq!(b"a/b", normalize(b"/./a/b"));
+        assert_eq!(b"a/./b", normalize(b"a/./b"));
+    }
+
+}
@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use super::events::{Event, EventSink};
+use super::logging::Logger;
+use super::metrics::Direction;
+use super::options::Options;
+use super::packet::{ErrorCode, TransferMode};
+use super::progress::TransferStats;
+
+
+/// A read or write request, as it arrived at the server.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Request {
+    /// When the request arrived.
+    pub timestamp: time::SystemTime,
+    pub peer: net::SocketAddr,
+    pub direction: Direction,
+    pub filename: String,
+    pub txmode: TransferMode,
+    pub options: Options,
+}
+
+
+/// How a request turned out.
+#[derive(Clone,Debug,PartialEq)]
+pub enum Outcome {
+    /// The transfer completed.
+    Completed(TransferStats),
+    /// The handler rejected the request with an `ERROR` packet.
+    Rejected(ErrorCode, String),
+    /// The transfer began but failed, with the error that ended it.
+    Failed(String),
+    /// Nothing more was heard of the request before another arrived
+    /// from the same peer, or before the `Auditor` was flushed.
+    Abandoned,
+}
+
+
+/// A request joined with its outcome.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Record {
+    pub request: Request,
+    pub outcome: Outcome,
+    /// When the outcome became known.
+    pub finished: time::SystemTime,
+}
+
+
+/// Receives one consolidated record for each request.
+pub trait AuditLog: Send + Sync {
+
+    fn record(&self, record: Record);
+
+}
+
+
+impl fmt::Debug for dyn AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AuditLog")
+    }
+}
+
+
+/// An `EventSink` that remembers each request and, once its outcome is
+/// known, sends the two together to an `AuditLog`.
+///
+/// Requests and transfers are matched by peer and direction, so the
+/// same `Auditor` must be returned from `Handler::events` and set in
+/// the `events` field of `rrq::Config` and `wrq::Config`.
+#[derive(Debug)]
+pub struct Auditor {
+    log: Arc<dyn AuditLog>,
+    pending: Mutex<HashMap<(net::SocketAddr, Direction), Request>>,
+}
+
+
+impl Auditor {
+
+    pub fn new(log: Arc<dyn AuditLog>) -> Auditor {
+        Auditor{log, pending: Mutex::new(HashMap::new())}
+    }
+
+    /// Record every request still awaiting an outcome as abandoned,
+    /// for example when the server is shutting down.
+    pub fn flush(&self) {
+        let pending: Vec<Request> = self.pending.lock().unwrap()
+            .drain().map(|(_, request)| request).collect();
+        for request in pending {
+            self.finish(request, Outcome::Abandoned);
+        }
+    }
+
+    fn finish(&self, request: Request, outcome: Outcome) {
+        self.log.record(Record{
+            request, outcome, finished: time::SystemTime::now(),
+        });
+    }
+
+    /// The request from `peer` in `direction` that was awaiting an
+    /// outcome, if any.
+    fn take(
+        &self, peer: net::SocketAddr, direction: Direction)
+        -> Option<Request>
+    {
+        self.pending.lock().unwrap().remove(&(peer, direction))
+    }
+
+}
+
+
+impl EventSink for Auditor {
+
+    fn event(&self, event: Event) {
+        let (peer, direction, outcome) = match event {
+            Event::RequestReceived{
+                peer, direction, filename, txmode, options,
+            } => {
+                let request = Request{
+                    timestamp: time::SystemTime::now(),
+                    peer, direction, filename, txmode, options,
+                };
+                let earlier = self.pending.lock().unwrap()
+                    .insert((peer, direction), request);
+                if let Some(earlier) = earlier {
+                    self.finish(earlier, Outcome::Abandoned);
+                }
+                return;
+            },
+            Event::RequestRejected{peer, direction, code, message} =>
+                (peer, direction, Outcome::Rejected(code, message)),
+            Event::TransferCompleted{peer, direction, stats} =>
+                (peer, direction, Outcome::Completed(stats)),
+            Event::TransferFailed{peer, direction, error, ..} =>
+                (peer, direction, Outcome::Failed(error)),
+            _ => return,
+        };
+        // Transfers started without a request, by calling `rrq` or
+        // `wrq` directly, have nothing to be joined with.
+        if let Some(request) = self.take(peer, direction) {
+            self.finish(request, outcome);
+        }
+    }
+
+}
+
+
+/// An `AuditLog` that writes each record to a logger, as one event
+/// with the request and its outcome as key-value pairs.
+#[derive(Clone,Debug)]
+pub struct LogAudit {
+    logger: Logger,
+}
+
+
+impl LogAudit {
+
+    pub fn new(logger: Logger) -> LogAudit {
+        LogAudit{logger}
+    }
+
+}
+
+
+impl AuditLog for LogAudit {
+
+    fn record(&self, record: Record) {
+        let Record{request, outcome, finished} = record;
+        let (outcome, bytes, code, error) = match outcome {
+            Outcome::Completed(stats) =>
+                ("completed", stats.bytes, None, String::new()),
+            Outcome::Rejected(code, message) =>
+                ("rejected", 0, Some(code), message),
+            Outcome::Failed(error) => ("failed", 0, None, error),
+            Outcome::Abandoned => ("abandoned", 0, None, String::new()),
+        };
+        info!(
+            self.logger, "Audit";
+            "timestamp" => seconds(request.timestamp),
+            "finished" => seconds(finished),
+            "peer" => request.peer.to_string(),
+            "direction" => format!("{:?}", request.direction),
+            "filename" => request.filename,
+            "txmode" => request.txmode.to_string(),
+            "options" => request.options.to_string(),
+            "outcome" => outcome,
+            "bytes" => bytes.to_string(),
+            "code" => code.map_or_else(String::new, |code| code.to_string()),
+            "error" => error);
+    }
+
+}
+
+
+/// Seconds since the Unix epoch, to the millisecond.
+fn seconds(time: time::SystemTime) -> String {
+    match time.duration_since(time::UNIX_EPOCH) {
+        Ok(since) => format!("{:.3}", since.as_secs_f64()),
+        Err(_) => "0.000".to_owned(),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::net;
+    use std::sync::{Arc, Mutex};
+    use std::time;
+
+    use super::{AuditLog, Auditor, Outcome, Record};
+    use super::super::events::{Event, EventSink};
+    use super::super::metrics::Direction;
+    use super::super::options::Options;
+    use super::super::packet::{ErrorCode, TransferMode};
+    use super::super::progress::{Progress, TransferStats};
+
+    #[derive(Default)]
+    struct Records(Mutex<Vec<Record>>);
+
+    impl AuditLog for Records {
+        fn record(&self, record: Record) {
+            self.0.lock().unwrap().push(record);
+        }
+    }
+
+    impl Records {
+        fn outcomes(&self) -> Vec<(String, Outcome)> {
+            self.0.lock().unwrap().iter().map(|record| (
+                record.request.filename.clone(), record.outcome.clone(),
+            )).collect()
+        }
+    }
+
+    fn auditor() -> (Arc<Records>, Auditor) {
+        let records = Arc::new(Records::default());
+        (records.clone(), Auditor::new(records))
+    }
+
+    fn peer() -> net::SocketAddr {
+        "127.0.0.1:1069".parse().unwrap()
+    }
+
+    fn request(direction: Direction, filename: &str) -> Event {
+        Event::RequestReceived{
+            peer: peer(), direction, filename: filename.to_owned(),
+            txmode: TransferMode::Octet, options: Options::new(),
+        }
+    }
+
+    fn stats(bytes: u64) -> TransferStats {
+        TransferStats{
+            bytes,
+            blocks: 1,
+            retransmits: 0,
+            duplicate_acks: 0,
+            duration: time::Duration::from_secs(0),
+            negotiated: Options::new(),
+        }
+    }
+
+    #[test]
+    fn test_auditor_joins_request_with_completion() {
+        let (records, auditor) = auditor();
+        auditor.event(request(Direction::Read, "boot"));
+        auditor.event(Event::TransferStarted{
+            peer: peer(), direction: Direction::Read, filename: None});
+        assert_eq!(0, records.outcomes().len());
+        auditor.event(Event::TransferCompleted{
+            peer: peer(), direction: Direction::Read, stats: stats(42)});
+        assert_eq!(
+            vec![("boot".to_owned(), Outcome::Completed(stats(42)))],
+            records.outcomes());
+    }
+
+    #[test]
+    fn test_auditor_joins_request_with_rejection() {
+        let (records, auditor) = auditor();
+        auditor.event(request(Direction::Write, "root"));
+        auditor.event(Event::RequestRejected{
+            peer: peer(), direction: Direction::Write,
+            code: ErrorCode::AccessViolation, message: "no".to_owned()});
+        assert_eq!(
+            vec![(
+                "root".to_owned(),
+                Outcome::Rejected(ErrorCode::AccessViolation, "no".to_owned()),
+            )],
+            records.outcomes());
+    }
+
+    #[test]
+    fn test_auditor_matches_on_direction() {
+        let (records, auditor) = auditor();
+        auditor.event(request(Direction::Read, "boot"));
+        auditor.event(request(Direction::Write, "log"));
+        auditor.event(Event::TransferFailed{
+            peer: peer(), direction: Direction::Write,
+            progress: Progress::new(None),
+            error: "oops".to_owned()});
+        assert_eq!(
+            vec![("log".to_owned(), Outcome::Failed("oops".to_owned()))],
+            records.outcomes());
+    }
+
+    #[test]
+    fn test_auditor_abandons_superseded_and_flushed_requests() {
+        let (records, auditor) = auditor();
+        auditor.event(request(Direction::Read, "one"));
+        auditor.event(request(Direction::Read, "two"));
+        auditor.flush();
+        assert_eq!(
+            vec![
+                ("one".to_owned(), Outcome::Abandoned),
+                ("two".to_owned(), Outcome::Abandoned),
+            ],
+            records.outcomes());
+    }
+
+    #[test]
+    fn test_auditor_ignores_transfers_without_request() {
+        let (records, auditor) = auditor();
+        auditor.event(Event::TransferCompleted{
+            peer: peer(), direction: Direction::Read, stats: stats(1)});
+        assert_eq!(0, records.outcomes().len());
+    }
+
+}
@@ -0,0 +1,359 @@
+use std::fmt::Write;
+use std::net;
+use std::sync::Arc;
+use std::time;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::{Access, Handler};
+use super::events::EventSink;
+use super::metrics::{Direction, Metrics};
+use super::options::{Duplicates, OutOfRange};
+use super::packet::{Filename, FilenameValidation, Packet, Strictness};
+use super::security::{SecurityEvent, Violation};
+use super::transport::WireObserver;
+
+
+type HmacSha256 = Hmac<Sha256>;
+
+
+/// Why a signed filename was refused.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Refusal {
+    /// The filename has no `;exp=…;sig=…` suffix, or it's garbled.
+    Unsigned,
+    /// The signature does not match.
+    BadSignature,
+    /// The expiry has passed.
+    Expired,
+}
+
+
+/// Signs filenames and checks their signatures.
+///
+/// A signed filename has the form `name;exp=EXPIRES;sig=SIGNATURE`,
+/// where `EXPIRES` is in seconds since the Unix epoch, and `SIGNATURE`
+/// is the HMAC-SHA256, in lowercase hex, of everything before `;sig=`.
+#[derive(Clone)]
+pub struct Signer {
+    key: Vec<u8>,
+}
+
+
+impl Signer {
+
+    pub fn new<K: Into<Vec<u8>>>(key: K) -> Signer {
+        Signer{key: key.into()}
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.key)
+            .expect("HMAC takes keys of any length")
+    }
+
+    /// Sign `name` so that it's good until `expires`.
+    pub fn sign(&self, name: &[u8], expires: time::SystemTime) -> Vec<u8> {
+        let expires = expires.duration_since(time::UNIX_EPOCH)
+            .map(|since| since.as_secs()).unwrap_or(0);
+        let mut signed = name.to_vec();
+        signed.extend_from_slice(format!(";exp={}", expires).as_bytes());
+        let mut mac = self.mac();
+        mac.update(&signed);
+        let mut sig = String::with_capacity(64);
+        for byte in mac.finalize().into_bytes() {
+            write!(sig, "{:02x}", byte).unwrap();
+        }
+        signed.extend_from_slice(b";sig=");
+        signed.extend_from_slice(sig.as_bytes());
+        signed
+    }
+
+    /// Check the signed filename `signed` at time `now`, returning the
+    /// name without its suffix.
+    pub fn verify<'a>(&self, signed: &'a [u8], now: time::SystemTime)
+                      -> Result<&'a [u8], Refusal> {
+        let (message, sig) = rsplit(signed, b";sig=")
+            .ok_or(Refusal::Unsigned)?;
+        let (name, expires) = rsplit(message, b";exp=")
+            .ok_or(Refusal::Unsigned)?;
+        let sig = unhex(sig).ok_or(Refusal::Unsigned)?;
+        let expires: u64 = std::str::from_utf8(expires).ok()
+            .filter(|expires| expires.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|expires| expires.parse().ok())
+            .ok_or(Refusal::Unsigned)?;
+        // Check the signature first so that a forger learns nothing
+        // from the expiry.
+        let mut mac = self.mac();
+        mac.update(message);
+        mac.verify_slice(&sig).map_err(|_| Refusal::BadSignature)?;
+        let expires = time::UNIX_EPOCH + time::Duration::from_secs(expires);
+        if now >= expires {
+            return Err(Refusal::Expired);
+        }
+        Ok(name)
+    }
+
+}
+
+
+/// Does not show the key.
+impl std::fmt::Debug for Signer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Signer").finish_non_exhaustive()
+    }
+}
+
+
+/// Split `bytes` at the last occurrence of `separator`.
+fn rsplit<'a>(bytes: &'a [u8], separator: &[u8])
+              -> Option<(&'a [u8], &'a [u8])> {
+    (0..=bytes.len().checked_sub(separator.len())?).rev()
+        .find(|&at| bytes[at..].starts_with(separator))
+        .map(|at| (&bytes[..at], &bytes[at + separator.len()..]))
+}
+
+
+/// Decode lowercase or uppercase hex.
+fn unhex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2).map(|pair| {
+        let digits = std::str::from_utf8(pair).ok()?;
+        u8::from_str_radix(digits, 16).ok()
+    }).collect()
+}
+
+
+/// A `Handler` that only passes on requests for signed filenames.
+///
+/// Each read or write request must be for a filename signed by
+/// `Signer::sign` that has not yet expired. The suffix is removed
+/// before the request is passed to the inner handler, which sees only
+/// the name. Other requests, and all the other `Handler` methods, go
+/// straight to the inner handler.
+///
+/// By default refused requests get an access violation in reply. To
+/// give nothing back to requests that may come from spoofed addresses,
+/// use [`ignore_refused`](#method.ignore_refused). Either way, each
+/// refusal is reported to the inner handler's event sink as a
+/// `BadSignature` security event.
+#[derive(Debug)]
+pub struct Authenticator<H> {
+    inner: H,
+    signer: Signer,
+    reply: bool,
+}
+
+
+impl<H: Handler> Authenticator<H> {
+
+    pub fn new(inner: H, signer: Signer) -> Authenticator<H> {
+        Authenticator{inner, signer, reply: true}
+    }
+
+    /// Silently drop refused requests rather than replying.
+    pub fn ignore_refused(self) -> Authenticator<H> {
+        Authenticator{reply: false, ..self}
+    }
+
+    /// The handler to which verified requests are passed.
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    fn refuse(
+        &self, remote: net::SocketAddr, direction: Direction,
+        filename: &Filename, refusal: Refusal)
+        -> Option<Packet<'_>>
+    {
+        let message = match refusal {
+            Refusal::Unsigned => "filename not signed",
+            Refusal::BadSignature => "bad signature",
+            Refusal::Expired => "signature expired",
+        };
+        SecurityEvent::new(remote, Violation::BadSignature, message)
+            .with_direction(direction)
+            .with_filename(filename.to_string_lossy())
+            .report(self.inner.events().as_deref());
+        if self.reply {
+            Some(Packet::access_violation(message))
+        }
+        else {
+            None
+        }
+    }
+
+}
+
+
+impl<H: Handler> Handler for Authenticator<H> {
+
+    fn strictness(&self) -> Strictness {
+        self.inner.strictness()
+    }
+
+    fn duplicate_options(&self) -> Duplicates {
+        self.inner.duplicate_options()
+    }
+
+    fn option_ranges(&self) -> OutOfRange {
+        self.inner.option_ranges()
+    }
+
+    fn filename_validation(&self) -> FilenameValidation {
+        self.inner.filename_validation()
+    }
+
+    fn access(&self) -> Access {
+        self.inner.access()
+    }
+
+    fn wire_observer(&self) -> Option<Arc<dyn WireObserver>> {
+        self.inner.wire_observer()
+    }
+
+    fn metrics(&self) -> Arc<dyn Metrics> {
+        self.inner.metrics()
+    }
+
+    fn events(&self) -> Option<Arc<dyn EventSink>> {
+        self.inner.events()
+    }
+
+    fn handle(
+        &self, local: net::SocketAddr, remote: net::SocketAddr, packet: Packet)
+        -> Option<Packet<'_>>
+    {
+        let now = time::SystemTime::now();
+        let packet = match packet {
+            Packet::Read(filename, txmode, options) => {
+                match self.signer.verify(filename.as_bytes(), now) {
+                    Ok(name) => Packet::Read(
+                        Filename::new(name), txmode, options),
+                    Err(refusal) => return self.refuse(
+                        remote, Direction::Read, &filename, refusal),
+                }
+            },
+            Packet::Write(filename, txmode, options) => {
+                match self.signer.verify(filename.as_bytes(), now) {
+                    Ok(name) => Packet::Write(
+                        Filename::new(name), txmode, options),
+                    Err(refusal) => return self.refuse(
+                        remote, Direction::Write, &filename, refusal),
+                }
+            },
+            packet => packet,
+        };
+        self.inner.handle(local, remote, packet)
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::net;
+    use std::time;
+
+    use super::{Authenticator, Refusal, Signer};
+    use super::super::Handler;
+    use super::super::options::Options;
+    use super::super::packet::{Filename, Packet, TransferMode};
+
+    fn at(secs: u64) -> time::SystemTime {
+        time::UNIX_EPOCH + time::Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_sign_appends_expiry_and_signature() {
+        let signed = Signer::new("key").sign(b"images/disk.img", at(1000));
+        let signed = String::from_utf8(signed).unwrap();
+        assert!(signed.starts_with("images/disk.img;exp=1000;sig="));
+        assert_eq!(29 + 64, signed.len());
+    }
+
+    #[test]
+    fn test_verify_returns_name() {
+        let signer = Signer::new("key");
+        let signed = signer.sign(b"images/disk.img", at(1000));
+        assert_eq!(Ok(&b"images/disk.img"[..]), signer.verify(&signed, at(999)));
+    }
+
+    #[test]
+    fn test_verify_refuses_expired() {
+        let signer = Signer::new("key");
+        let signed = signer.sign(b"disk.img", at(1000));
+        assert_eq!(Err(Refusal::Expired), signer.verify(&signed, at(1000)));
+    }
+
+    #[test]
+    fn test_verify_refuses_tampering() {
+        let signer = Signer::new("key");
+        let signed = signer.sign(b"disk.img", at(1000));
+        let later = String::from_utf8(signed).unwrap()
+            .replace("exp=1000", "exp=9000");
+        assert_eq!(
+            Err(Refusal::BadSignature),
+            signer.verify(later.as_bytes(), at(999)));
+        let other = Signer::new("other").sign(b"disk.img", at(1000));
+        assert_eq!(Err(Refusal::BadSignature), signer.verify(&other, at(999)));
+    }
+
+    #[test]
+    fn test_verify_refuses_unsigned() {
+        let signer = Signer::new("key");
+        for signed in &[
+            &b"disk.img"[..], b"disk.img;sig=00", b"disk.img;exp=1;sig=0g",
+            b"disk.img;exp=-1;sig=00", b"disk.img;exp=1;sig=0",
+        ] {
+            assert_eq!(Err(Refusal::Unsigned), signer.verify(signed, at(0)));
+        }
+    }
+
+    /// Replies with an error carrying the filename it was asked for.
+    struct Echo;
+
+    impl Handler for Echo {
+        fn handle_rrq(
+            &self, _local: net::SocketAddr, _remote: net::SocketAddr,
+            filename: Filename, _txmode: TransferMode, _options: Options)
+            -> Option<Packet<'_>>
+        {
+            Some(Packet::file_not_found(filename.to_string_lossy()))
+        }
+    }
+
+    fn read(handler: &dyn Handler, filename: &[u8]) -> Option<Packet<'static>> {
+        let addr: net::SocketAddr = "127.0.0.1:69".parse().unwrap();
+        let request = Packet::Read(
+            Filename::new(filename), TransferMode::Octet, Options::new());
+        handler.handle(addr, addr, request).map(|reply| match reply {
+            Packet::Error(code, message) => Packet::Error(code, message),
+            reply => panic!("Unexpected reply: {:?}", reply),
+        })
+    }
+
+    #[test]
+    fn test_authenticator_passes_name_to_inner_handler() {
+        let signer = Signer::new("key");
+        let signed = signer.sign(b"disk.img", time::SystemTime::now()
+                                 + time::Duration::from_secs(60));
+        let handler = Authenticator::new(Echo, signer);
+        assert_eq!(
+            Some(Packet::file_not_found("disk.img")), read(&handler, &signed));
+    }
+
+    #[test]
+    fn test_authenticator_refuses_unsigned() {
+        let handler = Authenticator::new(Echo, Signer::new("key"));
+        assert_eq!(
+            Some(Packet::access_violation("filename not signed")),
+            read(&handler, b"disk.img"));
+        let handler = handler.ignore_refused();
+        assert_eq!(None, read(&handler, b"disk.img"));
+    }
+
+}
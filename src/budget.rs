@@ -0,0 +1,191 @@
+use std::sync::Mutex;
+
+use super::options::{Negotiated, Options};
+
+
+/// The smallest block size to which a transfer is degraded; that of
+/// RFC-1350. Transfers that asked for less keep what they asked for.
+const MIN_BLKSIZE: usize = 512;
+
+
+/// Caps the memory committed to transfers in progress, across all
+/// clients.
+///
+/// A transfer with a 64KB block size and a window of 64 blocks can hold
+/// megabytes, and hundreds of clients PXE booting at once could hold
+/// more memory than there is. Each transfer is charged an estimate of
+/// what it will hold, `(4 + blksize) × (windowsize + 1)`: a window of
+/// `DATA` packets and one to receive into. When a transfer's options
+/// would not fit into what remains, its window is halved, then its
+/// block size, down to lock-step with 512-byte blocks; if even that
+/// does not fit, the transfer is rejected.
+///
+/// Put one budget in the `budget` field of both
+/// [`rrq::Config`](../rrq/struct.Config.html) and
+/// [`wrq::Config`](../wrq/struct.Config.html) to have reads and writes
+/// share it.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    committed: Mutex<usize>,
+}
+
+
+impl MemoryBudget {
+
+    /// A budget of `limit` bytes.
+    pub fn new(limit: usize) -> MemoryBudget {
+        MemoryBudget{limit, committed: Mutex::new(0)}
+    }
+
+    /// The budget, in bytes.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// The bytes committed to transfers in progress.
+    pub fn committed(&self) -> usize {
+        *self.committed.lock().unwrap()
+    }
+
+    /// Commit memory to a transfer that negotiated `blksize` and
+    /// `windowsize`, degrading them until they fit, or `None` if they
+    /// cannot. The memory remains committed until the returned
+    /// `Commitment` is dropped.
+    pub fn commit(&self, blksize: usize, windowsize: u16)
+                  -> Option<Commitment<'_>> {
+        let mut committed = self.committed.lock().unwrap();
+        let available = self.limit.saturating_sub(*committed);
+        let (mut blksize, mut windowsize) = (blksize, windowsize.max(1));
+        while cost(blksize, windowsize) > available {
+            if windowsize > 1 {
+                windowsize /= 2;
+            }
+            else if blksize > MIN_BLKSIZE {
+                blksize = MIN_BLKSIZE.max(blksize / 2);
+            }
+            else {
+                return None;
+            }
+        }
+        let bytes = cost(blksize, windowsize);
+        *committed += bytes;
+        Some(Commitment{budget: self, bytes, blksize, windowsize})
+    }
+
+}
+
+
+/// The memory estimated to be held by a transfer.
+fn cost(blksize: usize, windowsize: u16) -> usize {
+    (4 + blksize) * (windowsize as usize + 1)
+}
+
+
+/// Memory committed to one transfer, and the options it can afford.
+#[derive(Debug)]
+pub struct Commitment<'a> {
+    budget: &'a MemoryBudget,
+    bytes: usize,
+    blksize: usize,
+    windowsize: u16,
+}
+
+
+impl<'a> Commitment<'a> {
+
+    /// The bytes committed.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// The block size the transfer can afford.
+    pub fn blksize(&self) -> usize {
+        self.blksize
+    }
+
+    /// The window size the transfer can afford.
+    pub fn windowsize(&self) -> u16 {
+        self.windowsize
+    }
+
+    /// Bring `negotiated`, and the options to acknowledge, `accepted`,
+    /// down to what the transfer can afford.
+    pub fn apply(&self, accepted: &mut Options, negotiated: &mut Negotiated) {
+        if self.blksize < negotiated.blksize {
+            negotiated.blksize = self.blksize;
+            if accepted.blksize.is_some() {
+                accepted.blksize = Some(self.blksize as u16);
+            }
+        }
+        if self.windowsize < negotiated.windowsize {
+            negotiated.windowsize = self.windowsize;
+            if accepted.windowsize.is_some() {
+                accepted.windowsize = Some(self.windowsize);
+            }
+        }
+    }
+
+}
+
+
+impl<'a> Drop for Commitment<'a> {
+    fn drop(&mut self) {
+        *self.budget.committed.lock().unwrap() -= self.bytes;
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::time;
+
+    use super::MemoryBudget;
+    use super::super::options::{Negotiated, Options};
+
+    #[test]
+    fn test_commit_when_it_fits() {
+        let budget = MemoryBudget::new(10000);
+        let commitment = budget.commit(1024, 4).unwrap();
+        assert_eq!((1024, 4), (commitment.blksize(), commitment.windowsize()));
+        assert_eq!(1028 * 5, commitment.bytes());
+        assert_eq!(1028 * 5, budget.committed());
+        drop(commitment);
+        assert_eq!(0, budget.committed());
+    }
+
+    #[test]
+    fn test_commit_degrades_window_then_block_size() {
+        let budget = MemoryBudget::new(4000);
+        let first = budget.commit(1024, 8).unwrap();
+        assert_eq!((1024, 2), (first.blksize(), first.windowsize()));
+        assert!(budget.commit(4096, 1).is_none());
+        drop(first);
+        let second = budget.commit(4096, 1).unwrap();
+        assert_eq!((1024, 1), (second.blksize(), second.windowsize()));
+        let third = budget.commit(4096, 1).unwrap();
+        assert_eq!((512, 1), (third.blksize(), third.windowsize()));
+        assert!(budget.commit(512, 1).is_none());
+        // Small blocks are not made larger.
+        let fourth = budget.commit(128, 1).unwrap();
+        assert_eq!((128, 1), (fourth.blksize(), fourth.windowsize()));
+    }
+
+    #[test]
+    fn test_apply_degrades_only_acknowledged_options() {
+        let budget = MemoryBudget::new(2000);
+        let commitment = budget.commit(1468, 4).unwrap();
+        let mut accepted = Options::new();
+        accepted.blksize = Some(1468);
+        let mut negotiated = Negotiated{
+            blksize: 1468, timeout: time::Duration::from_secs(1),
+            windowsize: 4, rollover: 0,
+        };
+        commitment.apply(&mut accepted, &mut negotiated);
+        assert_eq!((734, 1), (negotiated.blksize, negotiated.windowsize));
+        assert_eq!(Some(734), accepted.blksize);
+        assert_eq!(None, accepted.windowsize);
+    }
+
+}
@@ -0,0 +1,315 @@
+use alloc::vec::Vec;
+use core::fmt::Display;
+use core::result;
+
+use super::options::{InvalidOption, Multicast, Options};
+use super::packet::{Error, Filename, Packet, Result, TransferMode};
+use super::packetwriter;
+
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+enum Kind {
+    Read,
+    Write,
+    OAck,
+}
+
+
+/// Constructs `RRQ`, `WRQ`, and `OACK` packets in a chainable style:
+///
+/// ```
+/// # use allenap_libtftp::builder::PacketBuilder;
+/// let packet = PacketBuilder::rrq("kernel")
+///     .octet().blksize(1468).tsize_query().build().unwrap();
+/// assert_eq!(
+///     "RRQ \"kernel\" octet blksize=1468 tsize=0", packet.to_string());
+/// ```
+///
+/// Constraints are checked by `build`: option values must be within the
+/// ranges their RFCs permit, and the filename must not contain NUL.
+#[derive(Clone,Debug)]
+pub struct PacketBuilder {
+    kind: Kind,
+    filename: Filename,
+    txmode: TransferMode,
+    options: Options,
+}
+
+
+impl PacketBuilder {
+
+    fn new(kind: Kind, filename: Filename) -> PacketBuilder {
+        PacketBuilder{
+            kind,
+            filename,
+            txmode: TransferMode::Octet,
+            options: Options::new(),
+        }
+    }
+
+    /// Begin a read request. The transfer mode defaults to octet.
+    pub fn rrq<B: Into<Vec<u8>>>(filename: B) -> PacketBuilder {
+        PacketBuilder::new(Kind::Read, Filename::new(filename))
+    }
+
+    /// Begin a write request. The transfer mode defaults to octet.
+    pub fn wrq<B: Into<Vec<u8>>>(filename: B) -> PacketBuilder {
+        PacketBuilder::new(Kind::Write, Filename::new(filename))
+    }
+
+    /// Begin an option acknowledgement. Any filename or transfer mode
+    /// given is ignored.
+    pub fn oack() -> PacketBuilder {
+        PacketBuilder::new(Kind::OAck, Filename::new(""))
+    }
+
+    pub fn mode(mut self, txmode: TransferMode) -> PacketBuilder {
+        self.txmode = txmode;
+        self
+    }
+
+    pub fn octet(self) -> PacketBuilder {
+        self.mode(TransferMode::Octet)
+    }
+
+    pub fn netascii(self) -> PacketBuilder {
+        self.mode(TransferMode::NetASCII)
+    }
+
+    /// Replace all options at once.
+    pub fn options(mut self, options: Options) -> PacketBuilder {
+        self.options = options;
+        self
+    }
+
+    pub fn blksize(mut self, blksize: u16) -> PacketBuilder {
+        self.options.blksize = Some(blksize);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: u8) -> PacketBuilder {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    pub fn tsize(mut self, tsize: u64) -> PacketBuilder {
+        self.options.tsize = Some(tsize);
+        self
+    }
+
+    /// Ask the server for the size of the file, as in a `RRQ`.
+    pub fn tsize_query(self) -> PacketBuilder {
+        self.tsize(0)
+    }
+
+    pub fn windowsize(mut self, windowsize: u16) -> PacketBuilder {
+        self.options.windowsize = Some(windowsize);
+        self
+    }
+
+    pub fn rollover(mut self, rollover: u16) -> PacketBuilder {
+        self.options.rollover = Some(rollover);
+        self
+    }
+
+    pub fn utimeout(mut self, utimeout: u32) -> PacketBuilder {
+        self.options.utimeout = Some(utimeout);
+        self
+    }
+
+    /// Check constraints and build the packet.
+    pub fn build(self) -> Result<Packet<'static>> {
+        check_options(&self.options)?;
+        match self.kind {
+            Kind::Read | Kind::Write => {
+                if self.filename.as_bytes().contains(&0u8) {
+                    return Err(Error::WriteError(
+                        packetwriter::Error::StringContainsNull));
+                }
+            },
+            Kind::OAck => {},
+        };
+        Ok(match self.kind {
+            Kind::Read => Packet::Read(
+                self.filename, self.txmode, self.options),
+            Kind::Write => Packet::Write(
+                self.filename, self.txmode, self.options),
+            Kind::OAck => Packet::OAck(self.options),
+        })
+    }
+
+}
+
+
+/// Constructs `Options` in a chainable style, checking when built that
+/// values are within the ranges their RFCs permit:
+///
+/// ```
+/// # use allenap_libtftp::builder::OptionsBuilder;
+/// # use allenap_libtftp::options::InvalidOption;
+/// let options = OptionsBuilder::new().blksize(1468).timeout(3).build();
+/// assert_eq!("blksize=1468 timeout=3", options.unwrap().to_string());
+/// let options = OptionsBuilder::new().blksize(7).build();
+/// assert_eq!(Err(InvalidOption::Blksize(7)), options);
+/// ```
+#[derive(Clone,Debug,Default)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+
+impl OptionsBuilder {
+
+    pub fn new() -> OptionsBuilder {
+        OptionsBuilder{options: Options::new()}
+    }
+
+    pub fn blksize(mut self, blksize: u16) -> OptionsBuilder {
+        self.options.blksize = Some(blksize);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: u8) -> OptionsBuilder {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    pub fn tsize(mut self, tsize: u64) -> OptionsBuilder {
+        self.options.tsize = Some(tsize);
+        self
+    }
+
+    /// Ask the server for the size of the file, as in a `RRQ`.
+    pub fn tsize_query(self) -> OptionsBuilder {
+        self.tsize(0)
+    }
+
+    pub fn windowsize(mut self, windowsize: u16) -> OptionsBuilder {
+        self.options.windowsize = Some(windowsize);
+        self
+    }
+
+    pub fn rollover(mut self, rollover: u16) -> OptionsBuilder {
+        self.options.rollover = Some(rollover);
+        self
+    }
+
+    pub fn utimeout(mut self, utimeout: u32) -> OptionsBuilder {
+        self.options.utimeout = Some(utimeout);
+        self
+    }
+
+    pub fn multicast(mut self, multicast: Multicast) -> OptionsBuilder {
+        self.options.multicast = Some(multicast);
+        self
+    }
+
+    /// Set an extension option; see `Options::set_extension`.
+    pub fn extension<T: Display>(mut self, name: &str, value: T)
+        -> OptionsBuilder
+    {
+        self.options.set_extension(name, value);
+        self
+    }
+
+    /// Check constraints and build the options.
+    pub fn build(self) -> result::Result<Options, InvalidOption> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+
+}
+
+
+/// Check that option values are within the ranges their RFCs permit.
+fn check_options(options: &Options) -> Result<()> {
+    options.validate().map_err(|error| Error::InvalidOptions(error.into()))
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{OptionsBuilder, PacketBuilder};
+    use super::super::options::{
+        InvalidOption,
+        Multicast,
+        OptionParseError,
+        Options,
+    };
+    use super::super::packet::{Error, Filename, Packet, TransferMode};
+
+    #[test]
+    fn test_build_rrq() {
+        let mut options = Options::new();
+        options.blksize = Some(1468);
+        options.tsize = Some(0);
+        assert_eq!(
+            Packet::Read(
+                Filename::new("kernel"), TransferMode::Octet, options),
+            PacketBuilder::rrq("kernel")
+                .octet().blksize(1468).tsize_query().build().unwrap());
+    }
+
+    #[test]
+    fn test_build_wrq_and_oack() {
+        let mut options = Options::new();
+        options.timeout = Some(3);
+        assert_eq!(
+            Packet::Write(
+                Filename::new("log"), TransferMode::NetASCII,
+                options.clone()),
+            PacketBuilder::wrq("log").netascii().timeout(3).build().unwrap());
+        assert_eq!(
+            Packet::OAck(options),
+            PacketBuilder::oack().timeout(3).build().unwrap());
+    }
+
+    #[test]
+    fn test_build_checks_constraints() {
+        let invalid = |builder: PacketBuilder| match builder.build() {
+            Err(Error::InvalidOptions(OptionParseError::OutOfRange(_))) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        invalid(PacketBuilder::rrq("kernel").blksize(7));
+        invalid(PacketBuilder::rrq("kernel").blksize(65465));
+        invalid(PacketBuilder::rrq("kernel").timeout(0));
+        invalid(PacketBuilder::oack().windowsize(0));
+        invalid(PacketBuilder::oack().rollover(2));
+        invalid(PacketBuilder::oack().utimeout(0));
+        assert!(PacketBuilder::rrq("ker\0nel").build().is_err());
+    }
+
+    #[test]
+    fn test_build_options() {
+        let mut options = Options::new();
+        options.windowsize = Some(4);
+        options.rollover = Some(0);
+        options.multicast = Some(Multicast::Request);
+        options.set_extension("x-foo", 1);
+        assert_eq!(
+            Ok(options),
+            OptionsBuilder::new().windowsize(4).rollover(0)
+                .multicast(Multicast::Request).extension("x-foo", 1).build());
+    }
+
+    #[test]
+    fn test_build_options_checks_constraints() {
+        assert_eq!(
+            Err(InvalidOption::Blksize(7)),
+            OptionsBuilder::new().blksize(7).build());
+        assert_eq!(
+            Err(InvalidOption::Timeout(0)),
+            OptionsBuilder::new().timeout(0).build());
+        assert_eq!(
+            Err(InvalidOption::Windowsize(0)),
+            OptionsBuilder::new().windowsize(0).build());
+        assert_eq!(
+            Err(InvalidOption::Rollover(2)),
+            OptionsBuilder::new().rollover(2).build());
+        assert_eq!(
+            Err(InvalidOption::Utimeout(0)),
+            OptionsBuilder::new().utimeout(0).build());
+    }
+
+}
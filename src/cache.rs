@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+
+/// Keeps small, frequently requested files in memory, evicting those
+/// least recently used to stay within a limit in bytes.
+///
+/// During a mass boot hundreds of clients ask for the same handful of
+/// files, like `pxelinux.0`, its configuration, and iPXE scripts. Put a
+/// cache in an [`rrq::Config`](../rrq/struct.Config.html) to have these
+/// read once rather than on every request.
+///
+/// Each request still looks at the file's metadata, so that a file that
+/// changes, by size or modification time, is read afresh.
+#[derive(Debug)]
+pub struct FileCache {
+    capacity: u64,
+    max_file: u64,
+    state: Mutex<State>,
+}
+
+
+#[derive(Debug,Default)]
+struct State {
+    entries: HashMap<PathBuf, Entry>,
+    /// The total size of the entries.
+    size: u64,
+    /// Incremented on each use, to order entries by recency.
+    clock: u64,
+}
+
+
+#[derive(Debug)]
+struct Entry {
+    data: Arc<[u8]>,
+    modified: Option<time::SystemTime>,
+    used: u64,
+}
+
+
+impl FileCache {
+
+    /// A cache of up to `capacity` bytes in all, holding files of up to
+    /// `max_file` bytes each.
+    pub fn new(capacity: u64, max_file: u64) -> FileCache {
+        FileCache{
+            capacity,
+            max_file: max_file.min(capacity),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// The contents of the file at `path`, from memory if possible, or
+    /// `None` if it's not a regular file or too large to cache.
+    pub fn read(&self, path: &Path) -> io::Result<Option<Arc<[u8]>>> {
+        let metadata = fs::metadata(path)?;
+        if !metadata.is_file() || metadata.len() > self.max_file {
+            return Ok(None);
+        }
+        let modified = metadata.modified().ok();
+        if let Some(data) = self.get(path, metadata.len(), modified) {
+            return Ok(Some(data));
+        }
+        let data: Arc<[u8]> = fs::read(path)?.into();
+        // The file may have grown since its metadata was read.
+        if data.len() as u64 > self.max_file {
+            return Ok(None);
+        }
+        self.insert(path, data.clone(), modified);
+        Ok(Some(data))
+    }
+
+    /// The number of bytes held.
+    pub fn size(&self) -> u64 {
+        self.state.lock().unwrap().size
+    }
+
+    fn get(
+        &self, path: &Path, len: u64, modified: Option<time::SystemTime>)
+        -> Option<Arc<[u8]>>
+    {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        match state.entries.get_mut(path) {
+            Some(entry) if entry.data.len() as u64 == len &&
+                entry.modified == modified => {
+                entry.used = clock;
+                Some(entry.data.clone())
+            },
+            _ => None,
+        }
+    }
+
+    fn insert(
+        &self, path: &Path, data: Arc<[u8]>,
+        modified: Option<time::SystemTime>)
+    {
+        let mut state = self.state.lock().unwrap();
+        if let Some(stale) = state.entries.remove(path) {
+            state.size -= stale.data.len() as u64;
+        }
+        let len = data.len() as u64;
+        while state.size + len > self.capacity {
+            let oldest = state.entries.iter()
+                .min_by_key(|&(_, entry)| entry.used)
+                .map(|(path, _)| path.clone());
+            match oldest.and_then(|path| state.entries.remove(&path)) {
+                Some(evicted) => state.size -= evicted.data.len() as u64,
+                None => return,
+            }
+        }
+        state.clock += 1;
+        let used = state.clock;
+        state.size += len;
+        state.entries.insert(path.to_owned(), Entry{data, modified, used});
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use super::FileCache;
+    use super::super::testing::Scratch;
+
+    /// Write `len` copies of the first byte of `name` to a file of that
+    /// name in `scratch`.
+    fn write(scratch: &Scratch, name: &str, len: usize) -> PathBuf {
+        let path = scratch.join(name);
+        fs::write(&path, vec![name.as_bytes()[0]; len]).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_cache_serves_from_memory() {
+        let scratch = Scratch::new("cache-memory");
+        let path = write(&scratch, "a", 10);
+        let cache = FileCache::new(100, 100);
+        let first = cache.read(&path).unwrap().unwrap();
+        assert_eq!(vec![b'a'; 10], &first[..]);
+        assert_eq!(10, cache.size());
+        let second = cache.read(&path).unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn test_cache_rereads_changed_files() {
+        let scratch = Scratch::new("cache-changed");
+        let path = write(&scratch, "a", 10);
+        let cache = FileCache::new(100, 100);
+        cache.read(&path).unwrap();
+        fs::write(&path, b"changed").unwrap();
+        assert_eq!(b"changed", &cache.read(&path).unwrap().unwrap()[..]);
+        assert_eq!(7, cache.size());
+    }
+
+    #[test]
+    fn test_cache_skips_large_files() {
+        let scratch = Scratch::new("cache-large");
+        let path = write(&scratch, "a", 60);
+        let cache = FileCache::new(100, 50);
+        assert_eq!(None, cache.read(&path).unwrap());
+        assert_eq!(0, cache.size());
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let scratch = Scratch::new("cache-evict");
+        let (a, b, c) = (
+            write(&scratch, "a", 40), write(&scratch, "b", 40),
+            write(&scratch, "c", 40));
+        let cache = FileCache::new(100, 100);
+        cache.read(&a).unwrap();
+        cache.read(&b).unwrap();
+        // Use a again, so that b is the least recently used.
+        cache.read(&a).unwrap();
+        cache.read(&c).unwrap();
+        assert_eq!(80, cache.size());
+        let state = cache.state.lock().unwrap();
+        assert!(state.entries.contains_key(&a));
+        assert!(!state.entries.contains_key(&b));
+        assert!(state.entries.contains_key(&c));
+    }
+
+    #[test]
+    fn test_cache_passes_errors_through() {
+        let scratch = Scratch::new("cache-missing");
+        let cache = FileCache::new(100, 100);
+        assert!(cache.read(&scratch.join("missing")).is_err());
+    }
+
+}
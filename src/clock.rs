@@ -0,0 +1,42 @@
+use std::fmt;
+use std::thread;
+use std::time;
+
+
+/// A source of time.
+///
+/// Transfers, quotas, and transports that wait take the time from a
+/// `Clock` rather than asking the system, so that tests can substitute
+/// [`testing::MockClock`](../testing/struct.MockClock.html) and run
+/// time-outs and retransmissions in no time at all.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> time::Instant;
+    /// Wait for `duration` to pass.
+    fn sleep(&self, duration: time::Duration);
+}
+
+
+impl fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Clock")
+    }
+}
+
+
+/// The system's monotonic clock.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct SystemClock;
+
+
+impl Clock for SystemClock {
+
+    fn now(&self) -> time::Instant {
+        time::Instant::now()
+    }
+
+    fn sleep(&self, duration: time::Duration) {
+        thread::sleep(duration)
+    }
+
+}
@@ -0,0 +1,362 @@
+use std::fmt;
+use std::io;
+use std::net;
+use std::sync::Mutex;
+use std::time;
+
+use openssl::ssl::{
+    HandshakeError,
+    Ssl,
+    SslAcceptor,
+    SslConnector,
+    SslStream,
+};
+
+use super::transport::Transport;
+
+
+/// The largest datagram, headers included, to send during handshakes.
+/// Application data is not fragmented, so `DATA` packets of large block
+/// sizes still go out whole.
+const MTU: u32 = 1400;
+
+/// How long to wait for each flight of the handshake before OpenSSL
+/// retransmits it.
+const HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+/// How many times to wait for each flight of the handshake.
+const HANDSHAKE_ATTEMPTS: u32 = 8;
+
+
+/// A UDP socket connected to one peer, as a stream for OpenSSL to run
+/// over. Each read and write is one datagram.
+#[derive(Debug)]
+struct Datagrams(net::UdpSocket);
+
+
+impl io::Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+}
+
+
+impl io::Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+/// A DTLS session with one peer over a UDP socket.
+///
+/// Both ends must speak DTLS, so this is for running TFTP between
+/// devices that can be configured to, or through a tunnel that wraps it
+/// on their behalf. One session carries everything between the two
+/// ends: a server accepts a session, reads the request with `recv_from`
+/// and then runs `rrq::serve_file_over` or `wrq::receive_over` on the
+/// same transport, rather than on a new socket as it would for plain
+/// TFTP.
+///
+/// `send_to` and `connect` accept only the session's peer. Sending
+/// waits while another thread is receiving, so use a transport from one
+/// thread at a time, as transfers do.
+pub struct DtlsTransport {
+    stream: Mutex<SslStream<Datagrams>>,
+    /// A clone of the socket in `stream`, for setting time-outs without
+    /// taking the lock.
+    socket: net::UdpSocket,
+    peer: net::SocketAddr,
+}
+
+
+impl fmt::Debug for DtlsTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DtlsTransport")
+            .field("socket", &self.socket)
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+
+impl DtlsTransport {
+
+    /// Open a session with the server at `peer` as a client, checking
+    /// that its certificate is valid for `domain`.
+    pub fn connect(
+        socket: net::UdpSocket, peer: net::SocketAddr,
+        connector: &SslConnector, domain: &str)
+        -> io::Result<DtlsTransport>
+    {
+        let mut ssl = connector.configure().map_err(io::Error::other)?
+            .into_ssl(domain).map_err(io::Error::other)?;
+        ssl.set_mtu(MTU).map_err(io::Error::other)?;
+        DtlsTransport::handshake(socket, peer, |stream| ssl.connect(stream))
+    }
+
+    /// Accept a session from the client at `peer` as a server.
+    pub fn accept(
+        socket: net::UdpSocket, peer: net::SocketAddr, acceptor: &SslAcceptor)
+        -> io::Result<DtlsTransport>
+    {
+        let mut ssl = Ssl::new(acceptor.context())
+            .map_err(io::Error::other)?;
+        ssl.set_mtu(MTU).map_err(io::Error::other)?;
+        DtlsTransport::handshake(socket, peer, |stream| ssl.accept(stream))
+    }
+
+    /// Connect `socket` to `peer` and complete the handshake begun by
+    /// `begin`, waiting for each flight in turn.
+    fn handshake<F>(socket: net::UdpSocket, peer: net::SocketAddr, begin: F)
+                    -> io::Result<DtlsTransport>
+        where F: FnOnce(Datagrams)
+                        -> Result<SslStream<Datagrams>,
+                                  HandshakeError<Datagrams>>
+    {
+        socket.connect(peer)?;
+        socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let clone = socket.try_clone()?;
+        let mut result = begin(Datagrams(socket));
+        let mut attempts = 1;
+        let stream = loop {
+            match result {
+                Ok(stream) => break stream,
+                // OpenSSL retransmits its last flight, if it's due, when
+                // the handshake is resumed.
+                Err(HandshakeError::WouldBlock(mid))
+                    if attempts < HANDSHAKE_ATTEMPTS => {
+                    attempts += 1;
+                    result = mid.handshake();
+                },
+                Err(HandshakeError::WouldBlock(_)) => return Err(
+                    io::Error::new(
+                        io::ErrorKind::TimedOut, "DTLS handshake timed out")),
+                Err(HandshakeError::Failure(mid)) => return Err(
+                    io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        format!("DTLS handshake failed: {}", mid.error()))),
+                Err(HandshakeError::SetupFailure(error)) =>
+                    return Err(io::Error::other(error)),
+            }
+        };
+        Ok(DtlsTransport{stream: Mutex::new(stream), socket: clone, peer})
+    }
+
+    /// The peer at the other end of the session.
+    pub fn peer_addr(&self) -> net::SocketAddr {
+        self.peer
+    }
+
+    /// Tell the peer that the session is over.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.stream.lock().unwrap().shutdown().map_err(
+            |error| error.into_io_error().unwrap_or_else(io::Error::other))?;
+        Ok(())
+    }
+
+    fn check_peer(&self, addr: net::SocketAddr) -> io::Result<()> {
+        if addr == self.peer {
+            Ok(())
+        }
+        else {
+            Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("DTLS session is with {}, not {}", self.peer, addr)))
+        }
+    }
+
+}
+
+
+impl Transport for DtlsTransport {
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut stream = self.stream.lock().unwrap();
+        stream.ssl_write(buf).map_err(
+            |error| error.into_io_error().unwrap_or_else(io::Error::other))
+    }
+
+    fn send_to(&self, buf: &[u8], addr: net::SocketAddr) -> io::Result<usize> {
+        self.check_peer(addr)?;
+        self.send(buf)
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<usize>
+    {
+        self.socket.set_read_timeout(timeout)?;
+        let mut stream = self.stream.lock().unwrap();
+        match io::Read::read(&mut *stream, buf)? {
+            // TFTP never sends an empty datagram, so the peer has closed
+            // the session.
+            0 => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted, "DTLS session closed")),
+            size => Ok(size),
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<(usize, net::SocketAddr)>
+    {
+        Ok((self.recv(buf, timeout)?, self.peer))
+    }
+
+    fn connect(&self, addr: net::SocketAddr) -> io::Result<()> {
+        self.check_peer(addr)
+    }
+
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.socket.local_addr()
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::io;
+    use std::net;
+    use std::thread;
+    use std::time;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::ssl::{SslAcceptor, SslConnector, SslMethod};
+    use openssl::x509::{X509, X509NameBuilder};
+    use openssl::x509::extension::SubjectAlternativeName;
+
+    use super::DtlsTransport;
+    use super::super::logging;
+    use super::super::options::Options;
+    use super::super::packet::{
+        BlockNum, Data, Filename, Packet, TransferMode};
+    use super::super::transport::Transport;
+    use super::super::wrq;
+
+    /// A self-signed certificate for `localhost`, and its key.
+    fn certificate() -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let san = SubjectAlternativeName::new().dns("localhost")
+            .build(&cert.x509v3_context(None, None)).unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        (cert.build(), key)
+    }
+
+    /// A server and client in session with each other.
+    fn session() -> (DtlsTransport, DtlsTransport) {
+        let (cert, key) = certificate();
+        let mut acceptor = SslAcceptor::mozilla_intermediate(
+            SslMethod::dtls()).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        let acceptor = acceptor.build();
+        let mut connector = SslConnector::builder(SslMethod::dtls()).unwrap();
+        connector.cert_store_mut().add_cert(cert).unwrap();
+        let connector = connector.build();
+        let server = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (server_addr, client_addr) = (
+            server.local_addr().unwrap(), client.local_addr().unwrap());
+        let accepting = thread::spawn(move || DtlsTransport::accept(
+            server, client_addr, &acceptor));
+        let client = DtlsTransport::connect(
+            client, server_addr, &connector, "localhost").unwrap();
+        (accepting.join().unwrap().unwrap(), client)
+    }
+
+    #[test]
+    fn test_send_and_receive() {
+        let (server, client) = session();
+        client.send(b"hello").unwrap();
+        let mut buf = [0u8; 16];
+        let (size, peer) = server.recv_from(&mut buf, None).unwrap();
+        assert_eq!(b"hello", &buf[..size]);
+        assert_eq!(client.local_addr().unwrap(), peer);
+        server.send_to(b"world", peer).unwrap();
+        assert_eq!(5, client.recv(&mut buf, None).unwrap());
+        assert_eq!(b"world", &buf[..5]);
+    }
+
+    #[test]
+    fn test_only_talks_to_peer() {
+        let (server, _client) = session();
+        let stranger: net::SocketAddr = "127.0.0.1:9".parse().unwrap();
+        assert_eq!(
+            io::ErrorKind::AddrNotAvailable,
+            server.send_to(b"hello", stranger).unwrap_err().kind());
+        assert!(server.connect(stranger).is_err());
+        let mut buf = [0u8; 16];
+        let timeout = Some(time::Duration::from_millis(10));
+        let error = server.recv(&mut buf, timeout).unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut));
+    }
+
+    #[test]
+    fn test_closed_session() {
+        let (server, client) = session();
+        client.shutdown().unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(
+            io::ErrorKind::ConnectionAborted,
+            server.recv(&mut buf, None).unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_receive_over_dtls() {
+        let (server, client) = session();
+        let peer = client.local_addr().unwrap();
+        let receiver = thread::spawn(move || {
+            let logger = logging::discard();
+            let mut buf = [0u8; 64];
+            let (size, _) = server.recv_from(&mut buf, None).unwrap();
+            let (txmode, options) = match Packet::parse(&buf[..size]) {
+                Ok(Packet::Write(_, txmode, options)) => (txmode, options),
+                packet => panic!("Unexpected packet: {:?}", packet),
+            };
+            let mut sink = Vec::new();
+            wrq::receive_over(
+                &server, peer, &mut sink, txmode, options,
+                &wrq::Config::new(), &logger).unwrap();
+            sink
+        });
+        let request = Packet::Write(
+            Filename::new("upload"), TransferMode::Octet, Options::new());
+        client.send(&request.to_vec().unwrap()).unwrap();
+        let timeout = Some(time::Duration::from_secs(5));
+        let mut buf = [0u8; 64];
+        let size = client.recv(&mut buf, timeout).unwrap();
+        assert_eq!(
+            Packet::Ack(BlockNum(0)), Packet::parse(&buf[..size]).unwrap());
+        let size = Packet::Data(BlockNum(1), Data(b"data"))
+            .write(&mut buf).unwrap();
+        client.send(&buf[..size]).unwrap();
+        let size = client.recv(&mut buf, timeout).unwrap();
+        assert_eq!(
+            Packet::Ack(BlockNum(1)), Packet::parse(&buf[..size]).unwrap());
+        assert_eq!(b"data".to_vec(), receiver.join().unwrap());
+    }
+
+}
@@ -0,0 +1,192 @@
+use std::io;
+use std::net;
+use std::sync::Arc;
+
+use super::{Access, Handler, spawn_transfer};
+use super::events::EventSink;
+use super::logging::Logger;
+use super::metrics::Metrics;
+use super::options::Options;
+use super::packet::{Filename, Packet, TransferMode};
+use super::rrq;
+
+
+/// A `Handler` that serves content generated for each request, such as
+/// an iPXE script with the client's address filled in.
+///
+/// For each read request, `generate` is given the client's address, the
+/// filename, and the requested options, and returns the bytes to serve.
+/// They are sent with `rrq::serve_bytes` on a new thread, so `tsize` is
+/// answered correctly. Errors are sent to the client instead, converted
+/// with `Packet::from`, so an error of kind `NotFound` is sent as "file
+/// not found". For example:
+///
+/// ```
+/// # use std::io;
+/// # use allenap_libtftp::dynamic::DynamicHandler;
+/// # use allenap_libtftp::logging;
+/// let handler = DynamicHandler::new(
+///     |peer: std::net::SocketAddr, filename: &_, _: &_| {
+///         match filename.as_bytes() {
+///             b"boot.ipxe" => Ok(format!(
+///                 "#!ipxe\nchain http://boot/{}\n", peer.ip()).into_bytes()),
+///             _ => Err(io::Error::from(io::ErrorKind::NotFound)),
+///         }
+///     },
+///     logging::discard());
+/// ```
+///
+/// `generate` is called from the server's thread, so it should be
+/// quick. Write requests are refused.
+pub struct DynamicHandler<F> {
+    generate: F,
+    config: Arc<rrq::Config>,
+    logger: Logger,
+}
+
+
+impl<F> DynamicHandler<F>
+    where F: Fn(net::SocketAddr, &Filename, &Options) -> io::Result<Vec<u8>>
+{
+
+    pub fn new(generate: F, logger: Logger) -> DynamicHandler<F> {
+        let config = Arc::new(rrq::Config::new());
+        DynamicHandler{generate, config, logger}
+    }
+
+    /// Serve with `config`, rather than the default. Its `metrics` and
+    /// `events` are also those of the handler.
+    pub fn with_config(self, config: Arc<rrq::Config>) -> DynamicHandler<F> {
+        DynamicHandler{config, ..self}
+    }
+
+}
+
+
+impl<F> std::fmt::Debug for DynamicHandler<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("DynamicHandler")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+
+impl<F> Handler for DynamicHandler<F>
+    where F: Fn(net::SocketAddr, &Filename, &Options) -> io::Result<Vec<u8>>
+{
+
+    fn access(&self) -> Access {
+        Access::ReadOnly
+    }
+
+    fn metrics(&self) -> Arc<dyn Metrics> {
+        self.config.metrics.clone()
+    }
+
+    fn events(&self) -> Option<Arc<dyn EventSink>> {
+        self.config.events.clone()
+    }
+
+    fn handle_rrq(
+        &self, _local: net::SocketAddr, remote: net::SocketAddr,
+        filename: Filename, txmode: TransferMode, options: Options)
+        -> Option<Packet<'_>>
+    {
+        let data = match (self.generate)(remote, &filename, &options) {
+            Ok(data) => data,
+            Err(error) => {
+                warn!(
+                    self.logger, "Could not generate {:?}: {}",
+                    filename.to_string_lossy(), error);
+                return Some(Packet::from(&error));
+            },
+        };
+        let config = self.config.clone();
+        let logger = self.logger.clone();
+        spawn_transfer(remote, &self.logger, move || {
+            // Failures are logged by serve_bytes.
+            let _ = rrq::serve_bytes(
+                remote, filename, data, txmode, options, &config, &logger);
+        });
+        None
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::io;
+    use std::net;
+    use std::time;
+
+    use super::DynamicHandler;
+    use super::super::Handler;
+    use super::super::logging;
+    use super::super::options::Options;
+    use super::super::packet::{
+        BlockNum,
+        ErrorCode,
+        Filename,
+        Packet,
+        TransferMode,
+    };
+
+    fn ipxe(peer: net::SocketAddr, filename: &Filename, _: &Options)
+            -> io::Result<Vec<u8>> {
+        match filename.as_bytes() {
+            b"boot.ipxe" => Ok(format!(
+                "#!ipxe\nchain http://boot/{}\n", peer.ip()).into_bytes()),
+            _ => Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+    }
+
+    #[test]
+    fn test_dynamic_handler_serves_generated_content_with_tsize() {
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(time::Duration::from_secs(5))).unwrap();
+        let addr = client.local_addr().unwrap();
+        let handler = DynamicHandler::new(ipxe, logging::discard());
+        let mut options = Options::new();
+        options.tsize = Some(0);
+        let reply = handler.handle_rrq(
+            addr, addr, Filename::new("boot.ipxe"), TransferMode::Octet,
+            options);
+        assert_eq!(None, reply);
+        let expected = b"#!ipxe\nchain http://boot/127.0.0.1\n";
+        let mut buf = [0u8; 516];
+        let (size, server) = client.recv_from(&mut buf).unwrap();
+        match Packet::parse(&buf[..size]).unwrap() {
+            Packet::OAck(options) =>
+                assert_eq!(Some(expected.len() as u64), options.tsize),
+            packet => panic!("Unexpected packet: {:?}", packet),
+        };
+        let size = Packet::Ack(BlockNum(0)).write(&mut buf).unwrap();
+        client.send_to(&buf[..size], server).unwrap();
+        let (size, _) = client.recv_from(&mut buf).unwrap();
+        match Packet::parse(&buf[..size]).unwrap() {
+            Packet::Data(BlockNum(1), data) =>
+                assert_eq!(&expected[..], data.0),
+            packet => panic!("Unexpected packet: {:?}", packet),
+        };
+        let size = Packet::Ack(BlockNum(1)).write(&mut buf).unwrap();
+        client.send_to(&buf[..size], server).unwrap();
+    }
+
+    #[test]
+    fn test_dynamic_handler_replies_with_errors() {
+        let addr: net::SocketAddr = "127.0.0.1:69".parse().unwrap();
+        let handler = DynamicHandler::new(ipxe, logging::discard());
+        match handler.handle_rrq(
+            addr, addr, Filename::new("other"), TransferMode::Octet,
+            Options::new())
+        {
+            Some(Packet::Error(code, _)) =>
+                assert_eq!(ErrorCode::FileNotFound, code),
+            reply => panic!("Unexpected reply: {:?}", reply),
+        };
+    }
+
+}
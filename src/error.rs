@@ -0,0 +1,107 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+use super::packet;
+use super::wrq;
+
+
+/// Any error this library can produce, for applications that would
+/// rather handle one type.
+///
+/// Each of the module-specific error types converts into this with
+/// `From`, so `?` works across them.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum TftpError {
+    /// A packet could not be read or written.
+    Protocol(packet::Error),
+    /// A datagram could not be parsed; see `packet::ParseError`.
+    Parse(packet::ParseError),
+    /// A transfer failed.
+    Transfer(wrq::Error),
+    /// Some other I/O error.
+    Io(io::Error),
+}
+
+
+impl fmt::Display for TftpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TftpError::Protocol(ref error) =>
+                write!(f, "protocol error: {}", error),
+            TftpError::Parse(ref error) =>
+                write!(f, "parse error: {}", error),
+            TftpError::Transfer(ref error) =>
+                write!(f, "transfer error: {}", error),
+            TftpError::Io(ref error) =>
+                write!(f, "i/o error: {}", error),
+        }
+    }
+}
+
+
+impl error::Error for TftpError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            TftpError::Protocol(ref error) => Some(error),
+            TftpError::Parse(ref error) => Some(error),
+            TftpError::Transfer(ref error) => Some(error),
+            TftpError::Io(ref error) => Some(error),
+        }
+    }
+}
+
+
+impl From<packet::Error> for TftpError {
+    fn from(error: packet::Error) -> TftpError {
+        TftpError::Protocol(error)
+    }
+}
+
+
+impl From<packet::ParseError> for TftpError {
+    fn from(error: packet::ParseError) -> TftpError {
+        TftpError::Parse(error)
+    }
+}
+
+
+impl From<wrq::Error> for TftpError {
+    fn from(error: wrq::Error) -> TftpError {
+        TftpError::Transfer(error)
+    }
+}
+
+
+impl From<io::Error> for TftpError {
+    fn from(error: io::Error) -> TftpError {
+        TftpError::Io(error)
+    }
+}
+
+
+pub type Result<T> = result::Result<T, TftpError>;
+
+
+#[cfg(test)]
+mod test {
+
+    use std::error::Error;
+
+    use super::TftpError;
+    use super::super::packet::{self, Packet, Strictness};
+
+    #[test]
+    fn test_source_chain() {
+        let parsed = Packet::parse_detailed(b"\0\x01name", Strictness::Strict);
+        let error = TftpError::from(parsed.unwrap_err());
+        // TftpError -> ParseError -> packet::Error -> packetreader::Error.
+        let parse_error = error.source().unwrap();
+        let packet_error = parse_error.source().unwrap();
+        assert!(packet_error.downcast_ref::<packet::Error>().is_some());
+        assert!(packet_error.source().is_some());
+    }
+
+}
@@ -0,0 +1,84 @@
+use std::fmt;
+use std::net;
+
+use super::metrics::Direction;
+use super::options::Options;
+use super::packet::{BlockNum, ErrorCode, TransferMode};
+use super::progress::{Progress, TransferStats};
+use super::security::SecurityEvent;
+
+
+/// Something that happened to a request or a transfer.
+#[derive(Clone,Debug,PartialEq)]
+#[non_exhaustive]
+pub enum Event {
+    /// A well-formed read or write request arrived at the server. It is
+    /// yet to be passed to the handler, which may reject it.
+    RequestReceived{
+        peer: net::SocketAddr,
+        direction: Direction,
+        filename: String,
+        txmode: TransferMode,
+        options: Options,
+    },
+    /// The handler rejected a read or write request with an `ERROR`
+    /// packet, so no transfer will follow.
+    RequestRejected{
+        peer: net::SocketAddr,
+        direction: Direction,
+        code: ErrorCode,
+        message: String,
+    },
+    /// A transfer has begun. The filename is known to transfers started
+    /// through `rrq::serve_file` and `wrq::receive_file`, not otherwise.
+    TransferStarted{
+        peer: net::SocketAddr,
+        direction: Direction,
+        filename: Option<String>,
+    },
+    /// A packet was retransmitted, either because of a time-out or,
+    /// when receiving, to acknowledge a duplicate block.
+    BlockRetransmitted{
+        peer: net::SocketAddr,
+        direction: Direction,
+        block: BlockNum,
+    },
+    /// A transfer completed successfully.
+    TransferCompleted{
+        peer: net::SocketAddr,
+        direction: Direction,
+        stats: TransferStats,
+    },
+    /// A transfer failed, with the error that ended it.
+    TransferFailed{
+        peer: net::SocketAddr,
+        direction: Direction,
+        progress: Progress,
+        error: String,
+    },
+    /// A request was refused for breaking a security rule. These are
+    /// kept apart from the events above so that they can be sent on to
+    /// a SIEM; see `security::SecurityLog`.
+    Security(SecurityEvent),
+}
+
+
+/// Receives events from the server and from transfers in either
+/// direction, for driving webhooks, audit trails, and the like.
+///
+/// Unlike an `Observer`, which is told of every block, a sink is told
+/// only of the events that matter to a transfer's story. It is called
+/// from the thread doing the work, so it should be quick, handing
+/// anything slow to another thread.
+pub trait EventSink: Send + Sync {
+
+    fn event(&self, event: Event);
+
+}
+
+
+impl fmt::Debug for dyn EventSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EventSink")
+    }
+}
@@ -0,0 +1,290 @@
+use std::net;
+use std::sync::Arc;
+
+use super::{Access, Handler};
+use super::events::EventSink;
+use super::metrics::{Direction, Metrics};
+use super::options::{Duplicates, OutOfRange};
+use super::packet::{Filename, FilenameValidation, Packet, Strictness};
+use super::security::{SecurityEvent, Violation};
+use super::transport::WireObserver;
+
+
+/// Who a client is, as far as a `Gate` can tell.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct Identity<'a> {
+    /// The client's address.
+    pub addr: net::IpAddr,
+    /// The value of the request's token option, if the gate looks for
+    /// one and the client sent it.
+    pub token: Option<&'a str>,
+}
+
+
+/// Decides which files each client may have, or send.
+///
+/// This is implemented for closures, so a device that may fetch only
+/// its own configuration can be described as:
+///
+/// ```
+/// # use allenap_libtftp::gate::Identity;
+/// # use allenap_libtftp::metrics::Direction;
+/// # use allenap_libtftp::packet::Filename;
+/// let own_config = |client: &Identity, _: Direction, filename: &Filename| {
+///     match client.token {
+///         Some(serial) =>
+///             filename.as_bytes() == format!("configs/{}.cfg", serial)
+///                 .as_bytes(),
+///         None => false,
+///     }
+/// };
+/// ```
+///
+/// Anything more than a serial number, such as a per-device secret to
+/// look up and compare, belongs in an implementation of this.
+pub trait Entitlements: Send + Sync {
+
+    /// May `client` transfer `filename` in `direction`?
+    fn permits(
+        &self, client: &Identity, direction: Direction, filename: &Filename)
+        -> bool;
+
+}
+
+
+impl<F> Entitlements for F
+    where F: Fn(&Identity, Direction, &Filename) -> bool + Send + Sync
+{
+    fn permits(
+        &self, client: &Identity, direction: Direction, filename: &Filename)
+        -> bool
+    {
+        self(client, direction, filename)
+    }
+}
+
+
+/// A `Handler` that passes on only requests for files the client is
+/// entitled to, rejecting the rest as access violations.
+///
+/// Clients are identified by address and, if set with
+/// [`with_token_option`](#method.with_token_option), by a token sent as
+/// a request option. That option is removed before the request is
+/// passed to the inner handler. Other requests, and all the other
+/// `Handler` methods, go straight to the inner handler.
+///
+/// Each rejection is also reported to the inner handler's event sink as
+/// an `AccessDenied` security event.
+pub struct Gate<H> {
+    inner: H,
+    entitlements: Arc<dyn Entitlements>,
+    token_option: Option<String>,
+}
+
+
+impl<H: Handler> Gate<H> {
+
+    pub fn new(inner: H, entitlements: Arc<dyn Entitlements>) -> Gate<H> {
+        Gate{inner, entitlements, token_option: None}
+    }
+
+    /// Identify clients also by the value of the option `name`.
+    pub fn with_token_option(self, name: &str) -> Gate<H> {
+        Gate{token_option: Some(name.to_owned()), ..self}
+    }
+
+    /// The handler to which permitted requests are passed.
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+}
+
+
+impl<H> std::fmt::Debug for Gate<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Gate")
+            .field("token_option", &self.token_option)
+            .finish_non_exhaustive()
+    }
+}
+
+
+impl<H: Handler> Handler for Gate<H> {
+
+    fn strictness(&self) -> Strictness {
+        self.inner.strictness()
+    }
+
+    fn duplicate_options(&self) -> Duplicates {
+        self.inner.duplicate_options()
+    }
+
+    fn option_ranges(&self) -> OutOfRange {
+        self.inner.option_ranges()
+    }
+
+    fn filename_validation(&self) -> FilenameValidation {
+        self.inner.filename_validation()
+    }
+
+    fn access(&self) -> Access {
+        self.inner.access()
+    }
+
+    fn wire_observer(&self) -> Option<Arc<dyn WireObserver>> {
+        self.inner.wire_observer()
+    }
+
+    fn metrics(&self) -> Arc<dyn Metrics> {
+        self.inner.metrics()
+    }
+
+    fn events(&self) -> Option<Arc<dyn EventSink>> {
+        self.inner.events()
+    }
+
+    fn handle(
+        &self, local: net::SocketAddr, remote: net::SocketAddr,
+        mut packet: Packet)
+        -> Option<Packet<'_>>
+    {
+        let (direction, filename, options) = match packet {
+            Packet::Read(ref filename, _, ref mut options) =>
+                (Direction::Read, filename, options),
+            Packet::Write(ref filename, _, ref mut options) =>
+                (Direction::Write, filename, options),
+            _ => return self.inner.handle(local, remote, packet),
+        };
+        let token = match self.token_option {
+            Some(ref name) => options.extension(name).map(str::to_owned),
+            None => None,
+        };
+        let client = Identity{addr: remote.ip(), token: token.as_deref()};
+        if !self.entitlements.permits(&client, direction, filename) {
+            SecurityEvent::new(remote, Violation::AccessDenied, "entitlements")
+                .with_direction(direction)
+                .with_filename(filename.to_string_lossy())
+                .report(self.inner.events().as_deref());
+            return Some(Packet::access_violation("access denied"));
+        }
+        if let Some(ref name) = self.token_option {
+            options.unknown.retain(
+                |(option, _)| !option.eq_ignore_ascii_case(name));
+        }
+        self.inner.handle(local, remote, packet)
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::net;
+    use std::sync::Arc;
+
+    use super::{Gate, Identity};
+    use super::super::Handler;
+    use super::super::events::{Event, EventSink};
+    use super::super::metrics::Direction;
+    use super::super::options::Options;
+    use super::super::packet::{Filename, Packet, TransferMode};
+    use super::super::security::{SecurityEvent, Violation};
+    use super::super::testing::EventLog;
+
+    /// Replies with an error carrying the filename it was asked for,
+    /// and the options it was given.
+    struct Echo;
+
+    impl Handler for Echo {
+        fn handle_rrq(
+            &self, _local: net::SocketAddr, _remote: net::SocketAddr,
+            filename: Filename, _txmode: TransferMode, options: Options)
+            -> Option<Packet<'_>>
+        {
+            Some(Packet::file_not_found(format!(
+                "{} {}", filename.to_string_lossy(), options)))
+        }
+    }
+
+    fn read(
+        handler: &dyn Handler, remote: &str, filename: &str, token: &str)
+        -> Option<Packet<'static>>
+    {
+        let local: net::SocketAddr = "127.0.0.1:69".parse().unwrap();
+        let remote: net::SocketAddr = remote.parse().unwrap();
+        let mut options = Options::new();
+        if !token.is_empty() {
+            options.set_extension("serial", token);
+        }
+        let request = Packet::Read(
+            Filename::new(filename), TransferMode::Octet, options);
+        handler.handle(local, remote, request).map(|reply| match reply {
+            Packet::Error(code, message) => Packet::Error(code, message),
+            reply => panic!("Unexpected reply: {:?}", reply),
+        })
+    }
+
+    fn own_config(
+        client: &Identity, direction: Direction, filename: &Filename)
+        -> bool
+    {
+        let expected = match client.token {
+            Some(serial) => format!("configs/{}.cfg", serial),
+            None => format!("configs/{}.cfg", client.addr),
+        };
+        direction == Direction::Read && filename.as_bytes() ==
+            expected.as_bytes()
+    }
+
+    #[test]
+    fn test_gate_identifies_by_token() {
+        let gate = Gate::new(Echo, Arc::new(own_config))
+            .with_token_option("serial");
+        // The token is not passed on.
+        assert_eq!(
+            Some(Packet::file_not_found("configs/ab12.cfg ")),
+            read(&gate, "10.0.0.1:1234", "configs/ab12.cfg", "ab12"));
+        assert_eq!(
+            Some(Packet::access_violation("access denied")),
+            read(&gate, "10.0.0.1:1234", "configs/cd34.cfg", "ab12"));
+    }
+
+    #[test]
+    fn test_gate_identifies_by_address() {
+        let gate = Gate::new(Echo, Arc::new(own_config));
+        assert_eq!(
+            Some(Packet::file_not_found("configs/10.0.0.1.cfg serial=ab12")),
+            read(&gate, "10.0.0.1:1234", "configs/10.0.0.1.cfg", "ab12"));
+        assert_eq!(
+            Some(Packet::access_violation("access denied")),
+            read(&gate, "10.0.0.2:1234", "configs/10.0.0.1.cfg", ""));
+    }
+
+    struct Evented(Arc<EventLog>);
+
+    impl Handler for Evented {
+        fn events(&self) -> Option<Arc<dyn EventSink>> {
+            Some(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_gate_reports_denials() {
+        let events = Arc::new(EventLog::new());
+        let gate = Gate::new(Evented(events.clone()), Arc::new(own_config));
+        read(&gate, "10.0.0.2:1234", "configs/10.0.0.1.cfg", "");
+        assert_eq!(
+            vec![
+                Event::Security(
+                    SecurityEvent::new(
+                        "10.0.0.2:1234".parse().unwrap(),
+                        Violation::AccessDenied, "entitlements")
+                        .with_direction(Direction::Read)
+                        .with_filename("configs/10.0.0.1.cfg")),
+            ],
+            events.events());
+    }
+
+}
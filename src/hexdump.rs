@@ -0,0 +1,62 @@
+use core::fmt;
+
+
+/// Formats bytes in the customary hex dump format: offset, 16 bytes in
+/// hex, and the same bytes as ASCII, one line per 16 bytes.
+///
+/// ```
+/// # use allenap_libtftp::hexdump::HexDump;
+/// assert_eq!(
+///     "0000  00 04 00 01                                      |....|\n",
+///     HexDump(b"\0\x04\0\x01").to_string());
+/// ```
+#[derive(Clone,Copy,Debug)]
+pub struct HexDump<'a>(pub &'a [u8]);
+
+
+impl<'a> fmt::Display for HexDump<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (line, chunk) in self.0.chunks(16).enumerate() {
+            write!(f, "{:04x} ", line * 16)?;
+            for index in 0..16 {
+                match chunk.get(index) {
+                    Some(byte) => write!(f, " {:02x}", byte)?,
+                    None => f.write_str("   ")?,
+                };
+            }
+            f.write_str("  |")?;
+            for &byte in chunk {
+                match byte {
+                    0x20..=0x7e => write!(f, "{}", byte as char)?,
+                    _ => f.write_str(".")?,
+                };
+            }
+            f.write_str("|\n")?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::HexDump;
+
+    #[test]
+    fn test_hexdump_wraps_lines() {
+        let bytes: Vec<u8> = (0x40..0x52).collect();
+        assert_eq!(
+            "0000  40 41 42 43 44 45 46 47 48 49 4a 4b 4c 4d 4e 4f  \
+             |@ABCDEFGHIJKLMNO|\n\
+             0010  50 51                                            \
+             |PQ|\n",
+            HexDump(&bytes).to_string());
+    }
+
+    #[test]
+    fn test_hexdump_empty() {
+        assert_eq!("", HexDump(b"").to_string());
+    }
+
+}
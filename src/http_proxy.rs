@@ -0,0 +1,567 @@
+use std::fs;
+use std::io::{self, Write};
+use std::net;
+use std::path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time;
+
+use ureq;
+
+use super::{Access, Handler, send_error, spawn_transfer};
+use super::events::EventSink;
+use super::logging::Logger;
+use super::metrics::{Direction, Metrics};
+use super::options::Options;
+use super::packet::{ErrorCode, Filename, Packet, TransferMode};
+use super::rrq;
+use super::security::{SecurityEvent, Violation};
+
+
+/// A `Handler` that serves files fetched over HTTP or HTTPS from an
+/// origin, such as an internal artifact server, to firmware that speaks
+/// only TFTP.
+///
+/// Each requested filename is appended to the origin's URL, so with an
+/// origin of `http://artifacts.example/boot`, a request for
+/// `pxelinux.0` fetches `http://artifacts.example/boot/pxelinux.0`.
+/// Filenames with `..` in them are refused, so that requests stay
+/// beneath the origin. The response body is sent to the client as it
+/// arrives, with `tsize` answered from its `Content-Length`, if any. A
+/// 404 is sent to the client as "file not found", a 401 or 403 as an
+/// access violation, and anything else as an undefined error.
+///
+/// With [`with_cache`](#method.with_cache), each file fetched in full is
+/// kept in a local directory and served from there thereafter. Files
+/// are kept until removed from that directory. Write requests are
+/// refused.
+pub struct HttpProxy {
+    origin: String,
+    agent: ureq::Agent,
+    cache: Option<path::PathBuf>,
+    config: Arc<rrq::Config>,
+    logger: Logger,
+}
+
+
+impl HttpProxy {
+
+    /// A proxy for `origin`, a base URL, such as
+    /// `https://artifacts.example/boot`.
+    pub fn new<O: Into<String>>(origin: O, logger: Logger) -> HttpProxy {
+        let origin = origin.into().trim_end_matches('/').to_owned();
+        let agent = agent(DEFAULT_TIMEOUT);
+        let config = Arc::new(rrq::Config::new());
+        HttpProxy{origin, agent, cache: None, config, logger}
+    }
+
+    /// Give up on the origin if connecting to it, or any read of its
+    /// response, takes longer than `timeout`, rather than
+    /// `DEFAULT_TIMEOUT`.
+    pub fn with_timeout(self, timeout: time::Duration) -> HttpProxy {
+        HttpProxy{agent: agent(timeout), ..self}
+    }
+
+    /// Keep files fetched in full in `directory`, which must exist, and
+    /// serve them from there rather than fetching them again.
+    pub fn with_cache<P: Into<path::PathBuf>>(self, directory: P)
+                                              -> HttpProxy {
+        HttpProxy{cache: Some(directory.into()), ..self}
+    }
+
+    /// Serve with `config`, rather than the default. Its `metrics` and
+    /// `events` are also those of the handler.
+    pub fn with_config(self, config: Arc<rrq::Config>) -> HttpProxy {
+        HttpProxy{config, ..self}
+    }
+
+    /// The URL fetched for `filename`, or `None` if it has `..` in it.
+    pub fn url(&self, filename: &Filename) -> Option<String> {
+        relative(filename).map(|path| format!("{}/{}", self.origin, path))
+    }
+
+}
+
+
+impl std::fmt::Debug for HttpProxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("HttpProxy")
+            .field("origin", &self.origin)
+            .field("cache", &self.cache)
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+
+impl Handler for HttpProxy {
+
+    fn access(&self) -> Access {
+        Access::ReadOnly
+    }
+
+    fn metrics(&self) -> Arc<dyn Metrics> {
+        self.config.metrics.clone()
+    }
+
+    fn events(&self) -> Option<Arc<dyn EventSink>> {
+        self.config.events.clone()
+    }
+
+    fn handle_rrq(
+        &self, _local: net::SocketAddr, remote: net::SocketAddr,
+        filename: Filename, txmode: TransferMode, options: Options)
+        -> Option<Packet<'_>>
+    {
+        let cached = match (&self.cache, relative(&filename)) {
+            (Some(directory), Some(path)) if !path.is_empty() =>
+                Some(directory.join(path.replace('/', "%2F"))),
+            _ => None,
+        };
+        let agent = self.agent.clone();
+        let logger = self.logger.clone();
+        relay(
+            self.url(&filename), remote, filename, txmode, options,
+            &self.config, &self.logger,
+            move |url| open(&agent, url, cached, &logger))
+    }
+
+}
+
+
+/// How long to wait to connect to an origin or store, or for any read
+/// of its response, unless told otherwise.
+pub const DEFAULT_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+
+/// An agent that gives up on connecting, or on any read, after
+/// `timeout`.
+pub(crate) fn agent(timeout: time::Duration) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(timeout)
+        .timeout_read(timeout)
+        .build()
+}
+
+
+/// A reader of a file fetched, and its size, if known.
+pub(crate) type Fetched = (Box<dyn io::Read + Send>, Option<u64>);
+
+
+/// Reply to a request to read `filename` from `source`, a URL or key,
+/// which is `None` if the filename has `..` in it. Such requests are
+/// refused. Otherwise `fetch` opens `source` on the transfer's thread,
+/// since that can be slow, and it's served to `remote` with `config`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn relay<F>(
+    source: Option<String>, remote: net::SocketAddr, filename: Filename,
+    txmode: TransferMode, options: Options, config: &Arc<rrq::Config>,
+    logger: &Logger, fetch: F)
+    -> Option<Packet<'static>>
+    where F: FnOnce(&str) -> io::Result<Fetched> + Send + 'static
+{
+    let source = match source {
+        Some(source) => source,
+        None => {
+            warn!(
+                logger, "Refusing {:?}: has ..", filename.to_string_lossy());
+            SecurityEvent::new(remote, Violation::PathTraversal, "has ..")
+                .with_direction(Direction::Read)
+                .with_filename(filename.to_string_lossy())
+                .report(config.events.as_deref());
+            return Some(Packet::access_violation("path traversal"));
+        },
+    };
+    let config = config.clone();
+    let transfer_logger = logger.clone();
+    spawn_transfer(remote, logger, move || {
+        let logger = transfer_logger;
+        let (data, len) = match fetch(&source) {
+            Ok(fetched) => fetched,
+            Err(error) => {
+                warn!(logger, "Could not fetch {}: {}", source, error);
+                if let Err(error) = send_error(
+                    remote, ErrorCode::from(&error), &error.to_string(),
+                    config.wire_observer.as_ref(), &*config.metrics) {
+                    error!(logger, "Could not send error to peer: {}", error);
+                }
+                return;
+            },
+        };
+        // Failures are logged by serve_reader.
+        let _ = rrq::serve_reader(
+            remote, filename, data, len, txmode, options, &config, &logger);
+    });
+    None
+}
+
+
+/// `filename` as a path relative to the origin, without empty or `.`
+/// segments, and with each segment percent-encoded, or `None` if it has
+/// `..` in it.
+///
+/// This also names the file in the cache, once its slashes are encoded
+/// too; a `%` is always encoded, so no two filenames share a name.
+pub(crate) fn relative(filename: &Filename) -> Option<String> {
+    let mut path = String::new();
+    for segment in filename.as_bytes().split(|&byte| byte == b'/') {
+        match segment {
+            b"" | b"." => continue,
+            b".." => return None,
+            _ => {
+                if !path.is_empty() {
+                    path.push('/');
+                }
+                escape(segment, &mut path);
+            },
+        }
+    }
+    Some(path)
+}
+
+
+/// Append `segment` to `path`, percent-encoding all but the characters
+/// that RFC-3986 leaves unreserved.
+pub(crate) fn escape(segment: &[u8], path: &mut String) {
+    for &byte in segment {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' |
+            b'-' | b'.' | b'_' | b'~' => path.push(byte as char),
+            _ => path.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+}
+
+
+/// Open `cached` if there is such a file, or else fetch `url`, keeping
+/// what's fetched in `cached`, if given, once it's been read in full.
+fn open(
+    agent: &ureq::Agent,
+    url: &str,
+    cached: Option<path::PathBuf>,
+    logger: &Logger,
+)
+    -> io::Result<Fetched>
+{
+    if let Some(ref cached) = cached {
+        match fs::File::open(cached) {
+            Ok(file) => {
+                let len = file.metadata().ok().map(|m| m.len());
+                return Ok((Box::new(file), len));
+            },
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => {},
+            Err(error) => return Err(error),
+        }
+    }
+    let response = match agent.get(url).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(code, _)) => return Err(match code {
+            404 | 410 => io::ErrorKind::NotFound.into(),
+            401 | 403 => io::ErrorKind::PermissionDenied.into(),
+            _ => io::Error::other(format!("origin replied {}", code)),
+        }),
+        Err(ureq::Error::Transport(error)) =>
+            return Err(io::Error::other(error.to_string())),
+    };
+    let len = response.header("Content-Length")
+        .and_then(|len| len.parse().ok());
+    info!(logger, "Fetching {} ({:?} bytes)", url, len);
+    let body = response.into_reader();
+    match cached {
+        Some(cached) => match Caching::new(body, cached, len) {
+            Ok(caching) => Ok((Box::new(caching), len)),
+            Err((body, error)) => {
+                warn!(logger, "Not caching {}: {}", url, error);
+                Ok((body, len))
+            },
+        },
+        None => Ok((Box::new(body), len)),
+    }
+}
+
+
+/// Distinguishes the partial files of concurrent fetches.
+static FETCHES: AtomicUsize = AtomicUsize::new(0);
+
+
+/// A reader that writes what it reads into a partial file, and renames
+/// it into place once the end has been reached, if all the bytes
+/// expected were read. Otherwise the partial file is removed.
+struct Caching<R> {
+    inner: R,
+    file: Option<fs::File>,
+    partial: path::PathBuf,
+    path: path::PathBuf,
+    expected: Option<u64>,
+    read: u64,
+}
+
+
+impl<R: io::Read> Caching<R> {
+
+    fn new(inner: R, path: path::PathBuf, expected: Option<u64>)
+           -> Result<Caching<R>, (Box<dyn io::Read + Send>, io::Error)>
+        where R: Send + 'static
+    {
+        // Cached files are named by `relative`, which always encodes a
+        // `#`, so no request can name a partial file.
+        let mut partial = path.clone().into_os_string();
+        partial.push(format!(
+            "#{}.partial", FETCHES.fetch_add(1, Ordering::Relaxed)));
+        let partial = path::PathBuf::from(partial);
+        match fs::File::create(&partial) {
+            Ok(file) => Ok(Caching{
+                inner, file: Some(file), partial, path, expected, read: 0}),
+            Err(error) => Err((Box::new(inner), error)),
+        }
+    }
+
+    /// Stop caching, removing the partial file.
+    fn abandon(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.partial);
+        }
+    }
+
+}
+
+
+impl<R: io::Read> io::Read for Caching<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.inner.read(buf)?;
+        self.read += size as u64;
+        if size == 0 {
+            if self.expected.is_none_or(|expected| expected == self.read) {
+                if let Some(file) = self.file.take() {
+                    if file.sync_all().is_err() ||
+                        fs::rename(&self.partial, &self.path).is_err() {
+                        let _ = fs::remove_file(&self.partial);
+                    }
+                }
+            }
+            self.abandon();
+        }
+        else if let Some(ref mut file) = self.file {
+            if file.write_all(&buf[..size]).is_err() {
+                self.abandon();
+            }
+        }
+        Ok(size)
+    }
+}
+
+
+impl<R> Drop for Caching<R> {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.partial);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time;
+
+    use super::{DEFAULT_TIMEOUT, HttpProxy, agent, open, relative};
+    use super::super::Handler;
+    use super::super::logging;
+    use super::super::options::Options;
+    use super::super::packet::{
+        BlockNum,
+        ErrorCode,
+        Filename,
+        Packet,
+        TransferMode,
+    };
+    use super::super::testing::Scratch;
+
+    fn content() -> Vec<u8> {
+        (0..700).map(|n| (n % 251) as u8).collect()
+    }
+
+    /// Serve `content()` at `/boot/pxelinux.0` and 404s everywhere else,
+    /// counting the requests.
+    fn origin() -> (String, Arc<AtomicUsize>) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin = format!("http://{}/boot/", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                reader.read_line(&mut request).unwrap();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+                let response = match request.split(' ').nth(1) {
+                    Some("/boot/pxelinux.0") => {
+                        let mut response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\
+                             Connection: close\r\n\r\n",
+                            content().len()).into_bytes();
+                        response.extend(content());
+                        response
+                    },
+                    _ => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\
+                           Connection: close\r\n\r\n".to_vec(),
+                };
+                stream.write_all(&response).unwrap();
+            }
+        });
+        (origin, requests)
+    }
+
+    #[test]
+    fn test_url_appends_escaped_filename_to_origin() {
+        let proxy = HttpProxy::new("http://example/boot/", logging::discard());
+        assert_eq!(
+            Some("http://example/boot/efi/grub%20x64.efi".to_owned()),
+            proxy.url(&Filename::new("/efi/./grub x64.efi")));
+        assert_eq!(None, proxy.url(&Filename::new("efi/../../secret")));
+    }
+
+    #[test]
+    fn test_relative_drops_empty_and_dot_segments() {
+        assert_eq!(
+            Some("efi/grub%25.efi".to_owned()),
+            relative(&Filename::new("//efi/./grub%.efi")));
+        assert_eq!(Some("".to_owned()), relative(&Filename::new("/.")));
+        assert_eq!(None, relative(&Filename::new("a/..")));
+    }
+
+    #[test]
+    fn test_open_fetches_then_reads_from_cache() {
+        let (origin, requests) = origin();
+        let cache = Scratch::new("http-cache");
+        let cached = cache.join("pxelinux.0");
+        let agent = agent(DEFAULT_TIMEOUT);
+        let url = format!("{}pxelinux.0", origin);
+        for _ in 0..2 {
+            let (mut data, len) = open(
+                &agent, &url, Some(cached.clone()), &logging::discard())
+                .unwrap();
+            assert_eq!(Some(700), len);
+            let mut buf = Vec::new();
+            data.read_to_end(&mut buf).unwrap();
+            assert_eq!(content(), buf);
+        }
+        assert_eq!(1, requests.load(Ordering::SeqCst));
+        assert_eq!(content(), fs::read(&cached).unwrap());
+        assert_eq!(1, fs::read_dir(&cache).unwrap().count());
+    }
+
+    #[test]
+    fn test_open_caches_nothing_unless_read_in_full() {
+        let (origin, _) = origin();
+        let cache = Scratch::new("http-partial");
+        let cached = cache.join("pxelinux.0");
+        let agent = agent(DEFAULT_TIMEOUT);
+        let url = format!("{}pxelinux.0", origin);
+        let (mut data, _) = open(
+            &agent, &url, Some(cached), &logging::discard()).unwrap();
+        data.read_exact(&mut [0u8; 100]).unwrap();
+        drop(data);
+        assert_eq!(0, fs::read_dir(&cache).unwrap().count());
+    }
+
+    #[test]
+    fn test_partial_files_are_not_named_as_requests_are() {
+        let (origin, _) = origin();
+        let cache = Scratch::new("http-names");
+        let agent = agent(DEFAULT_TIMEOUT);
+        let url = format!("{}pxelinux.0", origin);
+        let (mut data, _) = open(
+            &agent, &url, Some(cache.join("pxelinux.0")),
+            &logging::discard()).unwrap();
+        data.read_exact(&mut [0u8; 100]).unwrap();
+        let entry = fs::read_dir(&cache).unwrap().next().unwrap().unwrap();
+        let partial = entry.file_name().into_string().unwrap();
+        assert!(partial.starts_with("pxelinux.0"));
+        assert_ne!(
+            Some(partial.clone()),
+            relative(&Filename::new(partial)).map(
+                |path| path.replace('/', "%2F")));
+    }
+
+    #[test]
+    fn test_handler_proxies_with_tsize() {
+        let (origin, _) = origin();
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(time::Duration::from_secs(5))).unwrap();
+        let addr = client.local_addr().unwrap();
+        let proxy = HttpProxy::new(origin, logging::discard());
+        let mut options = Options::new();
+        options.tsize = Some(0);
+        let reply = proxy.handle_rrq(
+            addr, addr, Filename::new("pxelinux.0"), TransferMode::Octet,
+            options);
+        assert_eq!(None, reply);
+        let mut buf = [0u8; 516];
+        let (size, server) = client.recv_from(&mut buf).unwrap();
+        match Packet::parse(&buf[..size]).unwrap() {
+            Packet::OAck(options) => assert_eq!(Some(700), options.tsize),
+            packet => panic!("Unexpected packet: {:?}", packet),
+        };
+        let size = Packet::Ack(BlockNum(0)).write(&mut buf).unwrap();
+        client.send_to(&buf[..size], server).unwrap();
+        let (size, _) = client.recv_from(&mut buf).unwrap();
+        match Packet::parse(&buf[..size]).unwrap() {
+            Packet::Data(BlockNum(1), data) =>
+                assert_eq!(&content()[..512], data.0),
+            packet => panic!("Unexpected packet: {:?}", packet),
+        };
+        let size = Packet::error(ErrorCode::NotDefined, "done")
+            .write(&mut buf).unwrap();
+        client.send_to(&buf[..size], server).unwrap();
+    }
+
+    #[test]
+    fn test_handler_sends_not_found() {
+        let (origin, _) = origin();
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(time::Duration::from_secs(5))).unwrap();
+        let addr = client.local_addr().unwrap();
+        let proxy = HttpProxy::new(origin, logging::discard());
+        let reply = proxy.handle_rrq(
+            addr, addr, Filename::new("missing"), TransferMode::Octet,
+            Options::new());
+        assert_eq!(None, reply);
+        let mut buf = [0u8; 516];
+        let size = client.recv(&mut buf).unwrap();
+        match Packet::parse(&buf[..size]).unwrap() {
+            Packet::Error(code, _) => assert_eq!(ErrorCode::FileNotFound, code),
+            packet => panic!("Unexpected packet: {:?}", packet),
+        };
+    }
+
+    #[test]
+    fn test_handler_refuses_traversal() {
+        let addr: net::SocketAddr = "127.0.0.1:69".parse().unwrap();
+        let proxy = HttpProxy::new("http://example/boot", logging::discard());
+        match proxy.handle_rrq(
+            addr, addr, Filename::new("../secret"), TransferMode::Octet,
+            Options::new())
+        {
+            Some(Packet::Error(code, _)) =>
+                assert_eq!(ErrorCode::AccessViolation, code),
+            reply => panic!("Unexpected reply: {:?}", reply),
+        };
+    }
+
+}
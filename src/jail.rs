@@ -0,0 +1,316 @@
+use std::fs;
+use std::io;
+use std::net;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use super::events::EventSink;
+use super::metrics::Direction;
+use super::packet::Filename;
+use super::security::{SecurityEvent, Violation};
+
+
+/// What to do with symbolic links met while resolving a filename.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Symlinks {
+    /// Refuse any filename that goes through a symbolic link.
+    Deny,
+    /// Follow symbolic links, but only to somewhere under the root.
+    WithinRoot,
+    /// Follow symbolic links wherever they go. Only the filename as
+    /// given by the client is confined to the root.
+    Follow,
+}
+
+
+/// Confines requested filenames to a root directory.
+///
+/// Each filename is resolved one component at a time, relative to the
+/// root, even if it looks absolute. Components of `..` are refused
+/// outright, and symbolic links are dealt with according to a
+/// `Symlinks` policy. Components that do not exist yet, as for an
+/// upload, are appended as given.
+///
+/// This checks the filesystem as it stands when called. A local user
+/// able to change the tree beneath the root between resolving and
+/// opening a path can still race it; don't let them.
+#[derive(Clone,Debug)]
+pub struct RootJail {
+    root: PathBuf,
+    symlinks: Symlinks,
+    events: Option<Arc<dyn EventSink>>,
+}
+
+
+/// Why a path could not be resolved.
+enum Failure {
+    /// It broke the rule given.
+    Denied(&'static str),
+    /// The filesystem said no.
+    Io(io::Error),
+}
+
+
+impl From<io::Error> for Failure {
+    fn from(error: io::Error) -> Failure {
+        Failure::Io(error)
+    }
+}
+
+
+impl RootJail {
+
+    /// Confine filenames to `root`, which must exist.
+    pub fn new<P: AsRef<Path>>(root: P, symlinks: Symlinks)
+                               -> io::Result<RootJail> {
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display())));
+        }
+        Ok(RootJail{root, symlinks, events: None})
+    }
+
+    /// Report filenames that try to escape the root, when resolved
+    /// with [`resolve_for`](#method.resolve_for), to `events` as
+    /// `PathTraversal` security events.
+    pub fn with_events(self, events: Arc<dyn EventSink>) -> RootJail {
+        RootJail{events: Some(events), ..self}
+    }
+
+    /// The root, canonicalized.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The policy for symbolic links.
+    pub fn symlinks(&self) -> Symlinks {
+        self.symlinks
+    }
+
+    /// Resolve `filename` from a request to a path under the root.
+    ///
+    /// Fails with `PermissionDenied`, which becomes an access violation
+    /// when converted to a `Packet`, if the filename would escape the
+    /// root or goes through a symbolic link the policy does not allow.
+    pub fn resolve(&self, filename: &Filename) -> io::Result<PathBuf> {
+        self.resolve_path(&filename.to_path())
+    }
+
+    /// Resolve `filename` from `peer`'s request to transfer it in
+    /// `direction`; see `resolve`. A filename that tries to escape the
+    /// root is also reported as a security event, if there's a sink.
+    pub fn resolve_for(
+        &self, peer: net::SocketAddr, direction: Direction,
+        filename: &Filename)
+        -> io::Result<PathBuf>
+    {
+        let path = filename.to_path();
+        self.walk(&path).map_err(|failure| match failure {
+            Failure::Denied(why) => {
+                SecurityEvent::new(peer, Violation::PathTraversal, why)
+                    .with_direction(direction)
+                    .with_filename(filename.to_string_lossy())
+                    .report(self.events.as_deref());
+                denied(&path, why)
+            },
+            Failure::Io(error) => error,
+        })
+    }
+
+    /// Resolve `path` to a path under the root; see `resolve`.
+    pub fn resolve_path(&self, path: &Path) -> io::Result<PathBuf> {
+        self.walk(path).map_err(|failure| match failure {
+            Failure::Denied(why) => denied(path, why),
+            Failure::Io(error) => error,
+        })
+    }
+
+    fn walk(&self, path: &Path) -> Result<PathBuf, Failure> {
+        let mut resolved = self.root.clone();
+        // Once a component is missing, those after it are too.
+        let mut missing = false;
+        for component in path.components() {
+            match component {
+                Component::Prefix(_) | Component::RootDir |
+                Component::CurDir => continue,
+                Component::ParentDir => return Err(Failure::Denied("has ..")),
+                Component::Normal(name) => resolved.push(name),
+            };
+            if missing {
+                continue;
+            }
+            match fs::symlink_metadata(&resolved) {
+                Ok(ref metadata) if metadata.file_type().is_symlink() => {
+                    resolved = self.follow(&resolved)?;
+                },
+                Ok(_) => {},
+                Err(ref error) if error.kind() == io::ErrorKind::NotFound =>
+                    missing = true,
+                Err(error) => return Err(error.into()),
+            };
+        }
+        Ok(resolved)
+    }
+
+    /// Follow the symbolic link at `link`.
+    fn follow(&self, link: &Path) -> Result<PathBuf, Failure> {
+        match self.symlinks {
+            Symlinks::Deny => Err(Failure::Denied("goes through a symlink")),
+            Symlinks::WithinRoot => {
+                let target = fs::canonicalize(link)?;
+                if target.starts_with(&self.root) {
+                    Ok(target)
+                }
+                else {
+                    Err(Failure::Denied("links outside the root"))
+                }
+            },
+            Symlinks::Follow => Ok(fs::canonicalize(link)?),
+        }
+    }
+
+}
+
+
+fn denied(path: &Path, why: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("{} {}", path.display(), why))
+}
+
+
+#[cfg(all(test, unix))]
+mod test {
+
+    use std::fs;
+    use std::io;
+    use std::net;
+    use std::os::unix::fs::symlink;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use super::{RootJail, Symlinks};
+    use super::super::events::Event;
+    use super::super::metrics::Direction;
+    use super::super::packet::Filename;
+    use super::super::security::{SecurityEvent, Violation};
+    use super::super::testing::{EventLog, Scratch};
+
+    /// A scratch directory holding a root and a file outside it.
+    fn scratch(name: &str) -> Scratch {
+        let scratch = Scratch::new(&format!("jail-{}", name));
+        fs::create_dir_all(scratch.join("root/boot")).unwrap();
+        fs::write(scratch.join("root/boot/pxelinux.0"), b"boot").unwrap();
+        fs::write(scratch.join("secret"), b"secret").unwrap();
+        scratch
+    }
+
+    fn jail(scratch: &Scratch, symlinks: Symlinks) -> RootJail {
+        RootJail::new(scratch.join("root"), symlinks).unwrap()
+    }
+
+    fn kind(result: io::Result<PathBuf>) -> io::ErrorKind {
+        result.unwrap_err().kind()
+    }
+
+    #[test]
+    fn test_resolve_confines_to_root() {
+        let scratch = scratch("confines");
+        let jail = jail(&scratch, Symlinks::Deny);
+        let expected = scratch.join("root").join("boot/pxelinux.0");
+        for filename in &[
+            "boot/pxelinux.0", "/boot/pxelinux.0", "./boot//pxelinux.0",
+        ] {
+            assert_eq!(
+                expected, jail.resolve(&Filename::new(*filename)).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_resolve_denies_parent_components() {
+        let scratch = scratch("parent");
+        let jail = jail(&scratch, Symlinks::Follow);
+        for filename in &["../secret", "boot/../../secret", "boot/.."] {
+            assert_eq!(
+                io::ErrorKind::PermissionDenied,
+                kind(jail.resolve_path(Path::new(filename))));
+        }
+    }
+
+    #[test]
+    fn test_resolve_for_reports_traversal() {
+        let scratch = scratch("report");
+        let events = Arc::new(EventLog::new());
+        let jail = jail(&scratch, Symlinks::Deny).with_events(events.clone());
+        let peer: net::SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let filename = Filename::new("boot/../../secret");
+        assert_eq!(
+            io::ErrorKind::PermissionDenied,
+            kind(jail.resolve_for(peer, Direction::Read, &filename)));
+        jail.resolve_for(peer, Direction::Read, &Filename::new("boot"))
+            .unwrap();
+        assert_eq!(
+            vec![
+                Event::Security(
+                    SecurityEvent::new(peer, Violation::PathTraversal, "has ..")
+                        .with_direction(Direction::Read)
+                        .with_filename("boot/../../secret")),
+            ],
+            events.events());
+    }
+
+    #[test]
+    fn test_resolve_appends_missing_components() {
+        let scratch = scratch("missing");
+        let jail = jail(&scratch, Symlinks::Deny);
+        assert_eq!(
+            scratch.join("root").join("new/upload"),
+            jail.resolve_path(Path::new("new/upload")).unwrap());
+    }
+
+    #[test]
+    fn test_resolve_with_symlinks_denied() {
+        let scratch = scratch("deny");
+        symlink("boot", scratch.join("root/link")).unwrap();
+        let jail = jail(&scratch, Symlinks::Deny);
+        assert_eq!(
+            io::ErrorKind::PermissionDenied,
+            kind(jail.resolve_path(Path::new("link/pxelinux.0"))));
+    }
+
+    #[test]
+    fn test_resolve_with_symlinks_within_root() {
+        let scratch = scratch("within");
+        symlink("boot", scratch.join("root/link")).unwrap();
+        symlink(scratch.join("secret"), scratch.join("root/out")).unwrap();
+        let jail = jail(&scratch, Symlinks::WithinRoot);
+        assert_eq!(
+            scratch.join("root").join("boot/pxelinux.0"),
+            jail.resolve_path(Path::new("link/pxelinux.0")).unwrap());
+        assert_eq!(
+            io::ErrorKind::PermissionDenied,
+            kind(jail.resolve_path(Path::new("out"))));
+    }
+
+    #[test]
+    fn test_resolve_with_symlinks_followed() {
+        let scratch = scratch("follow");
+        symlink(scratch.join("secret"), scratch.join("root/out")).unwrap();
+        let jail = jail(&scratch, Symlinks::Follow);
+        assert_eq!(
+            scratch.join("secret"),
+            jail.resolve_path(Path::new("out")).unwrap());
+    }
+
+    #[test]
+    fn test_root_must_be_a_directory() {
+        let scratch = scratch("file");
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            RootJail::new(scratch.join("secret"), Symlinks::Deny)
+                .unwrap_err().kind());
+    }
+
+}
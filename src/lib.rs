@@ -1,89 +1,461 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg_attr(not(feature = "std"), macro_use)]
+extern crate alloc;
+#[cfg(feature = "arbitrary")]
+extern crate arbitrary;
+#[cfg(feature = "bytes")]
+extern crate bytes;
+#[cfg(feature = "archive")]
+extern crate flate2;
+#[cfg(any(feature = "auth", feature = "s3"))]
+extern crate hmac;
+#[cfg(feature = "proptest")]
+extern crate proptest;
+#[cfg(feature = "regex")]
+extern crate regex;
+#[cfg(feature = "serde")]
 #[macro_use]
+extern crate serde;
+#[cfg(any(feature = "gso", feature = "recvmmsg"))]
+extern crate libc;
+#[cfg(feature = "log")]
+extern crate log;
+#[cfg(feature = "dtls")]
+extern crate openssl;
+#[cfg(feature = "slog")]
 extern crate slog;
+#[cfg(any(feature = "auth", feature = "s3"))]
+extern crate sha2;
+#[cfg(feature = "smoltcp")]
+extern crate smoltcp;
+#[cfg(feature = "archive")]
+extern crate tar;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "http")]
+extern crate ureq;
+#[cfg(feature = "archive")]
+extern crate zip;
+#[cfg(feature = "std")]
+extern crate core;
 
+#[cfg(feature = "std")]
 use std::io;
+#[cfg(feature = "std")]
 use std::net;
-use std::error::Error;
+#[cfg(feature = "std")]
+use std::panic;
+#[cfg(feature = "std")]
+use std::sync::Arc;
+#[cfg(feature = "std")]
+use std::thread;
+
+// First, so that its macros can be used by the modules that follow.
+#[cfg(feature = "std")]
+#[macro_use]
+pub mod logging;
 
+#[cfg(feature = "archive")]
+pub mod archive;
+#[cfg(feature = "std")]
+pub mod audit;
+#[cfg(feature = "auth")]
+pub mod auth;
+#[cfg(feature = "std")]
+pub mod budget;
+pub mod builder;
+#[cfg(feature = "std")]
+pub mod cache;
+#[cfg(feature = "std")]
+pub mod clock;
+#[cfg(feature = "dtls")]
+pub mod dtls_transport;
+#[cfg(feature = "std")]
+pub mod dynamic;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "std")]
+pub mod events;
+#[cfg(feature = "std")]
+pub mod gate;
+pub mod hexdump;
+#[cfg(feature = "http")]
+pub mod http_proxy;
+#[cfg(feature = "std")]
+pub mod jail;
+#[cfg(feature = "std")]
+pub mod limits;
+#[cfg(feature = "std")]
+pub mod metrics;
+#[cfg(feature = "std")]
+pub mod mtftp;
+pub mod netascii;
 pub mod options;
 pub mod packet;
-mod packetreader;
-mod packetwriter;
+pub mod packetreader;
+pub mod packetwriter;
+#[cfg(feature = "std")]
+pub mod pcap;
+#[cfg(feature = "std")]
+pub mod pool;
+#[cfg(feature = "std")]
+pub mod progress;
+#[cfg(feature = "std")]
+pub mod pxe;
+#[cfg(feature = "std")]
+pub mod quirks;
+#[cfg(feature = "std")]
+pub mod quota;
+#[cfg(feature = "std")]
+pub mod readahead;
+#[cfg(feature = "std")]
+pub mod rewrite;
+#[cfg(feature = "std")]
+pub mod roots;
+#[cfg(feature = "std")]
 pub mod rrq;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "std")]
+pub mod security;
+#[cfg(feature = "std")]
+pub mod session;
+#[cfg(feature = "smoltcp")]
+pub mod smoltcp_transport;
+#[cfg(feature = "proptest")]
+pub mod strategy;
+#[cfg(feature = "std")]
+pub mod testing;
+#[cfg(feature = "testutil")]
+pub mod testutil;
+#[cfg(feature = "std")]
+pub mod transport;
+#[cfg(feature = "std")]
+pub mod wrq;
 
+#[cfg(feature = "std")]
+pub use self::error::TftpError;
+
+#[cfg(feature = "std")]
+use self::logging::Logger;
+#[cfg(feature = "std")]
+use self::events::{Event, EventSink};
+#[cfg(feature = "std")]
+use self::security::{SecurityEvent, Violation};
+#[cfg(feature = "std")]
+use self::metrics::{Direction, Metrics, NoMetrics};
+#[cfg(feature = "std")]
 use self::options::Options;
+#[cfg(feature = "std")]
 use self::packet::{Filename, Packet, TransferMode};
+#[cfg(feature = "std")]
+use self::transport::{Tap, Transport, WireObserver};
+
+
+/// How many request datagrams `serve_over` receives at once, when its
+/// transport supports it; see `Transport::recv_from_many`.
+#[cfg(feature = "std")]
+const REQUEST_BATCH: usize = 32;
 
 
 /// Starts a TFTP server at the given address.
 ///
 /// Well-formed requests are passed to `handler`, and all logging is
-/// handled by `logger`.
+/// handled by `logger`. Should the handler panic, the panic is logged,
+/// the request is answered with an `ERROR`, and the server carries on;
+/// see `spawn_transfer` for doing likewise with transfers.
+#[cfg(feature = "std")]
 pub fn serve(
-    addr: net::SocketAddr, handler: &Handler, logger: &slog::Logger)
+    addr: net::SocketAddr, handler: &dyn Handler, logger: &Logger)
     -> io::Result<()>
 {
     let socket = net::UdpSocket::bind(addr)?;
+    serve_over(&socket, handler, logger)
+}
+
+
+/// Starts a TFTP server on the given transport.
+///
+/// This is `serve` for transports other than a UDP socket; see
+/// `Transport`.
+#[cfg(feature = "std")]
+pub fn serve_over(
+    transport: &dyn Transport, handler: &dyn Handler, logger: &Logger)
+    -> io::Result<()>
+{
+    let tap;
+    let transport: &dyn Transport = match handler.wire_observer() {
+        Some(observer) => {
+            tap = Tap::new(transport, observer);
+            &tap
+        },
+        None => transport,
+    };
+    let metrics = handler.metrics();
+    let events = handler.events();
+    let access = handler.access();
+    let addr = transport.local_addr()?;
     info!(logger, "Listening"; "address" => format!("{}", addr));
 
     // RFC-2347 says "The maximum size of a request packet is 512 octets."
-    let mut bufin = [0; 512];
+    let mut bufin = [[0; 512]; REQUEST_BATCH];
     let mut bufout = [0; 4 + 512];
     loop {
-        match socket.recv_from(&mut bufin) {
-            Ok((size, src)) => {
-                match Packet::parse(&mut bufin[..size]) {
-                    Ok(packet) => {
-                        match handler.handle(addr, src, packet) {
-                            Some(packet) => {
-                                let size = packet.write(&mut bufout)?;
-                                socket.send_to(&bufout[..size], &src)?;
+        let mut bufs: Vec<&mut [u8]> =
+            bufin.iter_mut().map(|buf| &mut buf[..]).collect();
+        let datagrams = match transport.recv_from_many(&mut bufs, None) {
+            Ok(datagrams) => datagrams,
+            Err(ref error) if transient(error) => {
+                warn!(
+                    logger, "Could not receive requests";
+                    "error" => error.to_string());
+                metrics.io_error();
+                continue;
+            },
+            Err(error) => return Err(error),
+        };
+        for (buf, (size, src)) in bufs.iter().zip(datagrams) {
+            let packet = Packet::parse_detailed_with(
+                &buf[..size], handler.strictness(), handler.option_ranges(),
+                handler.duplicate_options());
+            match packet {
+                Ok(packet) => {
+                    let direction = match packet {
+                        Packet::Read(..) => Some(Direction::Read),
+                        Packet::Write(..) => Some(Direction::Write),
+                        _ => None,
+                    };
+                    if let Some(ref sink) = events {
+                        received(&**sink, src, &packet);
+                    };
+                    let reply = match direction {
+                        Some(direction) if !access.allows(direction) => {
+                            if let Some(ref sink) = events {
+                                denied(&**sink, src, access, &packet);
+                            };
+                            Some(Packet::access_violation(match direction {
+                                Direction::Read => "read not permitted",
+                                Direction::Write => "write not permitted",
+                            }))
+                        },
+                        _ => match panic::catch_unwind(panic::AssertUnwindSafe(
+                            || handler.handle(addr, src, packet)))
+                        {
+                            Ok(reply) => reply,
+                            Err(panic) => {
+                                error!(
+                                    logger, "Handler panicked: {}",
+                                    panic_message(&*panic);
+                                    "peer" => src.to_string());
+                                Some(internal_error())
                             },
-                            None => {},
+                        },
+                    };
+                    if let Some(packet) = reply {
+                        let sent = packet.write(&mut bufout)
+                            .map_err(io::Error::from)
+                            .and_then(|size| transport.send_to(
+                                &bufout[..size], src));
+                        if let Err(error) = sent {
+                            warn!(
+                                logger, "Could not send reply";
+                                "peer" => src.to_string(),
+                                "error" => error.to_string());
+                            metrics.io_error();
+                            continue;
                         };
-                    },
-                    Err(error) => warn!(
+                        if let Packet::Error(code, ref message) = packet {
+                            metrics.error(code);
+                            if let (Some(sink), Some(direction)) =
+                                (&events, direction)
+                            {
+                                sink.event(Event::RequestRejected{
+                                    peer: src, direction, code,
+                                    message: message.0.clone(),
+                                });
+                            };
+                        };
+                    };
+                },
+                Err(error) => {
+                    warn!(
                         logger, "Ignoring malformed packet";
-                        "error" => error.description()),
-                }
-            },
-            Err(error) => return Err(error),
+                        "peer" => src.to_string(),
+                        "error" => error.to_string(),
+                        "datagram" => error.hexdump());
+                    metrics.malformed_packet();
+                },
+            }
         }
     };
 }
 
 
+/// Is `error`, from receiving requests, one that the next attempt may
+/// not see? Replies to earlier requests that could not be delivered,
+/// for example, may surface as a connection reset or refusal.
+#[cfg(feature = "std")]
+fn transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::Interrupted |
+        io::ErrorKind::WouldBlock |
+        io::ErrorKind::TimedOut |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::ConnectionRefused)
+}
+
+
+/// Which kinds of request a server accepts; see `Handler::access`.
+#[cfg(feature = "std")]
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Access {
+    /// Accept both read and write requests.
+    ReadWrite,
+    /// Reject write requests.
+    ReadOnly,
+    /// Reject read requests.
+    WriteOnly,
+}
+
+
+#[cfg(feature = "std")]
+impl Access {
+
+    /// Are requests in `direction` accepted?
+    pub fn allows(self, direction: Direction) -> bool {
+        matches!(
+            (self, direction),
+            (Access::ReadWrite, _) |
+            (Access::ReadOnly, Direction::Read) |
+            (Access::WriteOnly, Direction::Write))
+    }
+
+}
+
+
 /// A TFTP handler to which requests are passed once they've been
 /// parsed. A handler can choose to ignore, reject (with an error), or
 /// serve each request that comes in.
+#[cfg(feature = "std")]
 pub trait Handler {
 
+    /// How to parse incoming packets.
+    ///
+    /// By default packets with unrecognised operation codes are
+    /// discarded before reaching the handler. Return
+    /// `Strictness::Lenient` to have them passed to
+    /// [`handle_other`](#method.handle_other) as `Packet::Unknown`
+    /// instead.
+    fn strictness(&self) -> packet::Strictness {
+        packet::Strictness::Strict
+    }
+
+    /// How to treat options that appear more than once in a request.
+    ///
+    /// By default the last value given is used. Return
+    /// `Duplicates::Reject` to have such requests discarded as
+    /// malformed.
+    fn duplicate_options(&self) -> options::Duplicates {
+        options::Duplicates::LastWins
+    }
+
+    /// How to treat option values outside the ranges their RFCs permit.
+    ///
+    /// By default requests with such values are discarded as malformed.
+    /// Return `OutOfRange::Clamp` or `OutOfRange::Ignore` to have them
+    /// passed on with those values clamped or dropped instead.
+    fn option_ranges(&self) -> options::OutOfRange {
+        options::OutOfRange::Reject
+    }
+
+    /// How to validate filenames in requests.
+    ///
+    /// By default any filename is accepted. Return
+    /// `FilenameValidation::ASCII` to have requests for non-ASCII
+    /// filenames rejected as access violations without consulting the
+    /// other methods.
+    fn filename_validation(&self) -> packet::FilenameValidation {
+        packet::FilenameValidation::Bytes
+    }
+
+    /// Which requests the server accepts.
+    ///
+    /// By default both read and write requests are passed on. Return
+    /// `Access::ReadOnly` or `Access::WriteOnly` to have the server
+    /// reject the other kind as access violations, without consulting
+    /// [`handle`](#method.handle) or any other method, so that a bug in
+    /// the handler cannot let them through. This is asked once, when
+    /// the server starts.
+    fn access(&self) -> Access {
+        Access::ReadWrite
+    }
+
+    /// Shown every datagram the server receives and every reply it
+    /// sends, as raw bytes, before any parsing.
+    ///
+    /// By default there is none. Transfers are observed separately; see
+    /// the `wire_observer` field of `rrq::Config` and `wrq::Config`.
+    fn wire_observer(&self) -> Option<Arc<dyn WireObserver>> {
+        None
+    }
+
+    /// Counts malformed requests, and errors sent in reply to requests.
+    ///
+    /// By default these go nowhere. Transfers are counted separately;
+    /// see the `metrics` field of `rrq::Config` and `wrq::Config`.
+    fn metrics(&self) -> Arc<dyn Metrics> {
+        Arc::new(NoMetrics)
+    }
+
+    /// Sent an event for each well-formed read or write request, and
+    /// for each of those rejected with an `ERROR` packet.
+    ///
+    /// By default there is none. Transfers send their events
+    /// separately; see the `events` field of `rrq::Config` and
+    /// `wrq::Config`.
+    fn events(&self) -> Option<Arc<dyn EventSink>> {
+        None
+    }
+
     /// Handle a new, well-formed, TFTP request.
     ///
     /// The default implementation calls
     /// [`handle_rrq`](#method.handle_rrq) for a read request and
     /// [`handle_wrq`](#method.handle_wrq) for a write request, and
     /// [`handle_other`](#method.handle_other) for everything else.
+    /// Requests using the obsolete `mail` transfer mode are rejected as
+    /// illegal operations, and requests with filenames that fail
+    /// [`filename_validation`](#method.filename_validation) as access
+    /// violations, without consulting the other methods.
     ///
     /// In case of an error, this can return a `Packet` representing the
     /// error to be sent to the other side. For example:
     ///
     /// ```
     /// # use allenap_libtftp::packet;
-    /// Some(packet::Packet::Error(
-    ///     packet::ErrorCode::AccessViolation,
-    ///     packet::ErrorMessage("read not supported".to_owned()),
-    /// ));
+    /// Some(packet::Packet::access_violation("read not supported"));
     /// ```
     ///
+    /// An `io::Error` can be converted into an `ERROR` packet with the
+    /// nearest matching error code using `Packet::from`.
+    ///
     /// Use this when the error occurs prior the commencing the
     /// transfer; once the transfer has begin, send errors via the
     /// channel created for the transfer.
     fn handle(
         &self, local: net::SocketAddr, remote: net::SocketAddr, packet: Packet)
-        -> Option<Packet>
+        -> Option<Packet<'_>>
     {
         match packet {
+            Packet::Read(_, TransferMode::Mail, _) |
+            Packet::Write(_, TransferMode::Mail, _) =>
+                Some(mail_not_supported()),
+            Packet::Read(ref filename, _, _) |
+            Packet::Write(ref filename, _, _)
+                if !filename.is_valid(self.filename_validation()) =>
+                Some(Packet::access_violation("invalid filename")),
             Packet::Read(filename, txmode, options) =>
                 self.handle_rrq(local, remote, filename, txmode, options),
             Packet::Write(filename, txmode, options) =>
@@ -100,12 +472,9 @@ pub trait Handler {
     fn handle_rrq(
         &self, _local: net::SocketAddr, _remote: net::SocketAddr,
         _filename: Filename, _txmode: TransferMode, _options: Options)
-        -> Option<Packet>
+        -> Option<Packet<'_>>
     {
-        Some(Packet::Error(
-            packet::ErrorCode::AccessViolation,
-            packet::ErrorMessage("read not supported".to_owned()),
-        ))
+        Some(Packet::access_violation("read not supported"))
     }
 
     /// Handle a write request (`WRQ`).
@@ -115,12 +484,9 @@ pub trait Handler {
     fn handle_wrq(
         &self, _local: net::SocketAddr, _remote: net::SocketAddr,
         _filename: Filename, _txmode: TransferMode, _options: Options)
-        -> Option<Packet>
+        -> Option<Packet<'_>>
     {
-        Some(Packet::Error(
-            packet::ErrorCode::AccessViolation,
-            packet::ErrorMessage("write not supported".to_owned()),
-        ))
+        Some(Packet::access_violation("write not supported"))
     }
 
     /// Handle all other requests.
@@ -128,11 +494,12 @@ pub trait Handler {
     /// By default these are completely ignored. The TFTP specs do not
     /// define request types other than `RRQ` and `WRQ` so this might be
     /// a misdirected or corrupted packet. Implementors may want to log
-    /// this.
+    /// this. Packets with unrecognised operation codes only arrive here
+    /// if [`strictness`](#method.strictness) is lenient.
     fn handle_other(
         &self, _local: net::SocketAddr, _remote: net::SocketAddr,
         _packet: Packet)
-        -> Option<Packet>
+        -> Option<Packet<'_>>
     {
         None  // Ignore.
     }
@@ -140,10 +507,586 @@ pub trait Handler {
 }
 
 
+/// Run `transfer`, with `peer`, on a new thread, catching any panic.
+///
+/// Handlers can use this to start transfers from `handle_rrq` and
+/// `handle_wrq`. Should `transfer` panic, the panic is logged, the peer
+/// is sent an `ERROR` so that it need not wait to time out, and the
+/// thread ends as if `transfer` had returned. That `ERROR` comes from a
+/// new socket, so a peer part way through a transfer may ignore it.
+#[cfg(feature = "std")]
+pub fn spawn_transfer<F>(
+    peer: net::SocketAddr, logger: &Logger, transfer: F)
+    -> thread::JoinHandle<()>
+    where F: FnOnce() + Send + 'static
+{
+    let logger = logger.clone();
+    thread::spawn(move || {
+        if let Err(panic) = panic::catch_unwind(
+            panic::AssertUnwindSafe(transfer))
+        {
+            error!(
+                logger, "Transfer panicked: {}", panic_message(&*panic);
+                "peer" => peer.to_string());
+            if let Err(error) = send_error(
+                peer, packet::ErrorCode::NotDefined, INTERNAL_ERROR, None,
+                &NoMetrics)
+            {
+                error!(logger, "Could not send error to peer: {}", error);
+            }
+        }
+    })
+}
+
+
+/// The message sent to a peer when a handler or transfer panics.
+#[cfg(feature = "std")]
+const INTERNAL_ERROR: &str = "internal error";
+
+
+/// The reply to a request whose handler panicked.
+#[cfg(feature = "std")]
+fn internal_error() -> Packet<'static> {
+    Packet::error(packet::ErrorCode::NotDefined, INTERNAL_ERROR)
+}
+
+
+/// The message a panic was raised with, if it was a string.
+#[cfg(feature = "std")]
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic.downcast_ref::<String>().map_or(
+            "unknown cause", String::as_str),
+    }
+}
+
+
+/// Tell `sink` of `packet` from `peer`, if it's a read or write request.
+#[cfg(feature = "std")]
+fn received(sink: &dyn EventSink, peer: net::SocketAddr, packet: &Packet) {
+    let (direction, filename, txmode, options) = match *packet {
+        Packet::Read(ref filename, txmode, ref options) =>
+            (Direction::Read, filename, txmode, options),
+        Packet::Write(ref filename, txmode, ref options) =>
+            (Direction::Write, filename, txmode, options),
+        _ => return,
+    };
+    sink.event(Event::RequestReceived{
+        peer, direction, txmode, options: options.clone(),
+        filename: filename.to_string_lossy().into_owned(),
+    });
+}
+
+
+/// Tell `sink` that `packet` from `peer` was denied by `access`.
+#[cfg(feature = "std")]
+fn denied(
+    sink: &dyn EventSink, peer: net::SocketAddr, access: Access,
+    packet: &Packet)
+{
+    let (direction, filename) = match *packet {
+        Packet::Read(ref filename, ..) => (Direction::Read, filename),
+        Packet::Write(ref filename, ..) => (Direction::Write, filename),
+        _ => return,
+    };
+    let rule = match access {
+        Access::ReadWrite => "read-write",
+        Access::ReadOnly => "read-only",
+        Access::WriteOnly => "write-only",
+    };
+    SecurityEvent::new(peer, Violation::AccessDenied, rule)
+        .with_direction(direction)
+        .with_filename(filename.to_string_lossy())
+        .report(Some(sink));
+}
+
+
+/// The reply to requests using the obsolete `mail` transfer mode.
+#[cfg(feature = "std")]
+fn mail_not_supported() -> Packet<'static> {
+    Packet::illegal_operation("mail transfer mode not supported")
+}
+
+
 /// Bind a new UDP socket at the given address.
+#[cfg(feature = "std")]
 fn make_socket(peer: net::SocketAddr) -> io::Result<net::UdpSocket> {
     match peer {
         net::SocketAddr::V4(_) => net::UdpSocket::bind(("0.0.0.0", 0)),
         net::SocketAddr::V6(_) => net::UdpSocket::bind(("::", 0)),
     }
 }
+
+
+/// Send an `ERROR` packet to `peer` from a new socket, showing it to
+/// `observer` if there is one, and counting it in `metrics`.
+///
+/// This is for rejecting a request before a transfer has begun.
+#[cfg(feature = "std")]
+fn send_error(
+    peer: net::SocketAddr, code: packet::ErrorCode, message: &str,
+    observer: Option<&Arc<dyn WireObserver>>, metrics: &dyn Metrics)
+    -> io::Result<()>
+{
+    let socket = make_socket(peer)?;
+    match observer {
+        Some(observer) => send_error_via(
+            &Tap::new(&socket, observer.clone()), peer, code, message,
+            metrics),
+        None => send_error_via(&socket, peer, code, message, metrics),
+    }
+}
+
+
+/// Send an `ERROR` packet to `peer` via `transport`, counting it in
+/// `metrics`.
+#[cfg(feature = "std")]
+fn send_error_via(
+    transport: &dyn Transport, peer: net::SocketAddr,
+    code: packet::ErrorCode, message: &str, metrics: &dyn Metrics)
+    -> io::Result<()>
+{
+    let packet = Packet::error(code, message);
+    let mut buffer = vec![0u8; packet.encoded_len()];
+    let size = packet.write(&mut buffer)?;
+    transport.send_to(&buffer[..size], peer)?;
+    metrics.error(code);
+    Ok(())
+}
+
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+
+    use std::io;
+    use std::net;
+    use std::sync;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time;
+
+    use super::{Access, Handler, serve_over, spawn_transfer};
+    use super::events::{Event, EventSink};
+    use super::logging;
+    use super::metrics::{Direction as TransferDirection, Metrics};
+    use super::options::Options;
+    use super::packet::{
+        ErrorCode,
+        Filename,
+        FilenameValidation,
+        Packet,
+        TransferMode,
+    };
+    use super::security::{SecurityEvent, Violation};
+    use super::testing::{EventLog, MemoryTransport, MetricsLog, WireLog};
+    use super::transport::{Direction, Transport, WireObserver};
+
+    struct Accepting;
+
+    impl Handler for Accepting {
+        fn handle_wrq(
+            &self, _local: net::SocketAddr, _remote: net::SocketAddr,
+            _filename: Filename, _txmode: TransferMode, _options: Options)
+            -> Option<Packet<'_>>
+        {
+            None
+        }
+    }
+
+    #[test]
+    fn test_handle_rejects_mail_mode() {
+        let addr: net::SocketAddr = "127.0.0.1:69".parse().unwrap();
+        let request = |txmode| Packet::Write(
+            Filename::new("root"), txmode, Options::new());
+        match Accepting.handle(addr, addr, request(TransferMode::Mail)) {
+            Some(Packet::Error(ErrorCode::IllegalOperation, _)) => {},
+            reply => panic!("Unexpected reply: {:?}", reply),
+        };
+        assert_eq!(
+            None, Accepting.handle(addr, addr, request(TransferMode::Octet)));
+    }
+
+    struct ReadOnly;
+
+    impl Handler for ReadOnly {
+        fn access(&self) -> Access {
+            Access::ReadOnly
+        }
+
+        fn handle(
+            &self, _local: net::SocketAddr, _remote: net::SocketAddr,
+            _packet: Packet)
+            -> Option<Packet<'_>>
+        {
+            panic!("Handler consulted");
+        }
+    }
+
+    #[test]
+    fn test_serve_over_enforces_access() {
+        let (server, client) = MemoryTransport::pair();
+        let addr = server.local_addr().unwrap();
+        let serving = thread::spawn(move || {
+            let logger = logging::discard();
+            serve_over(&server, &ReadOnly, &logger)
+        });
+        client.send_to(b"\0\x02root\0octet\0", addr).unwrap();
+        let mut buf = [0u8; 64];
+        let (size, _) = client.recv_from(&mut buf, None).unwrap();
+        assert_eq!(
+            Packet::access_violation("write not permitted"),
+            Packet::parse(&buf[..size]).unwrap());
+        drop(client);
+        serving.join().unwrap().unwrap_err();
+    }
+
+    #[test]
+    fn test_access_allows() {
+        use super::metrics::Direction::{Read, Write};
+        assert!(Access::ReadWrite.allows(Read));
+        assert!(Access::ReadWrite.allows(Write));
+        assert!(Access::ReadOnly.allows(Read));
+        assert!(!Access::ReadOnly.allows(Write));
+        assert!(!Access::WriteOnly.allows(Read));
+        assert!(Access::WriteOnly.allows(Write));
+    }
+
+    struct AcceptingASCII;
+
+    impl Handler for AcceptingASCII {
+        fn filename_validation(&self) -> FilenameValidation {
+            FilenameValidation::ASCII
+        }
+
+        fn handle_wrq(
+            &self, _local: net::SocketAddr, _remote: net::SocketAddr,
+            _filename: Filename, _txmode: TransferMode, _options: Options)
+            -> Option<Packet<'_>>
+        {
+            None
+        }
+    }
+
+    #[test]
+    fn test_handle_validates_filenames() {
+        let addr: net::SocketAddr = "127.0.0.1:69".parse().unwrap();
+        let request = |filename: &[u8]| Packet::Write(
+            Filename::new(filename), TransferMode::Octet, Options::new());
+        assert_eq!(None, Accepting.handle(addr, addr, request(b"caf\xe9")));
+        match AcceptingASCII.handle(addr, addr, request(b"caf\xe9")) {
+            Some(Packet::Error(ErrorCode::AccessViolation, _)) => {},
+            reply => panic!("Unexpected reply: {:?}", reply),
+        };
+        assert_eq!(None, AcceptingASCII.handle(addr, addr, request(b"cafe")));
+    }
+
+    struct Observed(Arc<WireLog>);
+
+    impl Handler for Observed {
+        fn wire_observer(&self) -> Option<Arc<dyn WireObserver>> {
+            Some(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_serve_over_shows_wire_observer_every_datagram() {
+        let (server, client) = MemoryTransport::pair();
+        let log = Arc::new(WireLog::new());
+        let addr = server.local_addr().unwrap();
+        let peer = client.local_addr().unwrap();
+        let handler = Observed(log.clone());
+        let serving = thread::spawn(move || {
+            let logger = logging::discard();
+            serve_over(&server, &handler, &logger)
+        });
+        let request = b"\0\x02root\0octet\0";
+        client.send_to(request, addr).unwrap();
+        let mut buf = [0u8; 64];
+        let (size, _) = client.recv_from(&mut buf, None).unwrap();
+        let reply = buf[..size].to_vec();
+        // The server stops once its other end has gone.
+        drop(client);
+        let error = serving.join().unwrap().unwrap_err();
+        assert_eq!(io::ErrorKind::ConnectionAborted, error.kind());
+        assert_eq!(
+            Packet::access_violation("write not supported").to_vec().unwrap(),
+            reply);
+        assert_eq!(
+            vec![
+                (Direction::Received, peer, request.to_vec()),
+                (Direction::Sent, peer, reply),
+            ],
+            log.datagrams());
+    }
+
+    struct Metered(Arc<MetricsLog>);
+
+    impl Handler for Metered {
+        fn metrics(&self) -> Arc<dyn Metrics> {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn test_serve_over_reports_to_metrics() {
+        let (server, client) = MemoryTransport::pair();
+        let metrics = Arc::new(MetricsLog::new());
+        let addr = server.local_addr().unwrap();
+        let handler = Metered(metrics.clone());
+        let serving = thread::spawn(move || {
+            let logger = logging::discard();
+            serve_over(&server, &handler, &logger)
+        });
+        client.send_to(b"\0\x02root", addr).unwrap();
+        client.send_to(b"\0\x02root\0octet\0", addr).unwrap();
+        let mut buf = [0u8; 64];
+        client.recv_from(&mut buf, None).unwrap();
+        drop(client);
+        serving.join().unwrap().unwrap_err();
+        let counts = metrics.counts();
+        assert_eq!(1, counts.malformed);
+        assert_eq!(vec![ErrorCode::AccessViolation], counts.errors);
+    }
+
+    struct Evented(Arc<EventLog>);
+
+    impl Handler for Evented {
+        fn events(&self) -> Option<Arc<dyn EventSink>> {
+            Some(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_serve_over_sends_event_for_each_request() {
+        let (server, client) = MemoryTransport::pair();
+        let events = Arc::new(EventLog::new());
+        let addr = server.local_addr().unwrap();
+        let peer = client.local_addr().unwrap();
+        let handler = Evented(events.clone());
+        let serving = thread::spawn(move || {
+            let logger = logging::discard();
+            serve_over(&server, &handler, &logger)
+        });
+        let request = b"\0\x01boot\0octet\0blksize\x001468\0";
+        client.send_to(request, addr).unwrap();
+        let mut buf = [0u8; 64];
+        client.recv_from(&mut buf, None).unwrap();
+        drop(client);
+        serving.join().unwrap().unwrap_err();
+        let mut options = Options::new();
+        options.blksize = Some(1468);
+        assert_eq!(
+            vec![
+                Event::RequestReceived{
+                    peer, direction: TransferDirection::Read,
+                    filename: "boot".to_owned(), txmode: TransferMode::Octet,
+                    options,
+                },
+                Event::RequestRejected{
+                    peer, direction: TransferDirection::Read,
+                    code: ErrorCode::AccessViolation,
+                    message: "read not supported".to_owned(),
+                },
+            ],
+            events.events());
+    }
+
+    struct Panicking;
+
+    impl Handler for Panicking {
+        fn handle_rrq(
+            &self, _local: net::SocketAddr, _remote: net::SocketAddr,
+            filename: Filename, _txmode: TransferMode, _options: Options)
+            -> Option<Packet<'_>>
+        {
+            if filename.as_bytes() == b"panic" {
+                panic!("Handler panicked on purpose");
+            }
+            Some(Packet::file_not_found("not panicking"))
+        }
+    }
+
+    #[test]
+    fn test_serve_over_survives_panicking_handler() {
+        let (server, client) = MemoryTransport::pair();
+        let addr = server.local_addr().unwrap();
+        let serving = thread::spawn(move || {
+            let logger = logging::discard();
+            serve_over(&server, &Panicking, &logger)
+        });
+        let mut buf = [0u8; 64];
+        client.send_to(b"\0\x01panic\0octet\0", addr).unwrap();
+        let (size, _) = client.recv_from(&mut buf, None).unwrap();
+        assert_eq!(
+            Packet::error(ErrorCode::NotDefined, "internal error"),
+            Packet::parse(&buf[..size]).unwrap());
+        client.send_to(b"\0\x01boot\0octet\0", addr).unwrap();
+        let (size, _) = client.recv_from(&mut buf, None).unwrap();
+        assert_eq!(
+            Packet::file_not_found("not panicking"),
+            Packet::parse(&buf[..size]).unwrap());
+        drop(client);
+        serving.join().unwrap().unwrap_err();
+    }
+
+    /// Replies to every read request with an error whose message is too
+    /// long for the server's reply buffer, except for `short`.
+    struct Verbose(Arc<MetricsLog>);
+
+    impl Handler for Verbose {
+        fn metrics(&self) -> Arc<dyn Metrics> {
+            self.0.clone()
+        }
+
+        fn handle_rrq(
+            &self, _local: net::SocketAddr, _remote: net::SocketAddr,
+            filename: Filename, _txmode: TransferMode, _options: Options)
+            -> Option<Packet<'_>>
+        {
+            match filename.as_bytes() {
+                b"short" => Some(Packet::file_not_found("short")),
+                _ => Some(Packet::file_not_found("x".repeat(600))),
+            }
+        }
+    }
+
+    #[test]
+    fn test_serve_over_survives_reply_that_cannot_be_sent() {
+        let (server, client) = MemoryTransport::pair();
+        let metrics = Arc::new(MetricsLog::new());
+        let addr = server.local_addr().unwrap();
+        let handler = Verbose(metrics.clone());
+        let serving = thread::spawn(move || {
+            let logger = logging::discard();
+            serve_over(&server, &handler, &logger)
+        });
+        let mut buf = [0u8; 64];
+        client.send_to(b"\0\x01long\0octet\0", addr).unwrap();
+        client.send_to(b"\0\x01short\0octet\0", addr).unwrap();
+        let (size, _) = client.recv_from(&mut buf, None).unwrap();
+        assert_eq!(
+            Packet::file_not_found("short"),
+            Packet::parse(&buf[..size]).unwrap());
+        drop(client);
+        serving.join().unwrap().unwrap_err();
+        let counts = metrics.counts();
+        assert_eq!(1, counts.io_errors);
+        assert_eq!(vec![ErrorCode::FileNotFound], counts.errors);
+    }
+
+    /// A transport that fails to receive, with `error`, the first time
+    /// it's asked to.
+    struct Flaky<T> {
+        inner: T,
+        error: sync::Mutex<Option<io::ErrorKind>>,
+    }
+
+    impl<T: Transport> Transport for Flaky<T> {
+        fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            self.inner.send(buf)
+        }
+
+        fn send_to(&self, buf: &[u8], addr: net::SocketAddr)
+                   -> io::Result<usize> {
+            self.inner.send_to(buf, addr)
+        }
+
+        fn recv(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+            -> io::Result<usize>
+        {
+            self.inner.recv(buf, timeout)
+        }
+
+        fn recv_from(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+            -> io::Result<(usize, net::SocketAddr)>
+        {
+            match self.error.lock().unwrap().take() {
+                Some(kind) => Err(kind.into()),
+                None => self.inner.recv_from(buf, timeout),
+            }
+        }
+
+        fn connect(&self, addr: net::SocketAddr) -> io::Result<()> {
+            self.inner.connect(addr)
+        }
+
+        fn local_addr(&self) -> io::Result<net::SocketAddr> {
+            self.inner.local_addr()
+        }
+    }
+
+    #[test]
+    fn test_serve_over_survives_transient_receive_errors() {
+        let (server, client) = MemoryTransport::pair();
+        let metrics = Arc::new(MetricsLog::new());
+        let addr = server.local_addr().unwrap();
+        let server = Flaky{
+            inner: server,
+            error: sync::Mutex::new(Some(io::ErrorKind::ConnectionReset)),
+        };
+        let handler = Metered(metrics.clone());
+        let serving = thread::spawn(move || {
+            let logger = logging::discard();
+            serve_over(&server, &handler, &logger)
+        });
+        client.send_to(b"\0\x02root\0octet\0", addr).unwrap();
+        let mut buf = [0u8; 64];
+        client.recv_from(&mut buf, None).unwrap();
+        drop(client);
+        serving.join().unwrap().unwrap_err();
+        assert_eq!(1, metrics.counts().io_errors);
+    }
+
+    #[test]
+    fn test_spawn_transfer_catches_panic_and_tells_peer() {
+        let peer = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let logger = logging::discard();
+        let transfer = spawn_transfer(
+            peer.local_addr().unwrap(), &logger,
+            || panic!("Transfer panicked on purpose"));
+        transfer.join().unwrap();
+        let mut buf = [0u8; 64];
+        let (size, _) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(
+            Packet::error(ErrorCode::NotDefined, "internal error"),
+            Packet::parse(&buf[..size]).unwrap());
+    }
+
+    struct EventedWriteOnly(Arc<EventLog>);
+
+    impl Handler for EventedWriteOnly {
+        fn access(&self) -> Access {
+            Access::WriteOnly
+        }
+
+        fn events(&self) -> Option<Arc<dyn EventSink>> {
+            Some(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_serve_over_sends_security_event_when_access_denied() {
+        let (server, client) = MemoryTransport::pair();
+        let events = Arc::new(EventLog::new());
+        let addr = server.local_addr().unwrap();
+        let peer = client.local_addr().unwrap();
+        let handler = EventedWriteOnly(events.clone());
+        let serving = thread::spawn(move || {
+            let logger = logging::discard();
+            serve_over(&server, &handler, &logger)
+        });
+        client.send_to(b"\0\x01boot\0octet\0", addr).unwrap();
+        let mut buf = [0u8; 64];
+        client.recv_from(&mut buf, None).unwrap();
+        drop(client);
+        serving.join().unwrap().unwrap_err();
+        assert_eq!(
+            Event::Security(
+                SecurityEvent::new(peer, Violation::AccessDenied, "write-only")
+                    .with_direction(TransferDirection::Read)
+                    .with_filename("boot")),
+            events.events()[1]);
+    }
+
+}
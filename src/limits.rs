@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use super::clock::{Clock, SystemClock};
+
+
+/// Limits how many transfers each client can have in progress at once,
+/// and how fast, in bytes per second, they can go in aggregate.
+///
+/// This is so that one misbehaving client, like a switch re-requesting
+/// its firmware in a loop, cannot monopolise the server. Clients are
+/// told apart by IP address alone, so all transfers from one address
+/// share its limits.
+///
+/// Like a `Quota`, this is shared between transfers; put it in the
+/// `limits` field of an [`rrq::Config`](../rrq/struct.Config.html) or
+/// [`wrq::Config`](../wrq/struct.Config.html), or both, to have reads
+/// and writes count against the same limits.
+#[derive(Debug)]
+pub struct ClientLimits {
+    max_transfers: Option<usize>,
+    rate: Option<u64>,
+    clients: Mutex<HashMap<net::IpAddr, Client>>,
+    clock: Arc<dyn Clock>,
+}
+
+
+#[derive(Debug)]
+struct Client {
+    transfers: usize,
+    /// Bytes that can be sent without waiting; negative when the client
+    /// has gone faster than its rate and must wait.
+    allowance: f64,
+    updated: time::Instant,
+}
+
+
+impl ClientLimits {
+
+    /// No limits, until some are set with
+    /// [`with_max_transfers`](#method.with_max_transfers) and
+    /// [`with_rate`](#method.with_rate).
+    pub fn new() -> ClientLimits {
+        ClientLimits{
+            max_transfers: None,
+            rate: None,
+            clients: Mutex::new(HashMap::new()),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Allow up to `max_transfers` simultaneous transfers per client.
+    pub fn with_max_transfers(self, max_transfers: usize) -> ClientLimits {
+        ClientLimits{max_transfers: Some(max_transfers), ..self}
+    }
+
+    /// Allow up to `rate` bytes per second per client, across all of
+    /// its transfers. A client may burst up to one second's worth. The
+    /// rate is at least one byte per second.
+    pub fn with_rate(self, rate: u64) -> ClientLimits {
+        ClientLimits{rate: Some(rate.max(1)), ..self}
+    }
+
+    /// Tell the time with `clock`.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> ClientLimits {
+        ClientLimits{clock, ..self}
+    }
+
+    /// The number of transfers `addr` has in progress.
+    pub fn transfers(&self, addr: net::IpAddr) -> usize {
+        self.clients.lock().unwrap().get(&addr)
+            .map_or(0, |client| client.transfers)
+    }
+
+    /// Start a transfer with `addr`, or `None` if it already has as
+    /// many as it's allowed. The transfer counts against the limit
+    /// until the returned `Permit` is dropped.
+    pub fn admit(&self, addr: net::IpAddr) -> Option<Permit<'_>> {
+        let mut clients = self.clients.lock().unwrap();
+        let now = self.clock.now();
+        let allowance = self.rate.unwrap_or(0) as f64;
+        let client = clients.entry(addr).or_insert(
+            Client{transfers: 0, allowance, updated: now});
+        match self.max_transfers {
+            Some(max) if client.transfers >= max => {
+                if client.transfers == 0 {
+                    clients.remove(&addr);
+                }
+                None
+            },
+            _ => {
+                client.transfers += 1;
+                Some(Permit{limits: self, addr})
+            },
+        }
+    }
+
+    /// Charge `addr` for `bytes` transferred, returning how long to
+    /// wait before transferring more to stay within its rate.
+    fn charge(&self, addr: net::IpAddr, bytes: u64) -> time::Duration {
+        let rate = match self.rate {
+            Some(rate) => rate as f64,
+            None => return time::Duration::ZERO,
+        };
+        let mut clients = self.clients.lock().unwrap();
+        let client = match clients.get_mut(&addr) {
+            Some(client) => client,
+            None => return time::Duration::ZERO,
+        };
+        let now = self.clock.now();
+        let elapsed = now.saturating_duration_since(client.updated);
+        client.allowance = rate.min(
+            client.allowance + elapsed.as_secs_f64() * rate);
+        client.allowance -= bytes as f64;
+        client.updated = now;
+        if client.allowance < 0.0 {
+            time::Duration::from_secs_f64(-client.allowance / rate)
+        }
+        else {
+            time::Duration::ZERO
+        }
+    }
+
+}
+
+
+impl Default for ClientLimits {
+    fn default() -> ClientLimits {
+        ClientLimits::new()
+    }
+}
+
+
+/// A transfer's place within a client's limits.
+#[derive(Debug)]
+pub struct Permit<'a> {
+    limits: &'a ClientLimits,
+    addr: net::IpAddr,
+}
+
+
+impl<'a> Permit<'a> {
+
+    /// Charge for `bytes` transferred, returning how long the transfer
+    /// should pause before going on.
+    pub fn charge(&self, bytes: u64) -> time::Duration {
+        self.limits.charge(self.addr, bytes)
+    }
+
+}
+
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        let mut clients = self.limits.clients.lock().unwrap();
+        if let Some(client) = clients.get_mut(&self.addr) {
+            client.transfers -= 1;
+            if client.transfers == 0 {
+                clients.remove(&self.addr);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::net;
+    use std::sync::Arc;
+    use std::time;
+
+    use super::ClientLimits;
+    use super::super::testing::MockClock;
+
+    #[test]
+    fn test_max_transfers_is_per_client() {
+        let limits = ClientLimits::new().with_max_transfers(2);
+        let one: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let two: net::IpAddr = "10.0.0.2".parse().unwrap();
+        let first = limits.admit(one).unwrap();
+        let _second = limits.admit(one).unwrap();
+        assert!(limits.admit(one).is_none());
+        assert!(limits.admit(two).is_some());
+        assert_eq!(2, limits.transfers(one));
+        drop(first);
+        assert_eq!(1, limits.transfers(one));
+        assert!(limits.admit(one).is_some());
+    }
+
+    #[test]
+    fn test_clients_are_forgotten_when_idle() {
+        let limits = ClientLimits::new().with_max_transfers(0);
+        let addr: net::IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(limits.admit(addr).is_none());
+        assert!(limits.clients.lock().unwrap().is_empty());
+        let limits = ClientLimits::new();
+        drop(limits.admit(addr));
+        assert!(limits.clients.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rate_is_shared_between_transfers() {
+        let clock = MockClock::new();
+        let limits = ClientLimits::new().with_rate(1000)
+            .with_clock(Arc::new(clock.clone()));
+        let addr: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let one = limits.admit(addr).unwrap();
+        let two = limits.admit(addr).unwrap();
+        // A second's worth can be sent at once.
+        assert_eq!(time::Duration::ZERO, one.charge(600));
+        assert_eq!(time::Duration::ZERO, two.charge(400));
+        assert_eq!(time::Duration::from_millis(500), one.charge(500));
+        clock.advance(time::Duration::from_millis(500));
+        assert_eq!(time::Duration::ZERO, two.charge(0));
+    }
+
+    #[test]
+    fn test_unlimited_rate_never_waits() {
+        let limits = ClientLimits::new();
+        let addr: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let permit = limits.admit(addr).unwrap();
+        assert_eq!(time::Duration::ZERO, permit.charge(u64::MAX));
+    }
+
+}
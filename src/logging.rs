@@ -0,0 +1,450 @@
+use std::error;
+#[cfg(not(feature = "slog"))]
+use std::fmt;
+#[cfg(not(feature = "slog"))]
+use std::fmt::Write;
+#[cfg(not(feature = "slog"))]
+pub(crate) use std::fmt::Display;
+#[cfg(not(feature = "tracing"))]
+use std::marker::PhantomData;
+use std::net;
+
+#[cfg(feature = "tracing")]
+use tracing::field;
+
+use super::options::{Negotiated, Options};
+use super::packet::BlockNum;
+use super::progress::{Progress, TransferStats};
+
+
+/// With the `slog` feature, on by default, events go to a
+/// `slog::Logger`, and to whatever drain it was built with.
+#[cfg(feature = "slog")]
+pub use slog::Logger;
+
+
+/// A logger that drops every event.
+#[cfg(feature = "slog")]
+pub fn discard() -> Logger {
+    Logger::root(slog::Discard, slog::o!())
+}
+
+
+/// A logger that drops every event.
+#[cfg(not(feature = "slog"))]
+pub fn discard() -> Logger {
+    Logger{context: String::new(), discard: true}
+}
+
+
+// Inside the crate, `error!`, `warn!`, `info!`, and `debug!` accept the
+// subset of slog's syntax used here: a logger, a format string and its
+// arguments, then optionally a semicolon and key-value pairs, or a
+// single value. `o!` builds context for `Logger::new`.
+
+#[cfg(feature = "slog")]
+macro_rules! error { ($($args:tt)*) => { ::slog::error!($($args)*) } }
+#[cfg(feature = "slog")]
+macro_rules! warn { ($($args:tt)*) => { ::slog::warn!($($args)*) } }
+#[cfg(feature = "slog")]
+macro_rules! info { ($($args:tt)*) => { ::slog::info!($($args)*) } }
+#[cfg(feature = "slog")]
+macro_rules! debug { ($($args:tt)*) => { ::slog::debug!($($args)*) } }
+#[cfg(feature = "slog")]
+macro_rules! o { ($($args:tt)*) => { ::slog::o!($($args)*) } }
+
+
+#[cfg(not(feature = "slog"))]
+macro_rules! error { ($($args:tt)*) => { event!(Error, $($args)*) } }
+#[cfg(not(feature = "slog"))]
+macro_rules! warn { ($($args:tt)*) => { event!(Warn, $($args)*) } }
+#[cfg(not(feature = "slog"))]
+macro_rules! info { ($($args:tt)*) => { event!(Info, $($args)*) } }
+#[cfg(not(feature = "slog"))]
+macro_rules! debug { ($($args:tt)*) => { event!(Debug, $($args)*) } }
+
+#[cfg(not(feature = "slog"))]
+macro_rules! o {
+    ($($key:literal => $value:expr),* $(,)*) => {
+        vec![$(($key, ::std::string::ToString::to_string(&$value))),*]
+    };
+}
+
+#[cfg(not(feature = "slog"))]
+macro_rules! event {
+    ($level:ident, $logger:expr, $fmt:literal $(, $arg:expr)* $(,)*) => {
+        $logger.event(
+            $crate::logging::Level::$level, format_args!($fmt $(, $arg)*),
+            &[])
+    };
+    ($level:ident, $logger:expr, $fmt:literal $(, $arg:expr)* ;
+     $($key:literal => $value:expr),+ $(,)*) => {
+        $logger.event(
+            $crate::logging::Level::$level, format_args!($fmt $(, $arg)*),
+            &[$(($key, &$value as &dyn $crate::logging::Display)),+])
+    };
+    ($level:ident, $logger:expr, $fmt:literal $(, $arg:expr)* ;
+     $value:expr) => {
+        $logger.event(
+            $crate::logging::Level::$level, format_args!($fmt $(, $arg)*),
+            &[("", &$value as &dyn $crate::logging::Display)])
+    };
+}
+
+
+#[cfg(not(feature = "slog"))]
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+
+/// Context for a child logger, built with `o!`.
+#[cfg(not(feature = "slog"))]
+pub(crate) type Context = Vec<(&'static str, String)>;
+
+
+/// Without the `slog` feature, events go to `tracing`, or to `log`,
+/// whichever feature is enabled, or nowhere if neither is. Should both
+/// be enabled, `tracing` is preferred. A logger carries context, as
+/// key-value pairs, to add to each event.
+#[cfg(not(feature = "slog"))]
+#[derive(Clone,Debug,Default)]
+pub struct Logger {
+    context: String,
+    #[cfg_attr(not(any(feature = "log", feature = "tracing")), allow(dead_code))]
+    discard: bool,
+}
+
+
+#[cfg(not(feature = "slog"))]
+impl Logger {
+
+    /// A logger with no context, passing events to the backend.
+    pub fn root() -> Logger {
+        Logger::default()
+    }
+
+    /// A child logger whose events carry `context` too. Named as in
+    /// slog so that call sites need not change.
+    #[allow(clippy::wrong_self_convention)]
+    pub(crate) fn new(&self, context: Context) -> Logger {
+        let mut logger = self.clone();
+        for (key, value) in context {
+            let _ = write!(logger.context, " {}={}", key, value);
+        }
+        logger
+    }
+
+    /// The message, then its fields, then the logger's context. Fields
+    /// without a key are shown by value alone.
+    #[cfg(any(feature = "log", feature = "tracing", test))]
+    fn render(
+        &self, message: fmt::Arguments,
+        fields: &[(&str, &dyn fmt::Display)])
+        -> String
+    {
+        let mut text = message.to_string();
+        for &(key, value) in fields {
+            let _ = match key {
+                "" => write!(text, " {}", value),
+                key => write!(text, " {}={}", key, value),
+            };
+        }
+        text.push_str(&self.context);
+        text
+    }
+
+    #[cfg(feature = "tracing")]
+    pub(crate) fn event(
+        &self, level: Level, message: fmt::Arguments,
+        fields: &[(&str, &dyn fmt::Display)])
+    {
+        if self.discard {
+            return;
+        }
+        let text = self.render(message, fields);
+        match level {
+            Level::Error => ::tracing::error!("{}", text),
+            Level::Warn => ::tracing::warn!("{}", text),
+            Level::Info => ::tracing::info!("{}", text),
+            Level::Debug => ::tracing::debug!("{}", text),
+        }
+    }
+
+    #[cfg(all(feature = "log", not(feature = "tracing")))]
+    pub(crate) fn event(
+        &self, level: Level, message: fmt::Arguments,
+        fields: &[(&str, &dyn fmt::Display)])
+    {
+        if self.discard {
+            return;
+        }
+        let level = match level {
+            Level::Error => ::log::Level::Error,
+            Level::Warn => ::log::Level::Warn,
+            Level::Info => ::log::Level::Info,
+            Level::Debug => ::log::Level::Debug,
+        };
+        if ::log::log_enabled!(level) {
+            ::log::log!(level, "{}", self.render(message, fields));
+        }
+    }
+
+    #[cfg(not(any(feature = "log", feature = "tracing")))]
+    pub(crate) fn event(
+        &self, _level: Level, _message: fmt::Arguments,
+        _fields: &[(&str, &dyn fmt::Display)])
+    {
+    }
+
+}
+
+
+/// A span around one transfer. With the `tracing` feature, it's a
+/// `tracing` span carrying the peer, the filename if known, the
+/// direction, and, once negotiated, the options in use; the transfer's
+/// `OACK`, retransmissions, and end are reported as events within it.
+/// Without `tracing` it does nothing.
+#[derive(Clone,Debug)]
+pub(crate) struct Span {
+    #[cfg(feature = "tracing")]
+    span: ::tracing::Span,
+}
+
+
+/// Guard returned by `Span::enter`; the span is exited when dropped.
+pub(crate) struct Entered<'a> {
+    #[cfg(feature = "tracing")]
+    _entered: ::tracing::span::Entered<'a>,
+    #[cfg(not(feature = "tracing"))]
+    _span: PhantomData<&'a Span>,
+}
+
+
+#[cfg(feature = "tracing")]
+impl Span {
+
+    /// A span that records nothing.
+    pub fn none() -> Span {
+        Span{span: ::tracing::Span::none()}
+    }
+
+    /// A span for a transfer with `peer`; `direction` is `"read"` when
+    /// serving an RRQ, `"write"` when receiving a WRQ.
+    pub fn transfer(
+        direction: &'static str, peer: net::SocketAddr,
+        filename: Option<&str>)
+        -> Span
+    {
+        Span{span: ::tracing::info_span!(
+            "transfer", %peer, filename, direction,
+            blksize = field::Empty, timeout_ms = field::Empty,
+            windowsize = field::Empty, tsize = field::Empty)}
+    }
+
+    /// Make this the current span until the guard is dropped.
+    pub fn enter(&self) -> Entered<'_> {
+        Entered{_entered: self.span.enter()}
+    }
+
+    /// Record the parameters negotiated, and the options acknowledged.
+    pub fn negotiated(&self, negotiated: &Negotiated, options: &Options) {
+        self.span.record("blksize", negotiated.blksize as u64);
+        self.span.record(
+            "timeout_ms", negotiated.timeout.as_millis() as u64);
+        self.span.record("windowsize", negotiated.windowsize);
+        if let Some(tsize) = options.tsize {
+            self.span.record("tsize", tsize);
+        }
+    }
+
+    pub fn oack(&self, size: usize, options: &Options) {
+        ::tracing::info!(
+            parent: &self.span, size, options = %options, "sent OACK");
+    }
+
+    pub fn retransmit(&self, blkno: BlockNum, retransmits: u64) {
+        ::tracing::info!(
+            parent: &self.span, block = blkno.0, retransmits,
+            "retransmitted");
+    }
+
+    pub fn completed(&self, stats: &TransferStats) {
+        ::tracing::info!(
+            parent: &self.span, bytes = stats.bytes, blocks = stats.blocks,
+            retransmits = stats.retransmits,
+            duplicate_acks = stats.duplicate_acks,
+            elapsed_ms = stats.duration.as_millis() as u64,
+            "transfer completed");
+    }
+
+    pub fn failed(&self, progress: &Progress, error: &dyn error::Error) {
+        ::tracing::warn!(
+            parent: &self.span, bytes = progress.bytes,
+            blocks = progress.blocks, retransmits = progress.retransmits,
+            elapsed_ms = progress.elapsed.as_millis() as u64,
+            error = %error, "transfer failed");
+    }
+
+}
+
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+
+    /// A span that records nothing.
+    pub fn none() -> Span {
+        Span{}
+    }
+
+    pub fn transfer(
+        _direction: &'static str, _peer: net::SocketAddr,
+        _filename: Option<&str>)
+        -> Span
+    {
+        Span{}
+    }
+
+    pub fn enter(&self) -> Entered<'_> {
+        Entered{_span: PhantomData}
+    }
+
+    pub fn negotiated(&self, _negotiated: &Negotiated, _options: &Options) {}
+
+    pub fn oack(&self, _size: usize, _options: &Options) {}
+
+    pub fn retransmit(&self, _blkno: BlockNum, _retransmits: u64) {}
+
+    pub fn completed(&self, _stats: &TransferStats) {}
+
+    pub fn failed(&self, _progress: &Progress, _error: &dyn error::Error) {}
+
+}
+
+
+#[cfg(all(test, not(feature = "slog")))]
+mod test {
+
+    use super::Logger;
+
+    #[test]
+    fn test_render() {
+        let logger = Logger::root().new(o!("peer" => "10.0.0.1:69"));
+        let logger = logger.new(o!("filename" => "boot"));
+        assert_eq!(
+            "Sent 4 bytes size=4 blksize=512 peer=10.0.0.1:69 filename=boot",
+            logger.render(
+                format_args!("Sent {} bytes", 4),
+                &[("size", &4), ("", &"blksize=512")]));
+    }
+
+}
+
+
+#[cfg(all(test, feature = "tracing"))]
+mod test_span {
+
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+    use std::time;
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    use super::Span;
+    use super::super::options::{Options, OptionsPolicy};
+    use super::super::packet::BlockNum;
+    use super::super::progress::TransferStats;
+
+    /// Collects fields as `name=value` strings.
+    struct Fields<'a>(&'a mut Vec<String>);
+
+    impl<'a> Visit for Fields<'a> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    /// Records the fields of the one span and of each event in it.
+    #[derive(Clone,Default)]
+    struct Recorder {
+        span: Arc<Mutex<Vec<String>>>,
+        events: Arc<Mutex<Vec<Vec<String>>>>,
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata) -> bool { true }
+
+        fn new_span(&self, span: &Attributes) -> Id {
+            span.record(&mut Fields(&mut self.span.lock().unwrap()));
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &Id, values: &Record) {
+            values.record(&mut Fields(&mut self.span.lock().unwrap()));
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event) {
+            let mut fields = Vec::new();
+            event.record(&mut Fields(&mut fields));
+            self.events.lock().unwrap().push(fields);
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[test]
+    fn test_transfer_span() {
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let peer = "10.0.0.1:1069".parse().unwrap();
+            let span = Span::transfer("read", peer, Some("boot"));
+            let mut options = Options::new();
+            options.blksize = Some(1468);
+            options.tsize = Some(4096);
+            let (options, negotiated) = options.negotiate(
+                &OptionsPolicy::new()).unwrap();
+            span.negotiated(&negotiated, &options);
+            span.oack(24, &options);
+            span.retransmit(BlockNum(2), 1);
+            span.completed(&TransferStats{
+                bytes: 4096,
+                blocks: 3,
+                retransmits: 1,
+                duplicate_acks: 0,
+                duration: time::Duration::from_millis(250),
+                negotiated: options,
+            });
+        });
+        assert_eq!(
+            vec![
+                "peer=10.0.0.1:1069", "filename=\"boot\"",
+                "direction=\"read\"", "blksize=1468", "timeout_ms=8000",
+                "windowsize=1", "tsize=4096",
+            ],
+            *recorder.span.lock().unwrap());
+        assert_eq!(
+            vec![
+                vec![
+                    "message=sent OACK", "size=24",
+                    "options=blksize=1468 tsize=4096",
+                ],
+                vec!["message=retransmitted", "block=2", "retransmits=1"],
+                vec![
+                    "message=transfer completed", "bytes=4096", "blocks=3",
+                    "retransmits=1", "duplicate_acks=0", "elapsed_ms=250",
+                ],
+            ],
+            *recorder.events.lock().unwrap());
+    }
+
+}
@@ -0,0 +1,464 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time;
+
+use super::packet::ErrorCode;
+
+
+/// Receives counts and measurements from the server, and from transfers
+/// in either direction, to pass on to a metrics system.
+///
+/// All methods have do-nothing default implementations, so implementors
+/// need only define those they're interested in. Like `Observer`, they
+/// are called from the thread doing the work, so they should be quick.
+pub trait Metrics: Send + Sync {
+
+    /// Called when a transfer begins.
+    fn transfer_started(&self) {}
+
+    /// Called once a transfer has completed successfully, with the
+    /// payload bytes transferred and the time it took.
+    fn transfer_completed(&self, _bytes: u64, _duration: time::Duration) {}
+
+    /// Called for each packet retransmitted, either because of a
+    /// time-out or, when receiving, to acknowledge a duplicate block.
+    fn retransmit(&self) {}
+
+    /// Called for each datagram received that could not be parsed.
+    fn malformed_packet(&self) {}
+
+    /// Called for each `ERROR` packet sent to a peer.
+    fn error(&self, _code: ErrorCode) {}
+
+    /// Called when the server fails to receive a request, or to send a
+    /// reply, but carries on serving.
+    fn io_error(&self) {}
+
+}
+
+
+impl fmt::Debug for dyn Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Metrics")
+    }
+}
+
+
+/// Metrics that go nowhere. This is the default.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct NoMetrics;
+
+
+impl Metrics for NoMetrics {}
+
+
+/// How many error codes there are, for counting each separately.
+const ERROR_CODES: usize = ErrorCode::BadOptions as usize + 1;
+
+
+/// Counts kept with atomics, so that they can be updated from many
+/// transfers at once and read at any time.
+#[derive(Debug,Default)]
+struct Counters {
+    started: AtomicU64,
+    completed: AtomicU64,
+    bytes: AtomicU64,
+    micros: AtomicU64,
+    retransmits: AtomicU64,
+    malformed: AtomicU64,
+    io_errors: AtomicU64,
+    errors: [AtomicU64; ERROR_CODES],
+}
+
+
+impl Counters {
+
+    fn counts(&self) -> Counts {
+        let errors = self.errors.iter().enumerate()
+            .filter_map(|(code, count)| {
+                match count.load(Ordering::Relaxed) {
+                    0 => None,
+                    count => ErrorCode::from_u16(code as u16)
+                        .map(|code| (code, count)),
+                }
+            })
+            .collect();
+        Counts{
+            started: self.started.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            duration: time::Duration::from_micros(
+                self.micros.load(Ordering::Relaxed)),
+            retransmits: self.retransmits.load(Ordering::Relaxed),
+            malformed: self.malformed.load(Ordering::Relaxed),
+            io_errors: self.io_errors.load(Ordering::Relaxed),
+            errors,
+        }
+    }
+
+}
+
+
+impl Metrics for Counters {
+
+    fn transfer_started(&self) {
+        self.started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn transfer_completed(&self, bytes: u64, duration: time::Duration) {
+        self.completed.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.micros.fetch_add(
+            duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn retransmit(&self) {
+        self.retransmits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn malformed_packet(&self) {
+        self.malformed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn error(&self, code: ErrorCode) {
+        self.errors[code as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn io_error(&self) {
+        self.io_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+}
+
+
+/// A transfer's direction, from the server's point of view.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub enum Direction {
+    /// Serving a read request, i.e. a download.
+    Read,
+    /// Receiving a write request, i.e. an upload.
+    Write,
+}
+
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Direction::Read => "read",
+            Direction::Write => "write",
+        })
+    }
+}
+
+
+/// Ready-made `Metrics` that count everything, kept separately for the
+/// server and for transfers in each direction.
+///
+/// Give `server()` to the `Handler`, `transfers(Direction::Read)` to
+/// `rrq::Config`, and `transfers(Direction::Write)` to `wrq::Config`,
+/// then take a `snapshot` whenever the counts are wanted. A snapshot
+/// formats itself for Prometheus to scrape.
+#[derive(Debug,Default)]
+pub struct Stats {
+    server: Counters,
+    read: Counters,
+    write: Counters,
+}
+
+
+impl Stats {
+
+    pub fn new() -> Arc<Stats> {
+        Arc::new(Stats::default())
+    }
+
+    /// `Metrics` for the server, counting malformed requests and the
+    /// errors sent in reply to requests.
+    pub fn server(self: &Arc<Stats>) -> Arc<dyn Metrics> {
+        Arc::new(View{stats: self.clone(), direction: None})
+    }
+
+    /// `Metrics` for transfers in the given direction.
+    pub fn transfers(self: &Arc<Stats>, direction: Direction)
+        -> Arc<dyn Metrics>
+    {
+        Arc::new(View{stats: self.clone(), direction: Some(direction)})
+    }
+
+    /// The counts so far.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot{
+            server: self.server.counts(),
+            read: self.read.counts(),
+            write: self.write.counts(),
+        }
+    }
+
+    fn counters(&self, direction: Option<Direction>) -> &Counters {
+        match direction {
+            None => &self.server,
+            Some(Direction::Read) => &self.read,
+            Some(Direction::Write) => &self.write,
+        }
+    }
+
+}
+
+
+/// One part of a `Stats`, as `Metrics`.
+struct View {
+    stats: Arc<Stats>,
+    direction: Option<Direction>,
+}
+
+
+impl Metrics for View {
+
+    fn transfer_started(&self) {
+        self.stats.counters(self.direction).transfer_started()
+    }
+
+    fn transfer_completed(&self, bytes: u64, duration: time::Duration) {
+        self.stats.counters(self.direction).transfer_completed(
+            bytes, duration)
+    }
+
+    fn retransmit(&self) {
+        self.stats.counters(self.direction).retransmit()
+    }
+
+    fn malformed_packet(&self) {
+        self.stats.counters(self.direction).malformed_packet()
+    }
+
+    fn error(&self, code: ErrorCode) {
+        self.stats.counters(self.direction).error(code)
+    }
+
+    fn io_error(&self) {
+        self.stats.counters(self.direction).io_error()
+    }
+
+}
+
+
+/// Counts from one part of a `Stats`.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Counts {
+    pub started: u64,
+    pub completed: u64,
+    /// Payload bytes in completed transfers.
+    pub bytes: u64,
+    /// Time taken by completed transfers, altogether.
+    pub duration: time::Duration,
+    pub retransmits: u64,
+    pub malformed: u64,
+    /// Requests that could not be received, or replies sent, by the
+    /// server.
+    pub io_errors: u64,
+    /// The number of `ERROR` packets sent with each code, omitting
+    /// codes that have not been sent.
+    pub errors: Vec<(ErrorCode, u64)>,
+}
+
+
+/// The counts in a `Stats` at one moment.
+///
+/// Formatted with `Display`, this is in Prometheus's text exposition
+/// format. Series for transfers are labelled with their `direction`;
+/// those for the server have no direction. Errors are further labelled
+/// with their numeric `code`.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Snapshot {
+    pub server: Counts,
+    pub read: Counts,
+    pub write: Counts,
+}
+
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let read = format!("direction=\"{}\"", Direction::Read);
+        let write = format!("direction=\"{}\"", Direction::Write);
+        let all = [
+            ("", &self.server),
+            (read.as_str(), &self.read),
+            (write.as_str(), &self.write),
+        ];
+        let transfers = &all[1..];
+        let series = |parts: &[(&str, &Counts)], value: fn(&Counts) -> u64| {
+            parts.iter()
+                .map(|&(labels, counts)| (labels.to_owned(), value(counts)))
+                .collect::<Vec<_>>()
+        };
+        family(
+            f, "tftp_transfers_started_total", "Transfers started.",
+            &series(transfers, |c| c.started))?;
+        family(
+            f, "tftp_transfers_completed_total", "Transfers completed.",
+            &series(transfers, |c| c.completed))?;
+        family(
+            f, "tftp_transfer_bytes_total",
+            "Payload bytes in completed transfers.",
+            &series(transfers, |c| c.bytes))?;
+        family(
+            f, "tftp_transfer_duration_seconds_total",
+            "Time taken by completed transfers.",
+            &transfers.iter()
+                .map(|&(labels, counts)| (
+                    labels.to_owned(), counts.duration.as_secs_f64()))
+                .collect::<Vec<_>>())?;
+        family(
+            f, "tftp_retransmits_total", "Packets retransmitted.",
+            &series(transfers, |c| c.retransmits))?;
+        family(
+            f, "tftp_malformed_packets_total",
+            "Datagrams received that could not be parsed.",
+            &series(&all, |c| c.malformed))?;
+        family(
+            f, "tftp_io_errors_total",
+            "Requests that could not be received or replies sent.",
+            &series(&all[..1], |c| c.io_errors))?;
+        let errors: Vec<_> = all.iter()
+            .flat_map(|&(labels, counts)| {
+                counts.errors.iter().map(move |&(code, count)| {
+                    let code = format!("code=\"{}\"", code as u16);
+                    match labels {
+                        "" => (code, count),
+                        labels => (format!("{},{}", labels, code), count),
+                    }
+                })
+            })
+            .collect();
+        family(f, "tftp_errors_total", "ERROR packets sent.", &errors)
+    }
+}
+
+
+/// Write a counter in Prometheus's text exposition format, with a line
+/// for each set of labels and its value.
+fn family<V: fmt::Display>(
+    f: &mut fmt::Formatter, name: &str, help: &str, series: &[(String, V)])
+    -> fmt::Result
+{
+    writeln!(f, "# HELP {} {}", name, help)?;
+    writeln!(f, "# TYPE {} counter", name)?;
+    for (labels, value) in series {
+        match labels.as_str() {
+            "" => writeln!(f, "{} {}", name, value)?,
+            labels => writeln!(f, "{}{{{}}} {}", name, labels, value)?,
+        };
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::time;
+
+    use super::{Counts, Direction, Stats};
+    use super::super::packet::ErrorCode;
+
+    #[test]
+    fn test_stats_counts_each_part_separately() {
+        let stats = Stats::new();
+        let server = stats.server();
+        let reads = stats.transfers(Direction::Read);
+        let writes = stats.transfers(Direction::Write);
+        server.malformed_packet();
+        server.io_error();
+        server.error(ErrorCode::AccessViolation);
+        reads.transfer_started();
+        reads.transfer_started();
+        reads.retransmit();
+        reads.transfer_completed(1024, time::Duration::from_millis(1500));
+        reads.error(ErrorCode::FileNotFound);
+        reads.error(ErrorCode::FileNotFound);
+        writes.transfer_started();
+        writes.error(ErrorCode::DiskFull);
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            Counts{
+                malformed: 1,
+                io_errors: 1,
+                errors: vec![(ErrorCode::AccessViolation, 1)],
+                ..Counts::default()
+            },
+            snapshot.server);
+        assert_eq!(
+            Counts{
+                started: 2,
+                completed: 1,
+                bytes: 1024,
+                duration: time::Duration::from_millis(1500),
+                retransmits: 1,
+                malformed: 0,
+                io_errors: 0,
+                errors: vec![(ErrorCode::FileNotFound, 2)],
+            },
+            snapshot.read);
+        assert_eq!(
+            Counts{
+                started: 1,
+                errors: vec![(ErrorCode::DiskFull, 1)],
+                ..Counts::default()
+            },
+            snapshot.write);
+    }
+
+    #[test]
+    fn test_snapshot_formats_for_prometheus() {
+        let stats = Stats::new();
+        stats.server().error(ErrorCode::AccessViolation);
+        let reads = stats.transfers(Direction::Read);
+        reads.transfer_started();
+        reads.transfer_completed(1024, time::Duration::from_millis(1500));
+        stats.transfers(Direction::Write).error(ErrorCode::DiskFull);
+        assert_eq!(
+            concat!(
+                "# HELP tftp_transfers_started_total Transfers started.\n",
+                "# TYPE tftp_transfers_started_total counter\n",
+                "tftp_transfers_started_total{direction=\"read\"} 1\n",
+                "tftp_transfers_started_total{direction=\"write\"} 0\n",
+                "# HELP tftp_transfers_completed_total Transfers completed.\n",
+                "# TYPE tftp_transfers_completed_total counter\n",
+                "tftp_transfers_completed_total{direction=\"read\"} 1\n",
+                "tftp_transfers_completed_total{direction=\"write\"} 0\n",
+                "# HELP tftp_transfer_bytes_total Payload bytes in ",
+                "completed transfers.\n",
+                "# TYPE tftp_transfer_bytes_total counter\n",
+                "tftp_transfer_bytes_total{direction=\"read\"} 1024\n",
+                "tftp_transfer_bytes_total{direction=\"write\"} 0\n",
+                "# HELP tftp_transfer_duration_seconds_total Time taken by ",
+                "completed transfers.\n",
+                "# TYPE tftp_transfer_duration_seconds_total counter\n",
+                "tftp_transfer_duration_seconds_total{direction=\"read\"} ",
+                "1.5\n",
+                "tftp_transfer_duration_seconds_total{direction=\"write\"} ",
+                "0\n",
+                "# HELP tftp_retransmits_total Packets retransmitted.\n",
+                "# TYPE tftp_retransmits_total counter\n",
+                "tftp_retransmits_total{direction=\"read\"} 0\n",
+                "tftp_retransmits_total{direction=\"write\"} 0\n",
+                "# HELP tftp_malformed_packets_total Datagrams received ",
+                "that could not be parsed.\n",
+                "# TYPE tftp_malformed_packets_total counter\n",
+                "tftp_malformed_packets_total 0\n",
+                "tftp_malformed_packets_total{direction=\"read\"} 0\n",
+                "tftp_malformed_packets_total{direction=\"write\"} 0\n",
+                "# HELP tftp_io_errors_total Requests that could not be ",
+                "received or replies sent.\n",
+                "# TYPE tftp_io_errors_total counter\n",
+                "tftp_io_errors_total 0\n",
+                "# HELP tftp_errors_total ERROR packets sent.\n",
+                "# TYPE tftp_errors_total counter\n",
+                "tftp_errors_total{code=\"2\"} 1\n",
+                "tftp_errors_total{direction=\"write\",code=\"3\"} 1\n",
+            ),
+            stats.snapshot().to_string());
+    }
+
+}
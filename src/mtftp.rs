@@ -0,0 +1,670 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use super::make_socket;
+use super::clock::{Clock, SystemClock};
+use super::logging::Logger;
+use super::metrics::{Metrics, NoMetrics};
+use super::options::Options;
+use super::packet::{BlockNum, Data, Filename, Packet, TransferMode};
+use super::progress::{TransferStats, Tracker};
+use super::transport::Transport;
+
+
+/// MTFTP negotiates no options, so every block but the last is this
+/// size, as in RFC-1350.
+const BLKSIZE: usize = 512;
+
+
+/// Configuration for sending files with the multicast TFTP, MTFTP, of
+/// the PXE specification.
+///
+/// A client opens a transfer with an ordinary `RRQ`, sent to the
+/// server's MTFTP port (`MTFTP_SPORT` in PXE's DHCP options). The file
+/// is then sent, in 512-byte blocks, to a multicast group (`MTFTP_IP`
+/// and `MTFTP_CPORT`), and acknowledged only by that client, the
+/// master. Every other client booting the same file listens in on the
+/// group, so one transfer serves them all.
+#[derive(Debug)]
+pub struct Config {
+    /// The multicast group and client port to which `DATA` is sent.
+    pub group: net::SocketAddr,
+    /// How long to wait for the master client to acknowledge a block
+    /// before sending it again.
+    pub timeout: time::Duration,
+    /// How many times to send a block again before giving up on the
+    /// master client. Clients that were listening then open the
+    /// transfer again themselves.
+    pub retransmits: u8,
+    pub metrics: Arc<dyn Metrics>,
+    /// Times acknowledgements and progress reports.
+    pub clock: Arc<dyn Clock>,
+}
+
+
+impl Config {
+
+    pub fn new(group: net::SocketAddr) -> Config {
+        Config{
+            group,
+            timeout: time::Duration::from_secs(1),
+            retransmits: 4,
+            metrics: Arc::new(NoMetrics),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+}
+
+
+/// The files being sent to the group.
+///
+/// Clients that miss the start of a transfer wait for it to end and
+/// then open another, so requests for a file that is already being sent
+/// must be dropped, without reply, rather than starting a transfer of
+/// their own. Share one of these between the requests arriving at the
+/// MTFTP port, and `open` a session for each file before sending it.
+#[derive(Debug, Default)]
+pub struct Sessions {
+    active: Arc<Mutex<HashSet<Vec<u8>>>>,
+}
+
+
+impl Sessions {
+
+    pub fn new() -> Sessions {
+        Sessions::default()
+    }
+
+    /// A session for sending `filename`, or `None` if it's already
+    /// being sent. The file counts as being sent until the session is
+    /// dropped.
+    pub fn open(&self, filename: &Filename) -> Option<Session> {
+        let key = filename.as_bytes().to_vec();
+        match self.active.lock().unwrap().insert(key.clone()) {
+            true => Some(Session{active: self.active.clone(), key}),
+            false => None,
+        }
+    }
+
+    /// Whether `filename` is being sent.
+    pub fn is_active(&self, filename: &Filename) -> bool {
+        self.active.lock().unwrap().contains(filename.as_bytes())
+    }
+
+}
+
+
+/// The sending of one file to the group; see `Sessions`.
+#[derive(Debug)]
+pub struct Session {
+    active: Arc<Mutex<HashSet<Vec<u8>>>>,
+    key: Vec<u8>,
+}
+
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.active.lock().unwrap().remove(&self.key);
+    }
+}
+
+
+/// Send `data` to the group in `config`, from a new socket, with
+/// `master` acknowledging, returning what the transfer did once it has
+/// completed.
+///
+/// Failures are logged as well as returned.
+pub fn serve(
+    master: net::SocketAddr,
+    data: &mut dyn io::Read,
+    config: &Config,
+    logger: &Logger,
+)
+    -> io::Result<TransferStats>
+{
+    match make_socket(config.group) {
+        Ok(socket) => serve_over(&socket, master, data, config, logger),
+        Err(error) => {
+            error!(logger, "Could not open socket: {}", error);
+            Err(error)
+        },
+    }
+}
+
+
+/// Send `data` to the group in `config` over the given transport, with
+/// `master` acknowledging.
+///
+/// This is `serve` for transports other than a UDP socket; see
+/// `Transport`. The transport should be dedicated to this transfer, and
+/// must not be connected, since `DATA` and `ACK` go to and come from
+/// different addresses.
+pub fn serve_over(
+    transport: &dyn Transport,
+    master: net::SocketAddr,
+    data: &mut dyn io::Read,
+    config: &Config,
+    logger: &Logger,
+)
+    -> io::Result<TransferStats>
+{
+    let mut tracker = Tracker::new(master, None, None, &*config.clock)
+        .with_metrics(&*config.metrics);
+    tracker.started(None);
+    match send_to(transport, master, data, config, &mut tracker, logger) {
+        Ok(()) => {
+            info!(
+                logger, "Sent to {} with {} acknowledging.",
+                config.group, master);
+            Ok(tracker.completed())
+        },
+        Err(error) => {
+            error!(logger, "Multicast transfer failed: {}", error);
+            tracker.failed(&error);
+            Err(error)
+        },
+    }
+}
+
+
+fn send_to(
+    transport: &dyn Transport,
+    master: net::SocketAddr,
+    data: &mut dyn io::Read,
+    config: &Config,
+    tracker: &mut Tracker,
+    logger: &Logger,
+)
+    -> io::Result<()>
+{
+    let mut bufout = [0u8; 4 + BLKSIZE];
+    let mut blkno = BlockNum(1);
+    loop {
+        let size = read_block(data, &mut bufout[4..])?;
+        bufout[..4].copy_from_slice(&Data::header(blkno));
+        transport.send_to(&bufout[..size + 4], config.group)?;
+        tracker.block(size);
+        await_ack(
+            transport, master, blkno, &bufout[..size + 4], config, tracker,
+            logger)?;
+        if size < BLKSIZE {
+            return Ok(());
+        }
+        else if blkno == BlockNum(u16::MAX) {
+            // Listeners joining part way through could not tell one
+            // pass of the block numbers from the next.
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData, "file too large for MTFTP"));
+        }
+        blkno = blkno.next();
+    }
+}
+
+
+/// Read until `buf` is full or there's nothing more to read.
+fn read_block(data: &mut dyn io::Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut size = 0;
+    while size < buf.len() {
+        match data.read(&mut buf[size..]) {
+            Ok(0) => break,
+            Ok(count) => size += count,
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => {},
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(size)
+}
+
+
+/// Wait for `master` to acknowledge `blkno`, sending `packet` to the
+/// group again each time it does not in time. Anything from other
+/// clients is ignored: they only listen.
+fn await_ack(
+    transport: &dyn Transport,
+    master: net::SocketAddr,
+    blkno: BlockNum,
+    packet: &[u8],
+    config: &Config,
+    tracker: &mut Tracker,
+    logger: &Logger,
+)
+    -> io::Result<()>
+{
+    let mut bufin = [0u8; 4 + BLKSIZE];
+    let mut timeouts = 0u8;
+    let mut deadline = config.clock.now() + config.timeout;
+    loop {
+        match remaining(deadline, &*config.clock).and_then(
+            |timeout| transport.recv_from(&mut bufin, Some(timeout)))
+        {
+            Ok((size, src)) if src == master => {
+                match Packet::parse(&bufin[..size]) {
+                    Ok(Packet::Ack(acked)) if acked == blkno =>
+                        return Ok(()),
+                    Ok(Packet::Ack(_)) => tracker.duplicate_ack(),
+                    Ok(Packet::Error(code, message)) => {
+                        return Err(io::Error::other(format!(
+                            "master sent error {:?}: {}", code, message.0)));
+                    },
+                    Ok(packet) => warn!(
+                        logger, "Ignoring unexpected packet from {}: {}",
+                        src, packet),
+                    Err(error) => warn!(
+                        logger, "Ignoring malformed packet from {}: {}",
+                        src, error),
+                }
+            },
+            Ok(_) => {},
+            Err(ref error) if timed_out(error) => {
+                if timeouts >= config.retransmits {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "master client did not acknowledge"));
+                }
+                timeouts += 1;
+                transport.send_to(packet, config.group)?;
+                tracker.retransmit(blkno);
+                info!(
+                    logger, "Sent DATA ({} bytes) to {} (attempt #{}).",
+                    packet.len() - 4, config.group, timeouts + 1);
+                deadline = config.clock.now() + config.timeout;
+            },
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+
+/// The time left until `deadline`, or a time-out error if it has
+/// passed: a socket would take a time-out of zero to mean none.
+fn remaining(deadline: time::Instant, clock: &dyn Clock)
+             -> io::Result<time::Duration> {
+    let now = clock.now();
+    match deadline > now {
+        true => Ok(deadline - now),
+        false => Err(io::ErrorKind::TimedOut.into()),
+    }
+}
+
+
+fn timed_out(error: &io::Error) -> bool {
+    // See the comment in Transport.recv to understand why both errors
+    // are matched.
+    error.kind() == io::ErrorKind::WouldBlock ||
+        error.kind() == io::ErrorKind::TimedOut
+}
+
+
+/// The timers of an MTFTP client, as given to PXE clients in the
+/// `MTFTP_TMOUT` and `MTFTP_DELAY` DHCP options.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct Timers {
+    /// How long to listen for a transfer already in progress before
+    /// opening one, and how long a silence must be to end a transfer.
+    pub listen: time::Duration,
+    /// How long to wait, after a transfer that was heard only in part
+    /// has ended, before opening another to fill in the gaps.
+    pub delay: time::Duration,
+    /// How many transfers to open before giving up.
+    pub attempts: u8,
+}
+
+
+impl Timers {
+
+    pub fn new() -> Timers {
+        Timers{
+            listen: time::Duration::from_secs(1),
+            delay: time::Duration::from_secs(2),
+            attempts: 5,
+        }
+    }
+
+}
+
+
+impl Default for Timers {
+    fn default() -> Timers {
+        Timers::new()
+    }
+}
+
+
+/// Download `filename` as an MTFTP client, from `server`'s MTFTP port,
+/// returning its contents.
+///
+/// The transport must receive what is sent to the group: for a UDP
+/// socket, bind it to the client port and join the group. It first
+/// listens for a transfer already in progress and collects its blocks.
+/// If that does not deliver the whole file, it opens a transfer of its
+/// own, acknowledging as the master, and keeps what it was missing. The
+/// timers run on `clock`.
+pub fn receive_over(
+    transport: &dyn Transport,
+    server: net::SocketAddr,
+    filename: &Filename,
+    timers: &Timers,
+    clock: &dyn Clock,
+    logger: &Logger,
+)
+    -> io::Result<Vec<u8>>
+{
+    let mut blocks = Blocks::default();
+    let mut wait = timers.listen;
+    let mut attempts = 0u8;
+    loop {
+        // Listen in on whatever the group is hearing, if anything.
+        while listen(
+            transport, false, wait, timers, clock, &mut blocks, logger)?
+        {
+            if let Some(data) = blocks.assemble() {
+                return Ok(data);
+            }
+            wait = timers.delay;
+        }
+        if attempts >= timers.attempts {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut, "could not complete transfer"));
+        }
+        attempts += 1;
+        let mut buf = [0u8; 4 + BLKSIZE];
+        let request = Packet::Read(
+            filename.clone(), TransferMode::Octet, Options::new());
+        let size = request.write(&mut buf).map_err(|error| io::Error::new(
+            io::ErrorKind::InvalidInput, error.to_string()))?;
+        transport.send_to(&buf[..size], server)?;
+        info!(
+            logger, "Opened transfer of {:?} from {} (attempt #{}).",
+            filename.to_string_lossy(), server, attempts);
+        listen(
+            transport, true, timers.listen, timers, clock, &mut blocks,
+            logger)?;
+        if let Some(data) = blocks.assemble() {
+            return Ok(data);
+        }
+        wait = timers.delay;
+    }
+}
+
+
+/// Collect `DATA` into `blocks`, waiting `wait` for the first and
+/// `timers.listen` for each after, and acknowledging them to their
+/// sender if `master`. Returns whether anything was heard.
+fn listen(
+    transport: &dyn Transport,
+    master: bool,
+    wait: time::Duration,
+    timers: &Timers,
+    clock: &dyn Clock,
+    blocks: &mut Blocks,
+    logger: &Logger,
+)
+    -> io::Result<bool>
+{
+    let mut buf = [0u8; 4 + BLKSIZE];
+    let mut heard = false;
+    let mut deadline = clock.now() + wait;
+    loop {
+        let received = remaining(deadline, clock).and_then(
+            |timeout| transport.recv_from(&mut buf, Some(timeout)));
+        let (size, src) = match received {
+            Ok(received) => received,
+            Err(ref error) if timed_out(error) => return Ok(heard),
+            Err(error) => return Err(error),
+        };
+        match Packet::parse(&buf[..size]) {
+            Ok(Packet::Data(blkno, data)) => {
+                heard = true;
+                deadline = clock.now() + timers.listen;
+                blocks.insert(blkno, data.0);
+                if master {
+                    let mut ack = [0u8; 4];
+                    Packet::Ack(blkno).write(&mut ack).unwrap();
+                    transport.send_to(&ack, src)?;
+                }
+                if blocks.is_complete() {
+                    return Ok(true);
+                }
+            },
+            Ok(Packet::Error(code, message)) if master => {
+                return Err(io::Error::other(format!(
+                    "server sent error {:?}: {}", code, message.0)));
+            },
+            Ok(packet) => debug!(
+                logger, "Ignoring packet from {}: {}", src, packet),
+            Err(error) => debug!(
+                logger, "Ignoring malformed packet from {}: {}", src, error),
+        }
+    }
+}
+
+
+/// The blocks of a file received so far, from however many transfers.
+#[derive(Debug, Default)]
+struct Blocks {
+    blocks: BTreeMap<u16, Vec<u8>>,
+    last: Option<u16>,
+}
+
+
+impl Blocks {
+
+    fn insert(&mut self, blkno: BlockNum, data: &[u8]) {
+        if blkno.0 == 0 || data.len() > BLKSIZE {
+            return;
+        }
+        if data.len() < BLKSIZE {
+            self.last = Some(blkno.0);
+        }
+        self.blocks.entry(blkno.0).or_insert_with(|| data.to_vec());
+    }
+
+    fn is_complete(&self) -> bool {
+        match self.last {
+            Some(last) =>
+                (1..=last).all(|blkno| self.blocks.contains_key(&blkno)),
+            None => false,
+        }
+    }
+
+    fn assemble(&self) -> Option<Vec<u8>> {
+        match self.is_complete() {
+            true => Some(self.blocks.range(..=self.last?)
+                         .flat_map(|(_, data)| data.iter().cloned())
+                         .collect()),
+            false => None,
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::io;
+    use std::net;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time;
+
+    use super::{
+        Blocks,
+        Config,
+        Sessions,
+        Timers,
+        receive_over,
+        serve_over,
+    };
+    use super::super::clock::SystemClock;
+    use super::super::logging;
+    use super::super::packet::{BlockNum, Data, Filename, Packet};
+    use super::super::testing::{MemoryTransport, MockClock};
+    use super::super::transport::Transport;
+
+    fn timers() -> Timers {
+        Timers{
+            listen: time::Duration::from_millis(200),
+            delay: time::Duration::from_millis(100),
+            attempts: 3,
+        }
+    }
+
+    /// Answer the first request arriving at the returned address by
+    /// sending `content` to where it came from, standing in for a
+    /// multicast group joined only by the requesting client.
+    fn server(content: Vec<u8>) -> net::SocketAddr {
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 516];
+            let (size, master) = socket.recv_from(&mut buf).unwrap();
+            let filename = match Packet::parse(&buf[..size]).unwrap() {
+                Packet::Read(filename, _, _) => filename,
+                packet => panic!("Unexpected packet: {:?}", packet),
+            };
+            let sessions = Sessions::new();
+            let _session = sessions.open(&filename).unwrap();
+            let transfer = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let config = Config::new(master);
+            serve_over(
+                &transfer, master, &mut io::Cursor::new(content), &config,
+                &logging::discard()).unwrap();
+        });
+        addr
+    }
+
+    fn content() -> Vec<u8> {
+        (0..1300).map(|n| n as u8).collect()
+    }
+
+    #[test]
+    fn test_client_opens_transfer_when_group_is_quiet() {
+        let server = server(content());
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let data = receive_over(
+            &client, server, &Filename::new("pxelinux.0"), &timers(),
+            &SystemClock, &logging::discard()).unwrap();
+        assert_eq!(content(), data);
+    }
+
+    #[test]
+    fn test_client_fills_in_blocks_it_did_not_hear() {
+        let server = server(content());
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        // Another client's transfer, of which only blocks 1 and 3 are
+        // heard; block 2 has to be fetched.
+        let other = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = client.local_addr().unwrap();
+        let content = content();
+        for &(blkno, data) in &[(1, &content[..512]), (3, &content[1024..])] {
+            let mut buf = [0u8; 516];
+            let size = Packet::Data(BlockNum(blkno), Data(data))
+                .write(&mut buf).unwrap();
+            other.send_to(&buf[..size], addr).unwrap();
+        }
+        let data = receive_over(
+            &client, server, &Filename::new("pxelinux.0"), &timers(),
+            &SystemClock, &logging::discard()).unwrap();
+        assert_eq!(content, data);
+    }
+
+    #[test]
+    fn test_server_gives_up_when_master_is_silent() {
+        let group = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let master = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let transfer = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut config = Config::new(group.local_addr().unwrap());
+        config.timeout = time::Duration::from_millis(10);
+        config.retransmits = 2;
+        let error = serve_over(
+            &transfer, master.local_addr().unwrap(),
+            &mut io::Cursor::new(content()), &config, &logging::discard())
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, error.kind());
+        group.set_read_timeout(Some(time::Duration::from_secs(5))).unwrap();
+        let mut buf = [0u8; 516];
+        for _ in 0..3 {
+            let size = group.recv(&mut buf).unwrap();
+            match Packet::parse(&buf[..size]).unwrap() {
+                Packet::Data(blkno, _) => assert_eq!(BlockNum(1), blkno),
+                packet => panic!("Unexpected packet: {:?}", packet),
+            };
+        }
+    }
+
+    #[test]
+    fn test_server_times_master_on_clock() {
+        let (transfer, master) = MemoryTransport::pair();
+        let clock = MockClock::new();
+        transfer.set_clock(Arc::new(clock.clone()));
+        let group = master.local_addr().unwrap();
+        let config = Config{
+            retransmits: 2, clock: Arc::new(clock.clone()),
+            ..Config::new(group)
+        };
+        // The master is at the group's address, but never acknowledges.
+        let error = serve_over(
+            &transfer, group, &mut io::Cursor::new(content()), &config,
+            &logging::discard()).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, error.kind());
+        assert_eq!(config.timeout * 3, clock.elapsed());
+    }
+
+    #[test]
+    fn test_client_times_listen_and_delay_on_clock() {
+        let (client, server) = MemoryTransport::pair();
+        let clock = MockClock::new();
+        client.set_clock(Arc::new(clock.clone()));
+        let timers = timers();
+        let error = receive_over(
+            &client, server.local_addr().unwrap(),
+            &Filename::new("pxelinux.0"), &timers, &clock,
+            &logging::discard()).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, error.kind());
+        // It listens before the first request and after each, and then
+        // listens for the delay after each.
+        let attempts = timers.attempts as u32;
+        assert_eq!(
+            timers.listen * (attempts + 1) + timers.delay * attempts,
+            clock.elapsed());
+        let mut buf = [0u8; 516];
+        for _ in 0..attempts {
+            let size = server.recv(&mut buf, None).unwrap();
+            match Packet::parse(&buf[..size]).unwrap() {
+                Packet::Read(filename, _, _) =>
+                    assert_eq!(Filename::new("pxelinux.0"), filename),
+                packet => panic!("Unexpected packet: {:?}", packet),
+            };
+        }
+    }
+
+    #[test]
+    fn test_sessions_open_each_file_once() {
+        let sessions = Sessions::new();
+        let filename = Filename::new("pxelinux.0");
+        let session = sessions.open(&filename).unwrap();
+        assert!(sessions.is_active(&filename));
+        assert!(sessions.open(&filename).is_none());
+        assert!(sessions.open(&Filename::new("other")).is_some());
+        drop(session);
+        assert!(!sessions.is_active(&filename));
+        assert!(sessions.open(&filename).is_some());
+    }
+
+    #[test]
+    fn test_blocks_assemble_only_when_complete() {
+        let mut blocks = Blocks::default();
+        blocks.insert(BlockNum(2), &[2u8; 100]);
+        assert_eq!(None, blocks.assemble());
+        blocks.insert(BlockNum(1), &[1u8; 512]);
+        let data = blocks.assemble().unwrap();
+        assert_eq!(612, data.len());
+        assert_eq!((1, 2), (data[0], data[611]));
+    }
+
+}
@@ -0,0 +1,651 @@
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io;
+
+
+/// The native line ending, to and from which NetASCII's CR,LF is
+/// converted.
+#[cfg(windows)]
+pub const NATIVE_EOL: &[u8] = b"\r\n";
+#[cfg(not(windows))]
+pub const NATIVE_EOL: &[u8] = b"\n";
+
+
+/// The line ending to and from which NetASCII's CR,LF is converted.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum LineEnding {
+    /// A bare LF, as on Unix.
+    LF,
+    /// CR,LF, as on Windows.
+    CRLF,
+    /// No conversion at all: bytes are passed through untouched.
+    PassThrough,
+}
+
+
+impl LineEnding {
+
+    /// The line ending of the platform we're running on; see
+    /// `NATIVE_EOL`.
+    pub fn native() -> LineEnding {
+        if NATIVE_EOL == b"\r\n" {
+            LineEnding::CRLF
+        }
+        else {
+            LineEnding::LF
+        }
+    }
+
+}
+
+
+impl Default for LineEnding {
+    fn default() -> LineEnding {
+        LineEnding::native()
+    }
+}
+
+
+/// How to convert between NetASCII and local text.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct Policy {
+    /// The local line ending. Defaults to the native line ending.
+    pub line_ending: LineEnding,
+    /// Whether encoding turns a CR that is not part of a line ending
+    /// into CR,NUL, as NetASCII requires, or leaves it bare for peers
+    /// that choke on the NUL. Decoding accepts both regardless.
+    pub escape_cr: bool,
+}
+
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy::new()
+    }
+}
+
+
+impl Policy {
+
+    pub fn new() -> Policy {
+        Policy{
+            line_ending: LineEnding::native(),
+            escape_cr: true,
+        }
+    }
+
+}
+
+
+/// Encodes a stream of local text as NetASCII.
+///
+/// The local line ending becomes CR,LF and any other CR becomes CR,NUL,
+/// according to the `Policy`. Where the line ending is CR,LF, an LF on
+/// its own becomes CR,LF too, since NetASCII has no bare LF; and a CR at
+/// the end of one push is held back until the next push, or `finish`,
+/// so that a line ending split across blocks is still recognised.
+///
+/// ```
+/// # use allenap_libtftp::netascii::NetAsciiEncoder;
+/// let mut encoder = NetAsciiEncoder::new();
+/// let mut out = Vec::new();
+/// encoder.push(b"carriage\rreturn", &mut out);
+/// encoder.finish(&mut out);
+/// assert_eq!(b"carriage\r\0return", &out[..]);
+/// ```
+#[derive(Clone,Debug,Default)]
+pub struct NetAsciiEncoder {
+    policy: Policy,
+    cr: bool,
+}
+
+
+impl NetAsciiEncoder {
+
+    /// An encoder using the default `Policy`.
+    pub fn new() -> NetAsciiEncoder {
+        NetAsciiEncoder::with_policy(Policy::new())
+    }
+
+    pub fn with_policy(policy: Policy) -> NetAsciiEncoder {
+        NetAsciiEncoder{policy, cr: false}
+    }
+
+    /// Encode `buf`, appending to `out`.
+    pub fn push(&mut self, buf: &[u8], out: &mut Vec<u8>) {
+        let line_ending = self.policy.line_ending;
+        if line_ending == LineEnding::PassThrough {
+            out.extend_from_slice(buf);
+            return;
+        }
+        out.reserve(buf.len());
+        for &byte in buf {
+            if self.cr {
+                self.cr = false;
+                if byte == b'\n' {
+                    out.extend_from_slice(b"\r\n");
+                    continue;
+                }
+                self.push_cr(out);
+            }
+            match byte {
+                b'\r' if line_ending == LineEnding::CRLF => self.cr = true,
+                b'\r' => self.push_cr(out),
+                b'\n' => out.extend_from_slice(b"\r\n"),
+                _ => out.push(byte),
+            }
+        }
+    }
+
+    /// Write out a held-back CR, if there is one.
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        if self.cr {
+            self.cr = false;
+            self.push_cr(out);
+        }
+    }
+
+    /// A CR that's not part of a line ending.
+    fn push_cr(&self, out: &mut Vec<u8>) {
+        if self.policy.escape_cr {
+            out.extend_from_slice(b"\r\0");
+        }
+        else {
+            out.push(b'\r');
+        }
+    }
+
+}
+
+
+/// Decodes a stream of NetASCII into local text.
+///
+/// CR,LF becomes the local line ending and CR,NUL becomes a bare CR,
+/// unless the `Policy` says to pass bytes through untouched. A CR at
+/// the end of one push is held back until the next push, or `finish`,
+/// so sequences split across `DATA` packets decode correctly.
+///
+/// ```
+/// # use allenap_libtftp::netascii::{NATIVE_EOL, NetAsciiDecoder};
+/// let mut decoder = NetAsciiDecoder::new();
+/// let mut out = Vec::new();
+/// decoder.push(b"one\r", &mut out);
+/// decoder.push(b"\ntwo", &mut out);
+/// decoder.finish(&mut out);
+/// assert_eq!([b"one", NATIVE_EOL, b"two"].concat(), out);
+/// ```
+#[derive(Clone,Debug,Default)]
+pub struct NetAsciiDecoder {
+    policy: Policy,
+    cr: bool,
+}
+
+
+impl NetAsciiDecoder {
+
+    /// A decoder using the default `Policy`.
+    pub fn new() -> NetAsciiDecoder {
+        NetAsciiDecoder::with_policy(Policy::new())
+    }
+
+    pub fn with_policy(policy: Policy) -> NetAsciiDecoder {
+        NetAsciiDecoder{policy, cr: false}
+    }
+
+    /// Decode `buf`, appending to `out`.
+    pub fn push(&mut self, buf: &[u8], out: &mut Vec<u8>) {
+        let eol: &[u8] = match self.policy.line_ending {
+            LineEnding::LF => b"\n",
+            LineEnding::CRLF => b"\r\n",
+            LineEnding::PassThrough => {
+                out.extend_from_slice(buf);
+                return;
+            },
+        };
+        out.reserve(buf.len());
+        for &byte in buf {
+            if self.cr {
+                self.cr = false;
+                match byte {
+                    b'\n' => out.extend_from_slice(eol),
+                    b'\0' => out.push(b'\r'),
+                    b'\r' => { out.push(b'\r'); self.cr = true; },
+                    _ => { out.push(b'\r'); out.push(byte); },
+                }
+            }
+            else if byte == b'\r' {
+                self.cr = true;
+            }
+            else {
+                out.push(byte);
+            }
+        }
+    }
+
+    /// Write out a held-back CR, if there is one. A well-formed
+    /// NetASCII stream never ends with a bare CR, but it's not ours to
+    /// discard.
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        if self.cr {
+            self.cr = false;
+            out.push(b'\r');
+        }
+    }
+
+}
+
+
+/// Reads local text from `inner` as NetASCII; see `NetAsciiEncoder`.
+///
+/// Reads may return fewer bytes than asked for even before the end of
+/// the input, so use `read_exact` or similar to fill a block.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct NetAsciiReader<R> {
+    inner: R,
+    encoder: NetAsciiEncoder,
+    /// Encoded bytes not yet returned, from `pos` onwards.
+    buf: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+
+#[cfg(feature = "std")]
+impl<R: io::Read> NetAsciiReader<R> {
+
+    /// A reader using the default `Policy`.
+    pub fn new(inner: R) -> NetAsciiReader<R> {
+        NetAsciiReader::with_policy(inner, Policy::new())
+    }
+
+    pub fn with_policy(inner: R, policy: Policy) -> NetAsciiReader<R> {
+        NetAsciiReader{
+            inner,
+            encoder: NetAsciiEncoder::with_policy(policy),
+            buf: Vec::new(),
+            pos: 0,
+            eof: false,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+}
+
+
+#[cfg(feature = "std")]
+impl<R: io::Read> io::Read for NetAsciiReader<R> {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // A push can produce nothing, e.g. when a CR is held back.
+        while self.pos == self.buf.len() && !self.eof {
+            self.buf.clear();
+            self.pos = 0;
+            let mut chunk = [0u8; 4096];
+            let size = self.inner.read(&mut chunk)?;
+            if size == 0 {
+                self.encoder.finish(&mut self.buf);
+                self.eof = true;
+            }
+            else {
+                self.encoder.push(&chunk[..size], &mut self.buf);
+            }
+        }
+        let size = buf.len().min(self.buf.len() - self.pos);
+        buf[..size].copy_from_slice(&self.buf[self.pos..self.pos + size]);
+        self.pos += size;
+        Ok(size)
+    }
+
+}
+
+
+/// Writes NetASCII to `inner` as local text; see `NetAsciiDecoder`.
+///
+/// Call `finish` once all data has been written, to write out a CR
+/// held back from the end of the stream.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct NetAsciiWriter<W> {
+    inner: W,
+    decoder: NetAsciiDecoder,
+}
+
+
+#[cfg(feature = "std")]
+impl<W: io::Write> NetAsciiWriter<W> {
+
+    /// A writer using the default `Policy`.
+    pub fn new(inner: W) -> NetAsciiWriter<W> {
+        NetAsciiWriter::with_policy(inner, Policy::new())
+    }
+
+    pub fn with_policy(inner: W, policy: Policy) -> NetAsciiWriter<W> {
+        NetAsciiWriter{inner, decoder: NetAsciiDecoder::with_policy(policy)}
+    }
+
+    /// Write out a trailing CR, if there is one, and flush `inner`.
+    pub fn finish(&mut self) -> io::Result<()> {
+        let mut out = Vec::new();
+        self.decoder.finish(&mut out);
+        self.inner.write_all(&out)?;
+        self.inner.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+}
+
+
+#[cfg(feature = "std")]
+impl<W: io::Write> io::Write for NetAsciiWriter<W> {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut out = Vec::with_capacity(buf.len());
+        self.decoder.push(buf, &mut out);
+        self.inner.write_all(&out)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::io;
+    use std::io::{Read, Write};
+
+    use super::{
+        LineEnding,
+        NATIVE_EOL,
+        NetAsciiDecoder,
+        NetAsciiEncoder,
+        NetAsciiReader,
+        NetAsciiWriter,
+        Policy,
+    };
+
+    /// The bytes that matter to NetASCII, and one that doesn't.
+    const ALPHABET: &[u8] = b"\r\n\0a";
+
+    /// Every combination of line ending and CR escaping.
+    fn policies() -> Vec<Policy> {
+        let mut policies = Vec::new();
+        for &line_ending in &[
+            LineEnding::LF, LineEnding::CRLF, LineEnding::PassThrough] {
+            for &escape_cr in &[true, false] {
+                policies.push(Policy{line_ending, escape_cr});
+            }
+        }
+        policies
+    }
+
+    /// Every string over `ALPHABET` of up to `max` bytes.
+    fn strings(max: usize) -> Vec<Vec<u8>> {
+        let mut strings = vec![vec![]];
+        let mut start = 0;
+        for _ in 0..max {
+            let end = strings.len();
+            for index in start..end {
+                for &byte in ALPHABET {
+                    let mut string = strings[index].clone();
+                    string.push(byte);
+                    strings.push(string);
+                }
+            }
+            start = end;
+        }
+        strings
+    }
+
+    fn encode(policy: Policy, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut encoder = NetAsciiEncoder::with_policy(policy);
+        let mut out = Vec::new();
+        for chunk in chunks {
+            encoder.push(chunk, &mut out);
+        }
+        encoder.finish(&mut out);
+        out
+    }
+
+    fn decode(policy: Policy, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut decoder = NetAsciiDecoder::with_policy(policy);
+        let mut out = Vec::new();
+        for chunk in chunks {
+            decoder.push(chunk, &mut out);
+        }
+        decoder.finish(&mut out);
+        out
+    }
+
+    #[test]
+    fn test_default_policy_is_native() {
+        let policy = Policy::default();
+        assert!(policy.escape_cr);
+        match policy.line_ending {
+            LineEnding::LF => assert_eq!(b"\n", NATIVE_EOL),
+            LineEnding::CRLF => assert_eq!(b"\r\n", NATIVE_EOL),
+            LineEnding::PassThrough => panic!("not native"),
+        };
+    }
+
+    #[test]
+    fn test_encode() {
+        let text = [b"one", NATIVE_EOL, b"two\rthree\0"].concat();
+        assert_eq!(
+            b"one\r\ntwo\r\0three\0".to_vec(),
+            encode(Policy::new(), &[&text]));
+    }
+
+    #[test]
+    fn test_encode_lf() {
+        let policy = Policy{line_ending: LineEnding::LF, escape_cr: true};
+        assert_eq!(b"a\r\nb\r\0c".to_vec(), encode(policy, &[b"a\nb\rc"]));
+    }
+
+    #[test]
+    fn test_encode_crlf() {
+        let policy = Policy{line_ending: LineEnding::CRLF, escape_cr: true};
+        assert_eq!(
+            b"a\r\nb\r\nc\r\0".to_vec(),
+            encode(policy, &[b"a\r\nb\nc\r"]));
+    }
+
+    #[test]
+    fn test_encode_without_escaping_cr() {
+        let policy = Policy{line_ending: LineEnding::LF, escape_cr: false};
+        assert_eq!(b"a\r\nb\rc".to_vec(), encode(policy, &[b"a\nb\rc"]));
+        let policy = Policy{line_ending: LineEnding::CRLF, escape_cr: false};
+        assert_eq!(b"a\r\nb\r".to_vec(), encode(policy, &[b"a\r\nb\r"]));
+    }
+
+    #[test]
+    fn test_pass_through() {
+        for &escape_cr in &[true, false] {
+            let policy = Policy{
+                line_ending: LineEnding::PassThrough, escape_cr};
+            for string in strings(4) {
+                assert_eq!(string, encode(policy, &[&string]));
+                assert_eq!(string, decode(policy, &[&string]));
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            [b"one", NATIVE_EOL, b"two\rthree\r"].concat(),
+            decode(Policy::new(), &[b"one\r\ntwo\r\0three\r"]));
+    }
+
+    #[test]
+    fn test_decode_crlf() {
+        let policy = Policy{line_ending: LineEnding::CRLF, escape_cr: true};
+        assert_eq!(
+            b"a\r\nb\rc".to_vec(), decode(policy, &[b"a\r\nb\r\0c"]));
+    }
+
+    #[test]
+    fn test_decode_bare_cr() {
+        assert_eq!(
+            b"a\rb\r\r".to_vec(), decode(Policy::new(), &[b"a\rb\r\r\0"]));
+    }
+
+    #[test]
+    fn test_encode_splits_are_invisible() {
+        for policy in policies() {
+            for string in strings(6) {
+                let whole = encode(policy, &[&string]);
+                for split in 0..=string.len() {
+                    let (one, two) = string.split_at(split);
+                    assert_eq!(
+                        whole, encode(policy, &[one, two]),
+                        "{:?} {:?}", policy, string);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_splits_are_invisible() {
+        for policy in policies() {
+            for string in strings(6) {
+                let whole = decode(policy, &[&string]);
+                for split in 0..=string.len() {
+                    let (one, two) = string.split_at(split);
+                    assert_eq!(
+                        whole, decode(policy, &[one, two]),
+                        "{:?} {:?}", policy, string);
+                }
+            }
+        }
+    }
+
+    /// Whether `string` has an LF not preceded by CR.
+    fn has_bare_lf(string: &[u8]) -> bool {
+        string.iter().enumerate().any(
+            |(index, &byte)| byte == b'\n' &&
+                (index == 0 || string[index - 1] != b'\r'))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for policy in policies().into_iter().filter(|p| p.escape_cr) {
+            // A bare LF in CR,LF text is encoded as a line ending.
+            let lossy = |string: &[u8]| has_bare_lf(string) &&
+                policy.line_ending == LineEnding::CRLF;
+            for string in strings(6).into_iter().filter(|s| !lossy(s)) {
+                let encoded = encode(policy, &[&string]);
+                for split in 0..=encoded.len() {
+                    let (one, two) = encoded.split_at(split);
+                    assert_eq!(
+                        string, decode(policy, &[one, two]),
+                        "{:?} {:?}", policy, string);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_encoded_has_no_bare_line_feeds_or_carriage_returns() {
+        for &line_ending in &[LineEnding::LF, LineEnding::CRLF] {
+            let policy = Policy{line_ending, escape_cr: true};
+            for string in strings(6) {
+                let encoded = encode(policy, &[&string]);
+                for (index, &byte) in encoded.iter().enumerate() {
+                    match byte {
+                        b'\r' => assert!(
+                            encoded.get(index + 1) == Some(&b'\n') ||
+                            encoded.get(index + 1) == Some(&b'\0'),
+                            "{:?}", encoded),
+                        b'\n' => assert_eq!(
+                            Some(&b'\r'), index.checked_sub(1).map(
+                                |index| &encoded[index]), "{:?}", encoded),
+                        _ => {},
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_writer() {
+        let mut sink: Vec<u8> = Vec::new();
+        {
+            let mut writer = NetAsciiWriter::new(&mut sink);
+            writer.write_all(b"one\r\ntwo\r\0three").unwrap();
+            writer.finish().unwrap();
+        }
+        let mut expected = b"one".to_vec();
+        expected.extend_from_slice(NATIVE_EOL);
+        expected.extend_from_slice(b"two\rthree");
+        assert_eq!(expected, sink);
+    }
+
+    #[test]
+    fn test_writer_across_writes() {
+        let mut sink: Vec<u8> = Vec::new();
+        {
+            let mut writer = NetAsciiWriter::new(&mut sink);
+            writer.write_all(b"one\r").unwrap();
+            writer.write_all(b"\ntwo\r").unwrap();
+            writer.write_all(b"\0three\r").unwrap();
+            writer.finish().unwrap();
+        }
+        let mut expected = b"one".to_vec();
+        expected.extend_from_slice(NATIVE_EOL);
+        expected.extend_from_slice(b"two\rthree\r");
+        assert_eq!(expected, sink);
+    }
+
+    #[test]
+    fn test_reader() {
+        let text = [b"one", NATIVE_EOL, b"two\r"].concat();
+        let mut reader = NetAsciiReader::new(&text[..]);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(b"one\r\ntwo\r\0".to_vec(), out);
+    }
+
+    #[test]
+    fn test_reader_in_small_reads() {
+        let text = [b"a\rb", NATIVE_EOL].concat().repeat(1000);
+        let mut reader = NetAsciiReader::new(&text[..]);
+        let mut out = Vec::new();
+        let mut buf = [0u8; 3];
+        loop {
+            match reader.read(&mut buf).unwrap() {
+                0 => break,
+                size => out.extend_from_slice(&buf[..size]),
+            }
+        }
+        assert_eq!(b"a\r\0b\r\n".repeat(1000), out);
+    }
+
+    #[test]
+    fn test_reader_and_writer_round_trip() {
+        let text = [b"one", NATIVE_EOL, b"\rtwo\0\r"].concat();
+        let mut reader = NetAsciiReader::new(&text[..]);
+        let mut sink: Vec<u8> = Vec::new();
+        {
+            let mut writer = NetAsciiWriter::new(&mut sink);
+            io::copy(&mut reader, &mut writer).unwrap();
+            writer.finish().unwrap();
+        }
+        assert_eq!(text, sink);
+    }
+
+}
@@ -1,6 +1,18 @@
-use std::fmt::Display;
-use std::result;
-use std::str::FromStr;
+#[cfg(feature = "slog")]
+extern crate slog;
+
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::error;
+use core::fmt;
+use core::fmt::Display;
+use core::net;
+use core::ops::RangeInclusive;
+use core::result;
+use core::str::FromStr;
+use core::time;
 
 use super::packet::{Error, Result};
 use super::packetreader;
@@ -8,7 +20,8 @@ use super::packetwriter;
 
 
 /// TFTP transfer options. Defined in RFC-2347.
-#[derive(Debug)]
+#[derive(Clone,Debug,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Options {
     /// Block size; 8-65464 inclusive. Defined in RFC-2348.
     pub blksize:    Option<u16>,
@@ -18,6 +31,118 @@ pub struct Options {
     pub tsize:      Option<u64>,
     /// Window size; 1-65535. Defined in RFC-7440.
     pub windowsize: Option<u16>,
+    /// The block number that follows 65535; 0 or 1. Not standardised,
+    /// but some clients use it for files larger than 32MB.
+    pub rollover:   Option<u16>,
+    /// Time-out in microseconds. Not standardised, but implemented by
+    /// several TFTP stacks for faster retransmission on LANs.
+    pub utimeout:   Option<u32>,
+    /// Multicast transfer. Defined in RFC-2090. Never acknowledged by
+    /// `negotiate`, since multicast transfers are not yet supported.
+    pub multicast:  Option<Multicast>,
+    /// Options this library does not recognise, as `(name, value)`
+    /// pairs in the order received. RFC-2347 says these should be
+    /// ignored, but handlers can use them for vendor extensions. They
+    /// are never acknowledged by this library.
+    pub unknown:    Vec<(String, String)>,
+    /// How recognised option names were spelled when received, where
+    /// that differs from the usual lowercase. They are written the same
+    /// way, since some clients only accept an `OACK` that uses their
+    /// spelling.
+    spellings:  Vec<String>,
+}
+
+
+/// Options are equal when they have the same values, however their
+/// names were spelled.
+impl PartialEq for Options {
+    fn eq(&self, other: &Options) -> bool {
+        self.blksize == other.blksize &&
+            self.timeout == other.timeout &&
+            self.tsize == other.tsize &&
+            self.windowsize == other.windowsize &&
+            self.rollover == other.rollover &&
+            self.utimeout == other.utimeout &&
+            self.multicast == other.multicast &&
+            self.unknown == other.unknown
+    }
+}
+
+
+/// Set options as space-separated `name=value` pairs, e.g.
+/// `blksize=1468 tsize=0`. Empty when no options are set.
+impl fmt::Display for Options {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut sep = "";
+        for (name, value) in self.iter() {
+            write!(f, "{}{}={}", sep, name, value)?;
+            sep = " ";
+        }
+        Ok(())
+    }
+}
+
+
+/// Log as a single field, formatted as with `Display`.
+#[cfg(feature = "slog")]
+impl slog::Value for Options {
+    fn serialize(
+        &self, _record: &slog::Record, key: slog::Key,
+        serializer: &mut dyn slog::Serializer)
+        -> slog::Result
+    {
+        serializer.emit_arguments(key, &format_args!("{}", self))
+    }
+}
+
+
+/// Log set options as individual fields, e.g. `blksize=1468
+/// windowsize=16`. Unrecognised options, whose names are not known in
+/// advance, are logged together as `extensions`.
+#[cfg(feature = "slog")]
+impl slog::KV for Options {
+    fn serialize(
+        &self, _record: &slog::Record, serializer: &mut dyn slog::Serializer)
+        -> slog::Result
+    {
+        if let Some(blksize) = self.blksize {
+            serializer.emit_u16("blksize", blksize)?;
+        };
+        if let Some(timeout) = self.timeout {
+            serializer.emit_u8("timeout", timeout)?;
+        };
+        if let Some(tsize) = self.tsize {
+            serializer.emit_u64("tsize", tsize)?;
+        };
+        if let Some(windowsize) = self.windowsize {
+            serializer.emit_u16("windowsize", windowsize)?;
+        };
+        if let Some(rollover) = self.rollover {
+            serializer.emit_u16("rollover", rollover)?;
+        };
+        if let Some(utimeout) = self.utimeout {
+            serializer.emit_u32("utimeout", utimeout)?;
+        };
+        if let Some(multicast) = self.multicast {
+            serializer.emit_arguments(
+                "multicast", &format_args!("{}", multicast))?;
+        };
+        if !self.unknown.is_empty() {
+            let extensions: Vec<String> = self.unknown.iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            serializer.emit_arguments(
+                "extensions", &format_args!("{}", extensions.join(" ")))?;
+        };
+        Ok(())
+    }
+}
+
+
+impl Default for Options {
+    fn default() -> Options {
+        Options::new()
+    }
 }
 
 
@@ -29,22 +154,169 @@ impl Options {
             timeout: None,
             tsize: None,
             windowsize: None,
+            rollover: None,
+            utimeout: None,
+            multicast: None,
+            unknown: Vec::new(),
+            spellings: Vec::new(),
         }
     }
 
     /// Is one or more of the options set?
     pub fn is_set(&self) -> bool {
         self.blksize.is_some() || self.timeout.is_some() ||
-            self.tsize.is_some() || self.windowsize.is_some()
+            self.tsize.is_some() || self.windowsize.is_some() ||
+            self.rollover.is_some() || self.utimeout.is_some() ||
+            self.multicast.is_some() || !self.unknown.is_empty()
+    }
+
+    /// Check that option values are within the ranges their RFCs
+    /// permit. The fields are public, so nothing else stops them being
+    /// set out of range.
+    pub fn validate(&self) -> result::Result<(), InvalidOption> {
+        let reject = OutOfRange::Reject;
+        self.blksize.map(|blksize| in_range(
+            "blksize", blksize, BLKSIZE, reject, InvalidOption::Blksize))
+            .transpose()?;
+        self.timeout.map(|timeout| in_range(
+            "timeout", timeout, TIMEOUT, reject, InvalidOption::Timeout))
+            .transpose()?;
+        self.windowsize.map(|windowsize| in_range(
+            "windowsize", windowsize, WINDOWSIZE, reject,
+            InvalidOption::Windowsize))
+            .transpose()?;
+        self.rollover.map(|rollover| in_range(
+            "rollover", rollover, ROLLOVER, reject, InvalidOption::Rollover))
+            .transpose()?;
+        self.utimeout.map(|utimeout| in_range(
+            "utimeout", utimeout, UTIMEOUT, reject, InvalidOption::Utimeout))
+            .transpose()?;
+        Ok(())
+    }
+
+    /// The options that are set, as `(name, value)` pairs in the order
+    /// they are written, followed by unrecognised options.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item=(&'a str, String)> + 'a
+    {
+        let set = |name: &'static str, value: Option<String>| {
+            let name = self.spellings.iter()
+                .find(|spelling| spelling.eq_ignore_ascii_case(name))
+                .map_or(name, |spelling| spelling.as_str());
+            value.map(|value| (name, value))
+        };
+        fn string<T: ToString>(value: Option<T>) -> Option<String> {
+            value.map(|value| value.to_string())
+        }
+        let known = vec![
+            set("blksize", string(self.blksize)),
+            set("timeout", string(self.timeout)),
+            set("tsize", string(self.tsize)),
+            set("windowsize", string(self.windowsize)),
+            set("rollover", string(self.rollover)),
+            set("utimeout", string(self.utimeout)),
+            set("multicast", string(self.multicast)),
+        ];
+        known.into_iter().flatten().chain(self.unknown.iter().map(
+            |(name, value)| (name.as_str(), value.clone())))
+    }
+
+    /// The value of the unrecognised option `name`, matched without
+    /// regard to case.
+    pub fn extension(&self, name: &str) -> Option<&str> {
+        self.unknown.iter()
+            .find(|&(option, _)| option.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Decide which of these requested options to accept under
+    /// `policy`, returning the options to acknowledge in an `OACK`, and
+    /// the parameters the transfer should use.
+    ///
+    /// A requested `tsize` is echoed when the policy honours it; for a
+    /// `RRQ` the caller must replace the zero with the file's size.
+    /// Whether to acknowledge any extensions is left to the caller.
+    ///
+    /// An `Err` carries a message suitable for sending to the client in
+    /// an `ERROR` packet with code 8, `BadOptions`.
+    pub fn negotiate(&self, policy: &OptionsPolicy)
+        -> result::Result<(Options, Negotiated), String>
+    {
+        let mut accepted = Options::new();
+        accepted.spellings = self.spellings.clone();
+        let mut negotiated = Negotiated{
+            blksize: 512,  // Default.
+            timeout: policy.default_timeout,
+            windowsize: 1,  // Lock-step, as in RFC-1350.
+            rollover: 0,
+        };
+        if let Some(blksize) = self.blksize {
+            accepted.blksize = bound(
+                "blksize", blksize, policy.min_blksize, policy.max_blksize,
+                policy.bad_blksize)?;
+            if let Some(blksize) = accepted.blksize {
+                negotiated.blksize = blksize as usize;
+            }
+        };
+        if let Some(timeout) = self.timeout {
+            accepted.timeout = bound(
+                "timeout", timeout, policy.min_timeout, policy.max_timeout,
+                policy.bad_timeout)?;
+            if let Some(timeout) = accepted.timeout {
+                negotiated.timeout = time::Duration::from_secs(timeout as u64);
+            }
+        };
+        // A time-out in microseconds takes precedence over one in
+        // seconds, but both are acknowledged.
+        if let Some(utimeout) = self.utimeout {
+            accepted.utimeout = bound(
+                "utimeout", utimeout, policy.min_utimeout,
+                policy.max_utimeout, policy.bad_timeout)?;
+            if let Some(utimeout) = accepted.utimeout {
+                negotiated.timeout =
+                    time::Duration::from_micros(utimeout as u64);
+            }
+        };
+        if let Some(windowsize) = self.windowsize {
+            accepted.windowsize = bound(
+                "windowsize", windowsize, 1, policy.max_windowsize,
+                policy.bad_windowsize)?;
+            if let Some(windowsize) = accepted.windowsize {
+                negotiated.windowsize = windowsize;
+            }
+        };
+        if let Some(rollover) = self.rollover {
+            accepted.rollover = Some(rollover);
+            negotiated.rollover = rollover;
+        };
+        if policy.honour_tsize {
+            accepted.tsize = self.tsize;
+        };
+        Ok((accepted, negotiated))
+    }
+
+    /// Set the unrecognised option `name`, replacing any existing value.
+    pub fn set_extension<T: Display>(&mut self, name: &str, value: T) {
+        self.unknown.retain(|(option, _)| !option.eq_ignore_ascii_case(name));
+        self.unknown.push((name.to_owned(), value.to_string()));
     }
 
     /// Read options from the given reader.
     pub fn read<'a>
         (reader: &mut packetreader::PacketReader<'a>)
          -> Result<Self>
+    {
+        Self::read_with(reader, OutOfRange::Reject, Duplicates::LastWins)
+    }
+
+    /// Read options from the given reader; see `parse_with`.
+    pub fn read_with<'a>
+        (reader: &mut packetreader::PacketReader<'a>,
+         ranges: OutOfRange, duplicates: Duplicates)
+         -> Result<Self>
     {
         match reader.take_remaining() {
-            Ok(buffer) => match Self::parse(buffer) {
+            Ok(buffer) => match Self::parse_with(
+                buffer, ranges, duplicates) {
                 Ok(options) => Ok(options),
                 Err(error) => Err(Error::InvalidOptions(error)),
             },
@@ -54,61 +326,78 @@ impl Options {
 
     /// Write options to the given writer.
     pub fn write
-        (self, writer: &mut packetwriter::PacketWriter)
+        (&self, writer: &mut packetwriter::PacketWriter)
         -> Result<()>
     {
-        if let Some(blksize) = self.blksize {
-            writer.put_string("blksize")?;
-            writer.put_string(&blksize.to_string())?;
-        };
-        if let Some(timeout) = self.timeout {
-            writer.put_string("timeout")?;
-            writer.put_string(&timeout.to_string())?;
-        };
-        if let Some(tsize) = self.tsize {
-            writer.put_string("tsize")?;
-            writer.put_string(&tsize.to_string())?;
-        };
-        if let Some(windowsize) = self.windowsize {
-            writer.put_string("windowsize")?;
-            writer.put_string(&windowsize.to_string())?;
-        };
+        for (name, value) in self.iter() {
+            writer.put_string(name)?;
+            writer.put_string(&value)?;
+        }
         Ok(())
     }
 
-    /// Parse options from the given buffer.
+    /// The number of bytes these options occupy when written.
+    pub fn encoded_len(&self) -> usize {
+        self.iter()
+            .map(|(name, value)| name.len() + 1 + value.len() + 1)
+            .sum()
+    }
+
+    /// Parse options from the given buffer. Values outside the ranges
+    /// their RFCs permit are rejected, and where an option appears more
+    /// than once the last value is kept.
     ///
-    /// Note that errors arising from this method are *strings*.
-    pub fn parse<'a>(buf: &'a [u8]) -> result::Result<Self, String> {
+    /// Names are matched as bytes, and values are only copied when they
+    /// are not valid UTF-8 or belong to an unrecognised option.
+    pub fn parse(buf: &[u8]) -> result::Result<Self, OptionParseError> {
+        Self::parse_with(buf, OutOfRange::Reject, Duplicates::LastWins)
+    }
+
+    /// Parse options from the given buffer. Values outside the ranges
+    /// their RFCs permit are treated according to `ranges`, and options
+    /// that appear more than once according to `duplicates`.
+    pub fn parse_with(
+        buf: &[u8], ranges: OutOfRange, duplicates: Duplicates)
+        -> result::Result<Self, OptionParseError>
+    {
         let mut container = Self::new();
         let mut options = OptionStringIter::new(buf);
         loop {
             match options.next() {
                 OptionString::Terminated(option) => {
-                    let option = &String::from_utf8_lossy(option);
                     match options.next() {
                         OptionString::Terminated(value) => {
-                            let value = &String::from_utf8_lossy(value);
-                            container.parse_option(option, value)?;
+                            // Each name is matched only once, here.
+                            let name = known(option);
+                            if container.is_present_as(name, option) {
+                                match duplicates {
+                                    Duplicates::LastWins => {},
+                                    Duplicates::FirstWins => continue,
+                                    Duplicates::Reject => return Err(
+                                        OptionParseError::Duplicate(
+                                            lossy(option))),
+                                };
+                            };
+                            match name {
+                                Some(name) => container.parse_known(
+                                    name, option, value, ranges)?,
+                                None => container.parse_unknown(
+                                    option, value),
+                            };
                         },
                         OptionString::Unterminated(value) => {
-                            let value = &String::from_utf8_lossy(value);
-                            return Err(format!(
-                                "Option {} has unterminated value {}",
-                                option, value));
+                            return Err(OptionParseError::UnterminatedValue(
+                                lossy(option), lossy(value)));
                         },
                         OptionString::None => {
-                            return Err(format!(
-                                "Option {} has no corresponding value",
-                                option));
+                            return Err(OptionParseError::MissingValue(
+                                lossy(option)));
                         },
                     };
                 },
                 OptionString::Unterminated(option) => {
-                    let option = &String::from_utf8_lossy(option);
-                    return Err(format!(
-                        "Option {} is unterminated",
-                        option));
+                    return Err(OptionParseError::Unterminated(
+                        lossy(option)));
                 },
                 OptionString::None => {
                     return Ok(container);
@@ -117,60 +406,668 @@ impl Options {
         };
     }
 
-    fn parse_option
-        (&mut self, option: &str, value: &str) -> result::Result<(), String>
+    /// These options, less any that are not also set in `requested`,
+    /// and spelled as in `requested`. RFC-2347 forbids acknowledging an
+    /// option the client did not ask for, so apply this to an `OACK`
+    /// before sending it.
+    pub fn restricted_to(&self, requested: &Options) -> Options {
+        fn only<T>(value: Option<T>, requested: Option<T>) -> Option<T> {
+            requested.and(value)
+        }
+        Options{
+            blksize: only(self.blksize, requested.blksize),
+            timeout: only(self.timeout, requested.timeout),
+            tsize: only(self.tsize, requested.tsize),
+            windowsize: only(self.windowsize, requested.windowsize),
+            rollover: only(self.rollover, requested.rollover),
+            utimeout: only(self.utimeout, requested.utimeout),
+            multicast: only(self.multicast, requested.multicast),
+            unknown: self.unknown.iter()
+                .filter(|(name, _)| requested.is_present(name.as_bytes()))
+                .cloned().collect(),
+            spellings: requested.spellings.clone(),
+        }
+    }
+
+    /// Is every option set here also set in `requested`?
+    pub fn is_subset_of(&self, requested: &Options) -> bool {
+        self.iter().all(|(name, _)| requested.is_present(name.as_bytes()))
+    }
+
+    /// Has the option `option` already been parsed?
+    fn is_present(&self, option: &[u8]) -> bool {
+        self.is_present_as(known(option), option)
+    }
+
+    /// Has the option `option`, known as `name` if recognised, already
+    /// been parsed?
+    fn is_present_as(&self, name: Option<&str>, option: &[u8]) -> bool {
+        match name {
+            Some("blksize") => self.blksize.is_some(),
+            Some("timeout") => self.timeout.is_some(),
+            Some("tsize") => self.tsize.is_some(),
+            Some("windowsize") => self.windowsize.is_some(),
+            Some("rollover") => self.rollover.is_some(),
+            Some("utimeout") => self.utimeout.is_some(),
+            Some("multicast") => self.multicast.is_some(),
+            _ => self.unknown.iter().any(
+                |(name, _)| option.eq_ignore_ascii_case(name.as_bytes())),
+        }
+    }
+
+    /// Parse the value of the recognised option `name`, received
+    /// spelled as `option`.
+    fn parse_known(
+        &mut self, name: &'static str, option: &[u8], value: &[u8],
+        ranges: OutOfRange)
+        -> result::Result<(), OptionParseError>
     {
-        match option.to_lowercase().as_ref() {
-            "blksize" => self.blksize = Some(
-                Options::parse_blksize(value)?),
-            "timeout" => self.timeout = Some(
-                Options::parse_timeout(value)?),
-            "tsize" => self.tsize = Some(
-                Options::parse_tsize(value)?),
-            "windowsize" => self.windowsize = Some(
-                Options::parse_windowsize(value)?),
-            _ => {
-                // Ignore, as advised in RFC-2347.
-                // TODO: Record or log unrecognised options?
-            },
+        self.spellings.retain(|spelling| !spelling.eq_ignore_ascii_case(name));
+        if option != name.as_bytes() {
+            self.spellings.push(lossy(option));
+        }
+        // Borrowed, so free, unless the value is not valid UTF-8.
+        let value = &String::from_utf8_lossy(value);
+        match name {
+            "blksize" => self.blksize = in_range(
+                "blksize", Options::parse_blksize(value)?,
+                BLKSIZE, ranges, InvalidOption::Blksize)?,
+            "timeout" => self.timeout = in_range(
+                "timeout", Options::parse_timeout(value)?,
+                TIMEOUT, ranges, InvalidOption::Timeout)?,
+            "tsize" => self.tsize = Some(Options::parse_tsize(value)?),
+            "windowsize" => self.windowsize = in_range(
+                "windowsize", Options::parse_windowsize(value)?,
+                WINDOWSIZE, ranges, InvalidOption::Windowsize)?,
+            "rollover" => self.rollover = in_range(
+                "rollover", Options::parse_rollover(value)?,
+                ROLLOVER, ranges, InvalidOption::Rollover)?,
+            "utimeout" => self.utimeout = in_range(
+                "utimeout", Options::parse_utimeout(value)?,
+                UTIMEOUT, ranges, InvalidOption::Utimeout)?,
+            "multicast" =>
+                self.multicast = Some(Options::parse_multicast(value)?),
+            _ => unreachable!("{} is not a known option", name),
         };
         Ok(())
     }
 
-    fn parse_blksize(value: &str) -> result::Result<u16, String> {
+    /// Keep an option this library does not recognise. RFC-2347 advises
+    /// ignoring these, but keep them so that handlers can see what
+    /// clients are asking for.
+    fn parse_unknown(&mut self, option: &[u8], value: &[u8]) {
+        self.unknown.retain(
+            |(name, _)| !option.eq_ignore_ascii_case(name.as_bytes()));
+        self.unknown.push((lossy(option), lossy(value)));
+    }
+
+    fn parse_blksize(value: &str) -> result::Result<u16, OptionParseError> {
         Options::parse_value("blksize", value)
     }
 
-    fn parse_timeout(value: &str) -> result::Result<u8, String> {
+    fn parse_timeout(value: &str) -> result::Result<u8, OptionParseError> {
         Options::parse_value("timeout", value)
     }
 
-    fn parse_tsize(value: &str) -> result::Result<u64, String> {
+    fn parse_tsize(value: &str) -> result::Result<u64, OptionParseError> {
         Options::parse_value("tsize", value)
     }
 
-    fn parse_windowsize(value: &str) -> result::Result<u16, String> {
+    fn parse_windowsize(value: &str) -> result::Result<u16, OptionParseError> {
         Options::parse_value("windowsize", value)
     }
 
+    fn parse_rollover(value: &str) -> result::Result<u16, OptionParseError> {
+        Options::parse_value("rollover", value)
+    }
+
+    fn parse_utimeout(value: &str) -> result::Result<u32, OptionParseError> {
+        Options::parse_value("utimeout", value)
+    }
+
+    fn parse_multicast(value: &str)
+        -> result::Result<Multicast, OptionParseError>
+    {
+        Multicast::from_str(value).map_err(|error| {
+            OptionParseError::InvalidValue(
+                "multicast", value.to_owned(), error)
+        })
+    }
+
     fn parse_value<T: FromStr>
-        (option: &str, value: &str) -> result::Result<T, String>
+        (option: &'static str, value: &str)
+         -> result::Result<T, OptionParseError>
         where <T as FromStr>::Err: Display
     {
         match T::from_str(value) {
             Ok(value) => Ok(value),
-            Err(error) => Err(format!(
-                "Invalid {} value {:?}: {}", option, value, error))
+            Err(error) => Err(OptionParseError::InvalidValue(
+                option, value.to_owned(), error.to_string())),
+        }
+    }
+
+}
+
+
+/// The names of the options this library recognises.
+const KNOWN: [&str; 7] = [
+    "blksize", "timeout", "tsize", "windowsize", "rollover", "utimeout",
+    "multicast",
+];
+
+
+/// The usual spelling of `option` if this library recognises it,
+/// compared without regard to case, and without lowercasing to avoid
+/// allocating.
+fn known(option: &[u8]) -> Option<&'static str> {
+    KNOWN.iter().find(|name| option.eq_ignore_ascii_case(name.as_bytes()))
+        .copied()
+}
+
+
+/// The block sizes RFC-2348 permits.
+const BLKSIZE: RangeInclusive<u16> = 8..=65464;
+/// The time-outs, in seconds, RFC-2349 permits.
+const TIMEOUT: RangeInclusive<u8> = 1..=255;
+/// The window sizes RFC-7440 permits.
+const WINDOWSIZE: RangeInclusive<u16> = 1..=65535;
+/// The block numbers that can follow 65535.
+const ROLLOVER: RangeInclusive<u16> = 0..=1;
+/// The time-outs, in microseconds, that make sense.
+const UTIMEOUT: RangeInclusive<u32> = 1..=u32::MAX;
+
+
+/// Check that `value` is within `range`, applying `action` to it if
+/// not, as `bound` does. A rejected value is described by `invalid`.
+fn in_range<T: Copy + Ord + Display>(
+    option: &'static str, value: T, range: RangeInclusive<T>,
+    action: OutOfRange, invalid: fn(T) -> InvalidOption)
+    -> result::Result<Option<T>, InvalidOption>
+{
+    bound(option, value, *range.start(), *range.end(), action)
+        .map_err(|_| invalid(value))
+}
+
+
+/// Convert an option name or value for display or storage.
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+
+/// How to treat an option that appears more than once in a request.
+/// RFC-2347 does not say; well-formed requests never do this.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Duplicates {
+    /// Keep the last value given.
+    LastWins,
+    /// Keep the first value given, and ignore the rest.
+    FirstWins,
+    /// Reject the request as invalid.
+    Reject,
+}
+
+
+/// Why options could not be parsed.
+#[derive(Clone,Debug,PartialEq,Eq)]
+#[non_exhaustive]
+pub enum OptionParseError {
+    /// The named option is not NUL-terminated.
+    Unterminated(String),
+    /// The named option's value is not NUL-terminated.
+    UnterminatedValue(String, String),
+    /// The named option has no value.
+    MissingValue(String),
+    /// The named option's value could not be parsed, for the reason
+    /// given.
+    InvalidValue(&'static str, String, String),
+    /// The named option appears more than once.
+    Duplicate(String),
+    /// An option's value is outside the range its RFC permits.
+    OutOfRange(InvalidOption),
+}
+
+
+impl fmt::Display for OptionParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OptionParseError::Unterminated(ref option) =>
+                write!(f, "Option {} is unterminated", option),
+            OptionParseError::UnterminatedValue(ref option, ref value) =>
+                write!(f, "Option {} has unterminated value {}",
+                       option, value),
+            OptionParseError::MissingValue(ref option) =>
+                write!(f, "Option {} has no corresponding value", option),
+            OptionParseError::InvalidValue(option, ref value, ref reason) =>
+                write!(f, "Invalid {} value {:?}: {}", option, value, reason),
+            OptionParseError::Duplicate(ref option) =>
+                write!(f, "Option {} appears more than once", option),
+            OptionParseError::OutOfRange(ref error) =>
+                write!(f, "Option {}", error),
+        }
+    }
+}
+
+
+impl error::Error for OptionParseError {}
+
+
+impl From<InvalidOption> for OptionParseError {
+    fn from(error: InvalidOption) -> OptionParseError {
+        OptionParseError::OutOfRange(error)
+    }
+}
+
+
+/// An option value outside the range its RFC permits.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+#[non_exhaustive]
+pub enum InvalidOption {
+    /// A block size outside 8-65464, per RFC-2348.
+    Blksize(u16),
+    /// A time-out of zero seconds; RFC-2349 permits 1-255.
+    Timeout(u8),
+    /// A window size of zero; RFC-7440 permits 1-65535.
+    Windowsize(u16),
+    /// A block number to follow 65535 that is neither 0 nor 1.
+    Rollover(u16),
+    /// A time-out of zero microseconds.
+    Utimeout(u32),
+}
+
+
+impl fmt::Display for InvalidOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InvalidOption::Blksize(value) => write!(
+                f, "blksize {} not in range {}-{}",
+                value, BLKSIZE.start(), BLKSIZE.end()),
+            InvalidOption::Timeout(value) => write!(
+                f, "timeout {} not in range {}-{}",
+                value, TIMEOUT.start(), TIMEOUT.end()),
+            InvalidOption::Windowsize(value) => write!(
+                f, "windowsize {} not in range {}-{}",
+                value, WINDOWSIZE.start(), WINDOWSIZE.end()),
+            InvalidOption::Rollover(value) => write!(
+                f, "rollover {} not {} or {}",
+                value, ROLLOVER.start(), ROLLOVER.end()),
+            InvalidOption::Utimeout(value) => write!(
+                f, "utimeout {} not in range {}-{}",
+                value, UTIMEOUT.start(), UTIMEOUT.end()),
+        }
+    }
+}
+
+
+impl error::Error for InvalidOption {}
+
+
+/// The value of the `multicast` option. Defined in RFC-2090.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Multicast {
+    /// A client asking for a multicast transfer; the value is empty.
+    Request,
+    /// A server's acknowledgement, written as `addr,port,mc`.
+    Ack {
+        /// The multicast group and port. May be omitted, leaving the
+        /// fields empty, when they have not changed since a previous
+        /// `OACK`.
+        group: Option<net::SocketAddrV4>,
+        /// Is the client the master client, which must acknowledge
+        /// every block? This is the `mc` flag.
+        master: bool,
+    },
+}
+
+
+impl fmt::Display for Multicast {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Multicast::Request => Ok(()),
+            Multicast::Ack{group: Some(group), master} => write!(
+                f, "{},{},{}", group.ip(), group.port(), master as u8),
+            Multicast::Ack{group: None, master} => write!(
+                f, ",,{}", master as u8),
+        }
+    }
+}
+
+
+impl FromStr for Multicast {
+    type Err = String;
+
+    fn from_str(value: &str) -> result::Result<Multicast, String> {
+        if value.is_empty() {
+            return Ok(Multicast::Request);
         }
+        let fields: Vec<&str> = value.split(',').collect();
+        let (addr, port, mc) = match fields[..] {
+            [addr, port, mc] => (addr, port, mc),
+            _ => return Err("expected addr,port,mc".to_owned()),
+        };
+        let master = match mc {
+            "0" => false,
+            "1" => true,
+            _ => return Err(format!("mc flag {:?} not 0 or 1", mc)),
+        };
+        let group = match (addr, port) {
+            ("", "") => None,
+            (addr, port) => {
+                let addr: net::Ipv4Addr = addr.parse().map_err(
+                    |error| format!("addr {:?}: {}", addr, error))?;
+                let port: u16 = port.parse().map_err(
+                    |error| format!("port {:?}: {}", port, error))?;
+                Some(net::SocketAddrV4::new(addr, port))
+            },
+        };
+        Ok(Multicast::Ack{group, master})
     }
+}
 
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Multicast {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>)
+        -> arbitrary::Result<Self>
+    {
+        if u.arbitrary()? {
+            return Ok(Multicast::Request);
+        }
+        let group = u.arbitrary::<Option<(u32, u16)>>()?.map(
+            |(addr, port)| net::SocketAddrV4::new(addr.into(), port));
+        Ok(Multicast::Ack{group, master: u.arbitrary()?})
+    }
+}
+
+
+/// What to do with a requested option value that falls outside the
+/// range an `OptionsPolicy` allows or, when parsing, the range its RFC
+/// permits.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum OutOfRange {
+    /// Reduce values above the maximum to it. Values below the minimum
+    /// are ignored: a server may acknowledge a smaller value than the
+    /// client asked for, but never a larger one.
+    Clamp,
+    /// Do not acknowledge the option; the transfer uses the default.
+    Ignore,
+    /// Refuse the request with an `ERROR` packet, code 8.
+    Reject,
+}
+
+
+/// Apply `action` to `value` if it lies outside `min..=max`.
+fn bound<T: Copy + Ord + Display>(
+    name: &str, value: T, min: T, max: T, action: OutOfRange)
+    -> result::Result<Option<T>, String>
+{
+    if value >= min && value <= max {
+        Ok(Some(value))
+    } else {
+        match action {
+            OutOfRange::Clamp if value > max => Ok(Some(max)),
+            OutOfRange::Clamp | OutOfRange::Ignore => Ok(None),
+            OutOfRange::Reject => Err(format!(
+                "Option {} value {} not in range {}-{}",
+                name, value, min, max)),
+        }
+    }
+}
+
+
+/// A server's limits when negotiating options, so that operators can
+/// bound the memory and bandwidth each transfer may use.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct OptionsPolicy {
+    /// The smallest block size that will be acknowledged.
+    pub min_blksize: u16,
+    /// The largest block size that will be acknowledged.
+    pub max_blksize: u16,
+    /// What to do with a block size outside `min_blksize..=max_blksize`.
+    pub bad_blksize: OutOfRange,
+    /// The shortest time-out, in seconds, that will be acknowledged.
+    pub min_timeout: u8,
+    /// The longest time-out, in seconds, that will be acknowledged.
+    pub max_timeout: u8,
+    /// The shortest `utimeout`, in microseconds, that will be
+    /// acknowledged.
+    pub min_utimeout: u32,
+    /// The longest `utimeout`, in microseconds, that will be
+    /// acknowledged.
+    pub max_utimeout: u32,
+    /// What to do with a time-out outside `min_timeout..=max_timeout`,
+    /// or a `utimeout` outside `min_utimeout..=max_utimeout`.
+    pub bad_timeout: OutOfRange,
+    /// The time-out to use when the client does not ask for one.
+    pub default_timeout: time::Duration,
+    /// The largest window size that will be acknowledged. Downloads
+    /// send this many blocks before awaiting an acknowledgement, as
+    /// RFC-7440 describes; uploads are acknowledged block by block, so
+    /// never acknowledge a window size at all.
+    pub max_windowsize: u16,
+    /// What to do with a window size outside `1..=max_windowsize`.
+    pub bad_windowsize: OutOfRange,
+    /// Whether to answer `tsize`.
+    pub honour_tsize: bool,
+}
+
+
+impl Default for OptionsPolicy {
+    fn default() -> OptionsPolicy {
+        OptionsPolicy::new()
+    }
+}
+
+
+impl OptionsPolicy {
+
+    pub fn new() -> OptionsPolicy {
+        OptionsPolicy{
+            min_blksize: 512,
+            max_blksize: 65464,
+            bad_blksize: OutOfRange::Clamp,
+            min_timeout: 1,
+            max_timeout: 255,
+            min_utimeout: 10_000,
+            max_utimeout: 255_000_000,
+            bad_timeout: OutOfRange::Clamp,
+            default_timeout: time::Duration::from_secs(8),
+            max_windowsize: 1,
+            bad_windowsize: OutOfRange::Clamp,
+            honour_tsize: true,
+        }
+    }
+
+}
+
+
+/// The parameters a transfer uses once options have been negotiated.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct Negotiated {
+    pub blksize: usize,
+    pub timeout: time::Duration,
+    pub windowsize: u16,
+    /// The block number that follows 65535.
+    pub rollover: u16,
+}
+
+
+/// A custom option, like `checksum` or `offset`, that an application
+/// has taught this library to negotiate.
+pub trait Extension: Send + Sync {
+
+    /// The option's name. Requests are matched without regard to case.
+    fn name(&self) -> &str;
+
+    /// Check the value a client has asked for, returning the value to
+    /// acknowledge, or an error message to send to the client.
+    fn accept(&self, value: &str) -> result::Result<String, String>;
+
+}
+
+
+/// An `Extension` whose value is parsed as a `T`, and accepted if
+/// `check` approves of it. The acknowledged value is the parsed value
+/// formatted again, so that, for example, `007` is acknowledged as `7`.
+pub struct TypedExtension<T> {
+    name: String,
+    check: fn(&T) -> bool,
+}
+
+
+impl<T> TypedExtension<T> {
+
+    pub fn new(name: &str, check: fn(&T) -> bool) -> TypedExtension<T> {
+        TypedExtension{name: name.to_owned(), check}
+    }
+
+}
+
+
+impl<T> Extension for TypedExtension<T>
+    where T: FromStr + Display, <T as FromStr>::Err: Display
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn accept(&self, value: &str) -> result::Result<String, String> {
+        let value: T = T::from_str(value).map_err(|error| format!(
+            "Invalid {} value {:?}: {}", self.name, value, error))?;
+        if (self.check)(&value) {
+            Ok(value.to_string())
+        } else {
+            Err(format!("Option {} value {} not acceptable", self.name, value))
+        }
+    }
+}
+
+
+/// The extensions an application has registered.
+#[derive(Default)]
+pub struct Extensions {
+    extensions: Vec<Box<dyn Extension>>,
+}
+
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.extensions.iter().map(|e| e.name()))
+            .finish()
+    }
+}
+
+
+impl Extensions {
+
+    pub fn new() -> Extensions {
+        Extensions{extensions: Vec::new()}
+    }
+
+    /// Register an extension, replacing any with the same name.
+    pub fn register<E: Extension + 'static>(&mut self, extension: E) {
+        self.extensions.retain(
+            |e| !e.name().eq_ignore_ascii_case(extension.name()));
+        self.extensions.push(Box::new(extension));
+    }
+
+    /// Decide which of the unrecognised options in `requested` to
+    /// acknowledge. Options that are not registered are left out, as
+    /// RFC-2347 requires; an error is returned if a registered option's
+    /// value is not acceptable. Names are spelled as in `requested`.
+    pub fn negotiate(&self, requested: &Options)
+        -> result::Result<Vec<(String, String)>, String>
+    {
+        let mut accepted = Vec::new();
+        for (name, value) in requested.unknown.iter() {
+            let extension = self.extensions.iter()
+                .find(|e| e.name().eq_ignore_ascii_case(name));
+            if let Some(extension) = extension {
+                let value = extension.accept(value)?;
+                accepted.push((name.clone(), value));
+            }
+        }
+        Ok(accepted)
+    }
+
+}
+
+
+/// Any combination of options with values in range, so that they
+/// survive a trip through `write` and `parse`.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Options {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>)
+        -> arbitrary::Result<Self>
+    {
+        // Unknown options must be ASCII without NUL to be written, and
+        // must not be mistaken for known options, or each other, when
+        // parsed.
+        fn string(u: &mut arbitrary::Unstructured) -> arbitrary::Result<String>
+        {
+            let string: String = u.arbitrary()?;
+            Ok(string.chars().filter(|c| c.is_ascii() && *c != '\0').collect())
+        }
+        let mut unknown: Vec<(String, String)> = Vec::new();
+        for _ in 0..u.int_in_range(0..=3)? {
+            let name = format!("x-{}", string(u)?);
+            let value = string(u)?;
+            let duplicate = unknown.iter().any(
+                |(other, _)| other.eq_ignore_ascii_case(&name));
+            if !duplicate {
+                unknown.push((name, value));
+            }
+        }
+        // Known options must be in range to survive parsing.
+        fn ranged<T>(u: &mut arbitrary::Unstructured, min: T, max: T)
+            -> arbitrary::Result<Option<T>>
+            where T: arbitrary::unstructured::Int
+        {
+            match u.arbitrary()? {
+                true => Ok(Some(u.int_in_range(min..=max)?)),
+                false => Ok(None),
+            }
+        }
+        Ok(Options{
+            blksize: ranged(u, 8, 65464)?,
+            timeout: ranged(u, 1, 255)?,
+            tsize: u.arbitrary()?,
+            windowsize: ranged(u, 1, 65535)?,
+            rollover: ranged(u, 0, 1)?,
+            utimeout: ranged(u, 1, u32::MAX)?,
+            multicast: u.arbitrary()?,
+            unknown,
+            spellings: Vec::new(),
+        })
+    }
 }
 
 
 #[cfg(test)]
 mod test_options {
 
-    use super::Options;
+    #[cfg(feature = "slog")]
+    use std::fmt;
+    use std::result;
+    use std::time;
+
+    #[cfg(feature = "slog")]
+    use slog::{self, b, record_static};
+
+    use super::{
+        Duplicates, Extensions, InvalidOption, Multicast, OptionParseError,
+        Options, OptionsPolicy, OutOfRange, TypedExtension};
+    use super::super::packet::Error;
+    use super::super::packetreader;
+    use super::super::packetwriter;
+
+    fn message<T>(result: result::Result<T, OptionParseError>)
+        -> result::Result<T, String>
+    {
+        result.map_err(|error| error.to_string())
+    }
 
     #[test]
     fn test_creating_new_options() {
@@ -181,15 +1078,27 @@ mod test_options {
         assert_eq!(options.windowsize, None);
     }
 
+    #[test]
+    fn test_display() {
+        let mut options = Options::new();
+        assert_eq!("", options.to_string());
+        options.timeout = Some(5);
+        assert_eq!("timeout=5", options.to_string());
+        options.blksize = Some(1468);
+        options.windowsize = Some(16);
+        assert_eq!(
+            "blksize=1468 timeout=5 windowsize=16", options.to_string());
+    }
+
     #[test]
     fn test_parsing_blksize() {
         assert_eq!(Options::parse_blksize("123"), Ok(123u16));
         assert_eq!(
-            Options::parse_blksize("foo"), Err(
+            message(Options::parse_blksize("foo")), Err(
                 "Invalid blksize value \"foo\": ".to_string() +
                     "invalid digit found in string"));
         assert_eq!(
-            Options::parse_blksize("65536"), Err(
+            message(Options::parse_blksize("65536")), Err(
                 "Invalid blksize value \"65536\": ".to_string() +
                     "number too large to fit in target type"));
     }
@@ -198,11 +1107,11 @@ mod test_options {
     fn test_parsing_timeout() {
         assert_eq!(Options::parse_timeout("123"), Ok(123u8));
         assert_eq!(
-            Options::parse_timeout("foo"), Err(
+            message(Options::parse_timeout("foo")), Err(
                 "Invalid timeout value \"foo\": ".to_string() +
                     "invalid digit found in string"));
         assert_eq!(
-            Options::parse_timeout("256"), Err(
+            message(Options::parse_timeout("256")), Err(
                 "Invalid timeout value \"256\": ".to_string() +
                     "number too large to fit in target type"));
     }
@@ -211,11 +1120,11 @@ mod test_options {
     fn test_parsing_tsize() {
         assert_eq!(Options::parse_tsize("123"), Ok(123u64));
         assert_eq!(
-            Options::parse_tsize("foo"), Err(
+            message(Options::parse_tsize("foo")), Err(
                 "Invalid tsize value \"foo\": ".to_string() +
                     "invalid digit found in string"));
         assert_eq!(
-            Options::parse_tsize("18446744073709551616"), Err(
+            message(Options::parse_tsize("18446744073709551616")), Err(
                 "Invalid tsize value \"18446744073709551616\": ".to_string() +
                  "number too large to fit in target type"));
     }
@@ -224,23 +1133,332 @@ mod test_options {
     fn test_parsing_windowsize() {
         assert_eq!(Options::parse_windowsize("123"), Ok(123u16));
         assert_eq!(
-            Options::parse_windowsize("foo"), Err(
+            message(Options::parse_windowsize("foo")), Err(
                 "Invalid windowsize value \"foo\": ".to_string() +
                     "invalid digit found in string"));
         assert_eq!(
-            Options::parse_windowsize("65536"), Err(
+            message(Options::parse_windowsize("65536")), Err(
                 "Invalid windowsize value \"65536\": ".to_string() +
                     "number too large to fit in target type"));
     }
 
+    #[test]
+    fn test_parsing_rollover() {
+        assert_eq!(Options::parse_rollover("0"), Ok(0u16));
+        assert_eq!(Options::parse_rollover("1"), Ok(1u16));
+        assert_eq!(
+            message(Options::parse(b"rollover\x002\0")), Err(
+                "Option rollover 2 not 0 or 1".to_string()));
+        let options = Options::parse(b"rollover\x001\0").unwrap();
+        assert_eq!(options.rollover, Some(1));
+        assert_eq!("rollover=1", options.to_string());
+    }
+
+    #[test]
+    fn test_negotiate_utimeout() {
+        let mut policy = OptionsPolicy::new();
+        let mut options = Options::new();
+        options.timeout = Some(3);
+        options.utimeout = Some(250_000);
+        let (accepted, negotiated) = options.negotiate(&policy).unwrap();
+        assert_eq!(Some(3), accepted.timeout);
+        assert_eq!(Some(250_000), accepted.utimeout);
+        assert_eq!(time::Duration::from_millis(250), negotiated.timeout);
+        // Too short to be useful.
+        options.utimeout = Some(10);
+        let (accepted, negotiated) = options.negotiate(&policy).unwrap();
+        assert_eq!(None, accepted.utimeout);
+        assert_eq!(time::Duration::from_secs(3), negotiated.timeout);
+        policy.bad_timeout = OutOfRange::Reject;
+        assert!(options.negotiate(&policy).is_err());
+        assert_eq!(
+            "timeout=3 UTIMEOUT=10",
+            Options::parse(b"timeout\x003\0UTIMEOUT\x0010\0")
+                .unwrap().to_string());
+    }
+
+    #[test]
+    fn test_parsing_multicast() {
+        assert_eq!(Ok(Multicast::Request), Options::parse_multicast(""));
+        let ack = Multicast::Ack{
+            group: Some("224.1.2.3:1758".parse().unwrap()), master: true};
+        assert_eq!(Ok(ack), Options::parse_multicast("224.1.2.3,1758,1"));
+        assert_eq!("224.1.2.3,1758,1", ack.to_string());
+        let ack = Multicast::Ack{group: None, master: false};
+        assert_eq!(Ok(ack), Options::parse_multicast(",,0"));
+        assert_eq!(",,0", ack.to_string());
+        assert_eq!(
+            Err("Invalid multicast value \"224.1.2.3,1758\": \
+                 expected addr,port,mc".to_owned()),
+            message(Options::parse_multicast("224.1.2.3,1758")));
+        assert_eq!(
+            Err("Invalid multicast value \"224.1.2.3,,1\": \
+                 port \"\": cannot parse integer from empty string"
+                .to_owned()),
+            message(Options::parse_multicast("224.1.2.3,,1")));
+        assert!(Options::parse_multicast(",,2").is_err());
+    }
+
+    #[test]
+    fn test_multicast_round_trip() {
+        let mut options = Options::new();
+        options.multicast = Some(Multicast::Request);
+        assert_eq!("multicast=", options.to_string());
+        let mut buffer = [0u8; 32];
+        let mut writer = packetwriter::PacketWriter::new(&mut buffer);
+        options.write(&mut writer).unwrap();
+        assert_eq!(options.encoded_len(), writer.pos());
+        let (buffer, size) = writer.get();
+        assert_eq!(b"multicast\0\0", &buffer[..size]);
+        assert_eq!(options, Options::parse(&buffer[..size]).unwrap());
+        // Not acknowledged.
+        let (accepted, _) = options.negotiate(&OptionsPolicy::new()).unwrap();
+        assert_eq!(None, accepted.multicast);
+    }
+
     #[test]
     fn test_parsing_options() {
-        let buf = "blksize\067\0timeout\076\0tsize\098\0windowsize\0429\0".as_bytes();
+        let buf = "blksize\x0067\0timeout\x0076\0tsize\x0098\0windowsize\x00429\0".as_bytes();
         let options = Options::parse(buf).unwrap();
         assert_eq!(options.blksize, Some(67));
         assert_eq!(options.timeout, Some(76));
         assert_eq!(options.tsize, Some(98));
         assert_eq!(options.windowsize, Some(429));
+        assert!(options.unknown.is_empty());
+    }
+
+    #[test]
+    fn test_negotiate_with_defaults() {
+        let (accepted, negotiated) =
+            Options::new().negotiate(&OptionsPolicy::new()).unwrap();
+        assert!(!accepted.is_set());
+        assert_eq!(512, negotiated.blksize);
+        assert_eq!(time::Duration::from_secs(8), negotiated.timeout);
+    }
+
+    #[test]
+    fn test_negotiate_applies_policy() {
+        let mut policy = OptionsPolicy::new();
+        policy.max_blksize = 1468;
+        policy.max_timeout = 10;
+        let mut options = Options::new();
+        options.blksize = Some(8192);
+        options.timeout = Some(3);
+        options.tsize = Some(0);
+        options.windowsize = Some(16);
+        let (accepted, negotiated) = options.negotiate(&policy).unwrap();
+        assert_eq!(Some(1468), accepted.blksize);
+        assert_eq!(Some(3), accepted.timeout);
+        assert_eq!(Some(0), accepted.tsize);
+        assert_eq!(Some(1), accepted.windowsize);
+        assert_eq!(1468, negotiated.blksize);
+        assert_eq!(time::Duration::from_secs(3), negotiated.timeout);
+        assert_eq!(1, negotiated.windowsize);
+        // Too small, and zero, are ignored.
+        options.blksize = Some(511);
+        options.timeout = Some(0);
+        options.windowsize = Some(0);
+        policy.honour_tsize = false;
+        let (accepted, negotiated) = options.negotiate(&policy).unwrap();
+        assert!(!accepted.is_set());
+        assert_eq!(512, negotiated.blksize);
+        assert_eq!(policy.default_timeout, negotiated.timeout);
+    }
+
+    #[test]
+    fn test_negotiate_out_of_range() {
+        let mut policy = OptionsPolicy::new();
+        policy.max_blksize = 1468;
+        let mut options = Options::new();
+        options.blksize = Some(8192);
+        policy.bad_blksize = OutOfRange::Ignore;
+        let (accepted, negotiated) = options.negotiate(&policy).unwrap();
+        assert_eq!(None, accepted.blksize);
+        assert_eq!(512, negotiated.blksize);
+        policy.bad_blksize = OutOfRange::Reject;
+        assert_eq!(
+            Err("Option blksize value 8192 not in range 512-1468".to_owned()),
+            options.negotiate(&policy));
+        options.blksize = Some(1024);
+        options.timeout = Some(0);
+        policy.bad_timeout = OutOfRange::Reject;
+        assert_eq!(
+            Err("Option timeout value 0 not in range 1-255".to_owned()),
+            options.negotiate(&policy));
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut options = Options::new();
+        assert_eq!(0, options.iter().count());
+        options.tsize = Some(0);
+        options.blksize = Some(1468);
+        options.set_extension("x-foo", "bar");
+        assert_eq!(
+            vec![
+                ("blksize", "1468".to_owned()),
+                ("tsize", "0".to_owned()),
+                ("x-foo", "bar".to_owned()),
+            ],
+            options.iter().collect::<Vec<_>>());
+    }
+
+    /// Collects serialized fields as strings.
+    #[cfg(feature = "slog")]
+    struct Fields(Vec<(String, String)>);
+
+    #[cfg(feature = "slog")]
+    impl slog::Serializer for Fields {
+        fn emit_arguments(&mut self, key: slog::Key, value: &fmt::Arguments)
+            -> slog::Result
+        {
+            self.0.push((key.to_string(), value.to_string()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "slog")]
+    fn test_logging() {
+        let mut options = Options::new();
+        options.blksize = Some(1468);
+        options.windowsize = Some(16);
+        options.set_extension("x-foo", "bar");
+        let rs = record_static!(slog::Level::Info, "");
+        let mut fields = Fields(Vec::new());
+        slog::KV::serialize(
+            &options, &slog::Record::new(&rs, &format_args!(""), b!()),
+            &mut fields).unwrap();
+        assert_eq!(
+            vec![
+                ("blksize".to_owned(), "1468".to_owned()),
+                ("windowsize".to_owned(), "16".to_owned()),
+                ("extensions".to_owned(), "x-foo=bar".to_owned()),
+            ],
+            fields.0);
+        let mut fields = Fields(Vec::new());
+        slog::Value::serialize(
+            &options, &slog::Record::new(&rs, &format_args!(""), b!()),
+            "options", &mut fields).unwrap();
+        assert_eq!(
+            vec![(
+                "options".to_owned(),
+                "blksize=1468 windowsize=16 x-foo=bar".to_owned(),
+            )],
+            fields.0);
+    }
+
+    #[test]
+    fn test_spelling_does_not_affect_equality() {
+        assert_eq!(
+            Options::parse(b"BlkSize\x001468\0").unwrap(),
+            Options::parse(b"blksize\x001468\0").unwrap());
+    }
+
+    #[test]
+    fn test_spelling_is_preserved() {
+        let requested = Options::parse(
+            b"BlkSize\x001468\0timeout\x003\0TSIZE\x000\0").unwrap();
+        assert_eq!(vec!["BlkSize", "TSIZE"], requested.spellings);
+        let (mut accepted, _) =
+            requested.negotiate(&OptionsPolicy::new()).unwrap();
+        accepted.tsize = Some(1234);
+        let mut buffer = [0u8; 64];
+        let mut writer = packetwriter::PacketWriter::new(&mut buffer);
+        accepted.write(&mut writer).unwrap();
+        let (buffer, size) = writer.get();
+        assert_eq!(
+            &b"BlkSize\x001468\0timeout\x003\0TSIZE\x001234\0"[..],
+            &buffer[..size]);
+        // Spelling comes from the request when restricting.
+        let mut options = Options::new();
+        options.blksize = Some(512);
+        assert_eq!(
+            "BlkSize=512", options.restricted_to(&requested).to_string());
+    }
+
+    #[test]
+    fn test_restricted_to() {
+        let mut requested = Options::new();
+        requested.blksize = Some(8192);
+        requested.set_extension("X-Foo", 1);
+        let mut options = Options::new();
+        options.blksize = Some(1468);
+        options.tsize = Some(1234);
+        options.set_extension("x-foo", 1);
+        options.set_extension("x-bar", 2);
+        assert!(!options.is_subset_of(&requested));
+        let options = options.restricted_to(&requested);
+        assert_eq!("blksize=1468 x-foo=1", options.to_string());
+        assert!(options.is_subset_of(&requested));
+    }
+
+    #[test]
+    fn test_negotiate_acknowledges_only_requested_options() {
+        let mut policy = OptionsPolicy::new();
+        policy.max_windowsize = 16;
+        let requests = [
+            "", "blksize\x001468\0", "timeout\x003\0tsize\x000\0",
+            "windowsize\x0032\0rollover\x000\0", "utimeout\x00500000\0",
+            "multicast\0\0", "x-foo\0bar\0",
+        ];
+        for request in requests.iter() {
+            let requested = Options::parse(request.as_bytes()).unwrap();
+            let (accepted, _) = requested.negotiate(&policy).unwrap();
+            assert!(accepted.is_subset_of(&requested), "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn test_extension_accessors() {
+        let mut options = Options::new();
+        options.set_extension("offset", 512);
+        options.set_extension("OFFSET", 1024);
+        assert_eq!(Some("1024"), options.extension("Offset"));
+        assert_eq!(None, options.extension("checksum"));
+        assert_eq!(1, options.unknown.len());
+    }
+
+    #[test]
+    fn test_extensions_negotiate() {
+        let mut extensions = Extensions::new();
+        extensions.register(TypedExtension::new("offset", |&o: &u64| {
+            o % 512 == 0
+        }));
+        let mut options = Options::new();
+        options.set_extension("Offset", "01024");
+        options.set_extension("checksum", "md5");
+        assert_eq!(
+            Ok(vec![("Offset".to_owned(), "1024".to_owned())]),
+            extensions.negotiate(&options));
+        options.set_extension("offset", 100);
+        assert_eq!(
+            Err("Option offset value 100 not acceptable".to_owned()),
+            extensions.negotiate(&options));
+        options.set_extension("offset", "foo");
+        assert!(extensions.negotiate(&options).is_err());
+    }
+
+    #[test]
+    fn test_parsing_unknown_options() {
+        let buf = b"Checksum\0md5\0blksize\x0067\0offset\x00512\0";
+        let options = Options::parse(buf).unwrap();
+        assert_eq!(options.blksize, Some(67));
+        assert_eq!(
+            vec![
+                ("Checksum".to_owned(), "md5".to_owned()),
+                ("offset".to_owned(), "512".to_owned()),
+            ],
+            options.unknown);
+        assert!(options.is_set());
+        assert_eq!(
+            "blksize=67 Checksum=md5 offset=512", options.to_string());
+        let mut buffer = [0u8; 64];
+        let mut writer = packetwriter::PacketWriter::new(&mut buffer);
+        options.write(&mut writer).unwrap();
+        assert_eq!(options.encoded_len(), writer.pos());
+        let (buffer, size) = writer.get();
+        assert_eq!(options, Options::parse(&buffer[..size]).unwrap());
     }
 
     #[test]
@@ -257,15 +1475,15 @@ mod test_options {
     fn test_parsing_incorrectly_terminated_option_results_in_error() {
         let buf = "blksize".as_bytes();  // No trailing null byte.
         assert_eq!(
-            Options::parse(buf).unwrap_err(),
+            Options::parse(buf).unwrap_err().to_string(),
             "Option blksize is unterminated");
     }
 
     #[test]
     fn test_parsing_incorrectly_terminated_value_results_in_error() {
-        let buf = "blksize\067".as_bytes();  // No trailing null byte.
+        let buf = "blksize\x0067".as_bytes();  // No trailing null byte.
         assert_eq!(
-            Options::parse(buf).unwrap_err(),
+            Options::parse(buf).unwrap_err().to_string(),
             "Option blksize has unterminated value 67");
     }
 
@@ -273,15 +1491,79 @@ mod test_options {
     fn test_parsing_option_without_value_results_in_error() {
         let buf = "foo\0".as_bytes();
         assert_eq!(
-            Options::parse(buf).unwrap_err(),
+            Options::parse(buf).unwrap_err().to_string(),
             "Option foo has no corresponding value");
     }
 
+    #[test]
+    fn test_parsing_errors_are_structured() {
+        assert_eq!(
+            Err(OptionParseError::MissingValue("foo".to_owned())),
+            Options::parse(b"foo\0"));
+        assert_eq!(
+            Err(OptionParseError::InvalidValue(
+                "timeout", "\u{fffd}".to_owned(),
+                "invalid digit found in string".to_owned())),
+            Options::parse(b"TimeOut\0\xff\0"));
+    }
+
+    #[test]
+    fn test_reading_keeps_parse_errors_structured() {
+        let mut reader = packetreader::PacketReader::new(b"foo\0");
+        assert_eq!(
+            Err(Error::InvalidOptions(
+                OptionParseError::MissingValue("foo".to_owned()))),
+            Options::read(&mut reader));
+    }
+
+    #[test]
+    fn test_parsing_checks_ranges() {
+        let buf = b"blksize\x004\0timeout\x000\0windowsize\x000\0";
+        assert_eq!(
+            Err(OptionParseError::OutOfRange(InvalidOption::Blksize(4))),
+            Options::parse(buf));
+        assert_eq!(
+            message(Options::parse(b"timeout\x000\0")), Err(
+                "Option timeout 0 not in range 1-255".to_string()));
+        // Values below the minimum are ignored when clamping, as when
+        // negotiating.
+        let options = Options::parse_with(
+            buf, OutOfRange::Clamp, Duplicates::LastWins).unwrap();
+        assert_eq!("", options.to_string());
+        let buf = b"blksize\x0065535\0utimeout\x000\0rollover\x009\0";
+        let options = Options::parse_with(
+            buf, OutOfRange::Clamp, Duplicates::LastWins).unwrap();
+        assert_eq!("blksize=65464 rollover=1", options.to_string());
+        let options = Options::parse_with(
+            buf, OutOfRange::Ignore, Duplicates::LastWins).unwrap();
+        assert_eq!("", options.to_string());
+    }
+
+    #[test]
+    fn test_parsing_duplicates() {
+        let buf = b"blksize\x00512\0x-foo\0a\0BLKSIZE\x001468\0X-Foo\0b\0";
+        let options = Options::parse_with(
+            buf, OutOfRange::Reject, Duplicates::LastWins).unwrap();
+        assert_eq!("BLKSIZE=1468 X-Foo=b", options.to_string());
+        let options = Options::parse_with(
+            buf, OutOfRange::Reject, Duplicates::FirstWins).unwrap();
+        assert_eq!("blksize=512 x-foo=a", options.to_string());
+        assert_eq!(
+            Err(OptionParseError::Duplicate("BLKSIZE".to_owned())),
+            Options::parse_with(
+                buf, OutOfRange::Reject, Duplicates::Reject));
+        // Invalid values are not examined if they are to be ignored.
+        let buf = b"timeout\x003\0timeout\0foo\0";
+        let options = Options::parse_with(
+            buf, OutOfRange::Reject, Duplicates::FirstWins).unwrap();
+        assert_eq!(Some(3), options.timeout);
+    }
+
     #[test]
     fn test_parsing_option_with_empty_value_results_in_error() {
         let buf = "blksize\0\0".as_bytes();
         assert_eq!(
-            Options::parse(buf).unwrap_err(),
+            Options::parse(buf).unwrap_err().to_string(),
             "Invalid blksize value \"\": ".to_string() +
                 "cannot parse integer from empty string");
     }
@@ -299,32 +1581,27 @@ enum OptionString<'a> {
 
 #[derive(Debug)]
 struct OptionStringIter<'a> {
-    buf: &'a [u8],
-    pos: usize,
+    reader: packetreader::PacketReader<'a>,
 }
 
 
 impl<'a> OptionStringIter<'a> {
 
     fn new(buf: &'a [u8]) -> OptionStringIter<'a> {
-        OptionStringIter{buf: buf, pos: 0}
+        OptionStringIter{reader: packetreader::PacketReader::new(buf)}
     }
 
     fn next(&mut self) -> OptionString<'a> {
-        for index in self.pos..self.buf.len() {
-            if self.buf[index] == 0u8 {
-                let cstr = &self.buf[self.pos..index];
-                self.pos = index + 1;
-                return OptionString::Terminated(cstr);
-            }
+        if self.reader.rem() == 0 {
+            OptionString::None
         }
-        if self.buf.len() > self.pos {
-            let cstr = &self.buf[self.pos..];
-            self.pos = self.buf.len();
-            return OptionString::Unterminated(cstr);
+        else if let Ok(cstr) = self.reader.take_cstr_bytes() {
+            OptionString::Terminated(cstr)
         }
         else {
-            return OptionString::None;
+            // take_remaining is infallible.
+            OptionString::Unterminated(
+                self.reader.take_remaining().unwrap_or_default())
         }
     }
 
@@ -1,17 +1,28 @@
 extern crate byteorder;
 
-use std::error;
-use std::fmt;
+use alloc::borrow;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::error;
+use core::fmt;
+use core::result;
+#[cfg(feature = "std")]
+use std::ffi;
+#[cfg(feature = "std")]
 use std::io;
-use std::result;
+#[cfg(feature = "std")]
+use std::path;
 
-use super::options::Options;
+use super::hexdump::HexDump;
+use super::options::{Duplicates, OptionParseError, Options, OutOfRange};
 use super::packetreader;
 use super::packetwriter;
 
 
 /// An error reading, parsing, or writing a packet.
 #[derive(Debug,PartialEq)]
+#[non_exhaustive]
 pub enum Error {
     /// The operation is invalid / not recognised.
     InvalidOpCode(u16),
@@ -26,7 +37,7 @@ pub enum Error {
     /// Only recognised options are ackowledged in an `OACK` packet,
     /// meaning that both sides know the options that the other side
     /// understands and does not understand before the transfer begins.
-    InvalidOptions(String),
+    InvalidOptions(OptionParseError),
     /// A packet could not be read / deserialised.
     ReadError(packetreader::Error),
     /// A packet could not be written / serialised.
@@ -43,24 +54,21 @@ impl fmt::Display for Error {
                 write!(f, "invalid transfer mode: {:?}", txmode),
             Error::InvalidErrorCode(errcode) =>
                 write!(f, "invalid error code: {}", errcode),
-            Error::InvalidOptions(ref options) =>
-                write!(f, "invalid options: {:?}", options),
+            Error::InvalidOptions(ref error) =>
+                write!(f, "invalid options: {}", error),
             Error::ReadError(ref error) =>
-                write!(f, "packet could not be read: {:?}", error),
+                write!(f, "packet could not be read: {}", error),
             Error::WriteError(ref error) =>
-                write!(f, "packet could not be written: {:?}", error),
+                write!(f, "packet could not be written: {}", error),
         }
     }
 }
 
 
 impl error::Error for Error {
-    fn description(&self) -> &str {
-        "tftp packet error"
-    }
-
-    fn cause(&self) -> Option<&error::Error> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
+            Error::InvalidOptions(ref error) => Some(error),
             Error::ReadError(ref error) => Some(error),
             Error::WriteError(ref error) => Some(error),
             _ => None,
@@ -83,9 +91,10 @@ impl From<packetwriter::Error> for Error {
 }
 
 
+#[cfg(feature = "std")]
 impl From<Error> for io::Error {
     fn from(error: Error) -> io::Error {
-        io::Error::new(io::ErrorKind::Other, error)
+        io::Error::other(error)
     }
 }
 
@@ -93,8 +102,44 @@ impl From<Error> for io::Error {
 pub type Result<T> = result::Result<T, Error>;
 
 
+/// A parse error together with the datagram that caused it, for
+/// diagnosing interoperability problems.
+#[derive(Debug,PartialEq)]
+pub struct ParseError {
+    /// Why parsing failed.
+    pub error: Error,
+    /// Where the read head was when parsing stopped: just after a field
+    /// that was rejected, or at the start of one that could not be read.
+    pub offset: usize,
+    /// The offending datagram.
+    pub datagram: Vec<u8>,
+}
+
+impl ParseError {
+    /// The datagram as a hex dump; see `HexDump`.
+    pub fn hexdump(&self) -> String {
+        HexDump(&self.datagram).to_string()
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "{} at offset {} of {}-byte datagram", self.error,
+            self.offset, self.datagram.len())
+    }
+}
+
+impl error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+
 /// The operation code that begins every TFTP packet.
-#[derive(Debug)]
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum OpCode {
     /// Read request.
     RRQ = 1,
@@ -110,15 +155,13 @@ pub enum OpCode {
     OACK = 6,
 }
 
-impl OpCode {
-    fn read(buffer: &mut packetreader::PacketReader) -> Result<Self> {
-        let code = buffer.take_u16()?;
-        match Self::from(code) {
-            Some(opcode) => Ok(opcode),
-            None => Err(Error::InvalidOpCode(code)),
-        }
+impl fmt::Display for OpCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
     }
+}
 
+impl OpCode {
     pub fn write(self, writer: &mut packetwriter::PacketWriter) -> Result<()> {
         writer.put_u16(self as u16)?;
         Ok(())
@@ -142,53 +185,130 @@ impl OpCode {
 /// A filename as found in a `RRQ` or `WRQ` packet.
 ///
 /// In TFTP, filenames are byte strings, transferred as NetASCII. There
-/// is no implicit or explicit encoding, like UTF-8, but this library
-/// makes a choice to parse and encode with UTF-8. This will be correct
-/// for 7-bit ASCII, which likely reflects the intentions of TFTP's
-/// creators, but may cause some incompatibility in real-world use,
-/// hence this choice may be revisited.
+/// is no implicit or explicit encoding, like UTF-8, so this library
+/// keeps the bytes exactly as received. Use `to_string_lossy` for
+/// display, and `to_path` (or, on Unix, `as_os_str`) to find the file.
+/// Use `is_valid` to insist on 7-bit ASCII, which likely reflects the
+/// intentions of TFTP's creators.
 ///
-/// NetASCII is an anachronistic fly in the ointment that this library
-/// does not yet even attempt to support.
-#[derive(Debug)]
-pub struct Filename(pub String);
+/// Unlike `ErrorMessage`, filenames are not decoded from NetASCII: a
+/// CR,LF or CR,NUL is kept as those two bytes, as clients send names
+/// without encoding them. NetASCII conversion applies only to error
+/// messages and to the contents of `netascii` transfers, for which see
+/// the `netascii` module.
+#[derive(Clone,Debug,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Filename(Vec<u8>);
 
 impl Filename {
+    pub fn new<B: Into<Vec<u8>>>(bytes: B) -> Filename {
+        Filename(bytes.into())
+    }
+
     fn read(buffer: &mut packetreader::PacketReader) -> Result<Self> {
-        Ok(Filename(buffer.take_string()?))
+        Ok(Filename(buffer.take_cstr_bytes()?.to_vec()))
     }
 
-    pub fn write(self, writer: &mut packetwriter::PacketWriter) -> Result<()> {
-        writer.put_string(&self.0)?;
+    pub fn write(&self, writer: &mut packetwriter::PacketWriter)
+        -> Result<()>
+    {
+        writer.put_bytestring(&self.0)?;
         Ok(())
     }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    /// The filename as a string, with non-UTF-8 content replaced.
+    pub fn to_string_lossy(&self) -> borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    /// The filename as an `OsStr`, without loss.
+    #[cfg(all(feature = "std", unix))]
+    pub fn as_os_str(&self) -> &ffi::OsStr {
+        use std::os::unix::ffi::OsStrExt;
+        ffi::OsStr::from_bytes(&self.0)
+    }
+
+    /// The filename as a path. This is lossless on Unix; elsewhere
+    /// non-UTF-8 content is replaced.
+    #[cfg(feature = "std")]
+    pub fn to_path(&self) -> borrow::Cow<'_, path::Path> {
+        #[cfg(unix)]
+        return borrow::Cow::Borrowed(path::Path::new(self.as_os_str()));
+        #[cfg(not(unix))]
+        return borrow::Cow::Owned(
+            path::PathBuf::from(self.to_string_lossy().into_owned()));
+    }
+
+    /// Does this filename pass the given validation?
+    pub fn is_valid(&self, validation: FilenameValidation) -> bool {
+        match validation {
+            FilenameValidation::Bytes => true,
+            FilenameValidation::ASCII => self.0.is_ascii(),
+        }
+    }
+}
+
+
+/// How strictly to validate filenames.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum FilenameValidation {
+    /// Accept any bytes; NUL cannot appear in a parsed filename anyway.
+    Bytes,
+    /// Accept only 7-bit ASCII.
+    ASCII,
 }
 
 
 /// The transfer mode to use.
-#[derive(Debug)]
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum TransferMode {
     /// NetASCII is obsolete and potentially **harmful** to your data.
     NetASCII,
     /// Octet means transfer bytes unaltered.
     Octet,
+    /// Mail means deliver to a user rather than a file. It's defined in
+    /// RFC-1350 but declared obsolete there too; it is parsed so that
+    /// requests using it can be rejected explicitly, but it is not
+    /// supported.
+    Mail,
+}
+
+impl fmt::Display for TransferMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 impl TransferMode {
     fn read(buffer: &mut packetreader::PacketReader) -> Result<Self> {
-        let mode = buffer.take_string()?;
-        match TransferMode::parse(&mode.as_bytes()) {
+        let mode = buffer.take_cstr_bytes()?;
+        match TransferMode::parse(mode) {
             Some(txmode) => Ok(txmode),
-            None => Err(Error::InvalidTransferMode(mode)),
+            None => Err(Error::InvalidTransferMode(
+                String::from_utf8_lossy(mode).into_owned())),
         }
     }
 
     pub fn write(self, writer: &mut packetwriter::PacketWriter) -> Result<()> {
-        writer.put_string(match self {
+        writer.put_string(self.as_str())?;
+        Ok(())
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
             TransferMode::NetASCII => "netascii",
             TransferMode::Octet => "octet",
-        })?;
-        Ok(())
+            TransferMode::Mail => "mail",
+        }
     }
 
     fn parse(buffer: &[u8]) -> Option<Self> {
@@ -198,6 +318,9 @@ impl TransferMode {
         else if buffer.eq_ignore_ascii_case("octet".as_bytes()) {
             Some(TransferMode::Octet)
         }
+        else if buffer.eq_ignore_ascii_case("mail".as_bytes()) {
+            Some(TransferMode::Mail)
+        }
         else {
             None
         }
@@ -206,9 +329,20 @@ impl TransferMode {
 
 
 /// The block number in a `DATA` or `ACK` packet.
-#[derive(Debug)]
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BlockNum(pub u16);
 
+impl fmt::Display for BlockNum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Block numbers are 16 bits and wrap around, so transfers of more than
+/// 65535 blocks reuse them. These helpers do the modulo-65536
+/// arithmetic; comparisons treat the half of the number space ahead of
+/// a block as after it, and the other half as before it.
 impl BlockNum {
     fn read(buffer: &mut packetreader::PacketReader) -> Result<Self> {
         let blocknum = buffer.take_u16()?;
@@ -219,11 +353,52 @@ impl BlockNum {
         writer.put_u16(self.0)?;
         Ok(())
     }
+
+    /// The block after this one, wrapping from 65535 to 0.
+    pub fn next(self) -> BlockNum {
+        BlockNum(self.0.wrapping_add(1))
+    }
+
+    /// The block before this one, wrapping from 0 to 65535.
+    pub fn prev(self) -> BlockNum {
+        BlockNum(self.0.wrapping_sub(1))
+    }
+
+    /// The block after this one, wrapping from 65535 to `rollover`, as
+    /// negotiated with the `rollover` option.
+    pub fn next_rolling(self, rollover: u16) -> BlockNum {
+        match self.0 {
+            0xffff => BlockNum(rollover),
+            blocknum => BlockNum(blocknum + 1),
+        }
+    }
+
+    /// The block before this one, wrapping from `rollover` to 65535. The
+    /// inverse of `next_rolling`.
+    pub fn prev_rolling(self, rollover: u16) -> BlockNum {
+        if self.0 == rollover { BlockNum(0xffff) } else { self.prev() }
+    }
+
+    /// The number of blocks from this one forward to `other`.
+    pub fn wrapping_distance(self, other: BlockNum) -> u16 {
+        other.0.wrapping_sub(self.0)
+    }
+
+    /// Is this block after `other`?
+    pub fn is_after(self, other: BlockNum) -> bool {
+        let distance = other.wrapping_distance(self);
+        distance != 0 && distance < 0x8000
+    }
+
+    /// Is this block before `other`?
+    pub fn is_before(self, other: BlockNum) -> bool {
+        other.is_after(self)
+    }
 }
 
 
 /// The payload of a `DATA` packet.
-#[derive(Debug)]
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
 pub struct Data<'a>(pub &'a [u8]);
 
 impl<'a> Data<'a> {
@@ -233,16 +408,25 @@ impl<'a> Data<'a> {
     }
 
     pub fn write(self, writer: &mut packetwriter::PacketWriter) -> Result<()> {
-        writer.put_bytes(&self.0)?;
+        writer.put_bytes(self.0)?;
         Ok(())
     }
+
+    /// The header of a `DATA` packet for `block`: the operation code and
+    /// the block number, to be sent before the payload.
+    pub fn header(block: BlockNum) -> [u8; 4] {
+        let opcode = (OpCode::DATA as u16).to_be_bytes();
+        let block = block.0.to_be_bytes();
+        [opcode[0], opcode[1], block[0], block[1]]
+    }
 }
 
 
 /// The code in an `ERROR` packet.
 ///
 /// Unless specified otherwise, these codes are all defined in RFC-1350.
-#[derive(Debug)]
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ErrorCode {
     /// Not defined, see error message (if any).
     NotDefined = 0,
@@ -264,10 +448,27 @@ pub enum ErrorCode {
     BadOptions = 8,
 }
 
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ErrorCode::*;
+        f.write_str(match *self {
+            NotDefined => "Not defined",
+            FileNotFound => "File not found",
+            AccessViolation => "Access violation",
+            DiskFull => "Disk full or allocation exceeded",
+            IllegalOperation => "Illegal TFTP operation",
+            UnknownTransferId => "Unknown transfer ID",
+            FileAlreadyExists => "File already exists",
+            NoSuchUser => "No such user",
+            BadOptions => "Options not acceptable",
+        })
+    }
+}
+
 impl ErrorCode {
     fn read(buffer: &mut packetreader::PacketReader) -> Result<Self> {
         let code = buffer.take_u16()?;
-        match Self::from(code) {
+        match Self::from_u16(code) {
             Some(errorcode) => Ok(errorcode),
             None => Err(Error::InvalidErrorCode(code)),
         }
@@ -278,7 +479,7 @@ impl ErrorCode {
         Ok(())
     }
 
-    fn from(code: u16) -> Option<Self> {
+    pub(crate) fn from_u16(code: u16) -> Option<Self> {
         use self::ErrorCode::*;
         match code {
             0 => Some(NotDefined),
@@ -295,25 +496,59 @@ impl ErrorCode {
     }
 }
 
+/// The nearest TFTP error code for an I/O error.
+#[cfg(feature = "std")]
+impl<'a> From<&'a io::Error> for ErrorCode {
+    fn from(error: &'a io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+            io::ErrorKind::PermissionDenied => ErrorCode::AccessViolation,
+            io::ErrorKind::AlreadyExists => ErrorCode::FileAlreadyExists,
+            io::ErrorKind::StorageFull | io::ErrorKind::WriteZero =>
+                ErrorCode::DiskFull,
+            _ => ErrorCode::NotDefined,
+        }
+    }
+}
 
-/// The message in an `ERROR` packet.
-#[derive(Debug)]
+
+/// The message in an `ERROR` packet. It is NetASCII on the wire, so line
+/// endings are escaped when written and unescaped when read.
+#[derive(Clone,Debug,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ErrorMessage(pub String);
 
 impl ErrorMessage {
     fn read(buffer: &mut packetreader::PacketReader) -> Result<Self> {
-        Ok(ErrorMessage(buffer.take_string()?))
+        Ok(ErrorMessage(buffer.take_netascii_string()?))
     }
 
-    pub fn write(self, writer: &mut packetwriter::PacketWriter) -> Result<()> {
-        writer.put_string(&self.0)?;
+    pub fn write(&self, writer: &mut packetwriter::PacketWriter)
+        -> Result<()>
+    {
+        writer.put_netascii_string(&self.0)?;
         Ok(())
     }
+
+    /// The length of the message on the wire, including its terminator.
+    fn encoded_len(&self) -> usize {
+        packetwriter::netascii_len(&self.0) + 1
+    }
+}
+
+
+/// How to treat packets with unrecognised operation codes.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Strictness {
+    /// Reject them with `Error::InvalidOpCode`.
+    Strict,
+    /// Parse them as `Packet::Unknown`, so that they can be observed.
+    Lenient,
 }
 
 
 /// A packet of the Trivial File Transfer Protocol.
-#[derive(Debug)]
+#[derive(Clone,Debug,PartialEq,Eq)]
 pub enum Packet<'a> {
     Read(Filename, TransferMode, Options),
     Write(Filename, TransferMode, Options),
@@ -321,81 +556,1044 @@ pub enum Packet<'a> {
     Ack(BlockNum),
     Error(ErrorCode, ErrorMessage),
     OAck(Options),
+    /// A packet with an unrecognised operation code, and everything
+    /// that followed it. Only produced by lenient parsing. Be careful
+    /// when constructing these: one with a recognised operation code
+    /// will not parse back into an `Unknown`.
+    Unknown(u16, &'a [u8]),
+}
+
+/// A one-line summary, e.g. `RRQ "pxelinux.0" octet blksize=1468`.
+impl<'a> fmt::Display for Packet<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.opcode() {
+            Some(opcode) => write!(f, "{}", opcode)?,
+            None => write!(f, "UNKNOWN")?,
+        };
+        match *self {
+            Packet::Read(ref filename, mode, ref options) |
+            Packet::Write(ref filename, mode, ref options) => {
+                write!(f, " {:?} {}", filename.to_string_lossy(), mode)?;
+                if options.is_set() {
+                    write!(f, " {}", options)?;
+                }
+                Ok(())
+            },
+            Packet::Data(BlockNum(block), Data(data)) =>
+                write!(f, " {} ({} bytes)", block, data.len()),
+            Packet::Ack(BlockNum(block)) =>
+                write!(f, " {}", block),
+            Packet::Error(code, ref message) =>
+                write!(f, " {} ({}) {:?}", code as u16, code, message.0),
+            Packet::OAck(ref options) =>
+                write!(f, " {}", options),
+            Packet::Unknown(code, data) =>
+                write!(f, " {} ({} bytes)", code, data.len()),
+        }
+    }
 }
 
 impl<'a> Packet<'a> {
+    /// Parse a packet, rejecting unrecognised operation codes.
     pub fn parse(buffer: &'a [u8]) -> Result<Self>
         where Self: 'a
     {
-        let mut buffer = packetreader::PacketReader::new(&buffer);
-        match OpCode::read(&mut buffer)? {
+        Self::parse_with(buffer, Strictness::Strict)
+    }
+
+    /// Parse a packet, treating unrecognised operation codes according
+    /// to `strictness`.
+    pub fn parse_with(buffer: &'a [u8], strictness: Strictness)
+        -> Result<Self>
+        where Self: 'a
+    {
+        let mut buffer = packetreader::PacketReader::new(buffer);
+        Self::parse_from(
+            &mut buffer, strictness, OutOfRange::Reject, Duplicates::LastWins)
+    }
+
+    /// Like `parse_with`, but on failure keep a copy of the datagram
+    /// and note where parsing stopped.
+    pub fn parse_detailed(buffer: &'a [u8], strictness: Strictness)
+        -> result::Result<Self, ParseError>
+        where Self: 'a
+    {
+        Self::parse_detailed_with(
+            buffer, strictness, OutOfRange::Reject, Duplicates::LastWins)
+    }
+
+    /// Like `parse_detailed`, also treating option values outside the
+    /// ranges their RFCs permit according to `ranges`, and options that
+    /// appear more than once according to `duplicates`.
+    pub fn parse_detailed_with(
+        buffer: &'a [u8], strictness: Strictness, ranges: OutOfRange,
+        duplicates: Duplicates)
+        -> result::Result<Self, ParseError>
+        where Self: 'a
+    {
+        let mut reader = packetreader::PacketReader::new(buffer);
+        let packet = Self::parse_from(
+            &mut reader, strictness, ranges, duplicates);
+        packet.map_err(|error| {
+            ParseError{
+                error,
+                offset: reader.pos(),
+                datagram: buffer.to_vec(),
+            }
+        })
+    }
+
+    fn parse_from(
+        buffer: &mut packetreader::PacketReader<'a>, strictness: Strictness,
+        ranges: OutOfRange, duplicates: Duplicates)
+        -> Result<Self>
+    {
+        let code = buffer.take_u16()?;
+        let opcode = match (OpCode::from(code), strictness) {
+            (Some(opcode), _) => opcode,
+            (None, Strictness::Strict) =>
+                return Err(Error::InvalidOpCode(code)),
+            (None, Strictness::Lenient) =>
+                return Ok(Packet::Unknown(code, buffer.take_remaining()?)),
+        };
+        match opcode {
             OpCode::RRQ => Ok(Packet::Read(
-                Filename::read(&mut buffer)?,
-                TransferMode::read(&mut buffer)?,
-                Options::read(&mut buffer)?,
+                Filename::read(buffer)?,
+                TransferMode::read(buffer)?,
+                Options::read_with(buffer, ranges, duplicates)?,
             )),
             OpCode::WRQ => Ok(Packet::Write(
-                Filename::read(&mut buffer)?,
-                TransferMode::read(&mut buffer)?,
-                Options::read(&mut buffer)?,
+                Filename::read(buffer)?,
+                TransferMode::read(buffer)?,
+                Options::read_with(buffer, ranges, duplicates)?,
             )),
             OpCode::DATA => Ok(Packet::Data(
-                BlockNum::read(&mut buffer)?,
-                Data::read(&mut buffer)?,
+                BlockNum::read(buffer)?,
+                Data::read(buffer)?,
             )),
             OpCode::ACK => Ok(Packet::Ack(
-                BlockNum::read(&mut buffer)?,
+                BlockNum::read(buffer)?,
             )),
             OpCode::ERROR => Ok(Packet::Error(
-                ErrorCode::read(&mut buffer)?,
-                ErrorMessage::read(&mut buffer)?,
+                ErrorCode::read(buffer)?,
+                ErrorMessage::read(buffer)?,
             )),
             OpCode::OACK => Ok(Packet::OAck(
-                Options::read(&mut buffer)?,
+                Options::read_with(buffer, ranges, duplicates)?,
             )),
         }
     }
 
-    pub fn opcode(&self) -> OpCode {
+    /// The operation code of this packet, or `None` if it's unknown.
+    pub fn opcode(&self) -> Option<OpCode> {
         match *self {
-            Packet::Read(..) => OpCode::RRQ,
-            Packet::Write(..) => OpCode::WRQ,
-            Packet::Data(..) => OpCode::DATA,
-            Packet::Ack(..) => OpCode::ACK,
-            Packet::Error(..) => OpCode::ERROR,
-            Packet::OAck(..) => OpCode::OACK,
+            Packet::Read(..) => Some(OpCode::RRQ),
+            Packet::Write(..) => Some(OpCode::WRQ),
+            Packet::Data(..) => Some(OpCode::DATA),
+            Packet::Ack(..) => Some(OpCode::ACK),
+            Packet::Error(..) => Some(OpCode::ERROR),
+            Packet::OAck(..) => Some(OpCode::OACK),
+            Packet::Unknown(..) => None,
         }
     }
 
-    pub fn write(self, mut buffer: &'a mut [u8]) -> Result<usize> {
-        let mut buffer = packetwriter::PacketWriter::new(&mut buffer);
-        self.opcode().write(&mut buffer)?;
-        match self {
-            Packet::Read(filename, mode, options) => {
-                filename.write(&mut buffer)?;
-                mode.write(&mut buffer)?;
-                options.write(&mut buffer)?;
+    /// The numeric operation code of this packet.
+    pub fn code(&self) -> u16 {
+        match (self.opcode(), self) {
+            (Some(opcode), _) => opcode as u16,
+            (None, &Packet::Unknown(code, _)) => code,
+            (None, _) => unreachable!(),
+        }
+    }
+
+    pub fn write(&self, buffer: &mut [u8]) -> Result<usize> {
+        self.write_into(&mut packetwriter::PacketWriter::new(buffer))
+    }
+
+    /// A `DATA` packet as two slices, its header and its payload, for
+    /// vectored writes that avoid copying the payload. The header is
+    /// written into `header`. Returns `None` for other packets.
+    #[cfg(feature = "std")]
+    pub fn as_io_slices<'b>(&'b self, header: &'b mut [u8; 4])
+        -> Option<[io::IoSlice<'b>; 2]>
+    {
+        match *self {
+            Packet::Data(block, Data(data)) => {
+                *header = Data::header(block);
+                Some([io::IoSlice::new(header), io::IoSlice::new(data)])
+            },
+            _ => None,
+        }
+    }
+
+    /// Serialise with `buffer`, returning the position of its write
+    /// head afterwards.
+    pub fn write_into(&self, buffer: &mut packetwriter::PacketWriter)
+        -> Result<usize>
+    {
+        buffer.put_u16(self.code())?;
+        match *self {
+            Packet::Read(ref filename, mode, ref options) => {
+                filename.write(buffer)?;
+                mode.write(buffer)?;
+                options.write(buffer)?;
             },
-            Packet::Write(filename, mode, options) => {
-                filename.write(&mut buffer)?;
-                mode.write(&mut buffer)?;
-                options.write(&mut buffer)?;
+            Packet::Write(ref filename, mode, ref options) => {
+                filename.write(buffer)?;
+                mode.write(buffer)?;
+                options.write(buffer)?;
             },
             Packet::Data(block, data) => {
-                block.write(&mut buffer)?;
-                data.write(&mut buffer)?;
+                block.write(buffer)?;
+                data.write(buffer)?;
             },
             Packet::Ack(block) => {
-                block.write(&mut buffer)?;
+                block.write(buffer)?;
             },
-            Packet::Error(code, message) => {
-                code.write(&mut buffer)?;
-                message.write(&mut buffer)?;
+            Packet::Error(code, ref message) => {
+                code.write(buffer)?;
+                message.write(buffer)?;
             },
-            Packet::OAck(options) => {
-                options.write(&mut buffer)?;
+            Packet::OAck(ref options) => {
+                options.write(buffer)?;
+            },
+            Packet::Unknown(_, data) => {
+                buffer.put_bytes(data)?;
             },
         };
         Ok(buffer.pos())
     }
+
+    /// Serialise into a new, exactly-sized, vector.
+    pub fn to_vec(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::with_capacity(self.encoded_len());
+        self.write_to(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// Serialise onto the end of `buffer`, growing it as necessary.
+    /// Returns the number of bytes written. On error, `buffer` is left
+    /// as it was.
+    pub fn write_to(&self, buffer: &mut Vec<u8>) -> Result<usize> {
+        let start = buffer.len();
+        let written = self.write_into(
+            &mut packetwriter::PacketWriter::growable(buffer));
+        if written.is_err() {
+            buffer.truncate(start);
+        };
+        written
+    }
+
+    /// The number of bytes this packet occupies when serialised, for
+    /// sizing buffers before calling `write`. This is exact for any
+    /// packet that can be written; it does not check that it can.
+    pub fn encoded_len(&self) -> usize {
+        2 + match *self {
+            Packet::Read(ref filename, mode, ref options) |
+            Packet::Write(ref filename, mode, ref options) =>
+                filename.as_bytes().len() + 1 + mode.as_str().len() + 1 +
+                options.encoded_len(),
+            Packet::Data(_, Data(data)) => 2 + data.len(),
+            Packet::Ack(_) => 2,
+            Packet::Error(_, ref message) => 2 + message.encoded_len(),
+            Packet::OAck(ref options) => options.encoded_len(),
+            Packet::Unknown(_, data) => data.len(),
+        }
+    }
+
+    /// Serialise and describe this packet in the manner of Wireshark:
+    /// a summary line, then each field with its byte range, then a hex
+    /// dump. Useful for debug-level logging of what's on the wire.
+    pub fn fmt_hex(&self) -> Result<String> {
+        let buffer = self.to_vec()?;
+        let mut fields = vec![(2, format!("opcode: {}", self.code()))];
+        match *self {
+            Packet::Read(ref filename, mode, ref options) |
+            Packet::Write(ref filename, mode, ref options) => {
+                fields.push((
+                    filename.as_bytes().len() + 1,
+                    format!("filename: {:?}", filename.to_string_lossy())));
+                fields.push((
+                    mode.as_str().len() + 1, format!("mode: {}", mode)));
+                fields.push((
+                    options.encoded_len(), format!("options: {}", options)));
+            },
+            Packet::Data(block, Data(data)) => {
+                fields.push((2, format!("block: {}", block)));
+                fields.push((
+                    data.len(), format!("data: {} bytes", data.len())));
+            },
+            Packet::Ack(block) => {
+                fields.push((2, format!("block: {}", block)));
+            },
+            Packet::Error(code, ref message) => {
+                fields.push((2, format!("code: {} ({})", code as u16, code)));
+                fields.push((
+                    message.encoded_len(),
+                    format!("message: {:?}", message.0)));
+            },
+            Packet::OAck(ref options) => {
+                fields.push((
+                    options.encoded_len(), format!("options: {}", options)));
+            },
+            Packet::Unknown(_, data) => {
+                fields.push((
+                    data.len(), format!("data: {} bytes", data.len())));
+            },
+        };
+        let mut dump = format!("{}\n", self);
+        let mut start = 0;
+        for (len, description) in fields {
+            if len != 0 {
+                dump.push_str(&format!(
+                    "  [{:04x}..{:04x}] {}\n", start, start + len,
+                    description));
+            }
+            start += len;
+        }
+        dump.push_str(&HexDump(&buffer).to_string());
+        Ok(dump)
+    }
+
+    /// Convert into an owned packet, copying the payload of a `DATA`
+    /// packet.
+    pub fn into_owned(self) -> PacketOwned {
+        self.into()
+    }
+}
+
+
+impl<'a> TryFrom<&'a [u8]> for Packet<'a> {
+    type Error = Error;
+
+    fn try_from(buffer: &'a [u8]) -> Result<Self> {
+        Packet::parse(buffer)
+    }
+}
+
+/// Serialisation can fail, e.g. when a filename contains a null byte,
+/// hence this is `TryFrom` rather than `From`.
+impl<'a, 'b> TryFrom<&'b Packet<'a>> for Vec<u8> {
+    type Error = Error;
+
+    fn try_from(packet: &'b Packet<'a>) -> Result<Self> {
+        packet.to_vec()
+    }
+}
+
+impl<'a> TryFrom<Packet<'a>> for Vec<u8> {
+    type Error = Error;
+
+    fn try_from(packet: Packet<'a>) -> Result<Self> {
+        packet.to_vec()
+    }
+}
+
+/// Constructors for `ERROR` packets.
+impl Packet<'static> {
+    pub fn error<M: Into<String>>(code: ErrorCode, message: M) -> Self {
+        Packet::Error(code, ErrorMessage(message.into()))
+    }
+
+    pub fn not_defined<M: Into<String>>(message: M) -> Self {
+        Self::error(ErrorCode::NotDefined, message)
+    }
+
+    pub fn file_not_found<M: Into<String>>(message: M) -> Self {
+        Self::error(ErrorCode::FileNotFound, message)
+    }
+
+    pub fn access_violation<M: Into<String>>(message: M) -> Self {
+        Self::error(ErrorCode::AccessViolation, message)
+    }
+
+    pub fn disk_full<M: Into<String>>(message: M) -> Self {
+        Self::error(ErrorCode::DiskFull, message)
+    }
+
+    pub fn illegal_operation<M: Into<String>>(message: M) -> Self {
+        Self::error(ErrorCode::IllegalOperation, message)
+    }
+
+    pub fn unknown_transfer_id<M: Into<String>>(message: M) -> Self {
+        Self::error(ErrorCode::UnknownTransferId, message)
+    }
+
+    pub fn file_already_exists<M: Into<String>>(message: M) -> Self {
+        Self::error(ErrorCode::FileAlreadyExists, message)
+    }
+
+    pub fn no_such_user<M: Into<String>>(message: M) -> Self {
+        Self::error(ErrorCode::NoSuchUser, message)
+    }
+
+    pub fn bad_options<M: Into<String>>(message: M) -> Self {
+        Self::error(ErrorCode::BadOptions, message)
+    }
+}
+
+/// An `ERROR` packet describing an I/O error, with the nearest error
+/// code and the error's description as the message.
+#[cfg(feature = "std")]
+impl<'a, 'b> From<&'b io::Error> for Packet<'a> {
+    fn from(error: &'b io::Error) -> Self {
+        Packet::error(ErrorCode::from(error), error.to_string())
+    }
+}
+
+
+/// An owned mirror of `Packet`.
+///
+/// `Packet::Data` borrows its payload from the buffer it was parsed
+/// from, which makes it impossible to queue, store, or send it to
+/// another thread. This owns its payload instead. Convert from a
+/// `Packet` with `From`/`Into`, and back again with `as_packet`.
+#[derive(Clone,Debug,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum PacketOwned {
+    Read(Filename, TransferMode, Options),
+    Write(Filename, TransferMode, Options),
+    Data(BlockNum, Vec<u8>),
+    Ack(BlockNum),
+    Error(ErrorCode, ErrorMessage),
+    OAck(Options),
+    Unknown(u16, Vec<u8>),
+}
+
+impl fmt::Display for PacketOwned {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.as_packet(), f)
+    }
+}
+
+impl PacketOwned {
+    pub fn parse(buffer: &[u8]) -> Result<Self> {
+        Ok(Packet::parse(buffer)?.into())
+    }
+
+    /// Borrow this as a `Packet`. Only the payload of a `DATA` packet
+    /// is borrowed; other fields are cheap to clone.
+    pub fn as_packet(&self) -> Packet<'_> {
+        match *self {
+            PacketOwned::Read(ref filename, mode, ref options) =>
+                Packet::Read(filename.clone(), mode, options.clone()),
+            PacketOwned::Write(ref filename, mode, ref options) =>
+                Packet::Write(filename.clone(), mode, options.clone()),
+            PacketOwned::Data(block, ref data) =>
+                Packet::Data(block, Data(data)),
+            PacketOwned::Ack(block) =>
+                Packet::Ack(block),
+            PacketOwned::Error(code, ref message) =>
+                Packet::Error(code, message.clone()),
+            PacketOwned::OAck(ref options) =>
+                Packet::OAck(options.clone()),
+            PacketOwned::Unknown(code, ref data) =>
+                Packet::Unknown(code, data),
+        }
+    }
+
+    pub fn opcode(&self) -> Option<OpCode> {
+        self.as_packet().opcode()
+    }
+
+    pub fn write(&self, buffer: &mut [u8]) -> Result<usize> {
+        self.as_packet().write(buffer)
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        self.as_packet().encoded_len()
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for PacketOwned {
+    type Error = Error;
+
+    fn try_from(buffer: &'a [u8]) -> Result<Self> {
+        PacketOwned::parse(buffer)
+    }
+}
+
+impl<'a> TryFrom<&'a PacketOwned> for Vec<u8> {
+    type Error = Error;
+
+    fn try_from(packet: &'a PacketOwned) -> Result<Self> {
+        packet.as_packet().to_vec()
+    }
+}
+
+impl TryFrom<PacketOwned> for Vec<u8> {
+    type Error = Error;
+
+    fn try_from(packet: PacketOwned) -> Result<Self> {
+        packet.as_packet().to_vec()
+    }
+}
+
+impl<'a> From<Packet<'a>> for PacketOwned {
+    fn from(packet: Packet<'a>) -> PacketOwned {
+        match packet {
+            Packet::Read(filename, mode, options) =>
+                PacketOwned::Read(filename, mode, options),
+            Packet::Write(filename, mode, options) =>
+                PacketOwned::Write(filename, mode, options),
+            Packet::Data(block, Data(data)) =>
+                PacketOwned::Data(block, data.to_vec()),
+            Packet::Ack(block) =>
+                PacketOwned::Ack(block),
+            Packet::Error(code, message) =>
+                PacketOwned::Error(code, message),
+            Packet::OAck(options) =>
+                PacketOwned::OAck(options),
+            Packet::Unknown(code, data) =>
+                PacketOwned::Unknown(code, data.to_vec()),
+        }
+    }
+}
+
+/// Encoding into, and decoding from, the buffers of the `bytes` crate,
+/// so that packets can be used directly in codecs, e.g. with tokio's
+/// `UdpFramed`. Each buffer holds exactly one datagram.
+#[cfg(feature = "bytes")]
+mod buffers {
+
+    use bytes::{Buf, BytesMut};
+
+    use super::{Packet, PacketOwned, Result};
+    use super::super::packetwriter::PacketWriter;
+
+    impl<'a> Packet<'a> {
+        /// Serialise onto the end of `dst`, growing it as necessary.
+        /// Returns the number of bytes written. On error, `dst` is left
+        /// as it was.
+        pub fn encode(&self, dst: &mut BytesMut) -> Result<usize> {
+            let start = dst.len();
+            let written = self.write_into(&mut PacketWriter::bytes(dst));
+            if written.is_err() {
+                dst.truncate(start);
+            };
+            written
+        }
+    }
+
+    impl PacketOwned {
+        /// Parse the whole of `src` as one datagram, consuming it
+        /// whether or not it parses. Contiguous buffers, like `Bytes`
+        /// and `BytesMut`, are parsed in place.
+        pub fn decode<B: Buf>(src: &mut B) -> Result<PacketOwned> {
+            let remaining = src.remaining();
+            let packet = if src.chunk().len() == remaining {
+                PacketOwned::parse(src.chunk())
+            } else {
+                PacketOwned::parse(&src.copy_to_bytes(remaining))
+            };
+            src.advance(src.remaining());
+            packet
+        }
+
+        /// See `Packet::encode`.
+        pub fn encode(&self, dst: &mut BytesMut) -> Result<usize> {
+            self.as_packet().encode(dst)
+        }
+    }
+
+}
+
+/// Structurally valid packets for fuzzing. Filenames never contain NUL,
+/// other strings are ASCII without NUL, and `Unknown` packets always
+/// have an unrecognised operation code, so every generated packet
+/// survives a trip through `write` and
+/// `parse_with(.., Strictness::Lenient)`.
+#[cfg(feature = "arbitrary")]
+mod fuzz {
+
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use arbitrary::{Arbitrary, Result, Unstructured};
+
+    use super::{
+        BlockNum,
+        ErrorCode,
+        ErrorMessage,
+        Filename,
+        PacketOwned,
+        TransferMode,
+    };
+    use super::super::options::Options;
+
+    fn string(u: &mut Unstructured) -> Result<String> {
+        let string = String::arbitrary(u)?;
+        Ok(string.chars().filter(|c| c.is_ascii() && *c != '\0').collect())
+    }
+
+    impl<'a> Arbitrary<'a> for Filename {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            let bytes = Vec::<u8>::arbitrary(u)?;
+            Ok(Filename(bytes.into_iter().filter(|&b| b != 0).collect()))
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for TransferMode {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            use super::TransferMode::*;
+            Ok(*u.choose(&[NetASCII, Octet, Mail])?)
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for BlockNum {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(BlockNum(u16::arbitrary(u)?))
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for ErrorCode {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            use super::ErrorCode::*;
+            Ok(*u.choose(&[
+                NotDefined, FileNotFound, AccessViolation, DiskFull,
+                IllegalOperation, UnknownTransferId, FileAlreadyExists,
+                NoSuchUser, BadOptions,
+            ])?)
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for ErrorMessage {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(ErrorMessage(string(u)?))
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for PacketOwned {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(match u.int_in_range(0..=6)? {
+                0 => PacketOwned::Read(
+                    Filename::arbitrary(u)?, TransferMode::arbitrary(u)?,
+                    Options::arbitrary(u)?),
+                1 => PacketOwned::Write(
+                    Filename::arbitrary(u)?, TransferMode::arbitrary(u)?,
+                    Options::arbitrary(u)?),
+                2 => PacketOwned::Data(
+                    BlockNum::arbitrary(u)?, Vec::arbitrary(u)?),
+                3 => PacketOwned::Ack(BlockNum::arbitrary(u)?),
+                4 => PacketOwned::Error(
+                    ErrorCode::arbitrary(u)?, ErrorMessage::arbitrary(u)?),
+                5 => PacketOwned::OAck(Options::arbitrary(u)?),
+                _ => PacketOwned::Unknown(
+                    u.int_in_range(7..=u16::MAX)?, Vec::arbitrary(u)?),
+            })
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::convert::TryFrom;
+    use std::io;
+    use std::thread;
+
+    use super::{
+        BlockNum,
+        Data,
+        Error,
+        ErrorCode,
+        ErrorMessage,
+        Filename,
+        FilenameValidation,
+        Packet,
+        PacketOwned,
+        Strictness,
+        TransferMode,
+    };
+    use super::super::options::Options;
+
+    #[test]
+    fn test_owned_data_packet_round_trip() {
+        let payload = vec![1u8, 2, 3, 4];
+        let packet = Packet::Data(BlockNum(7), Data(&payload));
+        let owned = packet.clone().into_owned();
+        // It can be sent to another thread.
+        let owned = thread::spawn(move || owned).join().unwrap();
+        assert_eq!(packet, owned.as_packet());
+    }
+
+    #[test]
+    fn test_owned_packet_parse_and_write() {
+        let mut buffer = [0u8; 16];
+        let size = Packet::Ack(BlockNum(9)).write(&mut buffer).unwrap();
+        let owned = PacketOwned::parse(&buffer[..size]).unwrap();
+        assert_eq!(PacketOwned::Ack(BlockNum(9)), owned);
+        let mut again = [0u8; 16];
+        assert_eq!(size, owned.write(&mut again).unwrap());
+        assert_eq!(&buffer[..size], &again[..size]);
+    }
+
+    #[test]
+    fn test_to_vec() {
+        let packet = Packet::Error(
+            ErrorCode::AccessViolation, ErrorMessage("denied".to_owned()));
+        assert_eq!(
+            vec![0u8, 5, 0, 2, b'd', b'e', b'n', b'i', b'e', b'd', 0],
+            packet.to_vec().unwrap());
+    }
+
+    #[test]
+    fn test_write_to_appends() {
+        let mut buffer = vec![0xffu8];
+        let size = Packet::Ack(BlockNum(258)).write_to(&mut buffer).unwrap();
+        assert_eq!(4, size);
+        assert_eq!(vec![0xffu8, 0, 4, 1, 2], buffer);
+    }
+
+    #[test]
+    fn test_write_to_leaves_buffer_alone_on_error() {
+        let mut buffer = vec![0xffu8];
+        let packet = Packet::Error(
+            ErrorCode::NotDefined, ErrorMessage("nul\0".to_owned()));
+        assert!(packet.write_to(&mut buffer).is_err());
+        assert_eq!(vec![0xffu8], buffer);
+    }
+
+    #[test]
+    fn test_parse_mail_mode() {
+        let buffer = b"\0\x02root\0MAIL\0";
+        assert_eq!(
+            Packet::Write(
+                Filename::new("root"), TransferMode::Mail,
+                Options::new()),
+            Packet::parse(buffer).unwrap());
+    }
+
+    #[test]
+    fn test_parse_unknown_opcode() {
+        let buffer = b"\0\x63vendor";
+        assert_eq!(
+            Error::InvalidOpCode(99), Packet::parse(buffer).unwrap_err());
+        let packet = Packet::parse_with(buffer, Strictness::Lenient).unwrap();
+        assert_eq!(Packet::Unknown(99, b"vendor"), packet);
+        assert_eq!(None, packet.opcode());
+        assert_eq!(99, packet.code());
+        assert_eq!(&buffer[..], &packet.to_vec().unwrap()[..]);
+        assert_eq!("UNKNOWN 99 (6 bytes)", packet.to_string());
+    }
+
+    #[test]
+    fn test_error_constructors() {
+        assert_eq!(
+            Packet::Error(
+                ErrorCode::FileNotFound, ErrorMessage("nope".to_owned())),
+            Packet::file_not_found("nope"));
+        assert_eq!(
+            Packet::Error(
+                ErrorCode::BadOptions, ErrorMessage("blksize".to_owned())),
+            Packet::bad_options("blksize".to_owned()));
+    }
+
+    #[test]
+    fn test_error_from_io_error() {
+        let error = io::Error::new(io::ErrorKind::NotFound, "gone");
+        assert_eq!(Packet::file_not_found("gone"), Packet::from(&error));
+        let error = io::Error::new(io::ErrorKind::PermissionDenied, "no");
+        assert_eq!(ErrorCode::AccessViolation, ErrorCode::from(&error));
+        let error = io::Error::new(io::ErrorKind::StorageFull, "full");
+        assert_eq!(ErrorCode::DiskFull, ErrorCode::from(&error));
+        let error = io::Error::other("what");
+        assert_eq!(ErrorCode::NotDefined, ErrorCode::from(&error));
+    }
+
+    #[test]
+    fn test_error_message_is_netascii() {
+        let packet = Packet::Error(
+            ErrorCode::NotDefined, ErrorMessage("one\ntwo\r".to_owned()));
+        let buffer = packet.to_vec().unwrap();
+        assert_eq!(b"\0\x05\0\0one\r\ntwo\r\0\0", &buffer[..]);
+        assert_eq!(packet.encoded_len(), buffer.len());
+        assert_eq!(packet, Packet::parse(&buffer).unwrap());
+    }
+
+    #[test]
+    fn test_blocknum_wraps() {
+        assert_eq!(BlockNum(0), BlockNum(65535).next());
+        assert_eq!(BlockNum(65535), BlockNum(0).prev());
+        assert_eq!(2, BlockNum(65535).wrapping_distance(BlockNum(1)));
+        assert_eq!(65534, BlockNum(1).wrapping_distance(BlockNum(65535)));
+        assert!(BlockNum(1).is_after(BlockNum(65535)));
+        assert!(BlockNum(65535).is_before(BlockNum(1)));
+        assert!(!BlockNum(7).is_after(BlockNum(7)));
+        assert!(!BlockNum(7).is_before(BlockNum(7)));
+        assert!(BlockNum(100).is_before(BlockNum(200)));
+    }
+
+    #[test]
+    fn test_blocknum_rolls_over() {
+        assert_eq!(BlockNum(0), BlockNum(65535).next_rolling(0));
+        assert_eq!(BlockNum(1), BlockNum(65535).next_rolling(1));
+        assert_eq!(BlockNum(8), BlockNum(7).next_rolling(1));
+        assert_eq!(BlockNum(65535), BlockNum(1).prev_rolling(1));
+        assert_eq!(BlockNum(65535), BlockNum(0).prev_rolling(0));
+        assert_eq!(BlockNum(0), BlockNum(1).prev_rolling(0));
+    }
+
+    #[test]
+    fn test_filename_bytes_survive() {
+        let buffer = b"\0\x01caf\xe9\0octet\0";
+        let packet = Packet::parse(buffer).unwrap();
+        match packet {
+            Packet::Read(ref filename, _, _) => {
+                assert_eq!(b"caf\xe9", filename.as_bytes());
+                assert_eq!("caf\u{fffd}", filename.to_string_lossy());
+                assert!(filename.is_valid(FilenameValidation::Bytes));
+                assert!(!filename.is_valid(FilenameValidation::ASCII));
+            },
+            _ => panic!("expected RRQ, got {:?}", packet),
+        };
+        assert_eq!(&buffer[..], &packet.to_vec().unwrap()[..]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_filename_as_os_str() {
+        use std::os::unix::ffi::OsStrExt;
+        let filename = Filename::new(&b"caf\xe9"[..]);
+        assert_eq!(b"caf\xe9", filename.as_os_str().as_bytes());
+        assert_eq!(filename.as_os_str(), filename.to_path().as_os_str());
+    }
+
+    #[test]
+    fn test_parse_detailed_keeps_datagram() {
+        let buffer = b"\0\x01kernel\0bogus\0";
+        let error = Packet::parse_detailed(buffer, Strictness::Strict)
+            .unwrap_err();
+        assert_eq!(
+            Error::InvalidTransferMode("bogus".to_owned()), error.error);
+        assert_eq!(15, error.offset);
+        assert_eq!(&buffer[..], &error.datagram[..]);
+        assert_eq!(
+            "0000  00 01 6b 65 72 6e 65 6c 00 62 6f 67 75 73 00     \
+             |..kernel.bogus.|\n",
+            error.hexdump());
+        assert!(error.to_string().ends_with(
+            "at offset 15 of 15-byte datagram"));
+    }
+
+    #[test]
+    fn test_fmt_hex() {
+        let packet = Packet::Error(
+            ErrorCode::FileNotFound, ErrorMessage("no".to_owned()));
+        assert_eq!(
+            "ERROR 1 (File not found) \"no\"\n  \
+             [0000..0002] opcode: 5\n  \
+             [0002..0004] code: 1 (File not found)\n  \
+             [0004..0007] message: \"no\"\n\
+             0000  00 05 00 01 6e 6f 00                             \
+             |....no.|\n",
+            packet.fmt_hex().unwrap());
+    }
+
+    #[test]
+    fn test_fmt_hex_spans_escaped_message() {
+        // The newline is sent as CR,LF, so the message takes 5 bytes.
+        let packet = Packet::Error(
+            ErrorCode::NotDefined, ErrorMessage("a\nb".to_owned()));
+        assert_eq!(
+            "ERROR 0 (Not defined) \"a\\nb\"\n  \
+             [0000..0002] opcode: 5\n  \
+             [0002..0004] code: 0 (Not defined)\n  \
+             [0004..0009] message: \"a\\nb\"\n\
+             0000  00 05 00 00 61 0d 0a 62 00                       \
+             |....a..b.|\n",
+            packet.fmt_hex().unwrap());
+    }
+
+    #[test]
+    fn test_conversions() {
+        let packet = Packet::Data(BlockNum(2), Data(b"abc"));
+        let buffer = Vec::try_from(&packet).unwrap();
+        assert_eq!(packet, Packet::try_from(&buffer[..]).unwrap());
+        let owned = PacketOwned::try_from(&buffer[..]).unwrap();
+        assert_eq!(buffer, Vec::try_from(owned).unwrap());
+        let bad = Packet::Read(
+            Filename::new("nul\0"), TransferMode::Octet,
+            Options::new());
+        assert!(Vec::try_from(bad).is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        let mut options = Options::new();
+        options.blksize = Some(1468);
+        options.tsize = Some(0);
+        assert_eq!(
+            "RRQ \"pxelinux.0\" octet blksize=1468 tsize=0",
+            Packet::Read(
+                Filename::new("pxelinux.0"), TransferMode::Octet,
+                options.clone()).to_string());
+        assert_eq!(
+            "WRQ \"config\" netascii",
+            Packet::Write(
+                Filename::new("config"), TransferMode::NetASCII,
+                Options::new()).to_string());
+        assert_eq!(
+            "DATA 3 (7 bytes)",
+            Packet::Data(BlockNum(3), Data(b"payload")).to_string());
+        assert_eq!("ACK 3", Packet::Ack(BlockNum(3)).to_string());
+        assert_eq!(
+            "ERROR 1 (File not found) \"nope\"",
+            Packet::Error(
+                ErrorCode::FileNotFound,
+                ErrorMessage("nope".to_owned())).to_string());
+        assert_eq!(
+            "OACK blksize=1468 tsize=0", Packet::OAck(options).to_string());
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn test_arbitrary_packets_round_trip() {
+        use arbitrary::{Arbitrary, Unstructured};
+        // A simple LCG provides reproducible input.
+        let mut seed = 1u32;
+        let input: Vec<u8> = (0..65536).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        }).collect();
+        let mut u = Unstructured::new(&input);
+        for _ in 0..256 {
+            let packet = PacketOwned::arbitrary(&mut u).unwrap();
+            let buffer = packet.as_packet().to_vec().unwrap();
+            let parsed = Packet::parse_with(&buffer, Strictness::Lenient);
+            assert_eq!(packet.as_packet(), parsed.unwrap());
+        }
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn test_bytes_round_trip() {
+        use bytes::{Buf, BytesMut};
+        let packet = PacketOwned::Data(BlockNum(3), b"data".to_vec());
+        let mut buffer = BytesMut::from(&b"xx"[..]);
+        assert_eq!(Ok(8), packet.encode(&mut buffer));
+        assert_eq!(&b"xx\0\x03\0\x03data"[..], &buffer[..]);
+        buffer.advance(2);
+        assert_eq!(Ok(packet), PacketOwned::decode(&mut buffer));
+        assert!(buffer.is_empty());
+        // Non-contiguous buffers are copied.
+        let mut chain = (&b"\0\x04"[..]).chain(&b"\0\x09"[..]);
+        assert_eq!(
+            Ok(PacketOwned::Ack(BlockNum(9))),
+            PacketOwned::decode(&mut chain));
+        assert_eq!(0, chain.remaining());
+    }
+
+    #[test]
+    fn test_as_io_slices() {
+        use std::io::Write;
+        let packet = Packet::Data(BlockNum(258), Data(b"data"));
+        let mut header = [0u8; 4];
+        let slices = packet.as_io_slices(&mut header).unwrap();
+        let mut buffer = Vec::new();
+        let size = buffer.write_vectored(&slices).unwrap();
+        assert_eq!(8, size);
+        assert_eq!(packet.to_vec().unwrap(), buffer);
+        assert!(Packet::Ack(BlockNum(1)).as_io_slices(&mut header).is_none());
+    }
+
+    #[test]
+    fn test_packets_round_trip() {
+        let mut options = Options::new();
+        options.blksize = Some(1468);
+        options.tsize = Some(0);
+        let packets = vec![
+            Packet::Read(
+                Filename::new("pxelinux.0"), TransferMode::Octet,
+                options.clone()),
+            Packet::Write(
+                Filename::new("config"), TransferMode::NetASCII,
+                Options::new()),
+            Packet::Data(BlockNum(1), Data(b"payload")),
+            Packet::Ack(BlockNum(65535)),
+            Packet::Error(
+                ErrorCode::FileNotFound, ErrorMessage("no such".to_owned())),
+            Packet::OAck(options),
+        ];
+        for packet in packets {
+            let buffer = packet.to_vec().unwrap();
+            assert_eq!(packet.encoded_len(), buffer.len());
+            assert_eq!(packet, Packet::parse(&buffer).unwrap());
+        }
+    }
+
+}
+
+
+/// The packets that make up the bulk of a transfer are parsed in its
+/// busiest loops, so parsing them must not allocate. A global allocator
+/// counts the allocations made by each thread to check.
+#[cfg(test)]
+mod test_alloc {
+
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    use super::{BlockNum, Data, Packet};
+    use super::super::options::Options;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    struct Counting;
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    /// The number of allocations made by this thread while calling `f`.
+    fn allocations<T, F: FnOnce() -> T>(f: F) -> usize {
+        let before = ALLOCATIONS.with(Cell::get);
+        let result = f();
+        let after = ALLOCATIONS.with(Cell::get);
+        drop(result);
+        after - before
+    }
+
+    #[test]
+    fn test_parsing_ack_does_not_allocate() {
+        let datagram = [0u8, 4, 0, 7];
+        assert_eq!(0, allocations(|| {
+            assert_eq!(
+                Packet::Ack(BlockNum(7)), Packet::parse(&datagram).unwrap());
+        }));
+    }
+
+    #[test]
+    fn test_parsing_data_does_not_allocate() {
+        let datagram = [0u8, 3, 0, 1, b'a', b'b', b'c'];
+        assert_eq!(0, allocations(|| {
+            assert_eq!(
+                Packet::Data(BlockNum(1), Data(b"abc")),
+                Packet::parse(&datagram).unwrap());
+        }));
+    }
+
+    #[test]
+    fn test_parsing_recognised_options_does_not_allocate() {
+        let buf = b"blksize\x001468\0tsize\x000\0windowsize\x004\0";
+        assert_eq!(0, allocations(|| Options::parse(buf).unwrap()));
+    }
+
+    #[test]
+    fn test_allocations_are_counted() {
+        assert_eq!(1, allocations(|| vec![0u8; 16]));
+    }
+
 }
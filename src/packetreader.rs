@@ -1,8 +1,12 @@
 extern crate byteorder;
 
-use std::error;
-use std::fmt;
-use std::result;
+use alloc::borrow;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::error;
+use core::fmt;
+use core::result;
+use core::str;
 
 use self::byteorder::{
     ByteOrder,
@@ -12,39 +16,55 @@ use self::byteorder::{
 
 /// Errors that a `PacketReader` can encounter.
 #[derive(Debug,PartialEq)]
+#[non_exhaustive]
 pub enum Error {
-    NotEnoughData,
-    StringNotTerminated,
+    /// A read of `requested` bytes at `pos` went beyond the `remaining`
+    /// data in the buffer.
+    NotEnoughData{pos: usize, requested: usize, remaining: usize},
+    /// The string starting at `pos` has no null terminator.
+    StringNotTerminated{pos: usize},
+    /// The string starting at `pos` is not valid UTF-8.
+    StringNotUTF8{pos: usize},
 }
 
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Error::NotEnoughData =>
-                write!(f, "not enough data"),
-            Error::StringNotTerminated =>
-                write!(f, "string not terminated with null byte"),
+            Error::NotEnoughData{pos, requested, remaining} =>
+                write!(
+                    f, "not enough data: {} bytes requested at position \
+                        {} with {} remaining", requested, pos, remaining),
+            Error::StringNotTerminated{pos} =>
+                write!(
+                    f, "string at position {} not terminated with null \
+                        byte", pos),
+            Error::StringNotUTF8{pos} =>
+                write!(f, "string at position {} not valid UTF-8", pos),
         }
     }
 }
 
 
-impl error::Error for Error {
-    fn description(&self) -> &str {
-        "tftp packet read error"
-    }
-
-    fn cause(&self) -> Option<&error::Error> {
-        None
-    }
-}
+impl error::Error for Error {}
 
 
 pub type Result<T> = result::Result<T, Error>;
 
 
 /// A mechanism for progressively reading parts from a packet buffer.
+///
+/// Integers are read in network byte order. Use this to decode custom
+/// packets, or payloads of `Packet::Unknown`:
+///
+/// ```
+/// # use allenap_libtftp::packetreader::PacketReader;
+/// let mut reader = PacketReader::new(b"\0\x2aname\0rest");
+/// assert_eq!(42, reader.take_u16().unwrap());
+/// assert_eq!(b"name", reader.take_cstr_bytes().unwrap());
+/// assert_eq!(b"rest", reader.take_remaining().unwrap());
+/// assert_eq!(0, reader.rem());
+/// ```
 #[derive(Debug)]
 pub struct PacketReader<'a> {
     buf: &'a [u8],
@@ -53,6 +73,7 @@ pub struct PacketReader<'a> {
 
 impl<'a> PacketReader<'a> {
 
+    /// Read from the start of `storage`.
     pub fn new(storage: &'a [u8]) -> PacketReader<'a> {
         PacketReader{
             buf: storage,
@@ -65,6 +86,11 @@ impl<'a> PacketReader<'a> {
         self.buf.len()
     }
 
+    /// Is the packet buffer empty?
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
     /// The current position of the read head.
     pub fn pos(&self) -> usize {
         self.pos
@@ -84,7 +110,8 @@ impl<'a> PacketReader<'a> {
             self.pos += 2;
             Ok(value)
         } else {
-            Err(Error::NotEnoughData)
+            Err(Error::NotEnoughData{
+                pos: self.pos, requested: 2, remaining: self.rem()})
         }
     }
 
@@ -92,18 +119,72 @@ impl<'a> PacketReader<'a> {
     /// read head.
     ///
     /// The string is decoded as UTF-8, with non-UTF-8 content being
-    /// discarded. No effort is yet made to deal with NetASCII.
-    pub fn take_string(&mut self) -> Result<String> {
+    /// replaced. It is borrowed from the buffer unless replacement was
+    /// needed. See `take_netascii_string` for fields defined as
+    /// NetASCII.
+    pub fn take_string(&mut self) -> Result<borrow::Cow<'a, str>> {
+        let bytes = self.take_cstr_bytes()?;
+        Ok(String::from_utf8_lossy(bytes))
+    }
+
+    /// Take a null-terminated NetASCII string from the buffer, advancing
+    /// the read head.
+    ///
+    /// CR,LF is decoded as LF and CR,NUL as CR; the NUL in the latter
+    /// does not terminate the string. A CR followed by anything else is
+    /// kept as-is. Content that is not UTF-8 is replaced, as with
+    /// `take_string`.
+    pub fn take_netascii_string(&mut self) -> Result<String> {
+        let mut bytes = Vec::new();
+        let mut pos = self.pos;
+        while pos < self.buf.len() {
+            match (self.buf[pos], self.buf.get(pos + 1)) {
+                (b'\0', _) => {
+                    self.pos = pos + 1;
+                    return Ok(String::from_utf8_lossy(&bytes).into_owned())
+                },
+                (b'\r', Some(&b'\n')) => {
+                    bytes.push(b'\n');
+                    pos += 2;
+                },
+                (b'\r', Some(&b'\0')) => {
+                    bytes.push(b'\r');
+                    pos += 2;
+                },
+                (byte, _) => {
+                    bytes.push(byte);
+                    pos += 1;
+                },
+            }
+        }
+        Err(Error::StringNotTerminated{pos: self.pos})
+    }
+
+    /// Take a null-terminated string from the buffer, advancing the
+    /// read head, borrowed from the buffer without allocating.
+    ///
+    /// Unlike `take_string`, content that is not valid UTF-8 is an
+    /// error, and the read head is left where it was.
+    pub fn take_str(&mut self) -> Result<&'a str> {
+        let pos = self.pos;
+        let bytes = self.take_cstr_bytes()?;
+        str::from_utf8(bytes).map_err(|_| {
+            self.pos = pos;
+            Error::StringNotUTF8{pos}
+        })
+    }
+
+    /// Take a null-terminated byte string from the buffer, advancing
+    /// the read head. The terminator is not included.
+    pub fn take_cstr_bytes(&mut self) -> Result<&'a [u8]> {
         for pos in self.pos..self.buf.len() {
             if self.buf[pos] == 0u8 {
-                let ref bytes = self.buf[self.pos..pos];
-                // TODO: Convert from NetASCII to native.
-                let string = String::from_utf8_lossy(bytes);
+                let bytes = &self.buf[self.pos..pos];
                 self.pos = pos + 1;
-                return Ok(string.into_owned())
+                return Ok(bytes)
             }
         }
-        Err(Error::StringNotTerminated)
+        Err(Error::StringNotTerminated{pos: self.pos})
     }
 
     /// Take the remaining bytes from the buffer, advancing the read
@@ -121,6 +202,8 @@ mod test {
 
     extern crate byteorder;
 
+    use std::borrow;
+
     use super::{Error, PacketReader};
     use self::byteorder::{
         ByteOrder,
@@ -129,8 +212,8 @@ mod test {
 
     #[test]
     fn test_create_new_buffer() {
-        let mut storage = vec![0u8; 10];
-        let buffer = PacketReader::new(&mut storage);
+        let storage = vec![0u8; 10];
+        let buffer = PacketReader::new(&storage);
         assert_eq!(10, buffer.len());
         assert_eq!(0, buffer.pos());
         assert_eq!(10, buffer.rem());
@@ -140,33 +223,103 @@ mod test {
     fn test_take_u16() {
         let mut storage = vec![0u8; 2];
         BigEndian::write_u16(&mut storage, 1234);
-        let mut buffer = PacketReader::new(&mut storage);
+        let mut buffer = PacketReader::new(&storage);
         assert_eq!(1234, buffer.take_u16().unwrap());
         assert_eq!(2, buffer.pos());
     }
 
     #[test]
     fn test_take_u16_out_of_range() {
-        let mut storage = vec![0u8; 1];
-        let mut buffer = PacketReader::new(&mut storage);
-        assert_eq!(Error::NotEnoughData, buffer.take_u16().unwrap_err());
+        let storage = vec![0u8; 1];
+        let mut buffer = PacketReader::new(&storage);
+        assert_eq!(
+            Error::NotEnoughData{pos: 0, requested: 2, remaining: 1},
+            buffer.take_u16().unwrap_err());
         assert_eq!(0, buffer.pos());
     }
 
+    #[test]
+    fn test_error_display() {
+        let error = Error::NotEnoughData{pos: 4, requested: 2, remaining: 1};
+        assert_eq!(
+            "not enough data: 2 bytes requested at position 4 with 1 \
+             remaining", error.to_string());
+        let error = Error::StringNotTerminated{pos: 2};
+        assert_eq!(
+            "string at position 2 not terminated with null byte",
+            error.to_string());
+    }
+
     #[test]
     fn test_take_string() {
-        let mut storage = "foobar\0".as_bytes();
-        let mut buffer = PacketReader::new(&mut storage);
+        let storage = "foobar\0".as_bytes();
+        let mut buffer = PacketReader::new(storage);
         assert_eq!("foobar", buffer.take_string().unwrap());
         assert_eq!(7, buffer.pos());
     }
 
+    #[test]
+    fn test_take_string_borrows() {
+        let storage = b"foo\0b\xe4r\0";
+        let mut buffer = PacketReader::new(storage);
+        match buffer.take_string().unwrap() {
+            borrow::Cow::Borrowed(string) => assert_eq!("foo", string),
+            string => panic!("Unexpectedly owned: {:?}", string),
+        };
+        match buffer.take_string().unwrap() {
+            borrow::Cow::Owned(string) => assert_eq!("b\u{fffd}r", string),
+            string => panic!("Unexpectedly borrowed: {:?}", string),
+        };
+    }
+
+    #[test]
+    fn test_take_netascii_string() {
+        let storage = b"a\r\nb\r\0c\rd\0e\0";
+        let mut buffer = PacketReader::new(storage);
+        assert_eq!("a\nb\rc\rd", buffer.take_netascii_string().unwrap());
+        assert_eq!(10, buffer.pos());
+        assert_eq!("e", buffer.take_netascii_string().unwrap());
+    }
+
+    #[test]
+    fn test_take_netascii_string_out_of_range() {
+        let storage = b"foo\r\0";
+        let mut buffer = PacketReader::new(storage);
+        assert_eq!(
+            Error::StringNotTerminated{pos: 0},
+            buffer.take_netascii_string().unwrap_err());
+        assert_eq!(0, buffer.pos());
+    }
+
+    #[test]
+    fn test_take_str() {
+        let storage = b"foo\0b\xe4r\0";
+        let mut buffer = PacketReader::new(storage);
+        assert_eq!("foo", buffer.take_str().unwrap());
+        assert_eq!(4, buffer.pos());
+        assert_eq!(
+            Error::StringNotUTF8{pos: 4}, buffer.take_str().unwrap_err());
+        assert_eq!(4, buffer.pos());
+        assert_eq!(b"b\xe4r", buffer.take_cstr_bytes().unwrap());
+        assert_eq!(8, buffer.pos());
+    }
+
+    #[test]
+    fn test_take_str_out_of_range() {
+        let storage = b"foo";
+        let mut buffer = PacketReader::new(storage);
+        assert_eq!(
+            Error::StringNotTerminated{pos: 0},
+            buffer.take_str().unwrap_err());
+        assert_eq!(0, buffer.pos());
+    }
+
     #[test]
     fn test_take_string_out_of_range() {
-        let mut storage = vec!['a' as u8; 10];
-        let mut buffer = PacketReader::new(&mut storage);
+        let storage = vec![b'a'; 10];
+        let mut buffer = PacketReader::new(&storage);
         assert_eq!(
-            Error::StringNotTerminated,
+            Error::StringNotTerminated{pos: 0},
             buffer.take_string().unwrap_err());
         assert_eq!(0, buffer.pos());
     }
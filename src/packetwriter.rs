@@ -1,8 +1,11 @@
 extern crate byteorder;
 
-use std::error;
-use std::fmt;
-use std::result;
+#[cfg(feature = "bytes")]
+use bytes::BytesMut;
+use alloc::vec::Vec;
+use core::error;
+use core::fmt;
+use core::result;
 
 use self::byteorder::{
     ByteOrder,
@@ -12,8 +15,11 @@ use self::byteorder::{
 
 /// Errors that a `PacketWriter` can encounter.
 #[derive(Debug,PartialEq)]
+#[non_exhaustive]
 pub enum Error {
-    NotEnoughSpace,
+    /// A write of `requested` bytes at `pos` did not fit in the
+    /// `remaining` space of a fixed buffer.
+    NotEnoughSpace{pos: usize, requested: usize, remaining: usize},
     StringNotASCII,
     StringContainsNull,
 }
@@ -22,8 +28,11 @@ pub enum Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Error::NotEnoughSpace =>
-                write!(f, "not enough space for packet data"),
+            Error::NotEnoughSpace{pos, requested, remaining} =>
+                write!(
+                    f, "not enough space for packet data: {} bytes \
+                        requested at position {} with {} remaining",
+                    requested, pos, remaining),
             Error::StringNotASCII =>
                 write!(f, "string is not ASCII"),
             Error::StringContainsNull =>
@@ -33,39 +42,101 @@ impl fmt::Display for Error {
 }
 
 
-impl error::Error for Error {
-    fn description(&self) -> &str {
-        "tftp packet write error"
-    }
-
-    fn cause(&self) -> Option<&error::Error> {
-        None
-    }
-}
+impl error::Error for Error {}
 
 
 pub type Result<T> = result::Result<T, Error>;
 
 
+/// The length of `value` once encoded as NetASCII, excluding the null
+/// terminator.
+pub fn netascii_len(value: &str) -> usize {
+    value.len() + value.bytes().filter(
+        |&byte| byte == b'\r' || byte == b'\n').count()
+}
+
+
 /// A mechanism for progressively writing parts to a packet buffer.
+///
+/// Integers are written in network byte order. Use this to encode
+/// custom packets:
+///
+/// ```
+/// # use allenap_libtftp::packetwriter::PacketWriter;
+/// let mut buffer = [0u8; 16];
+/// let mut writer = PacketWriter::new(&mut buffer);
+/// writer.put_u16(42).unwrap();
+/// writer.put_string("name").unwrap();
+/// writer.put_bytes(b"rest").unwrap();
+/// let (buffer, size) = writer.get();
+/// assert_eq!(b"\0\x2aname\0rest", &buffer[..size]);
+/// ```
 #[derive(Debug)]
 pub struct PacketWriter<'a> {
-    buf: &'a mut [u8],
+    buf: Storage<'a>,
     pos: usize,
 }
 
+
+#[derive(Debug)]
+enum Storage<'a> {
+    /// A slice that must be big enough for everything written.
+    Fixed(&'a mut [u8]),
+    /// A vector that is extended as needed; writing starts at the
+    /// offset given.
+    Growable(&'a mut Vec<u8>, usize),
+    /// As `Growable`, for the `bytes` crate's buffer.
+    #[cfg(feature = "bytes")]
+    Bytes(&'a mut BytesMut, usize),
+}
+
+
 impl<'a> PacketWriter<'a> {
 
+    /// Write from the start of `storage`. Writes that would overrun it
+    /// fail with `Error::NotEnoughSpace`.
     pub fn new(storage: &'a mut [u8]) -> Self {
         PacketWriter{
-            buf: storage,
+            buf: Storage::Fixed(storage),
             pos: 0,
         }
     }
 
-    /// The length of the packet buffer.
+    /// Write onto the end of `storage`, growing it as needed, so writes
+    /// never fail with `Error::NotEnoughSpace`. Positions are relative
+    /// to where `storage` ended when this was called.
+    pub fn growable(storage: &'a mut Vec<u8>) -> Self {
+        let start = storage.len();
+        PacketWriter{
+            buf: Storage::Growable(storage, start),
+            pos: 0,
+        }
+    }
+
+    /// Like `growable`, but writing onto the end of a `BytesMut`.
+    #[cfg(feature = "bytes")]
+    pub fn bytes(storage: &'a mut BytesMut) -> Self {
+        let start = storage.len();
+        PacketWriter{
+            buf: Storage::Bytes(storage, start),
+            pos: 0,
+        }
+    }
+
+    /// The length of the packet buffer. A growable buffer is only as
+    /// long as what has been written so far.
     pub fn len(&self) -> usize {
-        self.buf.len()
+        match self.buf {
+            Storage::Fixed(ref buf) => buf.len(),
+            Storage::Growable(ref buf, start) => buf.len() - start,
+            #[cfg(feature = "bytes")]
+            Storage::Bytes(ref buf, start) => buf.len() - start,
+        }
+    }
+
+    /// Is the packet buffer empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     /// The current position of the write head.
@@ -76,64 +147,128 @@ impl<'a> PacketWriter<'a> {
     /// The number of bytes remaining after the write-head before the
     /// end of the buffer.
     pub fn rem(&self) -> usize {
-        self.buf.len() - self.pos
+        self.len() - self.pos
+    }
+
+    /// The `size` bytes after the write head, growing the buffer if
+    /// possible. Does not advance the write head.
+    fn space(&mut self, size: usize) -> Result<&mut [u8]> {
+        let (begin, end) = (self.pos, self.pos + size);
+        match self.buf {
+            Storage::Fixed(ref mut buf) => {
+                let remaining = buf.len().saturating_sub(begin);
+                buf.get_mut(begin..end).ok_or(Error::NotEnoughSpace{
+                    pos: begin, requested: size, remaining})
+            },
+            Storage::Growable(ref mut buf, start) => {
+                if start + end > buf.len() {
+                    buf.resize(start + end, 0u8);
+                }
+                Ok(&mut buf[start + begin..start + end])
+            },
+            #[cfg(feature = "bytes")]
+            Storage::Bytes(ref mut buf, start) => {
+                if start + end > buf.len() {
+                    buf.resize(start + end, 0u8);
+                }
+                Ok(&mut buf[start + begin..start + end])
+            },
+        }
     }
 
     /// Put an unsigned 16-bit integer into the buffer, advancing the
     /// write head.
     pub fn put_u16(&mut self, value: u16) -> Result<()> {
-        if self.rem() >= 2 {
-            BigEndian::write_u16(&mut self.buf[self.pos..], value);
-            self.pos += 2;
-            Ok(())
-        } else {
-            Err(Error::NotEnoughSpace)
-        }
+        BigEndian::write_u16(self.space(2)?, value);
+        self.pos += 2;
+        Ok(())
     }
 
-    /// Put a null-terminated string from the buffer, advancing the
+    /// Put a null-terminated string into the buffer, advancing the
     /// write head.
     ///
-    /// The string is encoded as UTF-8. No effort is yet made to deal
-    /// with NetASCII.
+    /// The string must be ASCII, and is written as-is; see
+    /// `put_netascii_string` for fields defined as NetASCII.
     pub fn put_string(&mut self, value: &str) -> Result<()> {
         if value.is_ascii() {
-            if value.contains("\0") {
-                Err(Error::StringContainsNull)
-            }
-            else {
-                let end = self.pos + value.len();
-                // Greater-than-or-equals because of the null terminator.
-                if end >= self.buf.len() {
-                    Err(Error::NotEnoughSpace)
-                } else {
-                    // TODO: NetASCII nonsense.
-                    self.buf[self.pos..end].copy_from_slice(value.as_bytes());
-                    self.buf[end] = 0u8;
-                    self.pos = end + 1;
-                    Ok(())
-                }
-            }
+            self.put_bytestring(value.as_bytes())
         } else {
             Err(Error::StringNotASCII)
         }
     }
 
-    /// Put bytes into the buffer, advancing the write head.
-    pub fn put_bytes(&mut self, bytes: &[u8]) -> Result<()> {
-        let end = self.pos + bytes.len();
-        if end > self.buf.len() {
-            Err(Error::NotEnoughSpace)
-        } else {
-            self.buf[self.pos..end].copy_from_slice(bytes);
-            self.pos = end;
+    /// Put a null-terminated NetASCII string into the buffer, advancing
+    /// the write head.
+    ///
+    /// The string must be ASCII. LF is escaped as CR,LF and CR as
+    /// CR,NUL, so any string round-trips through
+    /// `PacketReader::take_netascii_string`.
+    pub fn put_netascii_string(&mut self, value: &str) -> Result<()> {
+        if !value.is_ascii() {
+            Err(Error::StringNotASCII)
+        }
+        else if value.contains('\0') {
+            Err(Error::StringContainsNull)
+        }
+        else {
+            // Plus one for the null terminator.
+            let size = netascii_len(value) + 1;
+            let space = self.space(size)?;
+            let mut pos = 0;
+            for byte in value.bytes() {
+                match byte {
+                    b'\n' => {
+                        space[pos..pos + 2].copy_from_slice(b"\r\n");
+                        pos += 2;
+                    },
+                    b'\r' => {
+                        space[pos..pos + 2].copy_from_slice(b"\r\0");
+                        pos += 2;
+                    },
+                    _ => {
+                        space[pos] = byte;
+                        pos += 1;
+                    },
+                }
+            }
+            space[pos] = 0u8;
+            self.pos += size;
+            Ok(())
+        }
+    }
+
+    /// Put a null-terminated byte string into the buffer, advancing the
+    /// write head. The bytes are written as-is.
+    pub fn put_bytestring(&mut self, value: &[u8]) -> Result<()> {
+        if value.contains(&0u8) {
+            Err(Error::StringContainsNull)
+        }
+        else {
+            // Plus one for the null terminator.
+            let space = self.space(value.len() + 1)?;
+            space[..value.len()].copy_from_slice(value);
+            space[value.len()] = 0u8;
+            self.pos += value.len() + 1;
             Ok(())
         }
     }
 
-    /// Get the underlying buffer.
+    /// Put bytes into the buffer, advancing the write head.
+    pub fn put_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.space(bytes.len())?.copy_from_slice(bytes);
+        self.pos += bytes.len();
+        Ok(())
+    }
+
+    /// Get the underlying buffer, and the number of bytes written. For a
+    /// growable buffer, this is only the part written by this writer.
     pub fn get(self) -> (&'a mut [u8], usize) {
-        (self.buf, self.pos)
+        match self.buf {
+            Storage::Fixed(buf) => (buf, self.pos),
+            Storage::Growable(buf, start) => (&mut buf[start..], self.pos),
+            #[cfg(feature = "bytes")]
+            Storage::Bytes(buf, start) => (&mut buf[start..], self.pos),
+        }
     }
 }
 
@@ -176,10 +311,26 @@ mod test {
     fn test_put_u16_out_of_range() {
         let mut storage = vec![0u8; 1];
         let mut buffer = PacketWriter::new(&mut storage);
-        assert_eq!(Error::NotEnoughSpace, buffer.put_u16(1).unwrap_err());
+        assert_eq!(
+            Error::NotEnoughSpace{pos: 0, requested: 2, remaining: 1},
+            buffer.put_u16(1).unwrap_err());
         assert_eq!(0, buffer.pos());
     }
 
+    #[test]
+    fn test_not_enough_space_reports_position() {
+        let mut storage = vec![0u8; 6];
+        let mut buffer = PacketWriter::new(&mut storage);
+        buffer.put_u16(1).unwrap();
+        assert_eq!(
+            Error::NotEnoughSpace{pos: 2, requested: 5, remaining: 4},
+            buffer.put_bytes(b"hello").unwrap_err());
+        assert_eq!(
+            "not enough space for packet data: 5 bytes requested at \
+             position 2 with 4 remaining",
+            buffer.put_bytes(b"hello").unwrap_err().to_string());
+    }
+
     #[test]
     fn test_put_string() {
         let mut storage = vec![0u8; 5];
@@ -196,7 +347,7 @@ mod test {
         let mut storage = vec![0u8; 6];
         let mut buffer = PacketWriter::new(&mut storage);
         assert_eq!(
-            Error::NotEnoughSpace,
+            Error::NotEnoughSpace{pos: 0, requested: 7, remaining: 6},
             buffer.put_string("foobar").unwrap_err());
         assert_eq!(0, buffer.pos());
     }
@@ -211,6 +362,38 @@ mod test {
         assert_eq!(0, buffer.pos());
     }
 
+    #[test]
+    fn test_put_netascii_string() {
+        let mut storage = vec![0u8; 12];
+        let mut buffer = PacketWriter::new(&mut storage);
+        buffer.put_netascii_string("a\nb\rc\r\n").unwrap();
+        assert_eq!(12, buffer.pos());
+        assert_eq!(b"a\r\nb\r\0c\r\0\r\n\0", &storage[..]);
+    }
+
+    #[test]
+    fn test_put_netascii_string_out_of_range() {
+        let mut storage = vec![0u8; 4];
+        let mut buffer = PacketWriter::new(&mut storage);
+        assert_eq!(
+            Error::NotEnoughSpace{pos: 0, requested: 5, remaining: 4},
+            buffer.put_netascii_string("ab\n").unwrap_err());
+        assert_eq!(0, buffer.pos());
+    }
+
+    #[test]
+    fn test_put_netascii_string_invalid() {
+        let mut storage = vec![0u8; 10];
+        let mut buffer = PacketWriter::new(&mut storage);
+        assert_eq!(
+            Error::StringNotASCII,
+            buffer.put_netascii_string("…").unwrap_err());
+        assert_eq!(
+            Error::StringContainsNull,
+            buffer.put_netascii_string("a\0b").unwrap_err());
+        assert_eq!(0, buffer.pos());
+    }
+
     #[test]
     fn test_put_string_with_null() {
         let mut storage = vec![0u8; 6];
@@ -221,4 +404,33 @@ mod test {
         assert_eq!(0, buffer.pos());
     }
 
+    #[test]
+    fn test_growable() {
+        let mut storage = b"prefix".to_vec();
+        {
+            let mut buffer = PacketWriter::growable(&mut storage);
+            assert_eq!(0, buffer.len());
+            buffer.put_u16(1234).unwrap();
+            buffer.put_string("foo").unwrap();
+            buffer.put_bytes(&[7u8; 100]).unwrap();
+            assert_eq!(106, buffer.pos());
+            assert_eq!(0, buffer.rem());
+            let (written, position) = buffer.get();
+            assert_eq!(106, position);
+            assert_eq!(&[4u8, 210, 102, 111, 111, 0, 7], &written[..7]);
+        }
+        assert_eq!(112, storage.len());
+        assert_eq!(b"prefix", &storage[..6]);
+    }
+
+    #[test]
+    fn test_growable_still_checks_strings() {
+        let mut storage = Vec::new();
+        let mut buffer = PacketWriter::growable(&mut storage);
+        assert_eq!(
+            Error::StringContainsNull,
+            buffer.put_string("foo\0bar").unwrap_err());
+        assert_eq!(0, buffer.pos());
+    }
+
 }
@@ -0,0 +1,653 @@
+extern crate byteorder;
+
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+use std::net;
+use std::sync::Mutex;
+use std::time;
+
+use self::byteorder::{
+    BigEndian,
+    ByteOrder,
+    LittleEndian,
+    ReadBytesExt,
+    WriteBytesExt,
+};
+
+use super::transport::Transport;
+
+
+/// Microsecond timestamps, in the writer's byte order.
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+/// Nanosecond timestamps, in the writer's byte order.
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const PROTOCOL_UDP: u8 = 17;
+
+/// Larger than any real capture's snapshot length, but small enough to
+/// allocate without a second thought.
+const MAX_FRAME: u32 = 262_144;
+
+
+/// A UDP datagram, as captured.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Record {
+    pub time: time::SystemTime,
+    pub src: net::SocketAddr,
+    pub dst: net::SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+
+/// Writes datagrams to a file in the classic pcap format, which
+/// Wireshark, tcpdump, and friends all read. Each is wrapped in made-up
+/// IP and UDP headers, with correct checksums.
+pub struct PcapWriter<W> {
+    writer: W,
+}
+
+
+impl<W: io::Write> PcapWriter<W> {
+
+    /// Write the file header and return a writer ready for records.
+    pub fn new(mut writer: W) -> io::Result<PcapWriter<W>> {
+        writer.write_u32::<LittleEndian>(MAGIC_MICROS)?;
+        writer.write_u16::<LittleEndian>(2)?;  // Major version.
+        writer.write_u16::<LittleEndian>(4)?;  // Minor version.
+        writer.write_i32::<LittleEndian>(0)?;  // Time zone; always UTC.
+        writer.write_u32::<LittleEndian>(0)?;  // Timestamp accuracy.
+        writer.write_u32::<LittleEndian>(65535)?;  // Snapshot length.
+        writer.write_u32::<LittleEndian>(LINKTYPE_RAW)?;
+        Ok(PcapWriter{writer})
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let packet = encode_ip(record)?;
+        let since = record.time.duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.writer.write_u32::<LittleEndian>(since.as_secs() as u32)?;
+        self.writer.write_u32::<LittleEndian>(since.subsec_micros())?;
+        self.writer.write_u32::<LittleEndian>(packet.len() as u32)?;
+        self.writer.write_u32::<LittleEndian>(packet.len() as u32)?;
+        self.writer.write_all(&packet)?;
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+}
+
+
+impl<W> fmt::Debug for PcapWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PcapWriter").finish()
+    }
+}
+
+
+/// Reads UDP datagrams from a classic pcap file, as written by
+/// `PcapWriter` or captured from a network. Ethernet, Linux "cooked",
+/// and raw IP captures are understood. Packets other than UDP, and
+/// fragments, are skipped.
+pub struct PcapReader<R> {
+    reader: R,
+    big_endian: bool,
+    nanos: bool,
+    linktype: u32,
+}
+
+
+impl<R: io::Read> PcapReader<R> {
+
+    /// Read the file header and return a reader ready for records.
+    pub fn new(mut reader: R) -> io::Result<PcapReader<R>> {
+        let mut header = [0u8; 24];
+        reader.read_exact(&mut header)?;
+        let (big_endian, nanos) = match LittleEndian::read_u32(&header) {
+            MAGIC_MICROS => (false, false),
+            MAGIC_NANOS => (false, true),
+            magic => match magic.swap_bytes() {
+                MAGIC_MICROS => (true, false),
+                MAGIC_NANOS => (true, true),
+                _ => return Err(invalid("not a pcap file")),
+            },
+        };
+        let linktype = if big_endian {
+            BigEndian::read_u32(&header[20..])
+        }
+        else {
+            LittleEndian::read_u32(&header[20..])
+        };
+        match linktype {
+            LINKTYPE_ETHERNET | LINKTYPE_RAW | LINKTYPE_LINUX_SLL |
+            LINKTYPE_IPV4 | LINKTYPE_IPV6 => {},
+            _ => return Err(invalid("unsupported link type")),
+        };
+        Ok(PcapReader{reader, big_endian, nanos, linktype})
+    }
+
+    /// The next UDP datagram, or `None` at the end of the file.
+    pub fn read(&mut self) -> io::Result<Option<Record>> {
+        loop {
+            let mut header = [0u8; 16];
+            match self.reader.read_exact(&mut header) {
+                Ok(()) => {},
+                Err(ref error)
+                    if error.kind() == io::ErrorKind::UnexpectedEof =>
+                    return Ok(None),
+                Err(error) => return Err(error),
+            };
+            let mut fields = &header[..];
+            let (secs, frac, len) = if self.big_endian {
+                (fields.read_u32::<BigEndian>()?,
+                 fields.read_u32::<BigEndian>()?,
+                 fields.read_u32::<BigEndian>()?)
+            }
+            else {
+                (fields.read_u32::<LittleEndian>()?,
+                 fields.read_u32::<LittleEndian>()?,
+                 fields.read_u32::<LittleEndian>()?)
+            };
+            if len > MAX_FRAME {
+                return Err(invalid("captured packet too large"));
+            }
+            let mut frame = vec![0u8; len as usize];
+            self.reader.read_exact(&mut frame)?;
+            let nanos = if self.nanos {
+                frac
+            }
+            else {
+                frac.saturating_mul(1000)
+            };
+            let time = time::UNIX_EPOCH + time::Duration::new(
+                secs.into(), nanos);
+            if let Some((src, dst, payload)) = self.decode(&frame) {
+                let payload = payload.to_vec();
+                return Ok(Some(Record{time, src, dst, payload}));
+            }
+        }
+    }
+
+    fn decode<'a>(&self, frame: &'a [u8]) -> Option<Datagram<'a>> {
+        let (ethertype, packet) = match self.linktype {
+            LINKTYPE_ETHERNET => {
+                let mut ethertype = BigEndian::read_u16(frame.get(12..14)?);
+                let mut offset = 14;
+                while ethertype == ETHERTYPE_VLAN {
+                    ethertype = BigEndian::read_u16(
+                        frame.get(offset + 2..offset + 4)?);
+                    offset += 4;
+                }
+                (Some(ethertype), frame.get(offset..)?)
+            },
+            LINKTYPE_LINUX_SLL => (
+                Some(BigEndian::read_u16(frame.get(14..16)?)),
+                frame.get(16..)?),
+            _ => (None, frame),
+        };
+        match ethertype {
+            Some(ETHERTYPE_IPV4) | Some(ETHERTYPE_IPV6) | None =>
+                decode_ip(packet),
+            Some(_) => None,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+}
+
+
+impl<R: io::Read> Iterator for PcapReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+
+impl<R> fmt::Debug for PcapReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PcapReader")
+            .field("linktype", &self.linktype)
+            .finish()
+    }
+}
+
+
+type Datagram<'a> = (net::SocketAddr, net::SocketAddr, &'a [u8]);
+
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+
+/// Find the UDP datagram in an IPv4 or IPv6 packet.
+fn decode_ip(packet: &[u8]) -> Option<Datagram<'_>> {
+    let (src, dst, udp): (net::IpAddr, net::IpAddr, _) =
+        match packet.first()? >> 4 {
+            4 => {
+                let header_len = usize::from(packet[0] & 0x0f) * 4;
+                let total_len = usize::from(
+                    BigEndian::read_u16(packet.get(2..4)?));
+                let fragment = BigEndian::read_u16(packet.get(6..8)?);
+                // Fragments can't be decoded without reassembly.
+                if fragment & 0x3fff != 0 ||
+                    *packet.get(9)? != PROTOCOL_UDP {
+                    return None;
+                }
+                let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+                let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+                (src.into(), dst.into(),
+                 packet.get(header_len..total_len.min(packet.len()))?)
+            },
+            6 => {
+                // Extension headers are not supported.
+                if *packet.get(6)? != PROTOCOL_UDP {
+                    return None;
+                }
+                let payload_len = usize::from(
+                    BigEndian::read_u16(packet.get(4..6)?));
+                let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+                let dst: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+                (src.into(), dst.into(),
+                 packet.get(40..(40 + payload_len).min(packet.len()))?)
+            },
+            _ => return None,
+        };
+    let src_port = BigEndian::read_u16(udp.get(0..2)?);
+    let dst_port = BigEndian::read_u16(udp.get(2..4)?);
+    let udp_len = usize::from(BigEndian::read_u16(udp.get(4..6)?));
+    let payload = udp.get(8..udp_len.min(udp.len()))?;
+    Some(((src, src_port).into(), (dst, dst_port).into(), payload))
+}
+
+
+/// Wrap a datagram in IP and UDP headers.
+fn encode_ip(record: &Record) -> io::Result<Vec<u8>> {
+    let udp_len = 8 + record.payload.len();
+    let mut packet = Vec::with_capacity(40 + udp_len);
+    let pseudo = match (record.src.ip(), record.dst.ip()) {
+        (net::IpAddr::V4(src), net::IpAddr::V4(dst)) => {
+            let total_len = 20 + udp_len;
+            if total_len > 0xffff {
+                return Err(invalid("datagram too large"));
+            }
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.write_u16::<BigEndian>(total_len as u16)?;
+            // Identification, don't fragment, TTL, protocol.
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, PROTOCOL_UDP]);
+            packet.extend_from_slice(&[0, 0]);  // Checksum, below.
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let checksum = checksum(&packet);
+            BigEndian::write_u16(&mut packet[10..12], checksum);
+            let mut pseudo = Vec::with_capacity(12);
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&[0, PROTOCOL_UDP]);
+            pseudo.write_u16::<BigEndian>(udp_len as u16)?;
+            pseudo
+        },
+        (net::IpAddr::V6(src), net::IpAddr::V6(dst)) => {
+            if udp_len > 0xffff {
+                return Err(invalid("datagram too large"));
+            }
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.write_u16::<BigEndian>(udp_len as u16)?;
+            packet.extend_from_slice(&[PROTOCOL_UDP, 64]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let mut pseudo = Vec::with_capacity(40);
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.write_u32::<BigEndian>(udp_len as u32)?;
+            pseudo.extend_from_slice(&[0, 0, 0, PROTOCOL_UDP]);
+            pseudo
+        },
+        _ => return Err(invalid("source and destination families differ")),
+    };
+    let start = packet.len();
+    packet.write_u16::<BigEndian>(record.src.port())?;
+    packet.write_u16::<BigEndian>(record.dst.port())?;
+    packet.write_u16::<BigEndian>(udp_len as u16)?;
+    packet.extend_from_slice(&[0, 0]);  // Checksum, below.
+    packet.extend_from_slice(&record.payload);
+    let mut summed = pseudo;
+    summed.extend_from_slice(&packet[start..]);
+    // Zero means "no checksum", so a sum of zero is sent as all ones.
+    let checksum = match checksum(&summed) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    BigEndian::write_u16(&mut packet[start + 6..start + 8], checksum);
+    Ok(packet)
+}
+
+
+/// The Internet checksum, from RFC-1071.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data.chunks(2)
+        .map(|pair| match *pair {
+            [high, low] => u32::from(high) << 8 | u32::from(low),
+            [high] => u32::from(high) << 8,
+            _ => unreachable!(),
+        })
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+
+/// Wraps another transport to record every datagram it sends and
+/// receives. Wrap a transfer's transport with this to capture it for
+/// later study or `replay`.
+pub struct Recorder<T, W> {
+    inner: T,
+    peer: Mutex<Option<net::SocketAddr>>,
+    writer: Mutex<PcapWriter<W>>,
+}
+
+
+impl<T: Transport, W: io::Write> Recorder<T, W> {
+
+    pub fn new(inner: T, writer: PcapWriter<W>) -> Recorder<T, W> {
+        Recorder{inner, peer: Mutex::new(None), writer: Mutex::new(writer)}
+    }
+
+    pub fn into_inner(self) -> (T, PcapWriter<W>) {
+        (self.inner, self.writer.into_inner().unwrap())
+    }
+
+    fn record(
+        &self, src: net::SocketAddr, dst: net::SocketAddr, payload: &[u8])
+        -> io::Result<()>
+    {
+        let time = time::SystemTime::now();
+        let payload = payload.to_vec();
+        self.writer.lock().unwrap().write(&Record{time, src, dst, payload})
+    }
+
+}
+
+
+impl<T, W> fmt::Debug for Recorder<T, W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Recorder").finish()
+    }
+}
+
+
+impl<T: Transport, W: io::Write> Transport for Recorder<T, W> {
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.inner.send(buf)?;
+        if let Some(peer) = *self.peer.lock().unwrap() {
+            self.record(self.inner.local_addr()?, peer, &buf[..size])?;
+        }
+        Ok(size)
+    }
+
+    fn send_to(&self, buf: &[u8], addr: net::SocketAddr) -> io::Result<usize> {
+        let size = self.inner.send_to(buf, addr)?;
+        self.record(self.inner.local_addr()?, addr, &buf[..size])?;
+        Ok(size)
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<usize>
+    {
+        let peer = *self.peer.lock().unwrap();
+        match peer {
+            Some(peer) => {
+                let size = self.inner.recv(buf, timeout)?;
+                self.record(peer, self.inner.local_addr()?, &buf[..size])?;
+                Ok(size)
+            },
+            None => self.recv_from(buf, timeout).map(|(size, _)| size),
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<(usize, net::SocketAddr)>
+    {
+        let (size, src) = self.inner.recv_from(buf, timeout)?;
+        self.record(src, self.inner.local_addr()?, &buf[..size])?;
+        Ok((size, src))
+    }
+
+    fn connect(&self, addr: net::SocketAddr) -> io::Result<()> {
+        self.inner.connect(addr)?;
+        *self.peer.lock().unwrap() = Some(addr);
+        Ok(())
+    }
+
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.inner.local_addr()
+    }
+
+}
+
+
+/// Replay the client in a capture against `server`, returning what the
+/// server sent back.
+///
+/// The client is taken to be the sender of the first record, and the
+/// server to be listening where that was sent. The client's datagrams
+/// are sent in order from a new socket. Where the server replied in
+/// the capture, a reply is awaited for up to `timeout` before going on,
+/// so the replay runs as fast as the server answers. The transfer IDs
+/// (ports) the server replies from are mapped from those in the
+/// capture in the order they are first seen.
+pub fn replay(
+    records: &[Record], server: net::SocketAddr, timeout: time::Duration)
+    -> io::Result<Vec<Record>>
+{
+    let (client, listening) = match records.first() {
+        Some(record) => (record.src, record.dst),
+        None => return Ok(Vec::new()),
+    };
+    let socket = match server {
+        net::SocketAddr::V4(_) => net::UdpSocket::bind(("0.0.0.0", 0)),
+        net::SocketAddr::V6(_) => net::UdpSocket::bind(("::", 0)),
+    }?;
+    let socket: &dyn Transport = &socket;
+    let mut tids: Vec<(net::SocketAddr, net::SocketAddr)> =
+        vec![(listening, server)];
+    let mut replies = Vec::new();
+    let mut buf = vec![0u8; 65536];
+    for record in records {
+        if record.src == client {
+            let live = tids.iter().find(|&&(captured, _)| {
+                captured == record.dst
+            });
+            if let Some(&(_, live)) = live {
+                socket.send_to(&record.payload, live)?;
+            }
+        }
+        else if record.dst == client {
+            match socket.recv_from(&mut buf, Some(timeout)) {
+                Ok((size, src)) => {
+                    let captured = tids.iter().any(|&(captured, _)| {
+                        captured == record.src
+                    });
+                    let live = tids.iter().any(|&(_, live)| live == src);
+                    if !captured && !live {
+                        tids.push((record.src, src));
+                    }
+                    replies.push(Record{
+                        time: time::SystemTime::now(),
+                        src,
+                        dst: socket.local_addr()?,
+                        payload: buf[..size].to_vec(),
+                    });
+                },
+                Err(ref error) if error.kind() == io::ErrorKind::WouldBlock ||
+                    error.kind() == io::ErrorKind::TimedOut => {},
+                Err(error) => return Err(error),
+            }
+        }
+    }
+    Ok(replies)
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::io;
+    use std::net;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time;
+
+    use super::{PcapReader, PcapWriter, Record, Recorder, replay};
+    use super::super::testing::MemoryTransport;
+    use super::super::transport::Transport;
+
+    fn record(src: &str, dst: &str, payload: &[u8]) -> Record {
+        Record{
+            time: time::UNIX_EPOCH + time::Duration::new(1_500_000_000, 1000),
+            src: src.parse().unwrap(),
+            dst: dst.parse().unwrap(),
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_write_then_read() {
+        let records = vec![
+            record("10.0.0.1:1000", "10.0.0.2:69", b"\0\x01kernel\0octet\0"),
+            record("[fe80::1]:1000", "[fe80::2]:69", b"\0\x04\0\x01"),
+            record("10.0.0.2:5000", "10.0.0.1:1000", b""),
+        ];
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        let capture = writer.into_inner();
+        let reader = PcapReader::new(&capture[..]).unwrap();
+        let read: Vec<Record> = reader.map(Result::unwrap).collect();
+        assert_eq!(records, read);
+    }
+
+    #[test]
+    fn test_write_sets_checksums() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write(&record("10.0.0.1:1000", "10.0.0.2:69", b"abc"))
+            .unwrap();
+        let capture = writer.into_inner();
+        let packet = &capture[24 + 16..];
+        // Both the IPv4 header and the UDP datagram sum to all ones.
+        assert_eq!(0, super::checksum(&packet[..20]));
+        let mut pseudo = packet[12..20].to_vec();
+        pseudo.extend_from_slice(&[0, 17, 0, 11]);
+        pseudo.extend_from_slice(&packet[20..]);
+        assert_eq!(0, super::checksum(&pseudo));
+    }
+
+    #[test]
+    fn test_read_ethernet() {
+        // A big-endian capture with a VLAN tag and a non-IP frame.
+        let mut capture = vec![
+            0xa1, 0xb2, 0xc3, 0xd4, 0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0xff, 0xff, 0, 0, 0, 1,
+        ];
+        let mut arp = vec![0u8; 12];
+        arp.extend_from_slice(&[0x08, 0x06, 0, 0]);
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&[0x81, 0x00, 0, 1, 0x08, 0x00]);
+        frame.extend_from_slice(&[
+            0x45, 0, 0, 32, 0, 0, 0x40, 0, 64, 17, 0, 0,
+            10, 0, 0, 1, 10, 0, 0, 2,
+            0x03, 0xe8, 0, 69, 0, 12, 0, 0, b'd', b'a', b't', b'a',
+        ]);
+        for packet in &[arp, frame] {
+            capture.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2]);
+            let len = packet.len() as u8;
+            capture.extend_from_slice(&[0, 0, 0, len, 0, 0, 0, len]);
+            capture.extend_from_slice(packet);
+        }
+        let mut reader = PcapReader::new(&capture[..]).unwrap();
+        let record = reader.read().unwrap().unwrap();
+        assert_eq!("10.0.0.1:1000".parse(), Ok(record.src));
+        assert_eq!("10.0.0.2:69".parse(), Ok(record.dst));
+        assert_eq!(b"data", &record.payload[..]);
+        assert_eq!(
+            time::UNIX_EPOCH + time::Duration::new(1, 2000), record.time);
+        assert!(reader.read().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_rejects_other_files() {
+        let error = PcapReader::new(&[0u8; 24][..]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+
+    #[test]
+    fn test_recorder() {
+        let (one, two) = MemoryTransport::pair();
+        let writer = PcapWriter::new(Vec::new()).unwrap();
+        let one = Recorder::new(one, writer);
+        one.connect(two.local_addr().unwrap()).unwrap();
+        one.send(b"ping").unwrap();
+        let mut buf = [0u8; 8];
+        let (_, src) = two.recv_from(&mut buf, None).unwrap();
+        two.send_to(b"pong", src).unwrap();
+        one.recv(&mut buf, None).unwrap();
+        let (_, writer) = one.into_inner();
+        let capture = writer.into_inner();
+        let records: Vec<Record> = PcapReader::new(&capture[..]).unwrap()
+            .map(Result::unwrap).collect();
+        assert_eq!(2, records.len());
+        assert_eq!((src, b"ping".to_vec()), (records[0].src,
+                                              records[0].payload.clone()));
+        assert_eq!((src, b"pong".to_vec()), (records[1].dst,
+                                              records[1].payload.clone()));
+    }
+
+    #[test]
+    fn test_replay() {
+        // A download, captured: the server replied from a new port.
+        let records = vec![
+            record("10.0.0.1:1000", "10.0.0.2:69", b"\0\x01kernel\0octet\0"),
+            record("10.0.0.2:5000", "10.0.0.1:1000", b"\0\x03\0\x01hi"),
+            record("10.0.0.1:1000", "10.0.0.2:5000", b"\0\x04\0\x01"),
+        ];
+        let listening = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = listening.local_addr().unwrap();
+        let (done, acked) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            let (_, client) = listening.recv_from(&mut buf).unwrap();
+            let transfer = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            transfer.send_to(b"\0\x03\0\x01hi", client).unwrap();
+            let (size, _) = transfer.recv_from(&mut buf).unwrap();
+            done.send(buf[..size].to_vec()).unwrap();
+        });
+        let timeout = time::Duration::from_secs(5);
+        let replies = replay(&records, server, timeout).unwrap();
+        assert_eq!(1, replies.len());
+        assert_eq!(b"\0\x03\0\x01hi", &replies[0].payload[..]);
+        assert_eq!(b"\0\x04\0\x01".to_vec(), acked.recv().unwrap());
+    }
+
+}
@@ -0,0 +1,181 @@
+use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+
+/// The smallest size class, enough for a `DATA` packet of the default
+/// block size. Each class is twice the size of the one before.
+const MIN_CLASS: usize = 4 + 512;
+
+/// The number of size classes, enough for the largest block size.
+const CLASSES: usize = 8;
+
+/// How many free buffers of each size are kept by default.
+const DEFAULT_KEEP: usize = 64;
+
+
+/// Buffers for transfers, kept once they're done with so that later
+/// transfers needn't allocate their own.
+///
+/// Buffers are grouped into classes by size, each twice the one before,
+/// so that transfers with similar block sizes share them. Put a pool in
+/// an [`rrq::Config`](../rrq/struct.Config.html) or a
+/// [`wrq::Config`](../wrq/struct.Config.html) shared by all transfers.
+pub struct BufferPool {
+    classes: Vec<Mutex<Vec<Vec<u8>>>>,
+    keep: usize,
+}
+
+
+impl BufferPool {
+
+    /// A pool keeping up to `keep` free buffers of each size.
+    pub fn new(keep: usize) -> BufferPool {
+        BufferPool{
+            classes: (0..CLASSES).map(|_| Mutex::new(Vec::new())).collect(),
+            keep,
+        }
+    }
+
+    /// A buffer of `len` bytes, reused if possible, that returns to the
+    /// pool when dropped. Its contents are unspecified.
+    pub fn acquire(&self, len: usize) -> Buffer<'_> {
+        let data = match class(len) {
+            Some(class) => {
+                let free = self.classes[class].lock().unwrap().pop();
+                free.unwrap_or_else(|| vec![0u8; MIN_CLASS << class])
+            },
+            // Too large to pool.
+            None => vec![0u8; len],
+        };
+        Buffer{pool: self, data, len}
+    }
+
+    /// The number of free buffers held, of all sizes.
+    pub fn free(&self) -> usize {
+        self.classes.iter().map(|free| free.lock().unwrap().len()).sum()
+    }
+
+    fn release(&self, data: Vec<u8>) {
+        // Only buffers allocated to the size of a class are returned.
+        if let Some(class) = class(data.len()) {
+            if data.len() == MIN_CLASS << class {
+                let mut free = self.classes[class].lock().unwrap();
+                if free.len() < self.keep {
+                    free.push(data);
+                }
+            }
+        }
+    }
+
+}
+
+
+impl Default for BufferPool {
+    fn default() -> BufferPool {
+        BufferPool::new(DEFAULT_KEEP)
+    }
+}
+
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("keep", &self.keep)
+            .field("free", &self.free())
+            .finish()
+    }
+}
+
+
+/// The smallest class that holds `len` bytes, if any.
+fn class(len: usize) -> Option<usize> {
+    (0..CLASSES).find(|&class| len <= MIN_CLASS << class)
+}
+
+
+/// A buffer from a `BufferPool`, returned to it when dropped.
+pub struct Buffer<'a> {
+    pool: &'a BufferPool,
+    data: Vec<u8>,
+    len: usize,
+}
+
+
+impl<'a> Deref for Buffer<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+
+impl<'a> DerefMut for Buffer<'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data[..self.len]
+    }
+}
+
+
+impl<'a> Drop for Buffer<'a> {
+    fn drop(&mut self) {
+        let data = mem::take(&mut self.data);
+        self.pool.release(data);
+    }
+}
+
+
+impl<'a> fmt::Debug for Buffer<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Buffer").field("len", &self.len).finish()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::{BufferPool, MIN_CLASS};
+
+    #[test]
+    fn test_acquire_gives_requested_length() {
+        let pool = BufferPool::default();
+        assert_eq!(516, pool.acquire(516).len());
+        assert_eq!(1472, pool.acquire(1472).len());
+        assert_eq!(100_000, pool.acquire(100_000).len());
+    }
+
+    #[test]
+    fn test_buffers_are_reused_within_a_class() {
+        let pool = BufferPool::default();
+        let address = {
+            let buffer = pool.acquire(1024);
+            buffer.as_ptr()
+        };
+        assert_eq!(1, pool.free());
+        // Any size in the same class gets the same buffer back.
+        let buffer = pool.acquire(MIN_CLASS + 1);
+        assert_eq!(address, buffer.as_ptr());
+        assert_eq!(0, pool.free());
+    }
+
+    #[test]
+    fn test_pool_keeps_limited_number_of_buffers() {
+        let pool = BufferPool::new(1);
+        let one = pool.acquire(512);
+        let two = pool.acquire(512);
+        drop(one);
+        drop(two);
+        assert_eq!(1, pool.free());
+    }
+
+    #[test]
+    fn test_large_buffers_are_not_kept() {
+        let pool = BufferPool::default();
+        drop(pool.acquire(100_000));
+        assert_eq!(0, pool.free());
+    }
+
+}
@@ -0,0 +1,469 @@
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::net;
+use std::time;
+
+use super::clock::Clock;
+use super::events::{Event, EventSink};
+use super::logging::Span;
+use super::metrics::{Direction, Metrics, NoMetrics};
+use super::options::{Negotiated, Options};
+use super::packet::BlockNum;
+
+
+/// A snapshot of a transfer's progress.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Progress {
+    /// Payload bytes transferred so far.
+    pub bytes: u64,
+    /// `DATA` blocks transferred so far.
+    pub blocks: u64,
+    /// Packets retransmitted, either because of a time-out or, when
+    /// receiving, to acknowledge a duplicate block.
+    pub retransmits: u64,
+    /// Time since the transfer began.
+    pub elapsed: time::Duration,
+    /// The expected size of the transfer, if known.
+    pub tsize: Option<u64>,
+}
+
+
+impl Progress {
+
+    pub fn new(tsize: Option<u64>) -> Progress {
+        Progress{
+            bytes: 0,
+            blocks: 0,
+            retransmits: 0,
+            elapsed: time::Duration::from_secs(0),
+            tsize,
+        }
+    }
+
+}
+
+
+/// What a transfer did, once it has completed.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct TransferStats {
+    /// Payload bytes transferred.
+    pub bytes: u64,
+    /// `DATA` blocks transferred.
+    pub blocks: u64,
+    /// Packets retransmitted, either because of a time-out or, when
+    /// receiving, to acknowledge a duplicate block.
+    pub retransmits: u64,
+    /// `ACK`s received for a block already acknowledged. Only the
+    /// sending side sees these.
+    pub duplicate_acks: u64,
+    /// Time from the start of the transfer to its end.
+    pub duration: time::Duration,
+    /// The options acknowledged with an `OACK`; none are set if there
+    /// was no `OACK`.
+    pub negotiated: Options,
+}
+
+
+/// Receives progress and completion notifications from transfers in
+/// either direction.
+///
+/// All methods have do-nothing default implementations, so implementors
+/// need only define those they're interested in. Observers are called
+/// from the thread doing the transfer, so they should be quick.
+pub trait Observer: Send + Sync {
+
+    /// Called after each block has been acknowledged.
+    fn progress(&self, _peer: net::SocketAddr, _progress: &Progress) {}
+
+    /// Called once the transfer has completed successfully.
+    fn completed(&self, _peer: net::SocketAddr, _stats: &TransferStats) {}
+
+    /// Called when the transfer fails.
+    fn failed(
+        &self, _peer: net::SocketAddr, _progress: &Progress,
+        _error: &dyn error::Error) {}
+
+}
+
+
+impl fmt::Debug for dyn Observer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Observer")
+    }
+}
+
+
+/// Estimates a transfer's throughput from the progress reported over a
+/// recent window of time and, when its size is known, how long it has
+/// left to run.
+///
+/// Feed it each `Progress` given to `Observer::progress`. It needs a
+/// mutable reference, so an observer keeps one per peer behind a lock.
+#[derive(Clone,Debug)]
+pub struct Throughput {
+    window: time::Duration,
+    /// Elapsed time and bytes transferred, oldest first.
+    samples: VecDeque<(time::Duration, u64)>,
+    tsize: Option<u64>,
+}
+
+
+impl Throughput {
+
+    pub fn new(window: time::Duration) -> Throughput {
+        Throughput{window, samples: VecDeque::new(), tsize: None}
+    }
+
+    pub fn update(&mut self, progress: &Progress) {
+        self.samples.push_back((progress.elapsed, progress.bytes));
+        self.tsize = progress.tsize;
+        // Keep one sample from at or before the start of the window so
+        // that the rate always spans the whole of it.
+        if let Some(start) = progress.elapsed.checked_sub(self.window) {
+            while self.samples.len() > 1 && self.samples[1].0 <= start {
+                self.samples.pop_front();
+            }
+        }
+    }
+
+    /// Bytes per second over the window, or `None` until two samples
+    /// have been seen some time apart.
+    pub fn rate(&self) -> Option<f64> {
+        let (first, last) = (self.samples.front()?, self.samples.back()?);
+        let secs = (last.0 - first.0).as_secs_f64();
+        if secs > 0.0 {
+            Some((last.1 - first.1) as f64 / secs)
+        }
+        else {
+            None
+        }
+    }
+
+    /// Time remaining at the current rate, or `None` if the size of
+    /// the transfer or its rate is unknown, or nothing is moving.
+    pub fn eta(&self) -> Option<time::Duration> {
+        let remaining = self.tsize?.saturating_sub(self.samples.back()?.1);
+        if remaining == 0 {
+            return Some(time::Duration::from_secs(0));
+        }
+        match self.rate() {
+            Some(rate) if rate > 0.0 =>
+                Some(time::Duration::from_secs_f64(remaining as f64 / rate)),
+            _ => None,
+        }
+    }
+
+}
+
+
+/// Tracks the progress of a transfer and reports it to an observer, to
+/// metrics, to an event sink, and to the transfer's span.
+pub(crate) struct Tracker<'a> {
+    peer: net::SocketAddr,
+    observer: Option<&'a dyn Observer>,
+    clock: &'a dyn Clock,
+    started: time::Instant,
+    progress: Progress,
+    duplicate_acks: u64,
+    negotiated: Options,
+    metrics: &'a dyn Metrics,
+    events: Option<(&'a dyn EventSink, Direction)>,
+    span: Span,
+}
+
+
+impl<'a> Tracker<'a> {
+
+    pub fn new(
+        peer: net::SocketAddr, observer: Option<&'a dyn Observer>,
+        tsize: Option<u64>, clock: &'a dyn Clock)
+        -> Tracker<'a>
+    {
+        Tracker{
+            peer,
+            observer,
+            clock,
+            started: clock.now(),
+            progress: Progress::new(tsize),
+            duplicate_acks: 0,
+            negotiated: Options::new(),
+            metrics: &NoMetrics,
+            events: None,
+            span: Span::none(),
+        }
+    }
+
+    /// Report to `metrics` too.
+    pub fn with_metrics(mut self, metrics: &'a dyn Metrics) -> Tracker<'a> {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Send events to `sink` too, if there is one, as a transfer in the
+    /// given direction.
+    pub fn with_events(
+        mut self, sink: Option<&'a dyn EventSink>, direction: Direction)
+        -> Tracker<'a>
+    {
+        self.events = sink.map(|sink| (sink, direction));
+        self
+    }
+
+    /// Report to `span` too.
+    pub fn with_span(mut self, span: Span) -> Tracker<'a> {
+        self.span = span;
+        self
+    }
+
+    /// Record the start of the transfer, of `filename` if it's known.
+    pub fn started(&self, filename: Option<&str>) {
+        self.metrics.transfer_started();
+        if let Some((sink, direction)) = self.events {
+            sink.event(Event::TransferStarted{
+                peer: self.peer, direction,
+                filename: filename.map(str::to_owned),
+            });
+        }
+    }
+
+    /// Record the parameters negotiated, and the options acknowledged.
+    pub fn negotiated(&mut self, negotiated: &Negotiated, options: &Options) {
+        self.negotiated = options.clone();
+        self.span.negotiated(negotiated, options);
+    }
+
+    /// Record the sending of an `OACK` of `size` bytes.
+    pub fn oack(&self, size: usize) {
+        self.span.oack(size, &self.negotiated);
+    }
+
+    /// Record a block of `size` bytes.
+    pub fn block(&mut self, size: usize) {
+        self.progress.bytes += size as u64;
+        self.progress.blocks += 1;
+        self.progress.elapsed = self.clock.now() - self.started;
+        if let Some(observer) = self.observer {
+            observer.progress(self.peer, &self.progress);
+        }
+    }
+
+    /// Record an `ACK` for a block already acknowledged.
+    pub fn duplicate_ack(&mut self) {
+        self.duplicate_acks += 1;
+    }
+
+    /// Record a retransmission of the packet for block `blkno`.
+    pub fn retransmit(&mut self, blkno: BlockNum) {
+        self.progress.retransmits += 1;
+        self.metrics.retransmit();
+        self.span.retransmit(blkno, self.progress.retransmits);
+        if let Some((sink, direction)) = self.events {
+            sink.event(Event::BlockRetransmitted{
+                peer: self.peer, direction, block: blkno});
+        }
+    }
+
+    /// Record the successful completion of the transfer, returning
+    /// what it did.
+    pub fn completed(&mut self) -> TransferStats {
+        self.progress.elapsed = self.clock.now() - self.started;
+        let stats = TransferStats{
+            bytes: self.progress.bytes,
+            blocks: self.progress.blocks,
+            retransmits: self.progress.retransmits,
+            duplicate_acks: self.duplicate_acks,
+            duration: self.progress.elapsed,
+            negotiated: self.negotiated.clone(),
+        };
+        self.metrics.transfer_completed(stats.bytes, stats.duration);
+        self.span.completed(&stats);
+        if let Some((sink, direction)) = self.events {
+            sink.event(Event::TransferCompleted{
+                peer: self.peer, direction, stats: stats.clone()});
+        }
+        if let Some(observer) = self.observer {
+            observer.completed(self.peer, &stats);
+        }
+        stats
+    }
+
+    /// Record the failure of the transfer.
+    pub fn failed(&mut self, error: &dyn error::Error) {
+        self.progress.elapsed = self.clock.now() - self.started;
+        self.span.failed(&self.progress, error);
+        if let Some((sink, direction)) = self.events {
+            sink.event(Event::TransferFailed{
+                peer: self.peer, direction, progress: self.progress.clone(),
+                error: error.to_string(),
+            });
+        }
+        if let Some(observer) = self.observer {
+            observer.failed(self.peer, &self.progress, error);
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::error;
+    use std::io;
+    use std::net;
+    use std::sync::Mutex;
+    use std::time;
+
+    use super::{Observer, Progress, Throughput, Tracker, TransferStats};
+    use super::super::clock::SystemClock;
+    use super::super::options::{Options, OptionsPolicy};
+    use super::super::packet::BlockNum;
+    use super::super::testing::MockClock;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<(&'static str, Progress)>>,
+        completed: Mutex<Option<TransferStats>>,
+    }
+
+    impl Observer for Recorder {
+        fn progress(&self, _peer: net::SocketAddr, progress: &Progress) {
+            self.events.lock().unwrap().push(("progress", progress.clone()));
+        }
+
+        fn completed(&self, _peer: net::SocketAddr, stats: &TransferStats) {
+            *self.completed.lock().unwrap() = Some(stats.clone());
+        }
+
+        fn failed(
+            &self, _peer: net::SocketAddr, progress: &Progress,
+            _error: &dyn error::Error)
+        {
+            self.events.lock().unwrap().push(("failed", progress.clone()));
+        }
+    }
+
+    #[test]
+    fn test_tracker_reports_to_observer() {
+        let recorder = Recorder::default();
+        let peer = "127.0.0.1:69".parse().unwrap();
+        let mut tracker = Tracker::new(
+            peer, Some(&recorder), Some(600), &SystemClock);
+        tracker.block(512);
+        tracker.retransmit(BlockNum(1));
+        tracker.block(88);
+        let stats = tracker.completed();
+        let events = recorder.events.lock().unwrap();
+        let names: Vec<&str> = events.iter().map(|e| e.0).collect();
+        assert_eq!(vec!["progress", "progress"], names);
+        let last = &events[1].1;
+        assert_eq!(600, last.bytes);
+        assert_eq!(2, last.blocks);
+        assert_eq!(1, last.retransmits);
+        assert_eq!(Some(600), last.tsize);
+        assert_eq!(Some(stats), *recorder.completed.lock().unwrap());
+    }
+
+    #[test]
+    fn test_tracker_returns_stats_on_completion() {
+        let clock = MockClock::new();
+        let peer = "127.0.0.1:69".parse().unwrap();
+        let mut tracker = Tracker::new(peer, None, None, &clock);
+        let mut options = Options::new();
+        options.blksize = Some(1024);
+        let (options, negotiated) =
+            options.negotiate(&OptionsPolicy::new()).unwrap();
+        tracker.negotiated(&negotiated, &options);
+        tracker.block(1024);
+        tracker.duplicate_ack();
+        tracker.retransmit(BlockNum(2));
+        tracker.block(10);
+        clock.advance(time::Duration::from_secs(2));
+        assert_eq!(
+            TransferStats{
+                bytes: 1034,
+                blocks: 2,
+                retransmits: 1,
+                duplicate_acks: 1,
+                duration: time::Duration::from_secs(2),
+                negotiated: options,
+            },
+            tracker.completed());
+    }
+
+    #[test]
+    fn test_tracker_reports_failure() {
+        let recorder = Recorder::default();
+        let peer = "127.0.0.1:69".parse().unwrap();
+        let mut tracker = Tracker::new(
+            peer, Some(&recorder), None, &SystemClock);
+        tracker.failed(&io::Error::other("oops"));
+        let events = recorder.events.lock().unwrap();
+        assert_eq!("failed", events[0].0);
+    }
+
+    #[test]
+    fn test_tracker_takes_time_from_clock() {
+        let recorder = Recorder::default();
+        let clock = MockClock::new();
+        let peer = "127.0.0.1:69".parse().unwrap();
+        let mut tracker = Tracker::new(
+            peer, Some(&recorder), None, &clock);
+        clock.advance(time::Duration::from_secs(3));
+        tracker.block(512);
+        clock.advance(time::Duration::from_secs(4));
+        tracker.completed();
+        let events = recorder.events.lock().unwrap();
+        assert_eq!(time::Duration::from_secs(3), events[0].1.elapsed);
+        let completed = recorder.completed.lock().unwrap();
+        assert_eq!(
+            time::Duration::from_secs(7), completed.as_ref().unwrap().duration);
+    }
+
+    fn sample(secs: u64, bytes: u64, tsize: Option<u64>) -> Progress {
+        Progress{
+            bytes,
+            elapsed: time::Duration::from_secs(secs),
+            ..Progress::new(tsize)
+        }
+    }
+
+    #[test]
+    fn test_throughput_needs_two_samples() {
+        let mut throughput = Throughput::new(time::Duration::from_secs(5));
+        assert_eq!(None, throughput.rate());
+        throughput.update(&sample(1, 512, Some(4096)));
+        assert_eq!(None, throughput.rate());
+        assert_eq!(None, throughput.eta());
+    }
+
+    #[test]
+    fn test_throughput_rate_and_eta() {
+        let mut throughput = Throughput::new(time::Duration::from_secs(5));
+        throughput.update(&sample(0, 0, Some(4096)));
+        throughput.update(&sample(2, 1024, Some(4096)));
+        assert_eq!(Some(512.0), throughput.rate());
+        assert_eq!(Some(time::Duration::from_secs(6)), throughput.eta());
+    }
+
+    #[test]
+    fn test_throughput_rolls_over_window() {
+        let mut throughput = Throughput::new(time::Duration::from_secs(2));
+        throughput.update(&sample(0, 0, None));
+        throughput.update(&sample(1, 4000, None));
+        throughput.update(&sample(2, 4100, None));
+        throughput.update(&sample(3, 4200, None));
+        // Only the samples from 1s onwards are within the window.
+        assert_eq!(Some(100.0), throughput.rate());
+        assert_eq!(None, throughput.eta());
+    }
+
+    #[test]
+    fn test_throughput_eta_is_zero_when_done() {
+        let mut throughput = Throughput::new(time::Duration::from_secs(5));
+        throughput.update(&sample(3, 4096, Some(4096)));
+        assert_eq!(Some(time::Duration::from_secs(0)), throughput.eta());
+    }
+
+}
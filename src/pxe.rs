@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use super::packet::Filename;
+
+
+/// The directory in which pxelinux looks for configuration files.
+const DIRECTORY: &str = "pxelinux.cfg";
+
+/// The ARP hardware type for Ethernet, with which pxelinux prefixes MAC
+/// addresses.
+const ETHERNET: u8 = 1;
+
+
+/// A configuration filename as requested by pxelinux, without its
+/// directory.
+///
+/// For a client pxelinux requests, in order, its UUID, then its MAC
+/// address, then its IPv4 address in upper-case hex, then that with
+/// one digit fewer at a time, then `default`, stopping at the first
+/// that is found. For example: `b8945908-d6a6-41a9-611d-74a6ab80b83d`,
+/// `01-88-99-aa-bb-cc-dd`, `C000025B`, `C000025`, …, `C`, `default`.
+#[derive(Clone,Debug,PartialEq,Eq,Hash)]
+pub enum ConfigName {
+    /// The client's UUID, in lower case.
+    Uuid(String),
+    /// The client's Ethernet MAC address.
+    Mac([u8; 6]),
+    /// The leading `bits` of the client's IPv4 address, a multiple of
+    /// four from 4 to 32. The remaining bits of `addr` are zero.
+    Ip{addr: Ipv4Addr, bits: u8},
+    /// The last resort.
+    Default,
+}
+
+
+impl ConfigName {
+
+    /// Recognise `name` as a pxelinux configuration filename.
+    pub fn parse(name: &str) -> Option<ConfigName> {
+        if name == "default" {
+            Some(ConfigName::Default)
+        }
+        else if let Some(mac) = parse_mac(name) {
+            Some(ConfigName::Mac(mac))
+        }
+        else if is_uuid(name) {
+            Some(ConfigName::Uuid(name.to_ascii_lowercase()))
+        }
+        else if (1..=8).contains(&name.len()) &&
+            name.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            let bits = name.len() as u8 * 4;
+            let prefix = u32::from_str_radix(name, 16).ok()?;
+            Some(ConfigName::Ip{
+                addr: Ipv4Addr::from(prefix << (32 - bits as u32)), bits,
+            })
+        }
+        else {
+            None
+        }
+    }
+
+    /// The names pxelinux requests for a client, in order.
+    pub fn lookup_order(
+        uuid: Option<&str>, mac: Option<[u8; 6]>, ip: Option<Ipv4Addr>)
+        -> Vec<ConfigName>
+    {
+        let mut names = Vec::with_capacity(11);
+        if let Some(uuid) = uuid {
+            names.push(ConfigName::Uuid(uuid.to_ascii_lowercase()));
+        }
+        if let Some(mac) = mac {
+            names.push(ConfigName::Mac(mac));
+        }
+        if let Some(ip) = ip {
+            for bits in (1..=8).rev().map(|digits| digits * 4) {
+                names.push(ConfigName::Ip{addr: mask(ip, bits), bits});
+            }
+        }
+        names.push(ConfigName::Default);
+        names
+    }
+
+}
+
+
+/// The name as pxelinux requests it.
+impl fmt::Display for ConfigName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigName::Uuid(ref uuid) => f.write_str(uuid),
+            ConfigName::Mac(ref mac) => {
+                write!(f, "{:02x}-", ETHERNET)?;
+                write_mac(f, mac)
+            },
+            ConfigName::Ip{addr, bits} => {
+                let digits = bits as usize / 4;
+                let hex = format!("{:08X}", u32::from(addr));
+                f.write_str(&hex[..digits])
+            },
+            ConfigName::Default => f.write_str("default"),
+        }
+    }
+}
+
+
+/// Parse `01-aa-bb-cc-dd-ee-ff`, in either case.
+fn parse_mac(name: &str) -> Option<[u8; 6]> {
+    let mut parts = name.split('-');
+    if parts.next()? != format!("{:02x}", ETHERNET) {
+        return None;
+    }
+    let mut mac = [0u8; 6];
+    for byte in mac.iter_mut() {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    match parts.next() {
+        None => Some(mac),
+        Some(_) => None,
+    }
+}
+
+
+/// Is `name` a UUID in the form `8-4-4-4-12` hex digits?
+fn is_uuid(name: &str) -> bool {
+    let lengths: Vec<usize> = name.split('-').map(str::len).collect();
+    lengths == [8, 4, 4, 4, 12] &&
+        name.bytes().all(|b| b == b'-' || b.is_ascii_hexdigit())
+}
+
+
+fn write_mac(f: &mut dyn fmt::Write, mac: &[u8; 6]) -> fmt::Result {
+    for (index, byte) in mac.iter().enumerate() {
+        if index > 0 {
+            f.write_char('-')?;
+        }
+        write!(f, "{:02x}", byte)?;
+    }
+    Ok(())
+}
+
+
+/// The leading `bits` of `ip`.
+fn mask(ip: Ipv4Addr, bits: u8) -> Ipv4Addr {
+    match bits {
+        0 => Ipv4Addr::UNSPECIFIED,
+        bits => Ipv4Addr::from(
+            u32::from(ip) & (u32::MAX << (32 - bits.min(32) as u32))),
+    }
+}
+
+
+/// The leading `bits` of `addr`, of either family.
+fn mask_ip(addr: IpAddr, bits: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => IpAddr::V4(mask(addr, bits)),
+        IpAddr::V6(addr) => IpAddr::V6(match bits {
+            0 => Ipv6Addr::UNSPECIFIED,
+            bits => Ipv6Addr::from(
+                u128::from(addr) &
+                    (u128::MAX << (128 - bits.min(128) as u32))),
+        }),
+    }
+}
+
+
+/// The number of bits in addresses like `addr`.
+fn width(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+
+/// Values keyed by subnet, of either family, found by the most specific
+/// subnet that contains an address. IPv4 addresses mapped into IPv6, as
+/// on dual-stack sockets, are treated as IPv4.
+#[derive(Clone,Debug)]
+pub(crate) struct Subnets<T>(Vec<(IpAddr, u8, T)>);
+
+
+impl<T> Default for Subnets<T> {
+    fn default() -> Subnets<T> {
+        Subnets(Vec::new())
+    }
+}
+
+
+impl<T> Subnets<T> {
+
+    /// Key `value` by the subnet `network/prefix`, replacing any value
+    /// already keyed by it.
+    pub(crate) fn insert(&mut self, network: IpAddr, prefix: u8, value: T) {
+        let network = network.to_canonical();
+        let prefix = prefix.min(width(network));
+        let network = mask_ip(network, prefix);
+        self.0.retain(|&(other, bits, _)| (other, bits) != (network, prefix));
+        self.0.push((network, prefix, value));
+        // Most specific first.
+        self.0.sort_by_key(|&(_, prefix, _)| std::cmp::Reverse(prefix));
+    }
+
+    /// The value of the most specific subnet that contains `addr`.
+    pub(crate) fn get(&self, addr: IpAddr) -> Option<&T> {
+        self.within(addr, u8::MAX)
+    }
+
+    /// The value of the most specific subnet that contains `addr` and is
+    /// no more specific than `bits`.
+    pub(crate) fn within(&self, addr: IpAddr, bits: u8) -> Option<&T> {
+        let addr = addr.to_canonical();
+        self.0.iter()
+            .find(|&&(network, prefix, _)| {
+                prefix <= bits && width(network) == width(addr) &&
+                    mask_ip(addr, prefix) == network
+            })
+            .map(|(_, _, value)| value)
+    }
+
+}
+
+
+/// Maps pxelinux configuration filenames to files through templates
+/// keyed by UUID, MAC address, and subnet.
+///
+/// A template is a filename in which `{uuid}`, `{mac}` (as
+/// `aa-bb-cc-dd-ee-ff`), `{ip}` (the address, or the network for a
+/// shortened hex address), and `{name}` (as requested) are replaced.
+/// For example, with `.with_mac(mac, "hosts/{mac}.cfg")`, a request
+/// for `pxelinux.cfg/01-aa-bb-cc-dd-ee-ff` maps to
+/// `hosts/aa-bb-cc-dd-ee-ff.cfg`.
+///
+/// Requests with no template map to nothing, so that a handler can
+/// reply that the file is not found and pxelinux falls through to its
+/// next name, just as it would with files on disk. A hex address maps
+/// through the most specific subnet that contains it and is no more
+/// specific than it, so each subnet answers at the first of pxelinux's
+/// requests to reach it.
+#[derive(Clone,Debug,Default)]
+pub struct Templates {
+    uuids: HashMap<String, String>,
+    macs: HashMap<[u8; 6], String>,
+    subnets: Subnets<String>,
+    default: Option<String>,
+}
+
+
+impl Templates {
+
+    pub fn new() -> Templates {
+        Templates::default()
+    }
+
+    /// Map the client with `uuid` through `template`.
+    pub fn with_uuid(mut self, uuid: &str, template: &str) -> Templates {
+        self.uuids.insert(uuid.to_ascii_lowercase(), template.to_owned());
+        self
+    }
+
+    /// Map the client with `mac` through `template`.
+    pub fn with_mac(mut self, mac: [u8; 6], template: &str) -> Templates {
+        self.macs.insert(mac, template.to_owned());
+        self
+    }
+
+    /// Map clients in the subnet `network/prefix` through `template`.
+    pub fn with_subnet(mut self, network: Ipv4Addr, prefix: u8,
+                       template: &str) -> Templates {
+        self.subnets.insert(network.into(), prefix, template.to_owned());
+        self
+    }
+
+    /// Map `default` through `template`.
+    pub fn with_default(self, template: &str) -> Templates {
+        Templates{default: Some(template.to_owned()), ..self}
+    }
+
+    /// Map the requested `filename`, if it's in `pxelinux.cfg/`.
+    pub fn expand(&self, filename: &Filename) -> Option<String> {
+        let filename = std::str::from_utf8(filename.as_bytes()).ok()?;
+        let name = filename.trim_start_matches('/')
+            .strip_prefix(DIRECTORY)?.strip_prefix('/')?;
+        self.map(&ConfigName::parse(name)?)
+    }
+
+    /// Map `name` through its template, if there is one.
+    pub fn map(&self, name: &ConfigName) -> Option<String> {
+        let requested = name.to_string();
+        match *name {
+            ConfigName::Uuid(ref uuid) => self.uuids.get(uuid).map(
+                |template| fill(template, &requested, Some(uuid), None, None)),
+            ConfigName::Mac(ref mac) => self.macs.get(mac).map(
+                |template| fill(template, &requested, None, Some(mac), None)),
+            ConfigName::Ip{addr, bits} => self.subnets
+                .within(addr.into(), bits)
+                .map(|template| fill(
+                    template, &requested, None, None, Some(addr))),
+            ConfigName::Default => self.default.as_ref().map(
+                |template| fill(template, &requested, None, None, None)),
+        }
+    }
+
+    /// Map the first of the names pxelinux would request for a client
+    /// that has a template; see `ConfigName::lookup_order`.
+    pub fn resolve(
+        &self, uuid: Option<&str>, mac: Option<[u8; 6]>,
+        ip: Option<Ipv4Addr>)
+        -> Option<String>
+    {
+        ConfigName::lookup_order(uuid, mac, ip).iter()
+            .find_map(|name| self.map(name))
+    }
+
+}
+
+
+/// Replace the placeholders in `template`.
+fn fill(
+    template: &str, name: &str, uuid: Option<&str>, mac: Option<&[u8; 6]>,
+    ip: Option<Ipv4Addr>)
+    -> String
+{
+    let mut filled = template.replace("{name}", name);
+    if let Some(uuid) = uuid {
+        filled = filled.replace("{uuid}", uuid);
+    }
+    if let Some(mac) = mac {
+        let mut text = String::with_capacity(17);
+        write_mac(&mut text, mac).unwrap();
+        filled = filled.replace("{mac}", &text);
+    }
+    if let Some(ip) = ip {
+        filled = filled.replace("{ip}", &ip.to_string());
+    }
+    filled
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::{ConfigName, Subnets, Templates, mask_ip};
+    use super::super::packet::Filename;
+
+    const MAC: [u8; 6] = [0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd];
+    const UUID: &str = "b8945908-d6a6-41a9-611d-74a6ab80b83d";
+
+    #[test]
+    fn test_lookup_order_matches_pxelinux() {
+        let names: Vec<String> = ConfigName::lookup_order(
+            Some(UUID), Some(MAC), Some(Ipv4Addr::new(192, 0, 2, 91)))
+            .iter().map(ConfigName::to_string).collect();
+        assert_eq!(
+            vec![
+                UUID, "01-88-99-aa-bb-cc-dd", "C000025B", "C000025",
+                "C00002", "C0000", "C000", "C00", "C0", "C", "default",
+            ],
+            names);
+    }
+
+    #[test]
+    fn test_parse_round_trips() {
+        for name in ConfigName::lookup_order(
+            Some(UUID), Some(MAC), Some(Ipv4Addr::new(192, 0, 2, 91)))
+        {
+            assert_eq!(Some(name.clone()), ConfigName::parse(&name.to_string()));
+        }
+        assert_eq!(
+            Some(ConfigName::Mac(MAC)),
+            ConfigName::parse("01-88-99-AA-BB-CC-DD"));
+        for name in &["02-88-99-aa-bb-cc-dd", "01-88-99", "C000025B0", "x"] {
+            assert_eq!(None, ConfigName::parse(name));
+        }
+    }
+
+    #[test]
+    fn test_expand_maps_through_templates() {
+        let templates = Templates::new()
+            .with_mac(MAC, "hosts/{mac}.cfg")
+            .with_uuid(UUID, "uuids/{uuid}.cfg")
+            .with_default("menus/{name}.cfg");
+        let expand = |name: &str| templates.expand(&Filename::new(name));
+        assert_eq!(
+            Some("hosts/88-99-aa-bb-cc-dd.cfg".to_owned()),
+            expand("pxelinux.cfg/01-88-99-aa-bb-cc-dd"));
+        assert_eq!(
+            Some(format!("uuids/{}.cfg", UUID)),
+            expand(&format!("/pxelinux.cfg/{}", UUID.to_uppercase())));
+        assert_eq!(
+            Some("menus/default.cfg".to_owned()),
+            expand("pxelinux.cfg/default"));
+        // Fall through to pxelinux's next name.
+        assert_eq!(None, expand("pxelinux.cfg/01-00-00-00-00-00-00"));
+        assert_eq!(None, expand("pxelinux.0"));
+    }
+
+    #[test]
+    fn test_subnets_answer_at_first_reaching_request() {
+        let templates = Templates::new()
+            .with_subnet(Ipv4Addr::new(192, 0, 2, 0), 24, "lab/{ip}")
+            .with_subnet(Ipv4Addr::new(192, 0, 0, 0), 16, "site/{ip}");
+        let ip = Ipv4Addr::new(192, 0, 2, 91);
+        let map = |bits| templates.map(&ConfigName::Ip{
+            addr: super::mask(ip, bits), bits,
+        });
+        assert_eq!(Some("lab/192.0.2.91".to_owned()), map(32));
+        assert_eq!(Some("lab/192.0.2.0".to_owned()), map(24));
+        assert_eq!(Some("site/192.0.0.0".to_owned()), map(20));
+        assert_eq!(Some("site/192.0.0.0".to_owned()), map(16));
+        assert_eq!(None, map(12));
+        assert_eq!(
+            Some("lab/192.0.2.91".to_owned()),
+            templates.resolve(Some(UUID), Some(MAC), Some(ip)));
+    }
+
+    #[test]
+    fn test_mask_ip() {
+        let masked = |addr: &str, bits| mask_ip(addr.parse().unwrap(), bits);
+        assert_eq!(masked("10.1.0.0", 16), masked("10.1.255.255", 16));
+        assert_eq!(masked("0.0.0.0", 0), masked("255.1.2.3", 0));
+        assert_eq!(masked("fd00::", 8), masked("fdff::1", 8));
+        assert_eq!(masked("::", 0), masked("fdff::1", 0));
+    }
+
+    #[test]
+    fn test_subnets_find_most_specific_of_same_family() {
+        let ip = |addr: &str| addr.parse::<IpAddr>().unwrap();
+        let mut subnets = Subnets::default();
+        subnets.insert(ip("10.1.0.0"), 16, "site");
+        subnets.insert(ip("10.1.2.3"), 24, "lab");
+        subnets.insert(ip("::"), 0, "v6");
+        assert_eq!(Some(&"lab"), subnets.get(ip("10.1.2.9")));
+        assert_eq!(Some(&"site"), subnets.get(ip("10.1.9.9")));
+        assert_eq!(Some(&"site"), subnets.within(ip("10.1.2.9"), 20));
+        assert_eq!(None, subnets.get(ip("10.2.0.1")));
+        assert_eq!(Some(&"lab"), subnets.get(ip("::ffff:10.1.2.9")));
+        assert_eq!(Some(&"v6"), subnets.get(ip("fd00::1")));
+        subnets.insert(ip("10.1.2.0"), 24, "rack");
+        assert_eq!(Some(&"rack"), subnets.get(ip("10.1.2.9")));
+    }
+
+}
@@ -0,0 +1,244 @@
+use std::fmt;
+use std::io;
+use std::net;
+use std::time;
+
+use super::clock::Clock;
+use super::options::Options;
+use super::packet::Filename;
+use super::transport::Transport;
+
+
+/// Departures from the RFCs that downloads can make to work with buggy
+/// clients, most often the TFTP clients in PXE ROMs. All are off by
+/// default.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct Quirks {
+    /// Resend the `OACK` along with the first `DATA` whenever that
+    /// times out, for clients that lose the `OACK` and then ignore
+    /// `DATA` until they see one.
+    pub resend_oack: bool,
+    /// Wait for the `OACK` to be acknowledged with `ACK(0)` before
+    /// sending any `DATA`, as RFC-2347 requires. Off by default since
+    /// many clients skip that `ACK`, and take the first `DATA` as
+    /// acknowledgement enough.
+    pub await_oack_ack: bool,
+    /// Accept acknowledgements from any port at the client's address,
+    /// not only the one the request came from, for clients behind NAT
+    /// that acknowledge from a port other than the one they asked from.
+    /// `DATA` is still sent to the port the request came from.
+    pub any_port: bool,
+    /// End a file whose size is a multiple of the block size with a
+    /// `DATA` of one zero byte rather than an empty one, for clients
+    /// that take an empty `DATA` to be malformed. This corrupts the
+    /// file: the client stores the zero byte at its end. Use it only
+    /// for files whose consumers ignore trailing padding.
+    pub pad_empty_final_block: bool,
+}
+
+
+/// Ready-made sets of `Quirks` for clients known to need them. These
+/// are starting points drawn from reports from the field; when they
+/// don't fit, use `QuirksProfile::Custom`.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+#[non_exhaustive]
+pub enum QuirksProfile {
+    /// No quirks: follow the RFCs.
+    Standard,
+    /// Intel UNDI PXE ROMs, which can lose the `OACK`.
+    IntelUndi,
+    /// Windows PE and WDS clients, often reached through NAT.
+    WindowsPe,
+    /// Whichever quirks are given.
+    Custom(Quirks),
+}
+
+
+impl QuirksProfile {
+
+    /// The quirks to apply.
+    pub fn quirks(self) -> Quirks {
+        match self {
+            QuirksProfile::Standard => Quirks::default(),
+            QuirksProfile::IntelUndi =>
+                Quirks{resend_oack: true, ..Quirks::default()},
+            QuirksProfile::WindowsPe =>
+                Quirks{any_port: true, ..Quirks::default()},
+            QuirksProfile::Custom(quirks) => quirks,
+        }
+    }
+
+}
+
+
+/// Chooses the quirks for each download, from the client's address, the
+/// filename, and the requested options.
+///
+/// This is implemented for closures returning a `QuirksProfile`, so
+/// that, for example, requests for images for one kind of client can be
+/// told apart by filename, and for a `QuirksProfile`, to use the same
+/// for everyone.
+pub trait SelectQuirks: Send + Sync {
+
+    fn select(
+        &self, peer: net::SocketAddr, filename: &Filename, options: &Options)
+        -> QuirksProfile;
+
+}
+
+
+impl<F> SelectQuirks for F
+    where F: Fn(net::SocketAddr, &Filename, &Options) -> QuirksProfile +
+        Send + Sync
+{
+    fn select(
+        &self, peer: net::SocketAddr, filename: &Filename, options: &Options)
+        -> QuirksProfile
+    {
+        self(peer, filename, options)
+    }
+}
+
+
+impl SelectQuirks for QuirksProfile {
+    fn select(&self, _: net::SocketAddr, _: &Filename, _: &Options)
+              -> QuirksProfile {
+        *self
+    }
+}
+
+
+impl fmt::Debug for dyn SelectQuirks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SelectQuirks")
+    }
+}
+
+
+/// A transport for `Quirks::any_port`: it sends to the peer, and
+/// receives from any port at the peer's address, without connecting.
+/// Time-outs are measured with `clock`.
+pub(crate) struct AnyPort<'a> {
+    inner: &'a dyn Transport,
+    peer: net::SocketAddr,
+    clock: &'a dyn Clock,
+}
+
+
+impl<'a> AnyPort<'a> {
+
+    pub(crate) fn new(
+        inner: &'a dyn Transport, peer: net::SocketAddr, clock: &'a dyn Clock)
+        -> AnyPort<'a>
+    {
+        AnyPort{inner, peer, clock}
+    }
+
+}
+
+
+impl<'a> Transport for AnyPort<'a> {
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.send_to(buf, self.peer)
+    }
+
+    fn send_to(&self, buf: &[u8], addr: net::SocketAddr) -> io::Result<usize> {
+        self.inner.send_to(buf, addr)
+    }
+
+    /// Datagrams from other addresses are discarded.
+    fn recv(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<usize>
+    {
+        let deadline = timeout.map(|timeout| self.clock.now() + timeout);
+        loop {
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = self.clock.now();
+                    if now >= deadline {
+                        return Err(io::ErrorKind::TimedOut.into());
+                    }
+                    Some(deadline - now)
+                },
+                None => None,
+            };
+            let (size, src) = self.inner.recv_from(buf, timeout)?;
+            if src.ip() == self.peer.ip() {
+                return Ok(size);
+            }
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<(usize, net::SocketAddr)>
+    {
+        self.inner.recv_from(buf, timeout)
+    }
+
+    /// Does nothing: the peer is fixed, and the port is not.
+    fn connect(&self, _addr: net::SocketAddr) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.inner.local_addr()
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::net;
+    use std::time;
+
+    use super::{AnyPort, Quirks, QuirksProfile, SelectQuirks};
+    use super::super::clock::SystemClock;
+    use super::super::options::Options;
+    use super::super::packet::Filename;
+    use super::super::transport::Transport;
+
+    #[test]
+    fn test_profiles_select_quirks() {
+        assert_eq!(Quirks::default(), QuirksProfile::Standard.quirks());
+        assert!(QuirksProfile::IntelUndi.quirks().resend_oack);
+        let quirks = Quirks{await_oack_ack: true, ..Quirks::default()};
+        assert_eq!(quirks, QuirksProfile::Custom(quirks).quirks());
+    }
+
+    #[test]
+    fn test_select_quirks_by_filename() {
+        let pad = Quirks{pad_empty_final_block: true, ..Quirks::default()};
+        let select = |_: net::SocketAddr, filename: &Filename, _: &Options| {
+            match filename.as_bytes().starts_with(b"u-boot/") {
+                true => QuirksProfile::Custom(pad),
+                false => QuirksProfile::Standard,
+            }
+        };
+        let peer: net::SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let select = |filename: &str| (&select as &dyn SelectQuirks).select(
+            peer, &Filename::new(filename), &Options::new());
+        assert_eq!(QuirksProfile::Custom(pad), select("u-boot/uImage"));
+        assert_eq!(QuirksProfile::Standard, select("pxelinux.0"));
+    }
+
+    #[test]
+    fn test_any_port_accepts_other_ports_at_peer_address() {
+        let server = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let first = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let second = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let transport = AnyPort::new(
+            &server, first.local_addr().unwrap(), &SystemClock);
+        transport.send(b"hello").unwrap();
+        let mut buf = [0u8; 16];
+        let (size, _) = first.recv_from(&mut buf).unwrap();
+        assert_eq!(b"hello", &buf[..size]);
+        second.send_to(b"world", server.local_addr().unwrap()).unwrap();
+        let timeout = Some(time::Duration::from_secs(5));
+        let size = transport.recv(&mut buf, timeout).unwrap();
+        assert_eq!(b"world", &buf[..size]);
+    }
+
+}
@@ -0,0 +1,155 @@
+use std::collections::{HashMap, VecDeque};
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use super::clock::{Clock, SystemClock};
+
+
+/// Tracks how many bytes each client has uploaded over a sliding window
+/// of time, so that clients exceeding a limit can be turned away.
+///
+/// A `Quota` is shared between transfers, so it uses interior
+/// mutability; put it in a [`wrq::Config`](../wrq/struct.Config.html)
+/// that's shared by all uploads.
+#[derive(Debug)]
+pub struct Quota {
+    limit: u64,
+    window: time::Duration,
+    usage: Mutex<HashMap<net::IpAddr, VecDeque<(time::Instant, u64)>>>,
+    clock: Arc<dyn Clock>,
+}
+
+
+impl Quota {
+
+    /// A quota of `limit` bytes per client in any period of `window`.
+    pub fn new(limit: u64, window: time::Duration) -> Quota {
+        Quota::with_clock(limit, window, Arc::new(SystemClock))
+    }
+
+    /// A quota that tells the time with `clock`.
+    pub fn with_clock(
+        limit: u64, window: time::Duration, clock: Arc<dyn Clock>)
+        -> Quota
+    {
+        Quota{
+            limit,
+            window,
+            usage: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    /// The number of bytes `addr` has uploaded within the window.
+    pub fn used(&self, addr: net::IpAddr) -> u64 {
+        let mut usage = self.usage.lock().unwrap();
+        let now = self.clock.now();
+        let used = match usage.get_mut(&addr) {
+            Some(entries) => {
+                self.expire(entries, now);
+                entries.iter().map(|&(_, bytes)| bytes).sum()
+            },
+            None => 0,
+        };
+        if used == 0 {
+            usage.remove(&addr);
+        }
+        used
+    }
+
+    /// Has `addr` used up its quota?
+    pub fn exceeded(&self, addr: net::IpAddr) -> bool {
+        self.used(addr) >= self.limit
+    }
+
+    /// Record that `addr` has uploaded `bytes` more bytes.
+    pub fn record(&self, addr: net::IpAddr, bytes: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        let now = self.clock.now();
+        let entries = usage.entry(addr).or_default();
+        self.expire(entries, now);
+        // Coalesce records that are close together in time so that a
+        // long upload doesn't create an entry for every block.
+        let granularity = self.window / 32;
+        match entries.back_mut() {
+            Some(&mut (when, ref mut total))
+                if now.duration_since(when) < granularity => {
+                *total += bytes;
+                return;
+            },
+            _ => {},
+        };
+        entries.push_back((now, bytes));
+    }
+
+    /// Discard entries that have fallen out of the window.
+    fn expire(
+        &self, entries: &mut VecDeque<(time::Instant, u64)>,
+        now: time::Instant)
+    {
+        while let Some(&(when, _)) = entries.front() {
+            if now.duration_since(when) >= self.window {
+                entries.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::net;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time;
+
+    use super::Quota;
+    use super::super::testing::MockClock;
+
+    #[test]
+    fn test_quota_is_per_client() {
+        let quota = Quota::new(1000, time::Duration::from_secs(60));
+        let one: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let two: net::IpAddr = "10.0.0.2".parse().unwrap();
+        quota.record(one, 600);
+        assert_eq!(600, quota.used(one));
+        assert_eq!(0, quota.used(two));
+        assert!(!quota.exceeded(one));
+        quota.record(one, 400);
+        assert!(quota.exceeded(one));
+        assert!(!quota.exceeded(two));
+    }
+
+    #[test]
+    fn test_quota_usage_expires() {
+        let quota = Quota::new(1000, time::Duration::from_millis(50));
+        let addr: net::IpAddr = "10.0.0.1".parse().unwrap();
+        quota.record(addr, 1000);
+        assert!(quota.exceeded(addr));
+        thread::sleep(time::Duration::from_millis(60));
+        assert!(!quota.exceeded(addr));
+        assert_eq!(0, quota.used(addr));
+    }
+
+    #[test]
+    fn test_quota_usage_expires_with_clock() {
+        let clock = MockClock::new();
+        let quota = Quota::with_clock(
+            1000, time::Duration::from_secs(3600), Arc::new(clock.clone()));
+        let addr: net::IpAddr = "10.0.0.1".parse().unwrap();
+        quota.record(addr, 600);
+        clock.advance(time::Duration::from_secs(1800));
+        quota.record(addr, 400);
+        assert!(quota.exceeded(addr));
+        clock.advance(time::Duration::from_secs(1800));
+        assert_eq!(400, quota.used(addr));
+        clock.advance(time::Duration::from_secs(1800));
+        assert_eq!(0, quota.used(addr));
+    }
+
+}
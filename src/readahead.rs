@@ -0,0 +1,149 @@
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+
+
+/// The most a worker reads at once.
+const MAX_CHUNK: usize = 64 * 1024;
+
+
+/// Wraps a reader to read from it on a worker thread, staying up to a
+/// given number of bytes ahead of what has been read from the wrapper.
+///
+/// Serving a file alternates between reading a block and waiting for
+/// the peer to acknowledge it. With slow storage, like an NFS-backed
+/// image store, the reads add to every round trip; reading ahead while
+/// waiting hides them.
+///
+/// The worker stops at the end of the input or at its first error,
+/// which is returned in turn. It also stops, after its current read,
+/// once the `ReadAhead` is dropped.
+pub struct ReadAhead {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    /// Bytes received from the worker not yet returned, from `pos`
+    /// onwards.
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+
+impl ReadAhead {
+
+    /// Read from `inner` on a new thread, up to `ahead` bytes in
+    /// advance, which must not be zero.
+    pub fn new<R>(inner: R, ahead: usize) -> io::Result<ReadAhead>
+        where R: io::Read + Send + 'static
+    {
+        if ahead == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "read-ahead must not be zero"));
+        }
+        let size = cmp::min(ahead, MAX_CHUNK);
+        let (sender, chunks) = mpsc::sync_channel(ahead.div_ceil(size));
+        thread::Builder::new()
+            .name("tftp-read-ahead".to_owned())
+            .spawn(move || read_into(inner, size, &sender))?;
+        Ok(ReadAhead{chunks, chunk: Vec::new(), pos: 0})
+    }
+
+}
+
+
+impl fmt::Debug for ReadAhead {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReadAhead").finish()
+    }
+}
+
+
+impl io::Read for ReadAhead {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                },
+                Ok(Err(error)) => return Err(error),
+                // The worker has finished.
+                Err(mpsc::RecvError) => return Ok(0),
+            }
+        }
+        let size = cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..size].copy_from_slice(&self.chunk[self.pos..self.pos + size]);
+        self.pos += size;
+        Ok(size)
+    }
+
+}
+
+
+/// Read chunks of up to `size` bytes from `inner` and send them on
+/// until the end of the input, an error, or the receiver goes away.
+fn read_into<R: io::Read>(
+    mut inner: R, size: usize, sender: &mpsc::SyncSender<io::Result<Vec<u8>>>)
+{
+    loop {
+        let mut chunk = vec![0u8; size];
+        let result = match inner.read(&mut chunk) {
+            Ok(0) => return,
+            Ok(amt) => {
+                chunk.truncate(amt);
+                Ok(chunk)
+            },
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted =>
+                continue,
+            Err(error) => Err(error),
+        };
+        let failed = result.is_err();
+        if sender.send(result).is_err() || failed {
+            return;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::io;
+    use std::io::Read;
+
+    use super::ReadAhead;
+
+    #[test]
+    fn test_read_ahead_passes_data_through() {
+        let payload: Vec<u8> = (0..200_000).map(|n| n as u8).collect();
+        let mut reader = ReadAhead::new(
+            io::Cursor::new(payload.clone()), 1000).unwrap();
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).unwrap();
+        assert_eq!(payload, received);
+    }
+
+    #[test]
+    fn test_read_ahead_passes_errors_through() {
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("broken"))
+            }
+        }
+        let mut reader = ReadAhead::new(Broken, 512).unwrap();
+        let mut buf = [0u8; 16];
+        let error = reader.read(&mut buf).unwrap_err();
+        assert_eq!("broken", error.to_string());
+        // The worker stops at the first error.
+        assert_eq!(0, reader.read(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn test_read_ahead_rejects_zero() {
+        let error = ReadAhead::new(io::empty(), 0).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    }
+
+}
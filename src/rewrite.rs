@@ -0,0 +1,297 @@
+use std::net;
+use std::sync::Arc;
+
+#[cfg(feature = "regex")]
+use regex;
+
+use super::{Access, Handler};
+use super::events::EventSink;
+use super::metrics::Metrics;
+use super::options::{Duplicates, OutOfRange};
+use super::packet::{Filename, FilenameValidation, Packet, Strictness};
+use super::transport::WireObserver;
+
+
+/// One way of rewriting a filename.
+#[derive(Clone,Debug)]
+#[non_exhaustive]
+pub enum Rule {
+    /// Replace a filename that is exactly `from` with `to`.
+    Exact{from: Vec<u8>, to: Vec<u8>},
+    /// Replace `from` at the start of a filename with `to`, keeping the
+    /// rest.
+    Prefix{from: Vec<u8>, to: Vec<u8>},
+    /// Replace the first match of `pattern` in a filename with `to`,
+    /// in which `$1`, `${1}`, or `${name}` stand for what the pattern's
+    /// groups captured, and `$$` for a `$`. Anchor the pattern with `^`
+    /// and `$` to match only whole filenames.
+    #[cfg(feature = "regex")]
+    Regex{pattern: regex::bytes::Regex, to: Vec<u8>},
+}
+
+
+impl Rule {
+
+    pub fn exact<F: Into<Vec<u8>>, T: Into<Vec<u8>>>(from: F, to: T) -> Rule {
+        Rule::Exact{from: from.into(), to: to.into()}
+    }
+
+    pub fn prefix<F: Into<Vec<u8>>, T: Into<Vec<u8>>>(from: F, to: T) -> Rule {
+        Rule::Prefix{from: from.into(), to: to.into()}
+    }
+
+    /// A `Rule::Regex`, or an error if `pattern` does not compile.
+    #[cfg(feature = "regex")]
+    pub fn regex<T: Into<Vec<u8>>>(pattern: &str, to: T)
+                                   -> Result<Rule, regex::Error> {
+        let pattern = regex::bytes::Regex::new(pattern)?;
+        Ok(Rule::Regex{pattern, to: to.into()})
+    }
+
+    /// `filename` rewritten, or `None` if this rule does not apply.
+    pub fn apply(&self, filename: &[u8]) -> Option<Vec<u8>> {
+        match *self {
+            Rule::Exact{ref from, ref to} if filename == &from[..] =>
+                Some(to.clone()),
+            Rule::Exact{..} => None,
+            Rule::Prefix{ref from, ref to} => {
+                filename.strip_prefix(&from[..]).map(|rest| {
+                    let mut rewritten = to.clone();
+                    rewritten.extend_from_slice(rest);
+                    rewritten
+                })
+            },
+            #[cfg(feature = "regex")]
+            Rule::Regex{ref pattern, ref to} => {
+                match pattern.is_match(filename) {
+                    true => Some(
+                        pattern.replacen(filename, 1, &to[..]).into_owned()),
+                    false => None,
+                }
+            },
+        }
+    }
+
+}
+
+
+/// An ordered list of rules for rewriting filenames. Each filename is
+/// rewritten by the first rule that applies to it, if any; the rest are
+/// not tried. For example, to serve the right GRUB to each architecture
+/// from its own directory:
+///
+/// ```
+/// # use allenap_libtftp::rewrite::{Rule, Rules};
+/// # use allenap_libtftp::packet::Filename;
+/// let rules = Rules::new()
+///     .with(Rule::exact("grubx64.efi", "efi/x86_64/grub.efi"))
+///     .with(Rule::exact("grubaa64.efi", "efi/arm64/grub.efi"))
+///     .with(Rule::prefix("/tftpboot/", ""));
+/// assert_eq!(
+///     Some(Filename::new("efi/arm64/grub.efi")),
+///     rules.rewrite(&Filename::new("grubaa64.efi")));
+/// ```
+#[derive(Clone,Debug,Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+
+impl Rules {
+
+    pub fn new() -> Rules {
+        Rules::default()
+    }
+
+    /// These rules, then `rule`.
+    pub fn with(mut self, rule: Rule) -> Rules {
+        self.rules.push(rule);
+        self
+    }
+
+    /// `filename` rewritten by the first rule that applies, or `None`
+    /// if none do.
+    pub fn rewrite(&self, filename: &Filename) -> Option<Filename> {
+        self.rules.iter()
+            .find_map(|rule| rule.apply(filename.as_bytes()))
+            .map(Filename::new)
+    }
+
+}
+
+
+/// A `Handler` that rewrites the filenames of requests with `Rules`
+/// before passing them to the inner handler, so that renaming is done
+/// in one place, for any handler. Filenames to which no rule applies
+/// are passed on as they are, as are all the other `Handler` methods.
+pub struct Rewrite<H> {
+    inner: H,
+    rules: Arc<Rules>,
+}
+
+
+impl<H: Handler> Rewrite<H> {
+
+    pub fn new(inner: H, rules: Arc<Rules>) -> Rewrite<H> {
+        Rewrite{inner, rules}
+    }
+
+    /// The handler to which rewritten requests are passed.
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+}
+
+
+impl<H> std::fmt::Debug for Rewrite<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Rewrite")
+            .field("rules", &self.rules)
+            .finish_non_exhaustive()
+    }
+}
+
+
+impl<H: Handler> Handler for Rewrite<H> {
+
+    fn strictness(&self) -> Strictness {
+        self.inner.strictness()
+    }
+
+    fn duplicate_options(&self) -> Duplicates {
+        self.inner.duplicate_options()
+    }
+
+    fn option_ranges(&self) -> OutOfRange {
+        self.inner.option_ranges()
+    }
+
+    fn filename_validation(&self) -> FilenameValidation {
+        self.inner.filename_validation()
+    }
+
+    fn access(&self) -> Access {
+        self.inner.access()
+    }
+
+    fn wire_observer(&self) -> Option<Arc<dyn WireObserver>> {
+        self.inner.wire_observer()
+    }
+
+    fn metrics(&self) -> Arc<dyn Metrics> {
+        self.inner.metrics()
+    }
+
+    fn events(&self) -> Option<Arc<dyn EventSink>> {
+        self.inner.events()
+    }
+
+    fn handle(
+        &self, local: net::SocketAddr, remote: net::SocketAddr,
+        mut packet: Packet)
+        -> Option<Packet<'_>>
+    {
+        match packet {
+            Packet::Read(ref mut filename, _, _) |
+            Packet::Write(ref mut filename, _, _) => {
+                if let Some(rewritten) = self.rules.rewrite(filename) {
+                    *filename = rewritten;
+                }
+            },
+            _ => {},
+        }
+        self.inner.handle(local, remote, packet)
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::net;
+    use std::sync::Arc;
+
+    use super::{Rewrite, Rule, Rules};
+    use super::super::Handler;
+    use super::super::options::Options;
+    use super::super::packet::{Filename, Packet, TransferMode};
+
+    /// Replies with an error carrying the filename it was asked for.
+    struct Echo;
+
+    impl Handler for Echo {
+        fn handle_rrq(
+            &self, _local: net::SocketAddr, _remote: net::SocketAddr,
+            filename: Filename, _txmode: TransferMode, _options: Options)
+            -> Option<Packet<'_>>
+        {
+            Some(Packet::file_not_found(filename.to_string_lossy()))
+        }
+    }
+
+    fn rewrite(rules: &Rules, filename: &str) -> Option<String> {
+        rules.rewrite(&Filename::new(filename))
+            .map(|filename| filename.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn test_exact_rule_matches_whole_filename() {
+        let rules = Rules::new().with(Rule::exact("a", "b"));
+        assert_eq!(Some("b".to_owned()), rewrite(&rules, "a"));
+        assert_eq!(None, rewrite(&rules, "ab"));
+    }
+
+    #[test]
+    fn test_prefix_rule_keeps_the_rest() {
+        let rules = Rules::new().with(Rule::prefix("/tftpboot/", "boot/"));
+        assert_eq!(
+            Some("boot/pxelinux.0".to_owned()),
+            rewrite(&rules, "/tftpboot/pxelinux.0"));
+        assert_eq!(None, rewrite(&rules, "pxelinux.0"));
+    }
+
+    #[test]
+    fn test_first_rule_that_applies_wins() {
+        let rules = Rules::new()
+            .with(Rule::exact("boot/a", "first"))
+            .with(Rule::prefix("boot/", "second/"))
+            .with(Rule::prefix("boot/", "third/"));
+        assert_eq!(Some("first".to_owned()), rewrite(&rules, "boot/a"));
+        assert_eq!(Some("second/b".to_owned()), rewrite(&rules, "boot/b"));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_rule_substitutes_captures() {
+        let rules = Rules::new()
+            .with(Rule::regex(
+                r"^grub(?P<arch>x64|aa64)\.efi$", "efi/${arch}/grub.efi")
+                  .unwrap())
+            .with(Rule::regex(r"\\", "/").unwrap());
+        assert_eq!(
+            Some("efi/aa64/grub.efi".to_owned()),
+            rewrite(&rules, "grubaa64.efi"));
+        assert_eq!(None, rewrite(&rules, "grubia32.efi"));
+        // Only the first match is replaced.
+        assert_eq!(
+            Some("boot/efi\\bootx64.efi".to_owned()),
+            rewrite(&rules, "boot\\efi\\bootx64.efi"));
+    }
+
+    #[test]
+    fn test_rewrite_passes_rewritten_requests_on() {
+        let rules = Rules::new().with(Rule::exact("grubx64.efi", "grub.efi"));
+        let handler = Rewrite::new(Echo, Arc::new(rules));
+        let addr: net::SocketAddr = "127.0.0.1:69".parse().unwrap();
+        let read = |filename: &str| handler.handle(
+            addr, addr, Packet::Read(
+                Filename::new(filename), TransferMode::Octet, Options::new()));
+        assert_eq!(
+            Some(Packet::file_not_found("grub.efi")), read("grubx64.efi"));
+        assert_eq!(
+            Some(Packet::file_not_found("other.efi")), read("other.efi"));
+    }
+
+}
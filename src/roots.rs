@@ -0,0 +1,177 @@
+use std::io;
+use std::net::{self, IpAddr};
+use std::path::PathBuf;
+
+use super::jail::RootJail;
+use super::metrics::Direction;
+use super::packet::Filename;
+use super::pxe::Subnets;
+
+
+/// Chooses the root directory from which to serve each request, by the
+/// client's subnet or by the local address at which the request
+/// arrived, so that one server can give lab, production, and guest
+/// networks boot trees of their own.
+///
+/// A root for the local address is chosen first; then one for the
+/// smallest subnet holding the client; then the default, if any. For
+/// example:
+///
+/// ```no_run
+/// # use std::net::IpAddr;
+/// # use allenap_libtftp::jail::{RootJail, Symlinks};
+/// # use allenap_libtftp::roots::Roots;
+/// # fn main() -> std::io::Result<()> {
+/// let lab: IpAddr = "10.1.0.0".parse().unwrap();
+/// let guest: IpAddr = "192.168.100.1".parse().unwrap();
+/// let roots = Roots::new()
+///     .with_subnet(lab, 16, RootJail::new("/srv/tftp/lab", Symlinks::Deny)?)
+///     .with_local(guest, RootJail::new("/srv/tftp/guest", Symlinks::Deny)?)
+///     .with_default(RootJail::new("/srv/tftp/production", Symlinks::Deny)?);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Handlers are told the local address of the socket a request arrived
+/// at, so to tell interfaces apart, `serve` on each interface's address
+/// rather than on the wildcard address.
+#[derive(Debug, Default)]
+pub struct Roots {
+    locals: Vec<(IpAddr, RootJail)>,
+    subnets: Subnets<RootJail>,
+    default: Option<RootJail>,
+}
+
+
+impl Roots {
+
+    pub fn new() -> Roots {
+        Roots::default()
+    }
+
+    /// Serve requests arriving at the local address `local` from
+    /// `root`, replacing any root already given for it.
+    pub fn with_local(mut self, local: IpAddr, root: RootJail) -> Roots {
+        let local = local.to_canonical();
+        self.locals.retain(|&(other, _)| other != local);
+        self.locals.push((local, root));
+        self
+    }
+
+    /// Serve clients in the subnet `network`/`prefix` from `root`,
+    /// replacing any root already given for that subnet.
+    pub fn with_subnet(mut self, network: IpAddr, prefix: u8, root: RootJail)
+                       -> Roots {
+        self.subnets.insert(network, prefix, root);
+        self
+    }
+
+    /// Serve requests for which no other root is chosen from `root`.
+    pub fn with_default(self, root: RootJail) -> Roots {
+        Roots{default: Some(root), ..self}
+    }
+
+    /// The root from which to serve `peer`'s request, received at
+    /// `local`, if there is one.
+    pub fn select(&self, local: net::SocketAddr, peer: net::SocketAddr)
+                  -> Option<&RootJail> {
+        let local = local.ip().to_canonical();
+        self.locals.iter()
+            .find(|&&(addr, _)| addr == local)
+            .map(|(_, root)| root)
+            .or_else(|| self.subnets.get(peer.ip()))
+            .or(self.default.as_ref())
+    }
+
+    /// Resolve `filename` within the root chosen for `peer`'s request,
+    /// received at `local`, as `RootJail::resolve_for` does. Requests
+    /// for which there's no root are refused as not found.
+    pub fn resolve_for(
+        &self, local: net::SocketAddr, peer: net::SocketAddr,
+        direction: Direction, filename: &Filename)
+        -> io::Result<PathBuf>
+    {
+        match self.select(local, peer) {
+            Some(root) => root.resolve_for(peer, direction, filename),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound, "no root for client")),
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::fs;
+    use std::io;
+    use std::net;
+
+    use super::Roots;
+    use super::super::jail::{RootJail, Symlinks};
+    use super::super::metrics::Direction;
+    use super::super::packet::Filename;
+    use super::super::testing::Scratch;
+
+    /// A scratch directory holding directories named `names`, each
+    /// with a file holding its name.
+    fn scratch(name: &str, names: &[&str]) -> Scratch {
+        let scratch = Scratch::new(&format!("roots-{}", name));
+        for name in names {
+            fs::create_dir_all(scratch.join(name)).unwrap();
+            fs::write(scratch.join(name).join("pxelinux.0"), name).unwrap();
+        }
+        scratch
+    }
+
+    fn jail(scratch: &Scratch, name: &str) -> RootJail {
+        RootJail::new(scratch.join(name), Symlinks::Deny).unwrap()
+    }
+
+    fn addr(addr: &str) -> net::SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    /// The name of the root chosen, or nothing if none was.
+    fn chosen(roots: &Roots, local: &str, peer: &str) -> String {
+        roots.resolve_for(
+            addr(local), addr(peer), Direction::Read,
+            &Filename::new("pxelinux.0"))
+            .map(|path| fs::read_to_string(path).unwrap())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_roots_chosen_by_local_then_subnet_then_default() {
+        let scratch = scratch(
+            "select", &["guest", "lab", "rack", "production"]);
+        let jail = |name: &str| jail(&scratch, name);
+        let roots = Roots::new()
+            .with_subnet("10.1.0.0".parse().unwrap(), 16, jail("lab"))
+            .with_subnet("10.1.2.3".parse().unwrap(), 24, jail("rack"))
+            .with_local("192.168.100.1".parse().unwrap(), jail("guest"))
+            .with_default(jail("production"));
+        let chosen = |local, peer| chosen(&roots, local, peer);
+        assert_eq!("lab", chosen("10.0.0.1:69", "10.1.9.9:999"));
+        assert_eq!("rack", chosen("10.0.0.1:69", "10.1.2.9:999"));
+        assert_eq!("guest", chosen("192.168.100.1:69", "10.1.2.9:999"));
+        assert_eq!("production", chosen("10.0.0.1:69", "10.2.0.1:999"));
+        // IPv4 clients on dual-stack sockets are treated as IPv4.
+        assert_eq!(
+            "rack", chosen("[::ffff:10.0.0.1]:69", "[::ffff:10.1.2.9]:999"));
+    }
+
+    #[test]
+    fn test_clients_without_a_root_are_refused() {
+        let scratch = scratch("refuse", &["lab"]);
+        let roots = Roots::new()
+            .with_subnet("fd00::".parse().unwrap(), 64, jail(&scratch, "lab"));
+        assert_eq!("lab", chosen(&roots, "[::1]:69", "[fd00::9]:999"));
+        let error = roots.resolve_for(
+            addr("[::1]:69"), addr("[fd01::9]:999"), Direction::Read,
+            &Filename::new("pxelinux.0")).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, error.kind());
+    }
+
+}
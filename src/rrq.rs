@@ -1,10 +1,10 @@
 extern crate byteorder;
-extern crate slog;
 
 use std::fs;
 use std::net;
 use std::io;
-use std::time;
+use std::path;
+use std::sync::Arc;
 
 use super::packet::{
     BlockNum,
@@ -15,204 +15,1400 @@ use super::packet::{
     Packet,
     TransferMode,
 };
-use super::options::Options;
-use super::make_socket;
+use super::cache::FileCache;
+use super::budget::MemoryBudget;
+use super::clock::{Clock, SystemClock};
+use super::events::EventSink;
+use super::limits::{ClientLimits, Permit};
+use super::logging::{Logger, Span};
+use super::metrics::{Direction, Metrics, NoMetrics};
+use super::netascii;
+use super::netascii::NetAsciiReader;
+use super::options::{Extensions, Options, OptionsPolicy};
+use super::pool::BufferPool;
+use super::progress::{Observer, Tracker, TransferStats};
+use super::quirks::{AnyPort, Quirks, SelectQuirks};
+use super::readahead::ReadAhead;
+use super::security::{SecurityEvent, Violation};
+use super::transport::{Tap, Transport, WireObserver};
+use super::{make_socket, send_error_via};
 
 
+/// Configuration for serving downloads.
+#[derive(Debug)]
+pub struct Config {
+    /// Notified of each download's progress and completion.
+    pub observer: Option<Arc<dyn Observer>>,
+    /// Sent an event as each download starts, retransmits, and ends.
+    pub events: Option<Arc<dyn EventSink>>,
+    /// Shown every datagram of each transfer, as raw bytes.
+    pub wire_observer: Option<Arc<dyn WireObserver>>,
+    /// Counts downloads, retransmissions, and errors.
+    pub metrics: Arc<dyn Metrics>,
+    /// Tells the time for progress reports.
+    pub clock: Arc<dyn Clock>,
+    /// Limits on the options clients can negotiate.
+    pub policy: OptionsPolicy,
+    /// Custom options to negotiate in addition to those defined by the
+    /// RFCs.
+    pub extensions: Extensions,
+    /// How to convert local text for `netascii` downloads.
+    pub netascii: netascii::Policy,
+    /// How many bytes of each file to read ahead on a worker thread
+    /// while waiting for acknowledgements, or zero, the default, to
+    /// read only as each block is sent; see `ReadAhead`.
+    pub read_ahead: usize,
+    /// Keeps small files in memory between downloads.
+    pub cache: Option<Arc<FileCache>>,
+    /// Per-client limits on simultaneous transfers and their rate.
+    /// Requests from clients with too many transfers in progress are
+    /// rejected with a `NotDefined` error.
+    pub limits: Option<Arc<ClientLimits>>,
+    /// Memory shared by all transfers. Those that would not fit have
+    /// their block and window sizes reduced or, failing that, are
+    /// rejected with a `NotDefined` error.
+    pub budget: Option<Arc<MemoryBudget>>,
+    /// Where each transfer's buffers come from, and go back to.
+    pub buffers: Arc<BufferPool>,
+    /// How many times to retransmit the first `DATA` packet before the
+    /// peer has acknowledged anything. Until then the request may have
+    /// come from a spoofed address, so this is kept low to limit the
+    /// server's use as a reflector.
+    pub unverified_retransmits: u8,
+    /// How many bytes, `OACK` included, to send in all before the peer
+    /// has acknowledged anything. The first `DATA` packet is always
+    /// sent, but is retransmitted only within this budget.
+    pub unverified_bytes: usize,
+    /// Chooses the quirks with which to serve each download, to work
+    /// with buggy clients; see `Quirks`. By default there are none.
+    pub quirks: Option<Arc<dyn SelectQuirks>>,
+}
+
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
+
+impl Config {
+
+    pub fn new() -> Config {
+        Config{
+            observer: None,
+            events: None,
+            wire_observer: None,
+            metrics: Arc::new(NoMetrics),
+            clock: Arc::new(SystemClock),
+            policy: OptionsPolicy::new(),
+            extensions: Extensions::new(),
+            netascii: netascii::Policy::new(),
+            read_ahead: 0,
+            cache: None,
+            limits: None,
+            budget: None,
+            buffers: Arc::new(BufferPool::default()),
+            unverified_retransmits: 2,
+            unverified_bytes: 8192,
+            quirks: None,
+        }
+    }
+
+}
+
+
+/// Serve a file to `peer`, returning what the transfer did once it has
+/// completed.
+///
+/// Failures are logged as well as returned, so callers that have
+/// nothing more to do with them can ignore them.
 pub fn serve_file(
     peer: net::SocketAddr,
     filename: Filename,
     txmode: TransferMode,
     options: Options,
-    logger: &slog::Logger,
-) {
-    info!(logger, "Received RRQ: {:?} {:?} {:?}", filename, txmode, options);
-    let Filename(filename) = filename;
+    config: &Config,
+    logger: &Logger,
+)
+    -> io::Result<TransferStats>
+{
     match make_socket(peer) {
-        Ok(socket) => match fs::File::open(&filename) {
-            Ok(mut file) => {
-                let len = file.metadata().ok().and_then(|m| Some(m.len()));
-                let logger = logger.new(o!(
-                    "peer" => format!("{}", peer),
-                    "filename" => filename,
-                ));
-                match send_to(
-                    &mut file, len, socket, peer, options, &logger) {
-                    Ok(_) => info!(
-                        logger, "Completed transfer to {:?}", peer),
-                    Err(error) => error!(
-                        logger, "Error transferring to {:?}: {}", peer, error),
-                };
-            },
-            Err(error) => {
-                error!(logger, "Problem with file {}: {}", &filename, error);
-                // TODO: Send error to peer.
-            },
+        Ok(socket) => serve_file_over(
+            &socket, peer, filename, txmode, options, config, logger),
+        Err(error) => {
+            error!(logger, "Could not open socket: {}", error);
+            Err(error)
         },
+    }
+}
+
+
+/// Serve a file to `peer` over the given transport.
+///
+/// This is `serve_file` for transports other than a UDP socket; see
+/// `Transport`. The transport should be dedicated to this transfer.
+pub fn serve_file_over(
+    transport: &dyn Transport,
+    peer: net::SocketAddr,
+    filename: Filename,
+    txmode: TransferMode,
+    options: Options,
+    config: &Config,
+    logger: &Logger,
+)
+    -> io::Result<TransferStats>
+{
+    let path = filename.to_path().into_owned();
+    serve_over(
+        transport, peer, &filename, txmode, options, config, logger,
+        || open(&path, config.cache.as_deref()).map_err(|error| {
+            error!(
+                logger, "Problem with file {}: {}", path.display(), error);
+            error
+        }))
+}
+
+
+/// Serve `data`, generated in memory in answer to a request for
+/// `filename`, to `peer`, returning what the transfer did once it has
+/// completed.
+///
+/// This is `serve_file` for content that is not in a file; `tsize` is
+/// answered with the length of `data`.
+pub fn serve_bytes(
+    peer: net::SocketAddr,
+    filename: Filename,
+    data: Vec<u8>,
+    txmode: TransferMode,
+    options: Options,
+    config: &Config,
+    logger: &Logger,
+)
+    -> io::Result<TransferStats>
+{
+    match make_socket(peer) {
+        Ok(socket) => serve_bytes_over(
+            &socket, peer, filename, data, txmode, options, config, logger),
         Err(error) => {
             error!(logger, "Could not open socket: {}", error);
+            Err(error)
         },
+    }
+}
+
+
+/// Serve `data` to `peer` over the given transport.
+///
+/// This is `serve_bytes` for transports other than a UDP socket; see
+/// `Transport`. The transport should be dedicated to this transfer.
+#[allow(clippy::too_many_arguments)]
+pub fn serve_bytes_over(
+    transport: &dyn Transport,
+    peer: net::SocketAddr,
+    filename: Filename,
+    data: Vec<u8>,
+    txmode: TransferMode,
+    options: Options,
+    config: &Config,
+    logger: &Logger,
+)
+    -> io::Result<TransferStats>
+{
+    serve_over(
+        transport, peer, &filename, txmode, options, config, logger, || {
+            let len = data.len() as u64;
+            Ok((Box::new(io::Cursor::new(data)), Some(len)))
+        })
+}
+
+
+/// Serve what's read from `data`, in answer to a request for
+/// `filename`, to `peer`, returning what the transfer did once it has
+/// completed.
+///
+/// This is `serve_file` for content read from elsewhere, such as a
+/// member of an archive; `tsize` is answered with `len`, if it's known.
+#[allow(clippy::too_many_arguments)]
+pub fn serve_reader(
+    peer: net::SocketAddr,
+    filename: Filename,
+    data: Box<dyn io::Read + Send>,
+    len: Option<u64>,
+    txmode: TransferMode,
+    options: Options,
+    config: &Config,
+    logger: &Logger,
+)
+    -> io::Result<TransferStats>
+{
+    match make_socket(peer) {
+        Ok(socket) => serve_reader_over(
+            &socket, peer, filename, data, len, txmode, options, config,
+            logger),
+        Err(error) => {
+            error!(logger, "Could not open socket: {}", error);
+            Err(error)
+        },
+    }
+}
+
+
+/// Serve what's read from `data` to `peer` over the given transport.
+///
+/// This is `serve_reader` for transports other than a UDP socket; see
+/// `Transport`. The transport should be dedicated to this transfer.
+#[allow(clippy::too_many_arguments)]
+pub fn serve_reader_over(
+    transport: &dyn Transport,
+    peer: net::SocketAddr,
+    filename: Filename,
+    data: Box<dyn io::Read + Send>,
+    len: Option<u64>,
+    txmode: TransferMode,
+    options: Options,
+    config: &Config,
+    logger: &Logger,
+)
+    -> io::Result<TransferStats>
+{
+    serve_over(
+        transport, peer, &filename, txmode, options, config, logger,
+        || Ok((data, len)))
+}
+
+
+/// Serve what `open` returns, with its size if known, to `peer`, in
+/// answer to a request for `filename`. Failures to open are sent to
+/// the peer as the nearest TFTP error, such as "file not found".
+#[allow(clippy::too_many_arguments)]
+fn serve_over<F>(
+    transport: &dyn Transport,
+    peer: net::SocketAddr,
+    filename: &Filename,
+    txmode: TransferMode,
+    options: Options,
+    config: &Config,
+    logger: &Logger,
+    open: F,
+)
+    -> io::Result<TransferStats>
+    where F: FnOnce() -> io::Result<(Box<dyn io::Read + Send>, Option<u64>)>
+{
+    let tap;
+    let transport: &dyn Transport = match config.wire_observer {
+        Some(ref observer) => {
+            tap = Tap::new(transport, observer.clone());
+            &tap
+        },
+        None => transport,
     };
+    let span = Span::transfer(
+        "read", peer, Some(&filename.to_string_lossy()));
+    let _entered = span.enter();
+    info!(
+        logger, "Received RRQ: {:?} {} {}", filename.to_string_lossy(),
+        txmode, options);
+    if txmode == TransferMode::Mail {
+        warn!(logger, "Rejecting RRQ: mail mode not supported");
+        if let Err(error) = send_error_via(
+            transport, peer, ErrorCode::IllegalOperation,
+            "mail transfer mode not supported", &*config.metrics) {
+            error!(logger, "Could not send error to peer: {}", error);
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput, "mail transfer mode not supported"));
+    }
+    let permit = match config.limits {
+        Some(ref limits) => match limits.admit(peer.ip()) {
+            Some(permit) => Some(permit),
+            None => {
+                warn!(logger, "Rejecting RRQ: too many transfers");
+                SecurityEvent::new(
+                    peer, Violation::QuotaExceeded, "max transfers")
+                    .with_direction(Direction::Read)
+                    .with_filename(filename.to_string_lossy())
+                    .report(config.events.as_deref());
+                if let Err(error) = send_error_via(
+                    transport, peer, ErrorCode::NotDefined,
+                    "too many transfers", &*config.metrics) {
+                    error!(logger, "Could not send error to peer: {}", error);
+                }
+                return Err(io::Error::other("too many transfers"));
+            },
+        },
+        None => None,
+    };
+    match open() {
+        Ok((file, len)) => {
+            let logger = logger.new(o!(
+                "peer" => format!("{}", peer),
+                "filename" => filename.to_string_lossy().into_owned(),
+            ));
+            // The size of a file once encoded as NetASCII is not known
+            // without reading it all, so it's not reported.
+            let (data, len): (Box<dyn io::Read + Send>, _) = match txmode {
+                TransferMode::NetASCII => (Box::new(
+                    NetAsciiReader::with_policy(file, config.netascii)),
+                    None),
+                _ => (file, len),
+            };
+            let mut data: Box<dyn io::Read + Send> = match config.read_ahead {
+                0 => data,
+                ahead => match ReadAhead::new(data, ahead) {
+                    Ok(data) => Box::new(data),
+                    Err(error) => {
+                        error!(logger, "Could not read ahead: {}", error);
+                        return Err(error);
+                    },
+                },
+            };
+            let mut tracker = Tracker::new(
+                peer, config.observer.as_deref(), len, &*config.clock)
+                .with_metrics(&*config.metrics)
+                .with_events(config.events.as_deref(), Direction::Read)
+                .with_span(span.clone());
+            tracker.started(Some(&filename.to_string_lossy()));
+            let quirks = config.quirks.as_ref().map_or_else(
+                Quirks::default,
+                |select| select.select(peer, filename, &options).quirks());
+            if quirks != Quirks::default() {
+                debug!(logger, "Serving with {:?}", quirks);
+            }
+            match send_to(
+                &mut *data, len, transport, peer, options, config, quirks,
+                permit.as_ref(), &mut tracker, &logger) {
+                Ok(_) => {
+                    info!(logger, "Completed transfer to {:?}", peer);
+                    Ok(tracker.completed())
+                },
+                Err(error) => {
+                    error!(
+                        logger, "Error transferring to {:?}: {}", peer, error);
+                    tracker.failed(&error);
+                    Err(error)
+                },
+            }
+        },
+        Err(error) => {
+            warn!(
+                logger, "Could not open {:?}: {}",
+                filename.to_string_lossy(), error);
+            if let Err(error) = send_error_via(
+                transport, peer, ErrorCode::from(&error), &error.to_string(),
+                &*config.metrics) {
+                error!(logger, "Could not send error to peer: {}", error);
+            }
+            Err(error)
+        },
+    }
+}
+
+
+/// Open the file at `path`, from `cache` if there is one and it will
+/// hold the file, returning it with its size if known.
+fn open(path: &path::Path, cache: Option<&FileCache>)
+    -> io::Result<(Box<dyn io::Read + Send>, Option<u64>)>
+{
+    if let Some(cache) = cache {
+        if let Some(data) = cache.read(path)? {
+            let len = data.len() as u64;
+            return Ok((Box::new(io::Cursor::new(data)), Some(len)));
+        }
+    }
+    let file = fs::File::open(path)?;
+    let len = file.metadata().ok().map(|m| m.len());
+    Ok((Box::new(file), len))
 }
 
 
-const EMPTY_DATA: Data<'static> = Data(&[]);
+/// Read from `data` until `buf` is full or there's no more to read. A
+/// short block marks the end of a transfer, so a short read from, say,
+/// a pipe or a `NetAsciiReader` must not be sent as one.
+fn read_block(data: &mut dyn io::Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut size = 0;
+    while size < buf.len() {
+        match data.read(&mut buf[size..]) {
+            Ok(0) => break,
+            Ok(amt) => size += amt,
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => {},
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(size)
+}
 
 
+#[allow(clippy::too_many_arguments)]
 fn send_to(
-    data: &mut io::Read,
+    data: &mut dyn io::Read,
     len: Option<u64>,
-    socket: net::UdpSocket,
+    socket: &dyn Transport,
     peer: net::SocketAddr,
     options: Options,
-    logger: &slog::Logger,
+    config: &Config,
+    quirks: Quirks,
+    permit: Option<&Permit>,
+    tracker: &mut Tracker,
+    logger: &Logger,
 )
     -> io::Result<()>
 {
+    let any_port;
+    let socket: &dyn Transport = if quirks.any_port {
+        any_port = AnyPort::new(socket, peer, &*config.clock);
+        &any_port
+    }
+    else {
+        socket
+    };
+
     // First, connect the socket to the peer so that we're only sending
     // and receiving traffic to/from the peer. TODO: Do this earlier?
     socket.connect(peer)?;
 
-    let mut options_out = Options::new();
-
-    let blksize: usize = match options.blksize {
-        Some(blksize) if blksize >= 512 => {
-            options_out.blksize = Some(blksize);
-            blksize as usize
+    // Only extensions that the application has registered, and that
+    // accept the requested value, are acknowledged.
+    let negotiated = options.negotiate(&config.policy).and_then(
+        |(mut options_out, negotiated)| {
+            options_out.unknown = config.extensions.negotiate(&options)?;
+            Ok((options_out, negotiated))
+        });
+    let (mut options_out, mut negotiated) = match negotiated {
+        Ok(negotiated) => negotiated,
+        Err(message) => {
+            warn!(logger, "Rejecting RRQ: {}", message);
+            socket.send(&Packet::bad_options(message.clone()).to_vec()?)?;
+            config.metrics.error(ErrorCode::BadOptions);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
         },
-        _ => 512,  // Default.
     };
 
-    socket.set_read_timeout(
-        Some(match options.timeout {
-            Some(timeout) if timeout >= 1 => {
-                options_out.timeout = Some(timeout);
-                time::Duration::from_secs(timeout as u64)
+    // Held until the transfer is done.
+    let _commitment = match config.budget {
+        Some(ref budget) => match budget.commit(
+            negotiated.blksize, negotiated.windowsize) {
+            Some(commitment) => {
+                commitment.apply(&mut options_out, &mut negotiated);
+                Some(commitment)
             },
-            _ => {
-                time::Duration::from_secs(8u64)  // Default.
+            None => {
+                warn!(logger, "Rejecting RRQ: memory budget exhausted");
+                socket.send(&Packet::error(
+                    ErrorCode::NotDefined, "server busy").to_vec()?)?;
+                config.metrics.error(ErrorCode::NotDefined);
+                return Err(io::Error::new(
+                    io::ErrorKind::OutOfMemory, "memory budget exhausted"));
             },
-        })
-    )?;
+        },
+        None => None,
+    };
 
-    match options.tsize {
+    let blksize = negotiated.blksize;
+    let timeout = Some(negotiated.timeout);
+
+    match options_out.tsize {
         Some(0) => {
             options_out.tsize = len;
         },
         Some(tsize) => {
             warn!(logger, "Option tsize should be zero, got: {}", tsize);
+            options_out.tsize = None;
         },
         None => {
             // Do nothing.
         },
     };
 
-    let mut bufout = vec![0u8; 4 + blksize];  // opcode + blkno + data
-    let mut bufin = vec![0u8; blksize];
+    let mut bufin = config.buffers.acquire(blksize);
+    // Blocks sent in lock-step are read into a buffer of their own, and
+    // sent with their headers as separate slices. A window of blocks is
+    // sent all at once, for the transport to split up, so it's laid out
+    // as whole packets, one after another, with room for each header.
+    let windowsize = negotiated.windowsize.max(1) as usize;
+    let header = if windowsize > 1 { 4 } else { 0 };
+    let stride = header + blksize;
+    let mut bufout = config.buffers.acquire(stride * windowsize);
+
+    // Never acknowledge an option the client did not ask for.
+    let options_out = options_out.restricted_to(&options);
+    tracker.negotiated(&negotiated, &options_out);
 
-    if options_out.is_set() {
-        let packet = Packet::OAck(options_out);
-        let size = packet.write(&mut bufout)?;
-        socket.send(&bufout[..size])?;
-        info!(logger, "Sent OACK ({} bytes) to {}.", size, &peer);
-        // TODO: Wait for ACK(0).
+    // Bytes sent before the peer has proven that it's really at its
+    // address by acknowledging something, from its TID since the socket
+    // is connected.
+    let mut unverified = 0;
+    let mut verified = false;
+
+    // Kept to be resent, should the peer need it.
+    let oack = if options_out.is_set() {
+        let oack = Packet::OAck(options_out.clone()).to_vec()?;
+        socket.send(&oack)?;
+        unverified += oack.len();
+        info!(
+            logger, "Sent OACK ({} bytes) to {}.", oack.len(), &peer;
+            &options_out);
+        tracker.oack(oack.len());
+        Some(oack)
+    }
+    else {
+        None
+    };
+
+    if let (Some(oack), true) = (&oack, quirks.await_oack_ack) {
+        let mut timeouts = 0u8;
+        loop {
+            match socket.recv(&mut bufin, timeout) {
+                Ok(amt) => match Packet::parse(&bufin[..amt]) {
+                    Ok(Packet::Ack(BlockNum(0))) => break,
+                    Ok(Packet::Error(code, message)) => {
+                        error!(logger, "{}: {:?}", code, message.0);
+                        return Err(io::Error::other(format!(
+                            "peer aborted transfer: {}: {:?}",
+                            code, message.0)));
+                    },
+                    _ => warn!(logger, "Ignoring packet awaiting ACK(0)."),
+                },
+                Err(ref error) if timed_out(error) => {
+                    if timeouts >= config.unverified_retransmits ||
+                        unverified + oack.len() > config.unverified_bytes {
+                        error!(
+                            logger, "Peer has not acknowledged OACK; \
+                                     aborting");
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "peer did not acknowledge"));
+                    }
+                    timeouts += 1;
+                    socket.send(oack)?;
+                    unverified += oack.len();
+                    tracker.retransmit(BlockNum(0));
+                },
+                Err(error) => return Err(error),
+            }
+        }
+        verified = true;
     }
 
     fn timed_out(error: &io::Error) -> bool {
-        // See the comment in UdpSocket.set_{read,write}_timeout to
-        // understand why both errors are matched.
+        // See the comment in Transport.recv to understand why both
+        // errors are matched.
         error.kind() == io::ErrorKind::WouldBlock ||
             error.kind() == io::ErrorKind::TimedOut
     }
 
-    'send: for blkno in (1 as u16).. {
+    // The blocks sent but not yet acknowledged, oldest first, with
+    // their sizes. Their packets are held in `bufout` in the same order,
+    // to be resent should the peer need them.
+    let mut window: Vec<(BlockNum, usize)> = Vec::with_capacity(windowsize);
+    let mut blkno = BlockNum(1);
+    let mut read_all = false;
+    let mut acked = None;
+    loop {
         let mut timeouts = 0u8;
-        match data.read(&mut bufout[4..]) {
-            Ok(size) => {
-                // To avoid an extra copy we cheat and use a Data packet
-                // to write headers only. We've already read the payload
-                // into the correct place in `bufout`.
-                let packet = Packet::Data(BlockNum(blkno), EMPTY_DATA);
-                packet.write(&mut bufout[..4])?;
-                socket.send(&bufout[..size + 4])?;
-                info!(logger, "Sent DATA ({} bytes) to {}.", size, &peer);
-
-                'recv: loop {
-                    match socket.recv(&mut bufin) {
-                        Ok(amt) => {
-                            match Packet::parse(&mut bufin[..amt]) {
-                                Ok(packet) => match packet {
-                                    Packet::Ack(BlockNum(blocknum)) => {
-                                        if blocknum == blkno {
-                                            break 'recv;
-                                        };
-                                    },
-                                    Packet::Error(code, message) => {
-                                        error!(logger, "{:?}: {:?}", code, message);
-                                        break 'send;
-                                    },
-                                    Packet::Data(..) => warn!(
-                                        logger, "Ignoring unexpected DATA packet."),
-                                    Packet::Read(..) => warn!(
-                                        logger, "Ignoring unexpected RRQ packet."),
-                                    Packet::Write(..) => warn!(
-                                        logger, "Ignoring unexpected WRQ packet."),
-                                    Packet::OAck(..) => warn!(
-                                        logger, "Ignoring unexpected OACK packet."),
-                                },
-                                Err(error) => {
-                                    warn!(
-                                        logger, "Ignoring mangled packet ({:?}).",
-                                        error);
-                                },
-                            };
+        while !read_all && window.len() < windowsize {
+            let start = window.len() * stride;
+            let payload = &mut bufout[start + header..start + stride];
+            let size = match read_block(data, payload) {
+                Ok(0) if quirks.pad_empty_final_block => {
+                    payload[0] = 0;
+                    1
+                },
+                Ok(size) => size,
+                Err(error) => {
+                    let packet = Packet::Error(
+                        ErrorCode::NotDefined, ErrorMessage(format!(
+                            "Something broke: {}", error)));
+
+                    match packet.to_vec() {
+                        Ok(buf) => {
+                            socket.send(&buf)?;
+                            config.metrics.error(ErrorCode::NotDefined);
+                        },
+                        Err(error) => {
+                            error!(
+                                logger, "Error preparing error packet: {:?}",
+                                error);
                         },
-                        Err(ref error) if timed_out(error) => {
-                            match timeouts {
-                                0...7 => {
-                                    timeouts += 1;
-                                    socket.send(&bufout[..size + 4])?;
-                                    info!(
-                                        logger,
-                                        "Sent DATA ({} bytes) to {} (attempt #{}).",
-                                        size, &peer, timeouts + 1);
-                                },
-                                _ => {
-                                    error!(logger, "Too many time-outs; aborting");
-                                    break 'send;
-                                },
-                            };
+                    };
+
+                    return Err(error);
+                },
+            };
+            if header > 0 {
+                bufout[start..start + header]
+                    .copy_from_slice(&Data::header(blkno));
+            }
+            window.push((blkno, size));
+            read_all = size < blksize;
+            blkno = blkno.next_rolling(negotiated.rollover);
+        }
+
+        let sent = send_window(socket, &bufout, stride, header, &window)?;
+        info!(logger, "Sent DATA ({} bytes) to {}.", sent, &peer);
+        if !verified {
+            unverified += sent;
+        }
+
+        'recv: loop {
+            match socket.recv(&mut bufin, timeout) {
+                Ok(amt) => {
+                    match Packet::parse(&bufin[..amt]) {
+                        Ok(packet) => match packet {
+                            Packet::Ack(blocknum) => {
+                                let position = window.iter()
+                                    .position(|&(blkno, _)| blkno == blocknum);
+                                // ACK(0) acknowledges the OACK.
+                                if position.is_some() ||
+                                    blocknum == BlockNum(0) {
+                                    verified = true;
+                                }
+                                if let Some(position) = position {
+                                    let mut size = 0;
+                                    for (_, block) in
+                                        window.drain(..=position) {
+                                        tracker.block(block);
+                                        size += block;
+                                    }
+                                    acked = Some(blocknum);
+                                    // Blocks after the one acknowledged
+                                    // were lost, and are resent with the
+                                    // next window.
+                                    bufout.copy_within(
+                                        (position + 1) * stride..
+                                            (position + 1 + window.len()) *
+                                            stride,
+                                        0);
+                                    for &(blkno, _) in &window {
+                                        tracker.retransmit(blkno);
+                                    }
+                                    if read_all && window.is_empty() {
+                                        return Ok(());
+                                    }
+                                    // Pause to keep within the client's
+                                    // rate.
+                                    if let Some(permit) = permit {
+                                        config.clock.sleep(
+                                            permit.charge(size as u64));
+                                    }
+                                    break 'recv;
+                                }
+                                else if Some(blocknum) == acked {
+                                    tracker.duplicate_ack();
+                                };
+                            },
+                            Packet::Error(code, message) => {
+                                error!(logger, "{}: {:?}", code, message.0);
+                                return Err(io::Error::other(format!(
+                                    "peer aborted transfer: {}: {:?}",
+                                    code, message.0)));
+                            },
+                            Packet::Data(..) => warn!(
+                                logger, "Ignoring unexpected DATA packet."),
+                            Packet::Read(..) => warn!(
+                                logger, "Ignoring unexpected RRQ packet."),
+                            Packet::Write(..) => warn!(
+                                logger, "Ignoring unexpected WRQ packet."),
+                            Packet::OAck(..) => warn!(
+                                logger, "Ignoring unexpected OACK packet."),
+                            Packet::Unknown(..) => warn!(
+                                logger, "Ignoring unknown packet."),
                         },
                         Err(error) => {
-                            error!(logger, "Error receiving packet: {}", error);
-                            break 'send;
+                            warn!(
+                                logger, "Ignoring mangled packet ({:?}).",
+                                error);
+                            config.metrics.malformed_packet();
+                        },
+                    };
+                },
+                Err(ref error) if timed_out(error) => {
+                    let oack = match oack {
+                        Some(ref oack) if quirks.resend_oack && !verified =>
+                            &oack[..],
+                        _ => &[],
+                    };
+                    let window_len: usize =
+                        window.iter().map(|&(_, size)| size + 4).sum();
+                    match timeouts {
+                        _ if !verified && (
+                            timeouts >= config.unverified_retransmits ||
+                            unverified + oack.len() + window_len >
+                                config.unverified_bytes) => {
+                            error!(
+                                logger, "Peer has not acknowledged \
+                                         anything; aborting");
+                            return Err(io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "peer did not acknowledge"));
+                        },
+                        0..=7 => {
+                            timeouts += 1;
+                            if !oack.is_empty() {
+                                socket.send(oack)?;
+                            }
+                            let sent = send_window(
+                                socket, &bufout, stride, header, &window)?;
+                            if !verified {
+                                unverified += oack.len() + sent;
+                            }
+                            for &(blkno, _) in &window {
+                                tracker.retransmit(blkno);
+                            }
+                            info!(
+                                logger,
+                                "Sent DATA ({} bytes) to {} (attempt #{}).",
+                                sent, &peer, timeouts + 1);
                         },
+                        _ => {
+                            error!(logger, "Too many time-outs; aborting");
+                            return Err(io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "too many time-outs"));
+                        },
+                    };
+                },
+                Err(error) => {
+                    error!(logger, "Error receiving packet: {}", error);
+                    return Err(error);
+                },
+            }
+        }
+    }
+}
+
+
+/// Send the blocks in `window`, whose packets are laid out in `buf`
+/// every `stride` bytes, each with `header` bytes of room for its
+/// header, returning the number of bytes sent.
+///
+/// A lone block, without room for its header, is sent with `send_data`.
+/// Otherwise the packets are sent together with `send_segments`, so
+/// that the transport can hand them to the kernel in one go, with UDP
+/// GSO if it can. Only the last packet of a window may be short, so
+/// all the others are `stride` bytes long, as that requires.
+fn send_window(
+    socket: &dyn Transport, buf: &[u8], stride: usize, header: usize,
+    window: &[(BlockNum, usize)])
+    -> io::Result<usize>
+{
+    match *window {
+        [(blkno, size)] if header == 0 =>
+            send_data(socket, blkno, &buf[..size]),
+        [.., (_, size)] => {
+            let len = (window.len() - 1) * stride + header + size;
+            socket.send_segments(&buf[..len], stride)
+        },
+        [] => Ok(0),
+    }
+}
+
+
+/// Send `payload` as block `blkno`, with the header and the payload
+/// gathered by the transport rather than copied together here.
+fn send_data(socket: &dyn Transport, blkno: BlockNum, payload: &[u8])
+    -> io::Result<usize>
+{
+    let packet = Packet::Data(blkno, Data(payload));
+    let mut header = [0u8; 4];
+    let slices = packet.as_io_slices(&mut header)
+        .expect("DATA packets have slices");
+    socket.send_vectored(&slices)
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::net;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time;
+
+    use super::{Config, serve_file_over};
+    use super::super::budget::MemoryBudget;
+    use super::super::cache::FileCache;
+    use super::super::limits::ClientLimits;
+    use super::super::logging;
+    use super::super::options::Options;
+    use super::super::packet::{
+        BlockNum,
+        ErrorCode,
+        Filename,
+        Packet,
+        TransferMode,
+    };
+    use super::super::quirks::{Quirks, QuirksProfile};
+    use super::super::testing::{MemoryTransport, MockClock};
+    use super::super::transport::Transport;
+
+    #[test]
+    fn test_serve_file_over_memory_transport() {
+        let path = env::temp_dir().join(
+            format!("libtftp-rrq-memory-{}", process::id()));
+        let payload: Vec<u8> = (0..1300).map(|n| n as u8).collect();
+        fs::write(&path, &payload).unwrap();
+        let (server, client) = MemoryTransport::pair();
+        let peer = client.local_addr().unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        // Lose the first ACK, so block 1 must be retransmitted.
+        client.drop_next(1);
+        let mut options = Options::new();
+        options.utimeout = Some(10_000);
+        let filename = Filename::new(path.to_str().unwrap());
+        let sender = thread::spawn(move || {
+            let logger = logging::discard();
+            let _ = serve_file_over(
+                &server, peer, filename, TransferMode::Octet, options,
+                &Config::new(), &logger);
+        });
+        // Fail rather than hang should the sender stop responding.
+        let timeout = Some(time::Duration::from_secs(5));
+        let mut buf = [0u8; 516];
+        let size = client.recv(&mut buf, timeout).unwrap();
+        match Packet::parse(&buf[..size]).unwrap() {
+            Packet::OAck(options) =>
+                assert_eq!(Some(10_000), options.utimeout),
+            packet => panic!("Unexpected packet: {:?}", packet),
+        };
+        let mut received = Vec::new();
+        let mut repeats = 0;
+        let mut expected = BlockNum(1);
+        loop {
+            let size = client.recv(&mut buf, timeout).unwrap();
+            let (blkno, len) = match Packet::parse(&buf[..size]).unwrap() {
+                Packet::Data(blkno, data) => {
+                    if blkno == expected {
+                        received.extend_from_slice(data.0);
+                        expected = blkno.next();
                     }
-                }
+                    else {
+                        repeats += 1;
+                    }
+                    (blkno, data.0.len())
+                },
+                packet => panic!("Unexpected packet: {:?}", packet),
+            };
+            let size = Packet::Ack(blkno).write(&mut buf).unwrap();
+            client.send(&buf[..size]).unwrap();
+            if len < 512 {
+                break;
+            }
+        }
+        sender.join().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(payload, received);
+        assert_eq!(1, repeats);
+    }
+
+    #[test]
+    fn test_serve_file_over_reads_ahead() {
+        let path = env::temp_dir().join(
+            format!("libtftp-rrq-read-ahead-{}", process::id()));
+        let payload: Vec<u8> = (0..1300).map(|n| n as u8).collect();
+        fs::write(&path, &payload).unwrap();
+        let (server, client) = MemoryTransport::pair();
+        let peer = client.local_addr().unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        let filename = Filename::new(path.to_str().unwrap());
+        let sender = thread::spawn(move || {
+            let logger = logging::discard();
+            let mut config = Config::new();
+            config.read_ahead = 700;
+            serve_file_over(
+                &server, peer, filename, TransferMode::Octet,
+                Options::new(), &config, &logger)
+        });
+        let received = download(&client);
+        let stats = sender.join().unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(payload, received);
+        assert_eq!(1300, stats.bytes);
+    }
+
+    #[test]
+    fn test_serve_file_over_limits_transfers_per_client() {
+        let path = env::temp_dir().join(
+            format!("libtftp-rrq-limits-{}", process::id()));
+        fs::write(&path, b"hello").unwrap();
+        let limits = Arc::new(ClientLimits::new().with_max_transfers(1));
+        let (server, client) = MemoryTransport::pair();
+        let peer = client.local_addr().unwrap();
+        let permit = limits.admit(peer.ip()).unwrap();
+        let mut config = Config::new();
+        config.limits = Some(limits.clone());
+        let logger = logging::discard();
+        let filename = Filename::new(path.to_str().unwrap());
+        assert!(serve_file_over(
+            &server, peer, filename.clone(), TransferMode::Octet,
+            Options::new(), &config, &logger).is_err());
+        let mut buf = [0u8; 64];
+        let size = client.recv(&mut buf, None).unwrap();
+        assert_eq!(
+            Packet::error(ErrorCode::NotDefined, "too many transfers"),
+            Packet::parse(&buf[..size]).unwrap());
+        // Once the other transfer is done, this one can go ahead.
+        drop(permit);
+        client.connect(server.local_addr().unwrap()).unwrap();
+        let sender = thread::spawn(move || {
+            let logger = logging::discard();
+            serve_file_over(
+                &server, peer, filename, TransferMode::Octet,
+                Options::new(), &config, &logger)
+        });
+        assert_eq!(b"hello", &download(&client)[..]);
+        sender.join().unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(0, limits.transfers(peer.ip()));
+    }
+
+    #[test]
+    fn test_serve_file_over_limits_rate_on_clock() {
+        let path = env::temp_dir().join(
+            format!("libtftp-rrq-rate-{}", process::id()));
+        fs::write(&path, vec![1u8; 2048]).unwrap();
+        let clock = MockClock::new();
+        let limits = Arc::new(
+            ClientLimits::new().with_rate(512)
+                .with_clock(Arc::new(clock.clone())));
+        let (server, client) = MemoryTransport::pair();
+        let peer = client.local_addr().unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        let mut config = Config::new();
+        config.limits = Some(limits);
+        config.clock = Arc::new(clock.clone());
+        let filename = Filename::new(path.to_str().unwrap());
+        let sender = thread::spawn(move || {
+            let logger = logging::discard();
+            serve_file_over(
+                &server, peer, filename, TransferMode::Octet,
+                Options::new(), &config, &logger)
+        });
+        assert_eq!(vec![1u8; 2048], download(&client));
+        sender.join().unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        // Four blocks at 512 bytes a second, less the first second's
+        // allowance, pass on the clock rather than in real time.
+        assert!(clock.elapsed() >= time::Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_serve_file_over_limits_retransmits_to_unverified_peer() {
+        let path = env::temp_dir().join(
+            format!("libtftp-rrq-unverified-{}", process::id()));
+        fs::write(&path, vec![1u8; 1468]).unwrap();
+        let (server, client) = MemoryTransport::pair();
+        let clock = MockClock::new();
+        server.set_clock(Arc::new(clock.clone()));
+        let peer = client.local_addr().unwrap();
+        let filename = Filename::new(path.to_str().unwrap());
+        let mut options = Options::new();
+        options.blksize = Some(1468);
+        let mut config = Config::new();
+        config.unverified_retransmits = 5;
+        config.unverified_bytes = 4000;
+        let logger = logging::discard();
+        // The peer never acknowledges anything.
+        let error = serve_file_over(
+            &server, peer, filename, TransferMode::Octet, options, &config,
+            &logger).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(io::ErrorKind::TimedOut, error.kind());
+        let mut buf = [0u8; 1472];
+        let mut sizes = Vec::new();
+        while let Ok(size) = client.recv(&mut buf, Some(time::Duration::ZERO)) {
+            sizes.push(size);
+        }
+        // The OACK, then DATA once and retransmitted once more, before
+        // the budget of bytes is spent.
+        assert_eq!(vec![15, 1472, 1472], sizes);
+    }
+
+    /// Serve a file of `len` bytes with `quirks` to a peer that never
+    /// acknowledges anything, returning the sizes of what was sent.
+    fn serve_unacknowledged(name: &str, len: usize, quirks: Quirks)
+                            -> Vec<usize> {
+        let path = env::temp_dir().join(
+            format!("libtftp-rrq-{}-{}", name, process::id()));
+        fs::write(&path, vec![1u8; len]).unwrap();
+        let (server, client) = MemoryTransport::pair();
+        server.set_clock(Arc::new(MockClock::new()));
+        let peer = client.local_addr().unwrap();
+        let filename = Filename::new(path.to_str().unwrap());
+        let mut options = Options::new();
+        options.blksize = Some(1468);
+        let mut config = Config::new();
+        config.unverified_retransmits = 2;
+        config.quirks = Some(Arc::new(QuirksProfile::Custom(quirks)));
+        let logger = logging::discard();
+        let error = serve_file_over(
+            &server, peer, filename, TransferMode::Octet, options, &config,
+            &logger).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(io::ErrorKind::TimedOut, error.kind());
+        let mut buf = [0u8; 1472];
+        let mut sizes = Vec::new();
+        while let Ok(size) = client.recv(&mut buf, Some(time::Duration::ZERO)) {
+            sizes.push(size);
+        }
+        sizes
+    }
+
+    #[test]
+    fn test_serve_file_over_with_quirks_resends_oack() {
+        let quirks = Quirks{resend_oack: true, ..Quirks::default()};
+        assert_eq!(
+            vec![15, 104, 15, 104, 15, 104],
+            serve_unacknowledged("resend-oack", 100, quirks));
+    }
+
+    #[test]
+    fn test_serve_file_over_with_quirks_awaits_oack_ack() {
+        let quirks = Quirks{await_oack_ack: true, ..Quirks::default()};
+        assert_eq!(
+            vec![15, 15, 15],
+            serve_unacknowledged("await-oack-ack", 100, quirks));
+    }
+
+    #[test]
+    fn test_serve_file_over_with_quirks_pads_empty_final_block() {
+        let path = env::temp_dir().join(
+            format!("libtftp-rrq-pad-{}", process::id()));
+        fs::write(&path, vec![1u8; 512]).unwrap();
+        let mut config = Config::new();
+        let quirks = Quirks{pad_empty_final_block: true, ..Quirks::default()};
+        config.quirks = Some(Arc::new(QuirksProfile::Custom(quirks)));
+        let (server, client) = MemoryTransport::pair();
+        let peer = client.local_addr().unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        let filename = Filename::new(path.to_str().unwrap());
+        let sender = thread::spawn(move || {
+            let logger = logging::discard();
+            serve_file_over(
+                &server, peer, filename, TransferMode::Octet,
+                Options::new(), &config, &logger)
+        });
+        let mut expected = vec![1u8; 512];
+        expected.push(0);
+        assert_eq!(expected, download(&client));
+        sender.join().unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_serve_file_over_reads_from_cache() {
+        let path = env::temp_dir().join(
+            format!("libtftp-rrq-cache-{}", process::id()));
+        let payload: Vec<u8> = (0..1300).map(|n| n as u8).collect();
+        fs::write(&path, &payload).unwrap();
+        let cache = Arc::new(FileCache::new(4096, 4096));
+        for _ in 0..2 {
+            let (server, client) = MemoryTransport::pair();
+            let peer = client.local_addr().unwrap();
+            client.connect(server.local_addr().unwrap()).unwrap();
+            let filename = Filename::new(path.to_str().unwrap());
+            let mut config = Config::new();
+            config.cache = Some(cache.clone());
+            let sender = thread::spawn(move || {
+                let logger = logging::discard();
+                serve_file_over(
+                    &server, peer, filename, TransferMode::Octet,
+                    Options::new(), &config, &logger)
+            });
+            assert_eq!(payload, download(&client));
+            sender.join().unwrap().unwrap();
+            assert_eq!(1300, cache.size());
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_serve_file_over_returns_buffers_to_pool() {
+        let path = env::temp_dir().join(
+            format!("libtftp-rrq-pool-{}", process::id()));
+        fs::write(&path, b"hello").unwrap();
+        let config = Arc::new(Config::new());
+        let (server, client) = MemoryTransport::pair();
+        let peer = client.local_addr().unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        let filename = Filename::new(path.to_str().unwrap());
+        let sender = {
+            let config = config.clone();
+            thread::spawn(move || {
+                let logger = logging::discard();
+                serve_file_over(
+                    &server, peer, filename, TransferMode::Octet,
+                    Options::new(), &config, &logger)
+            })
+        };
+        assert_eq!(b"hello", &download(&client)[..]);
+        sender.join().unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(2, config.buffers.free());
+    }
+
+    #[test]
+    fn test_serve_file_over_degrades_blksize_to_fit_budget() {
+        let path = env::temp_dir().join(
+            format!("libtftp-rrq-budget-{}", process::id()));
+        fs::write(&path, b"hello").unwrap();
+        let mut config = Config::new();
+        config.budget = Some(Arc::new(MemoryBudget::new(2000)));
+        let config = Arc::new(config);
+        let (server, client) = MemoryTransport::pair();
+        let peer = client.local_addr().unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        let mut options = Options::new();
+        options.blksize = Some(1468);
+        let filename = Filename::new(path.to_str().unwrap());
+        let sender = {
+            let config = config.clone();
+            thread::spawn(move || {
+                let logger = logging::discard();
+                serve_file_over(
+                    &server, peer, filename, TransferMode::Octet, options,
+                    &config, &logger)
+            })
+        };
+        let timeout = Some(time::Duration::from_secs(5));
+        let mut buf = [0u8; 1472];
+        let size = client.recv(&mut buf, timeout).unwrap();
+        match Packet::parse(&buf[..size]).unwrap() {
+            Packet::OAck(options) => assert_eq!(Some(734), options.blksize),
+            packet => panic!("Unexpected packet: {:?}", packet),
+        };
+        let size = Packet::Ack(BlockNum(0)).write(&mut buf).unwrap();
+        client.send(&buf[..size]).unwrap();
+        let size = client.recv(&mut buf, timeout).unwrap();
+        assert_eq!(b"\0\x03\0\x01hello", &buf[..size]);
+        let size = Packet::Ack(BlockNum(1)).write(&mut buf).unwrap();
+        client.send(&buf[..size]).unwrap();
+        sender.join().unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(0, config.budget.as_ref().unwrap().committed());
+    }
+
+    /// Records which of its sending methods are called, and with how
+    /// many bytes, passing everything on to a `MemoryTransport`.
+    struct Sends {
+        inner: MemoryTransport,
+        sends: Mutex<Vec<(&'static str, usize)>>,
+    }
+
+    impl Sends {
+        fn new(inner: MemoryTransport) -> Sends {
+            Sends{inner, sends: Mutex::new(Vec::new())}
+        }
+
+        fn sends(&self) -> Vec<(&'static str, usize)> {
+            self.sends.lock().unwrap().clone()
+        }
+
+        fn record(&self, method: &'static str, size: usize) {
+            self.sends.lock().unwrap().push((method, size));
+        }
+    }
+
+    impl Transport for Sends {
+        fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            self.record("send", buf.len());
+            self.inner.send(buf)
+        }
+
+        fn send_to(&self, buf: &[u8], addr: net::SocketAddr)
+                   -> io::Result<usize> {
+            self.record("send_to", buf.len());
+            self.inner.send_to(buf, addr)
+        }
+
+        fn send_vectored(&self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+            let size = self.inner.send_vectored(bufs)?;
+            self.record("send_vectored", size);
+            Ok(size)
+        }
+
+        fn send_segments(&self, buf: &[u8], segment: usize)
+                         -> io::Result<usize> {
+            self.record("send_segments", buf.len());
+            self.inner.send_segments(buf, segment)
+        }
+
+        fn recv(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+                -> io::Result<usize> {
+            self.inner.recv(buf, timeout)
+        }
+
+        fn recv_from(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+                     -> io::Result<(usize, net::SocketAddr)> {
+            self.inner.recv_from(buf, timeout)
+        }
+
+        fn connect(&self, addr: net::SocketAddr) -> io::Result<()> {
+            self.inner.connect(addr)
+        }
+
+        fn local_addr(&self) -> io::Result<net::SocketAddr> {
+            self.inner.local_addr()
+        }
+    }
+
+    #[test]
+    fn test_serve_file_over_sends_data_vectored() {
+        let path = env::temp_dir().join(
+            format!("libtftp-rrq-vectored-{}", process::id()));
+        let payload: Vec<u8> = (0..600).map(|n| n as u8).collect();
+        fs::write(&path, &payload).unwrap();
+        let (server, client) = MemoryTransport::pair();
+        let server = Arc::new(Sends::new(server));
+        let peer = client.local_addr().unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        let filename = Filename::new(path.to_str().unwrap());
+        let sender = {
+            let server = server.clone();
+            thread::spawn(move || {
+                let logger = logging::discard();
+                serve_file_over(
+                    &*server, peer, filename, TransferMode::Octet,
+                    Options::new(), &Config::new(), &logger)
+            })
+        };
+        assert_eq!(payload, download(&client));
+        sender.join().unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        // Each header and payload are handed over separately.
+        assert_eq!(
+            vec![("send_vectored", 516), ("send_vectored", 92)],
+            server.sends());
+    }
 
-                if size < blksize {
-                    break;
+    #[test]
+    fn test_serve_file_over_sends_windows_as_segments() {
+        let path = env::temp_dir().join(
+            format!("libtftp-rrq-window-{}", process::id()));
+        let payload: Vec<u8> = (0..2660).map(|n| n as u8).collect();
+        fs::write(&path, &payload).unwrap();
+        let (server, client) = MemoryTransport::pair();
+        let server = Arc::new(Sends::new(server));
+        let peer = client.local_addr().unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        let mut options = Options::new();
+        options.windowsize = Some(4);
+        let mut config = Config::new();
+        config.policy.max_windowsize = 4;
+        let filename = Filename::new(path.to_str().unwrap());
+        let sender = {
+            let server = server.clone();
+            thread::spawn(move || {
+                let logger = logging::discard();
+                serve_file_over(
+                    &*server, peer, filename, TransferMode::Octet, options,
+                    &config, &logger)
+            })
+        };
+        let timeout = Some(time::Duration::from_secs(5));
+        let mut buf = [0u8; 516];
+        let size = client.recv(&mut buf, timeout).unwrap();
+        match Packet::parse(&buf[..size]).unwrap() {
+            Packet::OAck(options) => assert_eq!(Some(4), options.windowsize),
+            packet => panic!("Unexpected packet: {:?}", packet),
+        };
+        // The block number and size of each DATA packet in a window.
+        let mut window = || -> Vec<(u16, usize)> {
+            (0..4).map(|_| {
+                let size = client.recv(&mut buf, timeout).unwrap();
+                match Packet::parse(&buf[..size]).unwrap() {
+                    Packet::Data(blkno, data) => (blkno.0, data.0.len()),
+                    packet => panic!("Unexpected packet: {:?}", packet),
                 }
-            },
-            Err(error) => {
-                let packet = Packet::Error(
-                    ErrorCode::NotDefined, ErrorMessage(format!(
-                        "Something broke: {}\0", error)));
-
-                match packet.write(&mut bufout) {
-                    Ok(length) => {
-                        socket.send(&bufout[..length])?;
-                    },
-                    Err(error) => {
-                        error!(
-                            logger, "Error preparing error packet: {:?}",
-                            error);
-                    },
-                };
+            }).collect()
+        };
+        assert_eq!(vec![(1, 512), (2, 512), (3, 512), (4, 512)], window());
+        // As if blocks 3 and 4 were lost, so they're sent again.
+        let ack = |blkno| Packet::Ack(BlockNum(blkno)).to_vec().unwrap();
+        client.send(&ack(2)).unwrap();
+        assert_eq!(vec![(3, 512), (4, 512), (5, 512), (6, 100)], window());
+        client.send(&ack(6)).unwrap();
+        let stats = sender.join().unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(2660, stats.bytes);
+        // Each window is sent in one go, for the transport to segment.
+        assert_eq!(
+            vec![("send", 15), ("send_segments", 2064),
+                 ("send_segments", 1652)],
+            server.sends());
+    }
 
-                break 'send;
-            },
+    /// Acknowledge each block of a download with a block size of 512
+    /// until a short one arrives, returning the data.
+    fn download(client: &MemoryTransport) -> Vec<u8> {
+        let timeout = Some(time::Duration::from_secs(5));
+        let mut buf = [0u8; 516];
+        let mut received = Vec::new();
+        loop {
+            let size = client.recv(&mut buf, timeout).unwrap();
+            let (blkno, len) = match Packet::parse(&buf[..size]).unwrap() {
+                Packet::Data(blkno, data) => {
+                    received.extend_from_slice(data.0);
+                    (blkno, data.0.len())
+                },
+                packet => panic!("Unexpected packet: {:?}", packet),
+            };
+            let size = Packet::Ack(blkno).write(&mut buf).unwrap();
+            client.send(&buf[..size]).unwrap();
+            if len < 512 {
+                return received;
+            }
         }
-    };
-    Result::Ok(())
+    }
+
+    #[test]
+    fn test_serve_file_over_sends_open_errors() {
+        let path = env::temp_dir().join(
+            format!("libtftp-rrq-missing-{}", process::id()));
+        let (server, client) = MemoryTransport::pair();
+        let peer = client.local_addr().unwrap();
+        let logger = logging::discard();
+        let error = serve_file_over(
+            &server, peer, Filename::new(path.to_str().unwrap()),
+            TransferMode::Octet, Options::new(), &Config::new(), &logger)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, error.kind());
+        let mut buf = [0u8; 516];
+        let size = client.recv(&mut buf, Some(time::Duration::ZERO)).unwrap();
+        match Packet::parse(&buf[..size]).unwrap() {
+            Packet::Error(code, _) =>
+                assert_eq!(ErrorCode::FileNotFound, code),
+            packet => panic!("Unexpected packet: {:?}", packet),
+        };
+    }
+
+    #[test]
+    fn test_serve_file_over_sends_read_errors() {
+        // A directory can be opened, but reading it fails.
+        let path = env::temp_dir();
+        let (server, client) = MemoryTransport::pair();
+        let peer = client.local_addr().unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        let filename = Filename::new(path.to_str().unwrap());
+        let sender = thread::spawn(move || {
+            let logger = logging::discard();
+            let _ = serve_file_over(
+                &server, peer, filename, TransferMode::Octet,
+                Options::new(), &Config::new(), &logger);
+        });
+        let timeout = Some(time::Duration::from_secs(5));
+        let mut buf = [0u8; 516];
+        let size = client.recv(&mut buf, timeout).unwrap();
+        match Packet::parse(&buf[..size]).unwrap() {
+            Packet::Error(ErrorCode::NotDefined, message) =>
+                assert!(message.0.starts_with("Something broke: ")),
+            packet => panic!("Unexpected packet: {:?}", packet),
+        };
+        sender.join().unwrap();
+    }
+
 }
@@ -0,0 +1,165 @@
+use std::fmt;
+use std::net;
+
+use super::events::{Event, EventSink};
+use super::logging::Logger;
+use super::metrics::Direction;
+
+
+/// The kind of rule a client broke.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+#[non_exhaustive]
+pub enum Violation {
+    /// The client asked for something it's not allowed: a read from a
+    /// write-only server, or a file it's not entitled to.
+    AccessDenied,
+    /// The filename tried to escape the root directory.
+    PathTraversal,
+    /// The filename's signature was missing, wrong, or expired.
+    BadSignature,
+    /// The client has used up its quota, or has too many transfers.
+    QuotaExceeded,
+}
+
+
+/// Short, stable names, for filtering in a SIEM.
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Violation::AccessDenied => "access-denied",
+            Violation::PathTraversal => "path-traversal",
+            Violation::BadSignature => "bad-signature",
+            Violation::QuotaExceeded => "quota-exceeded",
+        })
+    }
+}
+
+
+/// A request refused for breaking a security rule, sent to an
+/// `EventSink` as `Event::Security`.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct SecurityEvent {
+    pub peer: net::SocketAddr,
+    pub violation: Violation,
+    /// The rule broken, in a few words, e.g. `read-only` or `has ..`.
+    pub rule: String,
+    pub direction: Option<Direction>,
+    pub filename: Option<String>,
+}
+
+
+impl SecurityEvent {
+
+    pub fn new<R: Into<String>>(
+        peer: net::SocketAddr, violation: Violation, rule: R)
+        -> SecurityEvent
+    {
+        SecurityEvent{
+            peer, violation, rule: rule.into(), direction: None,
+            filename: None,
+        }
+    }
+
+    pub fn with_direction(self, direction: Direction) -> SecurityEvent {
+        SecurityEvent{direction: Some(direction), ..self}
+    }
+
+    pub fn with_filename<F: Into<String>>(self, filename: F)
+                                          -> SecurityEvent {
+        SecurityEvent{filename: Some(filename.into()), ..self}
+    }
+
+    /// Send this to `sink`, if there is one.
+    pub fn report(self, sink: Option<&dyn EventSink>) {
+        if let Some(sink) = sink {
+            sink.event(Event::Security(self));
+        }
+    }
+
+}
+
+
+/// One line of `key=value` pairs, with strings quoted, e.g.
+/// `violation=path-traversal peer=10.0.0.1:1234 rule="has .."
+/// direction=read filename="../etc/passwd"`. This suits syslog, and
+/// SIEMs that parse key-value messages.
+impl fmt::Display for SecurityEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "violation={} peer={} rule={:?}",
+            self.violation, self.peer, self.rule)?;
+        if let Some(direction) = self.direction {
+            write!(f, " direction={}", direction)?;
+        }
+        if let Some(ref filename) = self.filename {
+            write!(f, " filename={:?}", filename)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// An `EventSink` that writes security events to a logger of their
+/// own, as warnings, and ignores all other events.
+///
+/// Give it a logger that goes somewhere separate from the transfer
+/// logs, like syslog's `authpriv` facility. To use it alongside another
+/// sink, such as an `audit::Auditor`, pass each event to both.
+#[derive(Clone,Debug)]
+pub struct SecurityLog {
+    logger: Logger,
+}
+
+
+impl SecurityLog {
+
+    pub fn new(logger: Logger) -> SecurityLog {
+        SecurityLog{logger}
+    }
+
+}
+
+
+impl EventSink for SecurityLog {
+
+    fn event(&self, event: Event) {
+        if let Event::Security(event) = event {
+            warn!(
+                self.logger, "Security: {}", event;
+                "violation" => event.violation.to_string(),
+                "peer" => event.peer.to_string(),
+                "rule" => event.rule.clone(),
+                "direction" => event.direction.map_or_else(
+                    String::new, |direction| direction.to_string()),
+                "filename" => event.filename.clone().unwrap_or_default());
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::net;
+
+    use super::{SecurityEvent, Violation};
+    use super::super::metrics::Direction;
+
+    #[test]
+    fn test_security_event_formats_as_key_value_pairs() {
+        let peer: net::SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let event = SecurityEvent::new(peer, Violation::AccessDenied, "r/o");
+        assert_eq!(
+            "violation=access-denied peer=10.0.0.1:1234 rule=\"r/o\"",
+            event.to_string());
+        let event = SecurityEvent::new(peer, Violation::PathTraversal, "..")
+            .with_direction(Direction::Read)
+            .with_filename("../etc/\"passwd\"");
+        assert_eq!(
+            "violation=path-traversal peer=10.0.0.1:1234 rule=\"..\" \
+             direction=read filename=\"../etc/\\\"passwd\\\"\"",
+            event.to_string());
+    }
+
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+
+/// A flag that a transfer checks to see if it should stop.
+///
+/// Clones share the same flag, so one can be handed to a transfer while
+/// another is kept to cancel it.
+#[derive(Clone,Debug,Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+
+impl CancellationToken {
+
+    pub fn new() -> CancellationToken {
+        CancellationToken(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Ask the transfer to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Has this token been cancelled?
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+}
+
+
+/// How many shards a `Registry` has by default.
+const SHARDS: usize = 16;
+
+
+/// A registry of in-progress transfers, keyed by peer address, through
+/// which they can be cancelled.
+///
+/// A peer's address includes its port, which is its transfer ID, so
+/// each transfer has its own key. Keys are spread over several
+/// independently locked shards so that transfers starting and ending at
+/// high rates do not all contend on a single lock.
+#[derive(Debug)]
+pub struct Registry {
+    shards: Box<[Mutex<HashMap<net::SocketAddr, CancellationToken>>]>,
+}
+
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::new()
+    }
+}
+
+
+impl Registry {
+
+    pub fn new() -> Registry {
+        Registry::with_shards(SHARDS)
+    }
+
+    /// A registry with `shards` independently locked shards. At least
+    /// one shard is always created.
+    pub fn with_shards(shards: usize) -> Registry {
+        Registry{
+            shards: (0..shards.max(1))
+                .map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    /// The shard in which `peer` is kept.
+    fn shard(&self, peer: &net::SocketAddr)
+             -> &Mutex<HashMap<net::SocketAddr, CancellationToken>> {
+        let mut hasher = DefaultHasher::new();
+        peer.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Register a transfer with `peer`. The transfer remains registered
+    /// until the returned `Session` is dropped.
+    pub fn register(&self, peer: net::SocketAddr) -> Session<'_> {
+        let token = CancellationToken::new();
+        self.shard(&peer).lock().unwrap().insert(peer, token.clone());
+        Session{registry: self, peer, token}
+    }
+
+    /// Cancel the transfer with `peer`. Returns `false` if there is no
+    /// such transfer.
+    pub fn cancel(&self, peer: net::SocketAddr) -> bool {
+        match self.shard(&peer).lock().unwrap().get(&peer) {
+            Some(token) => {
+                token.cancel();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Cancel all registered transfers.
+    pub fn cancel_all(&self) {
+        for shard in self.shards.iter() {
+            for token in shard.lock().unwrap().values() {
+                token.cancel();
+            }
+        }
+    }
+
+    /// The peers of all registered transfers.
+    pub fn peers(&self) -> Vec<net::SocketAddr> {
+        self.shards.iter()
+            .flat_map(|shard| shard.lock().unwrap().keys().cloned()
+                      .collect::<Vec<_>>())
+            .collect()
+    }
+
+}
+
+
+/// A transfer's registration in a `Registry`.
+#[derive(Debug)]
+pub struct Session<'a> {
+    registry: &'a Registry,
+    peer: net::SocketAddr,
+    token: CancellationToken,
+}
+
+
+impl<'a> Session<'a> {
+
+    /// The token with which this transfer can be cancelled.
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+}
+
+
+impl<'a> Drop for Session<'a> {
+    fn drop(&mut self) {
+        let mut sessions =
+            self.registry.shard(&self.peer).lock().unwrap();
+        // Only remove our own registration; another transfer with the
+        // same peer may have replaced it.
+        let ours = match sessions.get(&self.peer) {
+            Some(token) => Arc::ptr_eq(&token.0, &self.token.0),
+            None => false,
+        };
+        if ours {
+            sessions.remove(&self.peer);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::net;
+
+    use super::{CancellationToken, Registry};
+
+    #[test]
+    fn test_cancellation_token_clones_share_flag() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+        token.cancel();
+        assert!(clone.is_cancelled());
+    }
+
+    #[test]
+    fn test_registry_cancels_by_peer() {
+        let registry = Registry::new();
+        let one: net::SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let two: net::SocketAddr = "10.0.0.2:1234".parse().unwrap();
+        let session_one = registry.register(one);
+        let session_two = registry.register(two);
+        assert!(registry.cancel(one));
+        assert!(session_one.token().is_cancelled());
+        assert!(!session_two.token().is_cancelled());
+        registry.cancel_all();
+        assert!(session_two.token().is_cancelled());
+    }
+
+    #[test]
+    fn test_registry_forgets_dropped_sessions() {
+        let registry = Registry::new();
+        let peer: net::SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        {
+            let _session = registry.register(peer);
+            assert_eq!(vec![peer], registry.peers());
+        }
+        assert!(registry.peers().is_empty());
+        assert!(!registry.cancel(peer));
+    }
+
+    #[test]
+    fn test_registry_spreads_peers_over_shards() {
+        let registry = Registry::with_shards(4);
+        let peers: Vec<net::SocketAddr> = (0..64)
+            .map(|port| net::SocketAddr::from(([10, 0, 0, 1], 1000 + port)))
+            .collect();
+        let sessions: Vec<_> = peers.iter()
+            .map(|peer| registry.register(*peer)).collect();
+        let mut registered = registry.peers();
+        registered.sort();
+        assert_eq!(peers, registered);
+        assert!(registry.shards.iter()
+                .all(|shard| !shard.lock().unwrap().is_empty()));
+        registry.cancel_all();
+        assert!(sessions.iter().all(|s| s.token().is_cancelled()));
+        drop(sessions);
+        assert!(registry.peers().is_empty());
+    }
+
+    #[test]
+    fn test_registry_has_at_least_one_shard() {
+        let registry = Registry::with_shards(0);
+        let peer: net::SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let _session = registry.register(peer);
+        assert!(registry.cancel(peer));
+    }
+
+}
@@ -0,0 +1,392 @@
+use std::fmt;
+use std::io;
+use std::net;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time;
+
+use smoltcp::iface::{Interface, SocketHandle, SocketSet};
+use smoltcp::phy::Device;
+use smoltcp::socket::udp;
+use smoltcp::wire::IpEndpoint;
+
+use super::transport::Transport;
+
+
+/// Room for a datagram of the largest block size, and then some.
+const BUFFER_SIZE: usize = 65536;
+
+/// How many datagrams each socket can queue in either direction.
+const BUFFER_DATAGRAMS: usize = 16;
+
+/// Where ephemeral ports, for `bind(0)`, begin.
+const EPHEMERAL_PORTS: u16 = 49152;
+
+/// How long to wait between polls of the interface while receiving.
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(1);
+
+
+/// A smoltcp interface and its device, shared by the UDP sockets bound
+/// on it with `bind`.
+///
+/// A server binds port 69 and runs `serve_over` on it; its handler
+/// binds a new socket, with `bind(0)`, for each transfer and runs
+/// `rrq::serve_file_over` or `wrq::receive_over` on that. The interface
+/// is polled whenever a socket sends or waits to receive; call `poll`
+/// to drive it at other times.
+pub struct Stack<D: Device> {
+    state: Arc<Mutex<State<D>>>,
+}
+
+
+struct State<D: Device> {
+    iface: Interface,
+    device: D,
+    sockets: SocketSet<'static>,
+    ports: Vec<u16>,
+    next_port: u16,
+}
+
+
+impl<D: Device> State<D> {
+
+    fn poll(&mut self) -> bool {
+        let now = smoltcp::time::Instant::now();
+        let result = self.iface.poll(now, &mut self.device, &mut self.sockets);
+        result == smoltcp::iface::PollResult::SocketStateChanged
+    }
+
+}
+
+
+impl<D: Device> Clone for Stack<D> {
+    fn clone(&self) -> Stack<D> {
+        Stack{state: self.state.clone()}
+    }
+}
+
+
+impl<D: Device> fmt::Debug for Stack<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stack").finish()
+    }
+}
+
+
+impl<D: Device> Stack<D> {
+
+    pub fn new(iface: Interface, device: D) -> Stack<D> {
+        Stack{state: Arc::new(Mutex::new(State{
+            iface,
+            device,
+            sockets: SocketSet::new(Vec::new()),
+            ports: Vec::new(),
+            next_port: EPHEMERAL_PORTS,
+        }))}
+    }
+
+    /// Bind a UDP socket to `port` on every address of the interface.
+    /// Port 0 picks an unused ephemeral port.
+    pub fn bind(&self, port: u16) -> io::Result<UdpTransport<D>> {
+        let mut state = self.state.lock().unwrap();
+        let port = match port {
+            0 => ephemeral_port(&mut state)?,
+            port if state.ports.contains(&port) => return Err(
+                io::Error::new(io::ErrorKind::AddrInUse, "port in use")),
+            port => port,
+        };
+        let buffer = || udp::PacketBuffer::new(
+            vec![udp::PacketMetadata::EMPTY; BUFFER_DATAGRAMS],
+            vec![0u8; BUFFER_SIZE]);
+        let mut socket = udp::Socket::new(buffer(), buffer());
+        socket.bind(port).map_err(
+            |error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        let handle = state.sockets.add(socket);
+        state.ports.push(port);
+        Ok(UdpTransport{
+            state: self.state.clone(),
+            handle,
+            port,
+            peer: Mutex::new(None),
+        })
+    }
+
+    /// Poll the interface, returning true if any socket's state may
+    /// have changed.
+    pub fn poll(&self) -> bool {
+        self.state.lock().unwrap().poll()
+    }
+
+}
+
+
+fn ephemeral_port<D: Device>(state: &mut State<D>) -> io::Result<u16> {
+    for _ in EPHEMERAL_PORTS..=u16::MAX {
+        let port = state.next_port;
+        state.next_port = port.checked_add(1).unwrap_or(EPHEMERAL_PORTS);
+        if !state.ports.contains(&port) {
+            return Ok(port);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AddrInUse, "no ephemeral ports"))
+}
+
+
+/// A UDP socket on a smoltcp `Stack`. It is closed when dropped.
+pub struct UdpTransport<D: Device> {
+    state: Arc<Mutex<State<D>>>,
+    handle: SocketHandle,
+    port: u16,
+    peer: Mutex<Option<net::SocketAddr>>,
+}
+
+
+impl<D: Device> fmt::Debug for UdpTransport<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UdpTransport")
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+
+impl<D: Device> Drop for UdpTransport<D> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.sockets.remove(self.handle);
+        state.ports.retain(|&port| port != self.port);
+    }
+}
+
+
+impl<D: Device> UdpTransport<D> {
+
+    fn lock(&self) -> MutexGuard<'_, State<D>> {
+        self.state.lock().unwrap()
+    }
+
+    /// Receive a datagram, from `from` only if given, waiting until
+    /// `timeout` has passed in total.
+    fn receive(
+        &self, buf: &mut [u8], timeout: Option<time::Duration>,
+        from: Option<net::SocketAddr>)
+        -> io::Result<(usize, net::SocketAddr)>
+    {
+        let deadline = timeout.map(|timeout| time::Instant::now() + timeout);
+        loop {
+            {
+                let mut state = self.lock();
+                state.poll();
+                let socket = state.sockets.get_mut::<udp::Socket>(
+                    self.handle);
+                while let Ok((datagram, meta)) = socket.recv() {
+                    let src = net::SocketAddr::new(
+                        meta.endpoint.addr.into(), meta.endpoint.port);
+                    if from.is_none() || from == Some(src) {
+                        // Datagrams larger than `buf` are truncated, as
+                        // with a UDP socket.
+                        let size = buf.len().min(datagram.len());
+                        buf[..size].copy_from_slice(&datagram[..size]);
+                        return Ok((size, src));
+                    }
+                }
+            }
+            if let Some(deadline) = deadline {
+                if time::Instant::now() >= deadline {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut, "timed out"));
+                }
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+}
+
+
+impl<D: Device> Transport for UdpTransport<D> {
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match *self.peer.lock().unwrap() {
+            Some(addr) => self.send_to(buf, addr),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected, "not connected")),
+        }
+    }
+
+    fn send_to(&self, buf: &[u8], addr: net::SocketAddr) -> io::Result<usize> {
+        let mut state = self.lock();
+        let socket = state.sockets.get_mut::<udp::Socket>(self.handle);
+        let endpoint = IpEndpoint::from(addr);
+        socket.send_slice(buf, endpoint).map_err(|error| match error {
+            udp::SendError::BufferFull => io::Error::new(
+                io::ErrorKind::WouldBlock, "send buffer full"),
+            udp::SendError::Unaddressable => io::Error::new(
+                io::ErrorKind::AddrNotAvailable, "unaddressable"),
+        })?;
+        state.poll();
+        Ok(buf.len())
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<usize>
+    {
+        let peer = *self.peer.lock().unwrap();
+        self.receive(buf, timeout, peer).map(|(size, _)| size)
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<(usize, net::SocketAddr)>
+    {
+        self.receive(buf, timeout, None)
+    }
+
+    fn connect(&self, addr: net::SocketAddr) -> io::Result<()> {
+        *self.peer.lock().unwrap() = Some(addr);
+        Ok(())
+    }
+
+    /// The interface's first address, with this socket's port.
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        let state = self.lock();
+        let addr = match state.iface.ip_addrs().first() {
+            Some(cidr) => cidr.address().into(),
+            None => net::Ipv4Addr::UNSPECIFIED.into(),
+        };
+        Ok(net::SocketAddr::new(addr, self.port))
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::io;
+    use std::net;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time;
+
+    use smoltcp::iface::{Config, Interface};
+    use smoltcp::phy::{Loopback, Medium};
+    use smoltcp::wire::{HardwareAddress, IpAddress, IpCidr};
+
+    use super::Stack;
+    use super::super::Handler;
+    use super::super::logging;
+    use super::super::options::Options;
+    use super::super::packet::{BlockNum, Data, Filename, Packet, TransferMode};
+    use super::super::transport::Transport;
+    use super::super::wrq;
+
+    fn stack() -> Stack<Loopback> {
+        let mut device = Loopback::new(Medium::Ip);
+        let config = Config::new(HardwareAddress::Ip);
+        let now = smoltcp::time::Instant::now();
+        let mut iface = Interface::new(config, &mut device, now);
+        iface.update_ip_addrs(|addrs| {
+            addrs.push(IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8)).unwrap();
+        });
+        Stack::new(iface, device)
+    }
+
+    #[test]
+    fn test_send_and_receive() {
+        let stack = stack();
+        let one = stack.bind(0).unwrap();
+        let two = stack.bind(0).unwrap();
+        let addr = two.local_addr().unwrap();
+        assert_eq!("127.0.0.1".parse(), Ok(addr.ip()));
+        one.send_to(b"hello", addr).unwrap();
+        let mut buf = [0u8; 3];
+        let (size, src) = two.recv_from(&mut buf, None).unwrap();
+        assert_eq!(b"hel", &buf[..size]);
+        assert_eq!(one.local_addr().unwrap(), src);
+    }
+
+    #[test]
+    fn test_bind() {
+        let stack = stack();
+        let one = stack.bind(69).unwrap();
+        assert_eq!(
+            io::ErrorKind::AddrInUse, stack.bind(69).unwrap_err().kind());
+        drop(one);
+        stack.bind(69).unwrap();
+    }
+
+    #[test]
+    fn test_recv_filters_and_times_out() {
+        let stack = stack();
+        let one = stack.bind(0).unwrap();
+        let two = stack.bind(0).unwrap();
+        let three = stack.bind(0).unwrap();
+        one.connect(two.local_addr().unwrap()).unwrap();
+        three.send_to(b"stray", one.local_addr().unwrap()).unwrap();
+        let mut buf = [0u8; 8];
+        let timeout = Some(time::Duration::from_millis(10));
+        assert_eq!(
+            io::ErrorKind::TimedOut,
+            one.recv(&mut buf, timeout).unwrap_err().kind());
+        assert_eq!(
+            io::ErrorKind::NotConnected,
+            two.send(b"hello").unwrap_err().kind());
+    }
+
+    /// Receives uploads into memory, each over a new socket.
+    struct Uploads {
+        stack: Stack<Loopback>,
+        uploads: mpsc::Sender<Vec<u8>>,
+    }
+
+    impl Handler for Uploads {
+        fn handle_wrq(
+            &self, _local: net::SocketAddr, remote: net::SocketAddr,
+            _filename: Filename, txmode: TransferMode, options: Options)
+            -> Option<Packet<'_>>
+        {
+            let transport = self.stack.bind(0).unwrap();
+            let uploads = self.uploads.clone();
+            thread::spawn(move || {
+                let logger = logging::discard();
+                let mut sink = Vec::new();
+                wrq::receive_over(
+                    &transport, remote, &mut sink, txmode, options,
+                    &wrq::Config::new(), &logger).unwrap();
+                uploads.send(sink).unwrap();
+            });
+            None
+        }
+    }
+
+    #[test]
+    fn test_serve() {
+        let stack = stack();
+        let (uploads, received) = mpsc::channel();
+        let listener = stack.bind(69).unwrap();
+        let server = listener.local_addr().unwrap();
+        let handler = Uploads{stack: stack.clone(), uploads};
+        thread::spawn(move || {
+            let logger = logging::discard();
+            super::super::serve_over(&listener, &handler, &logger)
+        });
+        let client = stack.bind(0).unwrap();
+        let timeout = Some(time::Duration::from_secs(5));
+        let mut buf = [0u8; 64];
+        client.send_to(b"\0\x02upload\0octet\0", server).unwrap();
+        let (size, tid) = client.recv_from(&mut buf, timeout).unwrap();
+        assert_eq!(
+            Packet::Ack(BlockNum(0)), Packet::parse(&buf[..size]).unwrap());
+        assert_ne!(server, tid);
+        client.connect(tid).unwrap();
+        let size = Packet::Data(BlockNum(1), Data(b"data"))
+            .write(&mut buf).unwrap();
+        client.send(&buf[..size]).unwrap();
+        let size = client.recv(&mut buf, timeout).unwrap();
+        assert_eq!(
+            Packet::Ack(BlockNum(1)), Packet::parse(&buf[..size]).unwrap());
+        let timeout = time::Duration::from_secs(5);
+        assert_eq!(b"data".to_vec(), received.recv_timeout(timeout).unwrap());
+    }
+
+}
@@ -0,0 +1,191 @@
+use std::net;
+
+use proptest::collection::vec;
+use proptest::option;
+use proptest::prelude::*;
+use proptest::sample::select;
+
+use super::options::{Multicast, Options};
+use super::packet::{
+    BlockNum,
+    ErrorCode,
+    ErrorMessage,
+    Filename,
+    PacketOwned,
+    TransferMode,
+};
+
+
+/// ASCII without NUL, as error messages and unknown options must be.
+fn ascii() -> impl Strategy<Value = String> {
+    "[\x01-\x7f]{0,32}"
+}
+
+
+/// Any filename without NUL.
+pub fn filename() -> impl Strategy<Value = Filename> {
+    vec(1u8.., 0..64).prop_map(Filename::new)
+}
+
+
+pub fn transfer_mode() -> impl Strategy<Value = TransferMode> {
+    select(vec![TransferMode::NetASCII, TransferMode::Octet,
+                TransferMode::Mail])
+}
+
+
+pub fn block_num() -> impl Strategy<Value = BlockNum> {
+    any::<u16>().prop_map(BlockNum)
+}
+
+
+pub fn error_code() -> impl Strategy<Value = ErrorCode> {
+    select(vec![
+        ErrorCode::NotDefined, ErrorCode::FileNotFound,
+        ErrorCode::AccessViolation, ErrorCode::DiskFull,
+        ErrorCode::IllegalOperation, ErrorCode::UnknownTransferId,
+        ErrorCode::FileAlreadyExists, ErrorCode::NoSuchUser,
+        ErrorCode::BadOptions,
+    ])
+}
+
+
+/// Any ASCII message without NUL; line endings included.
+pub fn error_message() -> impl Strategy<Value = ErrorMessage> {
+    ascii().prop_map(ErrorMessage)
+}
+
+
+pub fn multicast() -> impl Strategy<Value = Multicast> {
+    let group = option::of((any::<u32>(), any::<u16>()).prop_map(
+        |(addr, port)| net::SocketAddrV4::new(addr.into(), port)));
+    prop_oneof![
+        Just(Multicast::Request),
+        (group, any::<bool>()).prop_map(
+            |(group, master)| Multicast::Ack{group, master}),
+    ]
+}
+
+
+/// Any combination of options with values in range. Unknown options
+/// are named `x-...` so that they are not mistaken for known options,
+/// and are distinct without regard to case.
+pub fn options() -> impl Strategy<Value = Options> {
+    let unknown = vec(("x-[\x01-\x7f]{0,16}", ascii()), 0..=3).prop_map(
+        |pairs| {
+            let mut unknown: Vec<(String, String)> = Vec::new();
+            for (name, value) in pairs {
+                let duplicate = unknown.iter().any(
+                    |(other, _)| other.eq_ignore_ascii_case(&name));
+                if !duplicate {
+                    unknown.push((name, value));
+                }
+            }
+            unknown
+        });
+    (
+        option::of(8u16..=65464),
+        option::of(1u8..),
+        option::of(any::<u64>()),
+        option::of(1u16..),
+        option::of(0u16..=1),
+        option::of(1u32..),
+        option::of(multicast()),
+        unknown,
+    ).prop_map(
+        |(blksize, timeout, tsize, windowsize, rollover, utimeout,
+          multicast, unknown)| {
+            let mut options = Options::new();
+            options.blksize = blksize;
+            options.timeout = timeout;
+            options.tsize = tsize;
+            options.windowsize = windowsize;
+            options.rollover = rollover;
+            options.utimeout = utimeout;
+            options.multicast = multicast;
+            options.unknown = unknown;
+            options
+        })
+}
+
+
+/// Any packet that survives a trip through `write` and `parse`,
+/// including those with unrecognised operation codes, which parse only
+/// when lenient.
+///
+/// ```
+/// # extern crate allenap_libtftp;
+/// # extern crate proptest;
+/// # use allenap_libtftp::packet::{Packet, Strictness};
+/// # use allenap_libtftp::strategy;
+/// # use proptest::strategy::{Strategy, ValueTree};
+/// # use proptest::test_runner::TestRunner;
+/// # fn main() {
+/// let mut runner = TestRunner::deterministic();
+/// let packet = strategy::packet().new_tree(&mut runner).unwrap().current();
+/// let buffer = packet.as_packet().to_vec().unwrap();
+/// let parsed = Packet::parse_with(&buffer, Strictness::Lenient).unwrap();
+/// assert_eq!(packet.as_packet(), parsed);
+/// # }
+/// ```
+pub fn packet() -> impl Strategy<Value = PacketOwned> {
+    prop_oneof![
+        (filename(), transfer_mode(), options()).prop_map(
+            |(filename, txmode, options)|
+            PacketOwned::Read(filename, txmode, options)),
+        (filename(), transfer_mode(), options()).prop_map(
+            |(filename, txmode, options)|
+            PacketOwned::Write(filename, txmode, options)),
+        (block_num(), vec(any::<u8>(), 0..=1024)).prop_map(
+            |(blkno, data)| PacketOwned::Data(blkno, data)),
+        block_num().prop_map(PacketOwned::Ack),
+        (error_code(), error_message()).prop_map(
+            |(code, message)| PacketOwned::Error(code, message)),
+        options().prop_map(PacketOwned::OAck),
+        (7u16.., vec(any::<u8>(), 0..64)).prop_map(
+            |(code, data)| PacketOwned::Unknown(code, data)),
+    ]
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use proptest::prelude::*;
+
+    use super::{options, packet};
+    use super::super::options::Options;
+    use super::super::packet::{Packet, Strictness};
+    use super::super::packetwriter::PacketWriter;
+
+    proptest! {
+
+        #[test]
+        fn test_packets_round_trip(packet in packet()) {
+            let buffer = packet.as_packet().to_vec().unwrap();
+            let parsed = Packet::parse_with(&buffer, Strictness::Lenient);
+            prop_assert_eq!(packet.as_packet(), parsed.unwrap());
+        }
+
+        #[test]
+        fn test_packets_encoded_len(packet in packet()) {
+            let packet = packet.as_packet();
+            prop_assert_eq!(
+                packet.to_vec().unwrap().len(), packet.encoded_len());
+        }
+
+        #[test]
+        fn test_options_round_trip(options in options()) {
+            let mut buffer = vec![0u8; options.encoded_len()];
+            let size = {
+                let mut writer = PacketWriter::new(&mut buffer);
+                options.write(&mut writer).unwrap();
+                writer.pos()
+            };
+            prop_assert_eq!(buffer.len(), size);
+            prop_assert_eq!(options, Options::parse(&buffer).unwrap());
+        }
+
+    }
+
+}
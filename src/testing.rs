@@ -0,0 +1,961 @@
+use std::fmt;
+use std::io;
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::time;
+
+use super::clock::Clock;
+use super::events::{Event, EventSink};
+use super::metrics::Metrics;
+use super::packet::ErrorCode;
+use super::transport::{Direction, Transport, WireObserver};
+
+
+type Datagram = (Vec<u8>, net::SocketAddr);
+
+/// Decides whether a datagram is delivered; see `MemoryTransport`.
+type Delivery = Box<dyn FnMut(&[u8]) -> bool + Send>;
+
+
+/// One end of an in-memory transport, created with `pair`.
+///
+/// Datagrams are delivered in order, immediately, and without loss, so
+/// transfers run deterministically and as fast as they can. Loss can
+/// be introduced with `drop_next` or `deliver_if` to exercise time-outs
+/// and retransmission. With a `MockClock`, set with `set_clock`,
+/// those time-outs take no time at all.
+///
+/// ```
+/// # use allenap_libtftp::testing::MemoryTransport;
+/// # use allenap_libtftp::transport::Transport;
+/// let (one, two) = MemoryTransport::pair();
+/// one.send_to(b"hello", two.local_addr().unwrap()).unwrap();
+/// let mut buf = [0u8; 8];
+/// let (size, _) = two.recv_from(&mut buf, None).unwrap();
+/// assert_eq!(b"hello", &buf[..size]);
+/// ```
+pub struct MemoryTransport {
+    addr: net::SocketAddr,
+    peer_addr: net::SocketAddr,
+    connected: Mutex<Option<net::SocketAddr>>,
+    inbox: Mutex<mpsc::Receiver<Datagram>>,
+    outbox: mpsc::Sender<Datagram>,
+    delivery: Mutex<Option<Delivery>>,
+    clock: Mutex<Option<Arc<dyn Clock>>>,
+}
+
+
+impl MemoryTransport {
+
+    /// Two transports connected to each other, at 127.0.0.1:1 and
+    /// 127.0.0.1:2 respectively.
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        MemoryTransport::pair_at(
+            ([127, 0, 0, 1], 1).into(), ([127, 0, 0, 1], 2).into())
+    }
+
+    /// Two transports connected to each other, at the given addresses.
+    pub fn pair_at(one: net::SocketAddr, two: net::SocketAddr)
+        -> (MemoryTransport, MemoryTransport)
+    {
+        let (to_two, from_one) = mpsc::channel();
+        let (to_one, from_two) = mpsc::channel();
+        (MemoryTransport::new(one, two, from_two, to_two),
+         MemoryTransport::new(two, one, from_one, to_one))
+    }
+
+    fn new(
+        addr: net::SocketAddr, peer_addr: net::SocketAddr,
+        inbox: mpsc::Receiver<Datagram>, outbox: mpsc::Sender<Datagram>)
+        -> MemoryTransport
+    {
+        MemoryTransport{
+            addr,
+            peer_addr,
+            connected: Mutex::new(None),
+            inbox: Mutex::new(inbox),
+            outbox,
+            delivery: Mutex::new(None),
+            clock: Mutex::new(None),
+        }
+    }
+
+    /// Wait for datagrams by sleeping on `clock` for the whole time-out
+    /// and then looking once, rather than waiting in real time.
+    ///
+    /// With a `MockClock` a time-out expires at once if nothing has
+    /// been delivered, so the other end must have sent everything it's
+    /// going to before this end waits: script it on the same thread, or
+    /// leave it silent.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.clock.lock().unwrap() = Some(clock);
+    }
+
+    /// Silently drop the next `count` datagrams sent from this end.
+    pub fn drop_next(&self, count: usize) {
+        let mut remaining = count;
+        self.deliver_if(move |_| {
+            if remaining == 0 {
+                true
+            }
+            else {
+                remaining -= 1;
+                false
+            }
+        });
+    }
+
+    /// Deliver only those datagrams sent from this end for which
+    /// `predicate` returns true; the rest are silently dropped. This
+    /// replaces any earlier `drop_next` or `deliver_if`.
+    pub fn deliver_if<F>(&self, predicate: F)
+        where F: FnMut(&[u8]) -> bool + Send + 'static
+    {
+        *self.delivery.lock().unwrap() = Some(Box::new(predicate));
+    }
+
+    fn deliver(&self, buf: &[u8]) -> bool {
+        match *self.delivery.lock().unwrap() {
+            Some(ref mut predicate) => predicate(buf),
+            None => true,
+        }
+    }
+
+}
+
+
+impl fmt::Debug for MemoryTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MemoryTransport")
+            .field("addr", &self.addr)
+            .field("peer_addr", &self.peer_addr)
+            .finish()
+    }
+}
+
+
+impl Transport for MemoryTransport {
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match *self.connected.lock().unwrap() {
+            Some(addr) => self.send_to(buf, addr),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected, "not connected")),
+        }
+    }
+
+    /// Datagrams to addresses other than the other end are lost, as
+    /// they might be on a real network.
+    fn send_to(&self, buf: &[u8], addr: net::SocketAddr) -> io::Result<usize> {
+        if addr == self.peer_addr && self.deliver(buf) {
+            // The other end may have gone; that's loss too.
+            let _ = self.outbox.send((buf.to_vec(), self.addr));
+        }
+        Ok(buf.len())
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<usize>
+    {
+        let connected = *self.connected.lock().unwrap();
+        loop {
+            let (size, src) = self.recv_from(buf, timeout)?;
+            if connected.is_none() || connected == Some(src) {
+                return Ok(size);
+            }
+        }
+    }
+
+    /// Datagrams larger than `buf` are truncated, as with UDP.
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<(usize, net::SocketAddr)>
+    {
+        let inbox = self.inbox.lock().unwrap();
+        let clock = self.clock.lock().unwrap().clone();
+        let (datagram, src) = match (timeout, clock) {
+            (Some(timeout), Some(clock)) => match inbox.try_recv() {
+                Ok(datagram) => datagram,
+                Err(mpsc::TryRecvError::Empty) => {
+                    clock.sleep(timeout);
+                    inbox.try_recv().map_err(|error| match error {
+                        mpsc::TryRecvError::Empty => io::Error::new(
+                            io::ErrorKind::TimedOut, "timed out"),
+                        mpsc::TryRecvError::Disconnected => disconnected(),
+                    })?
+                },
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    return Err(disconnected());
+                },
+            },
+            (Some(timeout), None) => inbox.recv_timeout(timeout).map_err(
+                |error| match error {
+                    mpsc::RecvTimeoutError::Timeout => io::Error::new(
+                        io::ErrorKind::TimedOut, "timed out"),
+                    mpsc::RecvTimeoutError::Disconnected => disconnected(),
+                })?,
+            (None, _) => inbox.recv().map_err(|_| disconnected())?,
+        };
+        let size = buf.len().min(datagram.len());
+        buf[..size].copy_from_slice(&datagram[..size]);
+        Ok((size, src))
+    }
+
+    fn connect(&self, addr: net::SocketAddr) -> io::Result<()> {
+        *self.connected.lock().unwrap() = Some(addr);
+        Ok(())
+    }
+
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        Ok(self.addr)
+    }
+
+}
+
+
+fn disconnected() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "other end dropped")
+}
+
+
+/// A `Clock` that stands still until advanced, by `advance` or by
+/// `sleep`, which returns at once. Clones share the same time.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use allenap_libtftp::clock::Clock;
+/// # use allenap_libtftp::testing::MockClock;
+/// let clock = MockClock::new();
+/// let start = clock.now();
+/// clock.sleep(Duration::from_secs(60));
+/// assert_eq!(Duration::from_secs(60), clock.now() - start);
+/// ```
+#[derive(Clone,Debug)]
+pub struct MockClock {
+    start: time::Instant,
+    elapsed: Arc<Mutex<time::Duration>>,
+}
+
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+
+impl MockClock {
+
+    pub fn new() -> MockClock {
+        MockClock{
+            start: time::Instant::now(),
+            elapsed: Arc::new(Mutex::new(time::Duration::from_secs(0))),
+        }
+    }
+
+    /// Move time forward by `duration`.
+    pub fn advance(&self, duration: time::Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    /// How far time has moved since this clock was created.
+    pub fn elapsed(&self) -> time::Duration {
+        *self.elapsed.lock().unwrap()
+    }
+
+}
+
+
+impl Clock for MockClock {
+
+    fn now(&self) -> time::Instant {
+        self.start + self.elapsed()
+    }
+
+    fn sleep(&self, duration: time::Duration) {
+        self.advance(duration)
+    }
+
+}
+
+
+/// A `WireObserver` that keeps every datagram it's shown, for tests to
+/// inspect.
+#[derive(Debug,Default)]
+pub struct WireLog {
+    datagrams: Mutex<Vec<(Direction, net::SocketAddr, Vec<u8>)>>,
+}
+
+
+impl WireLog {
+
+    pub fn new() -> WireLog {
+        WireLog::default()
+    }
+
+    /// The datagrams seen so far, in order.
+    pub fn datagrams(&self) -> Vec<(Direction, net::SocketAddr, Vec<u8>)> {
+        self.datagrams.lock().unwrap().clone()
+    }
+
+}
+
+
+impl WireObserver for WireLog {
+    fn datagram(
+        &self, direction: Direction, peer: net::SocketAddr, bytes: &[u8])
+    {
+        self.datagrams.lock().unwrap().push((direction, peer, bytes.to_vec()));
+    }
+}
+
+/// An `EventSink` that keeps every event it's sent, for tests to
+/// inspect.
+#[derive(Debug,Default)]
+pub struct EventLog {
+    events: Mutex<Vec<Event>>,
+}
+
+
+impl EventLog {
+
+    pub fn new() -> EventLog {
+        EventLog::default()
+    }
+
+    /// The events sent so far, in order.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+}
+
+
+impl EventSink for EventLog {
+    fn event(&self, event: Event) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+
+/// `Metrics` that keeps a count of everything it's told, for tests to
+/// inspect.
+#[derive(Debug,Default)]
+pub struct MetricsLog {
+    counts: Mutex<MetricsCounts>,
+}
+
+
+/// What a `MetricsLog` has been told so far.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct MetricsCounts {
+    pub started: u64,
+    /// The bytes and duration of each completed transfer, in order.
+    pub completed: Vec<(u64, time::Duration)>,
+    pub retransmits: u64,
+    pub malformed: u64,
+    pub io_errors: u64,
+    /// The code of each `ERROR` packet sent, in order.
+    pub errors: Vec<ErrorCode>,
+}
+
+
+impl MetricsLog {
+
+    pub fn new() -> MetricsLog {
+        MetricsLog::default()
+    }
+
+    pub fn counts(&self) -> MetricsCounts {
+        self.counts.lock().unwrap().clone()
+    }
+
+}
+
+
+impl Metrics for MetricsLog {
+
+    fn transfer_started(&self) {
+        self.counts.lock().unwrap().started += 1;
+    }
+
+    fn transfer_completed(&self, bytes: u64, duration: time::Duration) {
+        self.counts.lock().unwrap().completed.push((bytes, duration));
+    }
+
+    fn retransmit(&self) {
+        self.counts.lock().unwrap().retransmits += 1;
+    }
+
+    fn malformed_packet(&self) {
+        self.counts.lock().unwrap().malformed += 1;
+    }
+
+    fn error(&self, code: ErrorCode) {
+        self.counts.lock().unwrap().errors.push(code);
+    }
+
+    fn io_error(&self) {
+        self.counts.lock().unwrap().io_errors += 1;
+    }
+
+}
+
+/// Faults for a `FaultyTransport` to inject into the datagrams it sends.
+#[derive(Clone,Debug)]
+pub struct Faults {
+    /// Drop every Nth datagram.
+    pub drop_every: Option<usize>,
+    /// The probability of a datagram being sent twice.
+    pub duplicate: f64,
+    /// The probability of a datagram being held back, to be sent after
+    /// up to `reorder_window` later datagrams.
+    pub reorder: f64,
+    /// How many later datagrams a held back datagram may wait for.
+    pub reorder_window: usize,
+    /// The probability of one byte in a datagram being flipped.
+    pub corrupt: f64,
+    /// Seeds the random choices, so that a run can be repeated exactly.
+    pub seed: u64,
+}
+
+
+impl Default for Faults {
+    fn default() -> Faults {
+        Faults::new()
+    }
+}
+
+
+impl Faults {
+
+    /// No faults at all.
+    pub fn new() -> Faults {
+        Faults{
+            drop_every: None,
+            duplicate: 0.0,
+            reorder: 0.0,
+            reorder_window: 0,
+            corrupt: 0.0,
+            seed: 0,
+        }
+    }
+
+}
+
+
+/// Wraps another transport to inject faults into the datagrams it sends.
+///
+/// Only outgoing datagrams are affected, so wrap both ends of a pair to
+/// disrupt traffic in both directions. Held back datagrams are sent
+/// only when later datagrams are sent; with TFTP's time-outs and
+/// retransmissions that is soon enough.
+///
+/// ```
+/// # use allenap_libtftp::testing::{Faults, FaultyTransport, MemoryTransport};
+/// # use allenap_libtftp::transport::Transport;
+/// let (one, two) = MemoryTransport::pair();
+/// let mut faults = Faults::new();
+/// faults.drop_every = Some(2);
+/// let one = FaultyTransport::new(one, faults);
+/// let addr = two.local_addr().unwrap();
+/// for datagram in &[b"a", b"b", b"c"] {
+///     one.send_to(*datagram, addr).unwrap();
+/// }
+/// let mut buf = [0u8; 8];
+/// two.recv(&mut buf, None).unwrap();
+/// assert_eq!(b'a', buf[0]);
+/// two.recv(&mut buf, None).unwrap();
+/// assert_eq!(b'c', buf[0]);
+/// ```
+#[derive(Debug)]
+pub struct FaultyTransport<T> {
+    inner: T,
+    faults: Faults,
+    state: Mutex<FaultState>,
+}
+
+
+#[derive(Debug)]
+struct FaultState {
+    rng: Rng,
+    sent: usize,
+    held: Vec<Held>,
+}
+
+
+/// A datagram held back; `addr` is `None` for datagrams from `send`.
+#[derive(Debug)]
+struct Held {
+    datagram: Vec<u8>,
+    addr: Option<net::SocketAddr>,
+    after: usize,
+}
+
+
+impl<T: Transport> FaultyTransport<T> {
+
+    pub fn new(inner: T, faults: Faults) -> FaultyTransport<T> {
+        let rng = Rng(faults.seed);
+        FaultyTransport{
+            inner,
+            faults,
+            state: Mutex::new(FaultState{rng, sent: 0, held: Vec::new()}),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn inject(&self, buf: &[u8], addr: Option<net::SocketAddr>)
+        -> io::Result<usize>
+    {
+        let window = self.faults.reorder_window;
+        let mut state = self.state.lock().unwrap();
+        state.sent += 1;
+        for held in &mut state.held {
+            held.after -= 1;
+        }
+        let (released, held) = state.held.drain(..).partition(
+            |held| held.after == 0);
+        state.held = held;
+        let dropped = match self.faults.drop_every {
+            Some(every) => state.sent.is_multiple_of(every),
+            None => false,
+        };
+        if !dropped {
+            let mut datagram = buf.to_vec();
+            if !datagram.is_empty() && state.rng.chance(self.faults.corrupt) {
+                let index = state.rng.below(datagram.len());
+                datagram[index] = !datagram[index];
+            }
+            let copies = if state.rng.chance(self.faults.duplicate) {
+                2
+            }
+            else {
+                1
+            };
+            for _ in 0..copies {
+                if window > 0 && state.rng.chance(self.faults.reorder) {
+                    let after = 1 + state.rng.below(window);
+                    state.held.push(
+                        Held{datagram: datagram.clone(), addr, after});
+                }
+                else {
+                    self.forward(&datagram, addr)?;
+                }
+            }
+        }
+        for held in released {
+            self.forward(&held.datagram, held.addr)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn forward(&self, buf: &[u8], addr: Option<net::SocketAddr>)
+        -> io::Result<usize>
+    {
+        match addr {
+            Some(addr) => self.inner.send_to(buf, addr),
+            None => self.inner.send(buf),
+        }
+    }
+
+}
+
+
+impl<T: Transport> Transport for FaultyTransport<T> {
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inject(buf, None)
+    }
+
+    fn send_to(&self, buf: &[u8], addr: net::SocketAddr) -> io::Result<usize> {
+        self.inject(buf, Some(addr))
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<usize>
+    {
+        self.inner.recv(buf, timeout)
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<(usize, net::SocketAddr)>
+    {
+        self.inner.recv_from(buf, timeout)
+    }
+
+    fn connect(&self, addr: net::SocketAddr) -> io::Result<()> {
+        self.inner.connect(addr)
+    }
+
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.inner.local_addr()
+    }
+
+}
+
+
+/// SplitMix64; plenty for choosing faults, and needs no dependencies.
+#[derive(Debug)]
+struct Rng(u64);
+
+
+impl Rng {
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// True with the given probability.
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 &&
+            ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+
+    /// A number in `0..limit`.
+    fn below(&mut self, limit: usize) -> usize {
+        (self.next() % limit as u64) as usize
+    }
+
+}
+
+
+/// A scratch directory for a test, named for `name` and this process,
+/// and removed when dropped.
+#[cfg(test)]
+pub(crate) struct Scratch(std::path::PathBuf);
+
+
+#[cfg(test)]
+impl Scratch {
+
+    pub(crate) fn new(name: &str) -> Scratch {
+        let path = std::env::temp_dir().join(
+            format!("libtftp-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Scratch(std::fs::canonicalize(path).unwrap())
+    }
+
+    /// The path of `name` in the directory.
+    pub(crate) fn join<P: AsRef<std::path::Path>>(&self, name: P)
+                                                 -> std::path::PathBuf {
+        self.0.join(name)
+    }
+
+}
+
+
+#[cfg(test)]
+impl AsRef<std::path::Path> for Scratch {
+    fn as_ref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+
+#[cfg(test)]
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::io;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time;
+
+    use super::{Faults, FaultyTransport, MemoryTransport, MockClock};
+    use super::super::logging;
+    use super::super::options::Options;
+    use super::super::packet::{BlockNum, Data, Packet, TransferMode};
+    use super::super::transport::Transport;
+    use super::super::progress::TransferStats;
+    use super::super::wrq;
+
+    #[test]
+    fn test_pair() {
+        let (one, two) = MemoryTransport::pair();
+        one.connect(two.local_addr().unwrap()).unwrap();
+        one.send(b"hello").unwrap();
+        let mut buf = [0u8; 3];
+        let (size, src) = two.recv_from(&mut buf, None).unwrap();
+        assert_eq!(b"hel", &buf[..size]);
+        assert_eq!(one.local_addr().unwrap(), src);
+    }
+
+    #[test]
+    fn test_send_requires_connect() {
+        let (one, _two) = MemoryTransport::pair();
+        assert_eq!(
+            io::ErrorKind::NotConnected,
+            one.send(b"hello").unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_drop_next() {
+        let (one, two) = MemoryTransport::pair();
+        let addr = two.local_addr().unwrap();
+        one.drop_next(2);
+        for datagram in &[b"a", b"b", b"c"] {
+            one.send_to(*datagram, addr).unwrap();
+        }
+        let mut buf = [0u8; 8];
+        assert_eq!(1, two.recv(&mut buf, None).unwrap());
+        assert_eq!(b'c', buf[0]);
+        let timeout = Some(time::Duration::from_millis(0));
+        assert_eq!(
+            io::ErrorKind::TimedOut,
+            two.recv(&mut buf, timeout).unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_deliver_if() {
+        let (one, two) = MemoryTransport::pair();
+        let addr = two.local_addr().unwrap();
+        one.deliver_if(|datagram| datagram != b"b");
+        for datagram in &[b"a", b"b", b"c"] {
+            one.send_to(*datagram, addr).unwrap();
+        }
+        let mut buf = [0u8; 8];
+        two.recv(&mut buf, None).unwrap();
+        assert_eq!(b'a', buf[0]);
+        two.recv(&mut buf, None).unwrap();
+        assert_eq!(b'c', buf[0]);
+    }
+
+    #[test]
+    fn test_recv_after_other_end_dropped() {
+        let (one, two) = MemoryTransport::pair();
+        drop(two);
+        let mut buf = [0u8; 8];
+        assert_eq!(
+            io::ErrorKind::ConnectionAborted,
+            one.recv(&mut buf, None).unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_mock_clock_expires_time_outs() {
+        let (one, two) = MemoryTransport::pair();
+        let clock = MockClock::new();
+        one.set_clock(Arc::new(clock.clone()));
+        let timeout = Some(time::Duration::from_secs(8));
+        let mut buf = [0u8; 8];
+        two.send_to(b"hello", one.local_addr().unwrap()).unwrap();
+        let (size, _) = one.recv_from(&mut buf, timeout).unwrap();
+        assert_eq!(b"hello", &buf[..size]);
+        assert_eq!(time::Duration::from_secs(0), clock.elapsed());
+        assert_eq!(
+            io::ErrorKind::TimedOut,
+            one.recv_from(&mut buf, timeout).unwrap_err().kind());
+        assert_eq!(time::Duration::from_secs(8), clock.elapsed());
+    }
+
+    #[test]
+    fn test_faulty_drop_every() {
+        let (one, two) = MemoryTransport::pair();
+        let mut faults = Faults::new();
+        faults.drop_every = Some(2);
+        let one = FaultyTransport::new(one, faults);
+        let addr = two.local_addr().unwrap();
+        for datagram in &[b"a", b"b", b"c", b"d", b"e"] {
+            one.send_to(*datagram, addr).unwrap();
+        }
+        let mut buf = [0u8; 8];
+        for expected in b"ace" {
+            two.recv(&mut buf, None).unwrap();
+            assert_eq!(*expected, buf[0]);
+        }
+    }
+
+    #[test]
+    fn test_faulty_reorder_holds_back_datagrams() {
+        let (one, two) = MemoryTransport::pair();
+        let mut faults = Faults::new();
+        faults.reorder = 1.0;
+        faults.reorder_window = 1;
+        let one = FaultyTransport::new(one, faults);
+        let addr = two.local_addr().unwrap();
+        let timeout = Some(time::Duration::from_millis(0));
+        let mut buf = [0u8; 8];
+        one.send_to(b"a", addr).unwrap();
+        assert_eq!(
+            io::ErrorKind::TimedOut,
+            two.recv(&mut buf, timeout).unwrap_err().kind());
+        // Each datagram is released by the next, and held in its place.
+        one.send_to(b"b", addr).unwrap();
+        two.recv(&mut buf, timeout).unwrap();
+        assert_eq!(b'a', buf[0]);
+    }
+
+    #[test]
+    fn test_faulty_is_repeatable() {
+        let mut faults = Faults::new();
+        faults.duplicate = 0.5;
+        faults.corrupt = 0.5;
+        faults.seed = 1234;
+        let received = || {
+            let (one, two) = MemoryTransport::pair();
+            let one = FaultyTransport::new(one, faults.clone());
+            let addr = two.local_addr().unwrap();
+            for datagram in &[b"a", b"b", b"c", b"d", b"e"] {
+                one.send_to(*datagram, addr).unwrap();
+            }
+            drop(one);
+            let mut received = Vec::new();
+            let mut buf = [0u8; 8];
+            while two.recv(&mut buf, None).is_ok() {
+                received.push(buf[0]);
+            }
+            received
+        };
+        assert_eq!(received(), received());
+    }
+
+    /// Upload `payload` to `wrq::receive_over` with `faults` injected
+    /// in both directions. The client is simple but, like the receiver,
+    /// retransmits after time-outs and ignores what it doesn't expect.
+    fn upload(payload: &[u8], faults: &Faults)
+        -> (wrq::Result<TransferStats>, Vec<u8>)
+    {
+        let (server, client) = MemoryTransport::pair();
+        let peer = client.local_addr().unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        let server = FaultyTransport::new(server, faults.clone());
+        let client = FaultyTransport::new(
+            client, Faults{seed: faults.seed + 1, ..faults.clone()});
+        let mut options = Options::new();
+        options.utimeout = Some(10_000);
+        let receiver = thread::spawn(move || {
+            let logger = logging::discard();
+            let mut sink = Vec::new();
+            let result = wrq::receive_over(
+                &server, peer, &mut sink, TransferMode::Octet, options,
+                &wrq::Config::new(), &logger);
+            (result, sink)
+        });
+        let timeout = Some(time::Duration::from_millis(10));
+        let mut buf = [0u8; 516];
+        // Wait for the OACK; the receiver retransmits it if it's lost.
+        'oack: loop {
+            match client.recv(&mut buf, timeout) {
+                Ok(size) => if let Ok(Packet::OAck(..)) =
+                    Packet::parse(&buf[..size]) {
+                    break 'oack;
+                },
+                Err(ref error) if error.kind() == io::ErrorKind::TimedOut
+                    => continue,
+                Err(_) => return receiver.join().unwrap(),
+            }
+        }
+        let mut blocks: Vec<&[u8]> = payload.chunks(512).collect();
+        if payload.len().is_multiple_of(512) {
+            blocks.push(&[]);
+        }
+        'blocks: for (index, block) in blocks.iter().enumerate() {
+            let blkno = BlockNum(index as u16 + 1);
+            let mut bufout = [0u8; 516];
+            let size = Packet::Data(blkno, Data(block))
+                .write(&mut bufout).unwrap();
+            client.send(&bufout[..size]).unwrap();
+            let mut timeouts = 0;
+            loop {
+                match client.recv(&mut buf, timeout) {
+                    Ok(size) => match Packet::parse(&buf[..size]) {
+                        Ok(Packet::Ack(acked)) if acked == blkno => break,
+                        Ok(Packet::Error(..)) => break 'blocks,
+                        _ => {},
+                    },
+                    Err(ref error) if error.kind() == io::ErrorKind::TimedOut
+                        && timeouts < 8 => {
+                        timeouts += 1;
+                        client.send(&bufout[..size]).unwrap();
+                    },
+                    Err(_) => break 'blocks,
+                }
+            }
+        }
+        receiver.join().unwrap()
+    }
+
+    fn payload() -> Vec<u8> {
+        (0..3000).map(|n| n as u8).collect()
+    }
+
+    #[test]
+    fn test_upload_without_faults() {
+        let payload = payload();
+        let (result, sink) = upload(&payload, &Faults::new());
+        result.unwrap();
+        assert_eq!(payload, sink);
+    }
+
+    #[test]
+    fn test_upload_with_loss() {
+        let payload = payload();
+        let mut faults = Faults::new();
+        faults.drop_every = Some(3);
+        let (result, sink) = upload(&payload, &faults);
+        result.unwrap();
+        assert_eq!(payload, sink);
+    }
+
+    #[test]
+    fn test_upload_with_duplication() {
+        let payload = payload();
+        let mut faults = Faults::new();
+        faults.duplicate = 0.5;
+        for seed in 0..10 {
+            faults.seed = seed;
+            let (result, sink) = upload(&payload, &faults);
+            result.unwrap();
+            assert_eq!(payload, sink);
+        }
+    }
+
+    #[test]
+    fn test_upload_with_reordering() {
+        let payload = payload();
+        let mut faults = Faults::new();
+        faults.reorder = 0.3;
+        faults.reorder_window = 3;
+        faults.duplicate = 0.2;
+        for seed in 0..10 {
+            faults.seed = seed;
+            let (result, sink) = upload(&payload, &faults);
+            result.unwrap();
+            assert_eq!(payload, sink);
+        }
+    }
+
+    #[test]
+    fn test_upload_with_corruption() {
+        // TFTP relies on UDP's checksum, so corruption can go unnoticed
+        // or abort the transfer. Either way the receiver must finish.
+        let payload = payload();
+        let mut faults = Faults::new();
+        faults.corrupt = 0.2;
+        for seed in 0..10 {
+            faults.seed = seed;
+            match upload(&payload, &faults) {
+                (Ok(_), sink) => assert_eq!(payload.len(), sink.len()),
+                (Err(wrq::Error::Aborted(..)), _) => {},
+                (Err(wrq::Error::TimedOut), _) => {},
+                (Err(error), _) => panic!("Unexpected error: {}", error),
+            }
+        }
+    }
+
+}
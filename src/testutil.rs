@@ -0,0 +1,599 @@
+use std::io;
+use std::net;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time;
+
+use super::{Handler, serve_over};
+use super::options::Options;
+use super::progress::TransferStats;
+use super::packet::{
+    BlockNum,
+    Data,
+    ErrorCode,
+    ErrorMessage,
+    Filename,
+    Packet,
+    TransferMode,
+};
+use super::transport::Transport;
+
+
+/// How long the listener waits between checks that it has been stopped.
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(50);
+
+/// Large enough for a `DATA` packet of the largest block size.
+const MAX_PACKET: usize = 4 + 65464;
+
+
+/// A `serve` loop running in the background on `127.0.0.1`, at a port
+/// chosen by the system. It is stopped when dropped.
+///
+/// ```
+/// # use allenap_libtftp::Handler;
+/// # use allenap_libtftp::packet::{ErrorCode, TransferMode};
+/// # use allenap_libtftp::options::Options;
+/// # use allenap_libtftp::testutil::Server;
+/// struct ReadOnly;
+/// impl Handler for ReadOnly {}
+///
+/// let server = Server::start(ReadOnly).unwrap();
+/// let transfer = server.client().write(
+///     "kernel", TransferMode::Octet, Options::new(), b"data").unwrap();
+/// transfer.assert_rejected(ErrorCode::AccessViolation);
+/// ```
+#[derive(Debug)]
+pub struct Server {
+    addr: net::SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<io::Result<()>>>,
+}
+
+
+impl Server {
+
+    /// Bind to `127.0.0.1:0` and serve requests with `handler` in a new
+    /// thread. Logging is discarded.
+    pub fn start<H>(handler: H) -> io::Result<Server>
+        where H: Handler + Send + 'static
+    {
+        let socket = net::UdpSocket::bind(("127.0.0.1", 0))?;
+        let addr = socket.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let listener = Listener{socket, stop: stop.clone()};
+        let thread = thread::spawn(move || {
+            let logger = super::logging::discard();
+            serve_over(&listener, &handler, &logger)
+        });
+        Ok(Server{addr, stop, thread: Some(thread)})
+    }
+
+    /// The address the server is bound to.
+    pub fn addr(&self) -> net::SocketAddr {
+        self.addr
+    }
+
+    /// The port the server is bound to.
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// A client for this server, with default settings.
+    pub fn client(&self) -> Client {
+        Client::new(self.addr)
+    }
+
+}
+
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            // The loop ends with an error once stopped; that's expected.
+            let _ = thread.join();
+        }
+    }
+}
+
+
+/// The server's socket, which stops receiving once `stop` is set.
+struct Listener {
+    socket: net::UdpSocket,
+    stop: Arc<AtomicBool>,
+}
+
+
+impl Transport for Listener {
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        Transport::send(&self.socket, buf)
+    }
+
+    fn send_to(&self, buf: &[u8], addr: net::SocketAddr) -> io::Result<usize> {
+        Transport::send_to(&self.socket, buf, addr)
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<usize>
+    {
+        Transport::recv(&self.socket, buf, timeout)
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<(usize, net::SocketAddr)>
+    {
+        if timeout.is_some() {
+            return Transport::recv_from(&self.socket, buf, timeout);
+        }
+        loop {
+            if self.stop.load(Ordering::SeqCst) {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted, "server stopped"));
+            }
+            let result = Transport::recv_from(
+                &self.socket, buf, Some(POLL_INTERVAL));
+            match result {
+                Err(ref error) if timed_out(error) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    fn connect(&self, addr: net::SocketAddr) -> io::Result<()> {
+        Transport::connect(&self.socket, addr)
+    }
+
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        Transport::local_addr(&self.socket)
+    }
+
+}
+
+
+/// A simple client that runs a single transfer at a time, in lockstep,
+/// and reports how it went.
+#[derive(Clone,Debug)]
+pub struct Client {
+    /// Where requests are sent.
+    pub server: net::SocketAddr,
+    /// How long to wait for each reply before sending again.
+    pub timeout: time::Duration,
+    /// How many times to send again before giving up.
+    pub retransmits: u8,
+}
+
+
+impl Client {
+
+    pub fn new(server: net::SocketAddr) -> Client {
+        Client{
+            server,
+            timeout: time::Duration::from_secs(1),
+            retransmits: 5,
+        }
+    }
+
+    /// Download `filename`. Data in `netascii` mode is returned as it
+    /// arrived, without conversion.
+    pub fn read<B: Into<Vec<u8>>>(
+        &self, filename: B, txmode: TransferMode, options: Options)
+        -> io::Result<Transfer>
+    {
+        let mut session = Session::new(self)?;
+        let filename = Filename::new(filename);
+        session.send(&Packet::Read(filename, txmode, options))?;
+        let mut blksize = 512;
+        let mut expected = BlockNum(1);
+        let mut data = Vec::new();
+        let mut buf = vec![0u8; MAX_PACKET];
+        loop {
+            let size = match session.recv(&mut buf)? {
+                Some(size) => size,
+                None => return Ok(session.finish(Outcome::TimedOut)),
+            };
+            match Packet::parse(&buf[..size]) {
+                Ok(Packet::OAck(options)) if expected == BlockNum(1) => {
+                    blksize = options.blksize.map_or(512, usize::from);
+                    session.options = Some(options);
+                    session.send(&Packet::Ack(BlockNum(0)))?;
+                },
+                Ok(Packet::Data(blkno, Data(block))) => {
+                    if blkno == expected {
+                        data.extend_from_slice(block);
+                        session.bytes += block.len() as u64;
+                        session.blocks += 1;
+                        session.send(&Packet::Ack(blkno))?;
+                        if block.len() < blksize {
+                            let outcome = Outcome::Completed(data);
+                            return Ok(session.finish(outcome));
+                        }
+                        expected = expected.next();
+                    }
+                    else if blkno == expected.prev() {
+                        // Our acknowledgement was lost; send it again.
+                        session.resend()?;
+                    }
+                },
+                Ok(Packet::Error(code, ErrorMessage(message))) => {
+                    let outcome = Outcome::Rejected(code, message);
+                    return Ok(session.finish(outcome));
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// Upload `data` as `filename`. Data in `netascii` mode is sent as
+    /// given, without conversion.
+    pub fn write<B: Into<Vec<u8>>>(
+        &self, filename: B, txmode: TransferMode, options: Options,
+        data: &[u8])
+        -> io::Result<Transfer>
+    {
+        let mut session = Session::new(self)?;
+        let filename = Filename::new(filename);
+        session.send(&Packet::Write(filename, txmode, options))?;
+        let mut blksize = 512;
+        let mut buf = vec![0u8; MAX_PACKET];
+        let mut expected = BlockNum(0);
+        let mut blocks = data.chunks(blksize);
+        let mut block = None;
+        loop {
+            let size = match session.recv(&mut buf)? {
+                Some(size) => size,
+                None => return Ok(session.finish(Outcome::TimedOut)),
+            };
+            let acked = match Packet::parse(&buf[..size]) {
+                Ok(Packet::OAck(ref options)) if expected == BlockNum(0) => {
+                    blksize = options.blksize.map_or(512, usize::from);
+                    blocks = data.chunks(blksize);
+                    session.options = Some(options.clone());
+                    true
+                },
+                Ok(Packet::Ack(blkno)) if blkno == expected => true,
+                Ok(Packet::Ack(blkno)) if blkno == expected.prev() => {
+                    session.duplicate_acks += 1;
+                    false
+                },
+                Ok(Packet::Error(code, ErrorMessage(message))) => {
+                    let outcome = Outcome::Rejected(code, message);
+                    return Ok(session.finish(outcome));
+                },
+                _ => false,
+            };
+            if acked {
+                // A short block ends the transfer, so when the data
+                // fills its last block an empty one must follow.
+                if let Some(len) = block {
+                    session.bytes += len as u64;
+                    session.blocks += 1;
+                    if len < blksize {
+                        return Ok(session.finish(
+                            Outcome::Completed(Vec::new())));
+                    }
+                }
+                let chunk = blocks.next().unwrap_or(&[]);
+                expected = expected.next();
+                block = Some(chunk.len());
+                session.send(&Packet::Data(expected, Data(chunk)))?;
+            }
+        }
+    }
+
+}
+
+
+/// What happened to a transfer run by a `Client`.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Transfer {
+    pub outcome: Outcome,
+    /// The options the server acknowledged, if it sent an `OACK`.
+    pub options: Option<Options>,
+    /// What the client saw of the transfer. Retransmits count only
+    /// the packets sent again after time-outs.
+    pub stats: TransferStats,
+}
+
+
+impl Transfer {
+
+    /// Panic unless the transfer completed, returning the data received.
+    pub fn assert_completed(&self) -> &[u8] {
+        match self.outcome {
+            Outcome::Completed(ref data) => data,
+            ref outcome => panic!(
+                "Expected transfer to complete, got: {:?}", outcome),
+        }
+    }
+
+    /// Panic unless the server rejected the transfer with `code`,
+    /// returning its message.
+    pub fn assert_rejected(&self, code: ErrorCode) -> &str {
+        match self.outcome {
+            Outcome::Rejected(actual, ref message) if actual == code =>
+                message,
+            ref outcome => panic!(
+                "Expected transfer to be rejected with {}, got: {:?}",
+                code, outcome),
+        }
+    }
+
+    /// Panic unless the server stopped responding.
+    pub fn assert_timed_out(&self) {
+        if self.outcome != Outcome::TimedOut {
+            panic!("Expected transfer to time out, got: {:?}", self.outcome);
+        }
+    }
+
+}
+
+
+/// How a transfer run by a `Client` ended.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Outcome {
+    /// The transfer completed. For a download this holds the data.
+    Completed(Vec<u8>),
+    /// The server sent an error.
+    Rejected(ErrorCode, String),
+    /// The server stopped responding.
+    TimedOut,
+}
+
+
+/// The client's end of one transfer.
+struct Session<'a> {
+    client: &'a Client,
+    socket: net::UdpSocket,
+    /// The server's transfer ID, i.e. the address replies come from.
+    peer: Option<net::SocketAddr>,
+    /// The last packet sent, kept for retransmission.
+    last: Vec<u8>,
+    options: Option<Options>,
+    started: time::Instant,
+    bytes: u64,
+    blocks: u64,
+    retransmits: u64,
+    duplicate_acks: u64,
+}
+
+
+impl<'a> Session<'a> {
+
+    fn new(client: &'a Client) -> io::Result<Session<'a>> {
+        let socket = match client.server {
+            net::SocketAddr::V4(_) => net::UdpSocket::bind(("127.0.0.1", 0)),
+            net::SocketAddr::V6(_) => net::UdpSocket::bind(("::1", 0)),
+        }?;
+        Ok(Session{
+            client,
+            socket,
+            peer: None,
+            last: Vec::new(),
+            options: None,
+            started: time::Instant::now(),
+            bytes: 0,
+            blocks: 0,
+            retransmits: 0,
+            duplicate_acks: 0,
+        })
+    }
+
+    fn send(&mut self, packet: &Packet) -> io::Result<()> {
+        self.last = packet.to_vec()?;
+        self.resend()
+    }
+
+    fn resend(&self) -> io::Result<()> {
+        let addr = self.peer.unwrap_or(self.client.server);
+        Transport::send_to(&self.socket, &self.last, addr)?;
+        Ok(())
+    }
+
+    /// Wait for a packet from the server, sending the last packet again
+    /// after each time-out. Returns `None` once out of retransmits.
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let timeout = Some(self.client.timeout);
+        let mut timeouts = 0;
+        loop {
+            match Transport::recv_from(&self.socket, buf, timeout) {
+                Ok((size, src)) => match self.peer {
+                    // Replies come from a new port for each transfer or,
+                    // for an immediate rejection, the server itself.
+                    None if src.ip() == self.client.server.ip() => {
+                        if src != self.client.server {
+                            self.peer = Some(src);
+                        }
+                        return Ok(Some(size));
+                    },
+                    Some(peer) if src == peer => return Ok(Some(size)),
+                    _ => {},
+                },
+                Err(ref error) if timed_out(error) => {
+                    if timeouts >= self.client.retransmits {
+                        return Ok(None);
+                    }
+                    timeouts += 1;
+                    self.retransmits += 1;
+                    self.resend()?;
+                },
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn finish(self, outcome: Outcome) -> Transfer {
+        Transfer{
+            outcome,
+            stats: TransferStats{
+                bytes: self.bytes,
+                blocks: self.blocks,
+                retransmits: self.retransmits,
+                duplicate_acks: self.duplicate_acks,
+                duration: self.started.elapsed(),
+                negotiated: self.options.clone().unwrap_or_default(),
+            },
+            options: self.options,
+        }
+    }
+
+}
+
+
+fn timed_out(error: &io::Error) -> bool {
+    // See the comment in Transport.recv to understand why both errors
+    // are matched.
+    error.kind() == io::ErrorKind::WouldBlock ||
+        error.kind() == io::ErrorKind::TimedOut
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::env;
+    use std::fs;
+    use std::net;
+    use std::process;
+    use std::sync::mpsc;
+    use std::time;
+
+    use super::{Outcome, Server};
+    use super::super::{Handler, spawn_transfer};
+    use super::super::logging;
+    use super::super::options::Options;
+    use super::super::packet::{ErrorCode, Filename, Packet, TransferMode};
+    use super::super::rrq;
+    use super::super::wrq;
+
+    /// An upload's filename and data.
+    type Upload = (Vec<u8>, Vec<u8>);
+
+    /// Serves downloads from the filesystem, and passes uploads, with
+    /// the names they were given, to `uploads`.
+    struct Files {
+        uploads: mpsc::Sender<Upload>,
+    }
+
+    impl Handler for Files {
+        fn handle_rrq(
+            &self, _local: net::SocketAddr, remote: net::SocketAddr,
+            filename: Filename, txmode: TransferMode, options: Options)
+            -> Option<Packet<'_>>
+        {
+            spawn_transfer(remote, &logging::discard(), move || {
+                let logger = logging::discard();
+                let _ = rrq::serve_file(
+                    remote, filename, txmode, options, &rrq::Config::new(),
+                    &logger);
+            });
+            None
+        }
+
+        fn handle_wrq(
+            &self, _local: net::SocketAddr, remote: net::SocketAddr,
+            filename: Filename, txmode: TransferMode, options: Options)
+            -> Option<Packet<'_>>
+        {
+            let uploads = self.uploads.clone();
+            spawn_transfer(remote, &logging::discard(), move || {
+                let logger = logging::discard();
+                let mut sink = Vec::new();
+                wrq::receive(
+                    remote, &mut sink, txmode, options, &wrq::Config::new(),
+                    &logger).unwrap();
+                uploads.send((filename.as_bytes().to_vec(), sink)).unwrap();
+            });
+            None
+        }
+    }
+
+    fn start() -> (Server, mpsc::Receiver<Upload>) {
+        let (uploads, received) = mpsc::channel();
+        (Server::start(Files{uploads}).unwrap(), received)
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|n| n as u8).collect()
+    }
+
+    #[test]
+    fn test_server_binds_loopback() {
+        let (server, _) = start();
+        assert!(server.addr().ip().is_loopback());
+        assert_ne!(0, server.port());
+    }
+
+    #[test]
+    fn test_read() {
+        let path = env::temp_dir().join(
+            format!("libtftp-testutil-read-{}", process::id()));
+        let payload = payload(1300);
+        fs::write(&path, &payload).unwrap();
+        let (server, _) = start();
+        let mut options = Options::new();
+        options.blksize = Some(1024);
+        let transfer = server.client().read(
+            path.to_str().unwrap(), TransferMode::Octet, options).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(&payload[..], transfer.assert_completed());
+        assert_eq!(1300, transfer.stats.bytes);
+        assert_eq!(2, transfer.stats.blocks);
+        assert_eq!(Some(1024), transfer.stats.negotiated.blksize);
+        assert_eq!(Some(1024), transfer.options.unwrap().blksize);
+    }
+
+    #[test]
+    fn test_write() {
+        let (server, received) = start();
+        let client = server.client();
+        for len in &[0, 700, 1024] {
+            let payload = payload(*len);
+            let transfer = client.write(
+                "upload", TransferMode::Octet, Options::new(), &payload)
+                .unwrap();
+            assert_eq!(Outcome::Completed(Vec::new()), transfer.outcome);
+            let timeout = time::Duration::from_secs(5);
+            assert_eq!(
+                (b"upload".to_vec(), payload),
+                received.recv_timeout(timeout).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_rejected() {
+        struct ReadOnly;
+        impl Handler for ReadOnly {}
+        let server = Server::start(ReadOnly).unwrap();
+        let transfer = server.client().write(
+            "upload", TransferMode::Octet, Options::new(), b"").unwrap();
+        assert_eq!(
+            "write not supported",
+            transfer.assert_rejected(ErrorCode::AccessViolation));
+    }
+
+    #[test]
+    fn test_timed_out() {
+        struct Silent;
+        impl Handler for Silent {
+            fn handle_rrq(
+                &self, _local: net::SocketAddr, _remote: net::SocketAddr,
+                _filename: Filename, _txmode: TransferMode,
+                _options: Options)
+                -> Option<Packet<'_>>
+            {
+                None
+            }
+        }
+        let server = Server::start(Silent).unwrap();
+        let mut client = server.client();
+        client.timeout = time::Duration::from_millis(10);
+        client.retransmits = 2;
+        let transfer = client.read(
+            "kernel", TransferMode::Octet, Options::new()).unwrap();
+        transfer.assert_timed_out();
+        assert_eq!(2, transfer.stats.retransmits);
+    }
+
+}
@@ -0,0 +1,669 @@
+use std::fmt;
+use std::io;
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+
+/// A datagram transport over which TFTP can run.
+///
+/// This is implemented for `UdpSocket`, which is what the convenience
+/// functions like `serve` and `rrq::serve_file` use. Implement it to
+/// run transfers over something else: an in-memory channel in tests,
+/// an async runtime's socket, or an embedded network stack.
+pub trait Transport {
+
+    /// Send a datagram to the peer set with `connect`.
+    fn send(&self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Send a datagram to `addr`.
+    fn send_to(&self, buf: &[u8], addr: net::SocketAddr) -> io::Result<usize>;
+
+    /// Send `bufs`, one after another, as a single datagram to the peer
+    /// set with `connect`, returning the number of bytes sent.
+    ///
+    /// By default they are first copied together. With the `sendmsg`
+    /// feature on Unix, `UdpSocket` gathers them with a single `sendmsg`
+    /// call, so that a header and its payload need not be contiguous.
+    fn send_vectored(&self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        self.send(&concat(bufs))
+    }
+
+    /// Send `buf` to the peer set with `connect` as consecutive
+    /// datagrams of `segment` bytes each, the last of which may be
+    /// shorter, returning the number of bytes sent.
+    ///
+    /// By default each datagram is sent separately. With the `gso`
+    /// feature on Linux, `UdpSocket` hands them to the kernel together
+    /// to be segmented, which costs far less per datagram.
+    fn send_segments(&self, buf: &[u8], segment: usize) -> io::Result<usize> {
+        if segment == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "segment size must not be zero"));
+        }
+        for datagram in buf.chunks(segment) {
+            self.send(datagram)?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Receive a datagram from the peer set with `connect`.
+    ///
+    /// If no datagram arrives within `timeout` this fails with an error
+    /// of kind `WouldBlock` or `TimedOut`. With no timeout this waits
+    /// indefinitely.
+    fn recv(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<usize>;
+
+    /// Receive a datagram from anyone, returning its size and sender.
+    /// Time-outs are as for `recv`.
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<(usize, net::SocketAddr)>;
+
+    /// Receive up to one datagram into each of `bufs`, returning the
+    /// size and sender of each in order. Time-outs are as for `recv`,
+    /// but apply only to the first datagram; the rest are those already
+    /// waiting.
+    ///
+    /// By default this receives just one datagram. With the `recvmmsg`
+    /// feature on Linux, `UdpSocket` drains several with each call.
+    fn recv_from_many(
+        &self, bufs: &mut [&mut [u8]], timeout: Option<time::Duration>)
+        -> io::Result<Vec<(usize, net::SocketAddr)>>
+    {
+        match bufs.first_mut() {
+            Some(buf) => Ok(vec![self.recv_from(buf, timeout)?]),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Restrict `send` and `recv` to the given peer.
+    fn connect(&self, addr: net::SocketAddr) -> io::Result<()>;
+
+    /// The address of this end of the transport.
+    fn local_addr(&self) -> io::Result<net::SocketAddr>;
+
+}
+
+
+impl Transport for net::UdpSocket {
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        net::UdpSocket::send(self, buf)
+    }
+
+    fn send_to(&self, buf: &[u8], addr: net::SocketAddr) -> io::Result<usize> {
+        net::UdpSocket::send_to(self, buf, addr)
+    }
+
+    #[cfg(all(feature = "sendmsg", unix))]
+    fn send_vectored(&self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        sendmsg(self, bufs)
+    }
+
+    #[cfg(all(feature = "gso", target_os = "linux"))]
+    fn send_segments(&self, buf: &[u8], segment: usize) -> io::Result<usize> {
+        if segment == 0 || segment > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "segment size out of range"));
+        }
+        // The kernel segments no more than 64 datagrams, and no more
+        // than 64KiB in all, from each send.
+        let count = (65000 / segment).clamp(1, 64);
+        for datagrams in buf.chunks(segment * count) {
+            if datagrams.len() <= segment {
+                net::UdpSocket::send(self, datagrams)?;
+                continue;
+            }
+            match sendmsg_gso(self, datagrams, segment as u16) {
+                Ok(()) => {},
+                // The device or the kernel cannot do it, so do it here.
+                Err(ref error) if matches!(
+                    error.raw_os_error(),
+                    Some(libc::EIO) | Some(libc::EINVAL) |
+                    Some(libc::ENOPROTOOPT)) => {
+                    for datagram in datagrams.chunks(segment) {
+                        net::UdpSocket::send(self, datagram)?;
+                    }
+                },
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<usize>
+    {
+        self.set_read_timeout(timeout)?;
+        net::UdpSocket::recv(self, buf)
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<(usize, net::SocketAddr)>
+    {
+        self.set_read_timeout(timeout)?;
+        net::UdpSocket::recv_from(self, buf)
+    }
+
+    #[cfg(all(feature = "recvmmsg", target_os = "linux"))]
+    fn recv_from_many(
+        &self, bufs: &mut [&mut [u8]], timeout: Option<time::Duration>)
+        -> io::Result<Vec<(usize, net::SocketAddr)>>
+    {
+        self.set_read_timeout(timeout)?;
+        recvmmsg(self, bufs)
+    }
+
+    fn connect(&self, addr: net::SocketAddr) -> io::Result<()> {
+        net::UdpSocket::connect(self, addr)
+    }
+
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        net::UdpSocket::local_addr(self)
+    }
+
+}
+
+
+impl<T: Transport + ?Sized> Transport for &T {
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        (**self).send(buf)
+    }
+
+    fn send_to(&self, buf: &[u8], addr: net::SocketAddr) -> io::Result<usize> {
+        (**self).send_to(buf, addr)
+    }
+
+    fn send_vectored(&self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        (**self).send_vectored(bufs)
+    }
+
+    fn send_segments(&self, buf: &[u8], segment: usize) -> io::Result<usize> {
+        (**self).send_segments(buf, segment)
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<usize>
+    {
+        (**self).recv(buf, timeout)
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<(usize, net::SocketAddr)>
+    {
+        (**self).recv_from(buf, timeout)
+    }
+
+    fn recv_from_many(
+        &self, bufs: &mut [&mut [u8]], timeout: Option<time::Duration>)
+        -> io::Result<Vec<(usize, net::SocketAddr)>>
+    {
+        (**self).recv_from_many(bufs, timeout)
+    }
+
+    fn connect(&self, addr: net::SocketAddr) -> io::Result<()> {
+        (**self).connect(addr)
+    }
+
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        (**self).local_addr()
+    }
+
+}
+
+
+/// `bufs` copied, one after another, into one buffer.
+fn concat(bufs: &[io::IoSlice]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(bufs.iter().map(|buf| buf.len()).sum());
+    for slice in bufs {
+        buf.extend_from_slice(slice);
+    }
+    buf
+}
+
+
+/// Receive as many datagrams as are waiting, up to one for each of
+/// `bufs`, with a single `recvmmsg` call. It blocks, subject to the
+/// socket's read time-out, only until the first arrives.
+#[cfg(all(feature = "recvmmsg", target_os = "linux"))]
+fn recvmmsg(socket: &net::UdpSocket, bufs: &mut [&mut [u8]])
+    -> io::Result<Vec<(usize, net::SocketAddr)>>
+{
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    let mut iovecs: Vec<libc::iovec> = bufs.iter_mut().map(|buf| {
+        libc::iovec{
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }
+    }).collect();
+    // All zeroes is a valid value for these C structs.
+    let mut addrs: Vec<libc::sockaddr_storage> =
+        vec![unsafe { mem::zeroed() }; bufs.len()];
+    let mut msgs: Vec<libc::mmsghdr> = iovecs.iter_mut().zip(&mut addrs)
+        .map(|(iovec, addr)| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen =
+                mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        }).collect();
+    // The buffers, addresses, and headers all outlive the call, and
+    // there are as many headers as the length given.
+    let count = unsafe {
+        libc::recvmmsg(
+            socket.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as libc::c_uint,
+            libc::MSG_WAITFORONE, ptr::null_mut())
+    };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    msgs.iter().zip(&addrs).take(count as usize)
+        .map(|(msg, addr)| Ok((msg.msg_len as usize, socket_addr(addr)?)))
+        .collect()
+}
+
+
+/// Send `bufs` as one datagram, gathered by a single `sendmsg` call, to
+/// the peer to which `socket` is connected.
+#[cfg(all(feature = "sendmsg", unix))]
+fn sendmsg(socket: &net::UdpSocket, bufs: &[io::IoSlice]) -> io::Result<usize> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    // `IoSlice` is guaranteed to be ABI-compatible with `iovec` on Unix,
+    // and the kernel only reads through it.
+    msg.msg_iov = bufs.as_ptr() as *mut libc::iovec;
+    msg.msg_iovlen = bufs.len() as _;
+    let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+
+/// Send `buf` with a single `sendmsg` call, asking the kernel to split
+/// it into datagrams of `segment` bytes (UDP generic segmentation
+/// offload).
+#[cfg(all(feature = "gso", target_os = "linux"))]
+fn sendmsg_gso(socket: &net::UdpSocket, buf: &[u8], segment: u16)
+    -> io::Result<()>
+{
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    let mut iovec = libc::iovec{
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // Room for one control message holding a u16, suitably aligned.
+    let mut control = [0u64; 4];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iovec;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    // Both the control buffer and `msg` outlive their use here, and
+    // the buffer is large enough for the one header written into it.
+    unsafe {
+        let size = mem::size_of::<u16>() as libc::c_uint;
+        msg.msg_controllen = libc::CMSG_SPACE(size) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(size) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment);
+    }
+    let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+
+/// Convert an address filled in by the kernel.
+#[cfg(all(feature = "recvmmsg", target_os = "linux"))]
+fn socket_addr(addr: &libc::sockaddr_storage) -> io::Result<net::SocketAddr> {
+    // The family says which of the overlaid structs has been filled in,
+    // and `sockaddr_storage` is large and aligned enough for any.
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe {
+                &*(addr as *const _ as *const libc::sockaddr_in) };
+            Ok(net::SocketAddr::V4(net::SocketAddrV4::new(
+                net::Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port))))
+        },
+        libc::AF_INET6 => {
+            let addr = unsafe {
+                &*(addr as *const _ as *const libc::sockaddr_in6) };
+            Ok(net::SocketAddr::V6(net::SocketAddrV6::new(
+                net::Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port), addr.sin6_flowinfo,
+                addr.sin6_scope_id)))
+        },
+        family => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected address family: {}", family))),
+    }
+}
+
+
+/// Which way a datagram went.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+
+/// Sees every datagram sent or received, as raw bytes, before any
+/// parsing. This is the building block for debugging, capture, and
+/// custom accounting.
+///
+/// Set one on a transfer's `Config`, return one from
+/// `Handler::wire_observer` to see a server's requests and replies, or
+/// wrap any transport with `Tap`. Observers are called from the thread
+/// doing the sending or receiving, so they should be quick.
+pub trait WireObserver: Send + Sync {
+
+    fn datagram(
+        &self, direction: Direction, peer: net::SocketAddr, bytes: &[u8]);
+
+}
+
+
+impl fmt::Debug for dyn WireObserver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WireObserver")
+    }
+}
+
+
+/// Wraps another transport to show every datagram it sends or receives
+/// to a `WireObserver`.
+pub struct Tap<T> {
+    inner: T,
+    observer: Arc<dyn WireObserver>,
+    peer: Mutex<Option<net::SocketAddr>>,
+}
+
+
+impl<T: Transport> Tap<T> {
+
+    pub fn new(inner: T, observer: Arc<dyn WireObserver>) -> Tap<T> {
+        Tap{inner, observer, peer: Mutex::new(None)}
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+}
+
+
+impl<T> fmt::Debug for Tap<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tap").finish()
+    }
+}
+
+
+impl<T: Transport> Transport for Tap<T> {
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.inner.send(buf)?;
+        if let Some(peer) = *self.peer.lock().unwrap() {
+            self.observer.datagram(Direction::Sent, peer, &buf[..size]);
+        }
+        Ok(size)
+    }
+
+    fn send_to(&self, buf: &[u8], addr: net::SocketAddr) -> io::Result<usize> {
+        let size = self.inner.send_to(buf, addr)?;
+        self.observer.datagram(Direction::Sent, addr, &buf[..size]);
+        Ok(size)
+    }
+
+    fn send_vectored(&self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        let size = self.inner.send_vectored(bufs)?;
+        if let Some(peer) = *self.peer.lock().unwrap() {
+            let buf = concat(bufs);
+            self.observer.datagram(Direction::Sent, peer, &buf[..size]);
+        }
+        Ok(size)
+    }
+
+    fn send_segments(&self, buf: &[u8], segment: usize) -> io::Result<usize> {
+        let size = self.inner.send_segments(buf, segment)?;
+        if let Some(peer) = *self.peer.lock().unwrap() {
+            for datagram in buf[..size].chunks(segment) {
+                self.observer.datagram(Direction::Sent, peer, datagram);
+            }
+        }
+        Ok(size)
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<usize>
+    {
+        let peer = *self.peer.lock().unwrap();
+        match peer {
+            Some(peer) => {
+                let size = self.inner.recv(buf, timeout)?;
+                self.observer.datagram(
+                    Direction::Received, peer, &buf[..size]);
+                Ok(size)
+            },
+            None => self.recv_from(buf, timeout).map(|(size, _)| size),
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<time::Duration>)
+        -> io::Result<(usize, net::SocketAddr)>
+    {
+        let (size, src) = self.inner.recv_from(buf, timeout)?;
+        self.observer.datagram(Direction::Received, src, &buf[..size]);
+        Ok((size, src))
+    }
+
+    fn recv_from_many(
+        &self, bufs: &mut [&mut [u8]], timeout: Option<time::Duration>)
+        -> io::Result<Vec<(usize, net::SocketAddr)>>
+    {
+        let received = self.inner.recv_from_many(bufs, timeout)?;
+        for (buf, &(size, src)) in bufs.iter().zip(&received) {
+            self.observer.datagram(Direction::Received, src, &buf[..size]);
+        }
+        Ok(received)
+    }
+
+    fn connect(&self, addr: net::SocketAddr) -> io::Result<()> {
+        self.inner.connect(addr)?;
+        *self.peer.lock().unwrap() = Some(addr);
+        Ok(())
+    }
+
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.inner.local_addr()
+    }
+
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::io;
+    use std::net;
+    use std::sync::Arc;
+    use std::time;
+
+    use super::{Direction, Tap, Transport};
+    use super::super::testing::{MemoryTransport, WireLog};
+
+    #[test]
+    fn test_udp_socket() {
+        let one = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let two = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (one, two): (&dyn Transport, &dyn Transport) = (&one, &two);
+        let addr = two.local_addr().unwrap();
+        one.connect(addr).unwrap();
+        one.send(b"hello").unwrap();
+        let mut buf = [0u8; 16];
+        let (size, src) = two.recv_from(&mut buf, None).unwrap();
+        assert_eq!(b"hello", &buf[..size]);
+        assert_eq!(one.local_addr().unwrap(), src);
+        two.send_to(b"there", src).unwrap();
+        let size = one.recv(&mut buf, None).unwrap();
+        assert_eq!(b"there", &buf[..size]);
+    }
+
+    #[test]
+    fn test_udp_socket_send_vectored() {
+        let one = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let two = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (one, two): (&dyn Transport, &dyn Transport) = (&one, &two);
+        one.connect(two.local_addr().unwrap()).unwrap();
+        let bufs = [io::IoSlice::new(b"hello, "), io::IoSlice::new(b"world")];
+        assert_eq!(12, one.send_vectored(&bufs).unwrap());
+        let mut buf = [0u8; 16];
+        let size = two.recv(&mut buf, None).unwrap();
+        assert_eq!(b"hello, world", &buf[..size]);
+    }
+
+    #[test]
+    fn test_udp_socket_send_segments() {
+        let one = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let two = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (one, two): (&dyn Transport, &dyn Transport) = (&one, &two);
+        one.connect(two.local_addr().unwrap()).unwrap();
+        assert_eq!(10, one.send_segments(b"onetwothre", 3).unwrap());
+        let mut buf = [0u8; 16];
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            let size = two.recv(&mut buf, None).unwrap();
+            received.push(buf[..size].to_vec());
+        }
+        assert_eq!(
+            vec![b"one".to_vec(), b"two".to_vec(), b"thr".to_vec(),
+                 b"e".to_vec()],
+            received);
+    }
+
+    #[test]
+    fn test_udp_socket_send_segments_rejects_zero() {
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let error = socket.send_segments(b"hello", 0).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    }
+
+    #[test]
+    fn test_udp_socket_recv_times_out() {
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let timeout = Some(time::Duration::from_millis(10));
+        let mut buf = [0u8; 16];
+        let error = Transport::recv_from(&socket, &mut buf, timeout)
+            .unwrap_err();
+        assert!(
+            error.kind() == io::ErrorKind::WouldBlock ||
+            error.kind() == io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_udp_socket_recv_from_many() {
+        let one = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let two = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = two.local_addr().unwrap();
+        for datagram in &[&b"one"[..], b"two", b"three"] {
+            Transport::send_to(&one, datagram, addr).unwrap();
+        }
+        let mut bufs = [[0u8; 16]; 4];
+        let mut slices: Vec<&mut [u8]> =
+            bufs.iter_mut().map(|buf| &mut buf[..]).collect();
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            let offset = received.len();
+            received.extend(
+                two.recv_from_many(&mut slices[offset..], None).unwrap());
+        }
+        let src = one.local_addr().unwrap();
+        assert_eq!(vec![(3, src), (3, src), (5, src)], received);
+        assert_eq!(b"three", &bufs[2][..5]);
+    }
+
+    #[test]
+    #[cfg(all(feature = "recvmmsg", target_os = "linux"))]
+    fn test_udp_socket_recv_from_many_drains_in_one_call() {
+        let one = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let two = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = two.local_addr().unwrap();
+        for _ in 0..3 {
+            Transport::send_to(&one, b"hello", addr).unwrap();
+        }
+        let mut bufs = [[0u8; 16]; 4];
+        let mut slices: Vec<&mut [u8]> =
+            bufs.iter_mut().map(|buf| &mut buf[..]).collect();
+        let received = two.recv_from_many(&mut slices, None).unwrap();
+        assert_eq!(3, received.len());
+    }
+
+    #[test]
+    fn test_udp_socket_recv_from_many_times_out() {
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let timeout = Some(time::Duration::from_millis(10));
+        let mut buf = [0u8; 16];
+        let error = socket.recv_from_many(&mut [&mut buf[..]], timeout)
+            .unwrap_err();
+        assert!(
+            error.kind() == io::ErrorKind::WouldBlock ||
+            error.kind() == io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_tap_recv_from_many() {
+        let (one, two) = MemoryTransport::pair();
+        let log = Arc::new(WireLog::new());
+        let one = Tap::new(one, log.clone());
+        let src = two.local_addr().unwrap();
+        two.send_to(b"hello", one.local_addr().unwrap()).unwrap();
+        let mut buf = [0u8; 16];
+        let received = one.recv_from_many(&mut [&mut buf[..]], None).unwrap();
+        assert_eq!(vec![(5, src)], received);
+        assert_eq!(
+            vec![(Direction::Received, src, b"hello".to_vec())],
+            log.datagrams());
+    }
+
+    #[test]
+    fn test_tap() {
+        let (one, two) = MemoryTransport::pair();
+        let log = Arc::new(WireLog::new());
+        let one = Tap::new(one, log.clone());
+        let addr = two.local_addr().unwrap();
+        one.send_to(b"hello", addr).unwrap();
+        one.connect(addr).unwrap();
+        one.send(b"again").unwrap();
+        let mut buf = [0u8; 16];
+        let (_, src) = two.recv_from(&mut buf, None).unwrap();
+        two.send_to(b"there", src).unwrap();
+        one.recv(&mut buf, None).unwrap();
+        assert_eq!(
+            vec![
+                (Direction::Sent, addr, b"hello".to_vec()),
+                (Direction::Sent, addr, b"again".to_vec()),
+                (Direction::Received, addr, b"there".to_vec()),
+            ],
+            log.datagrams());
+    }
+
+}
@@ -0,0 +1,1619 @@
+use std::error;
+use std::fmt;
+use std::fs;
+use std::net;
+use std::io;
+use std::path;
+use std::process;
+use std::result;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::packet;
+use super::packet::{
+    BlockNum,
+    Data,
+    ErrorCode,
+    ErrorMessage,
+    Filename,
+    Packet,
+    TransferMode,
+};
+use super::budget::MemoryBudget;
+use super::clock::{Clock, SystemClock};
+use super::events::EventSink;
+use super::limits::{ClientLimits, Permit};
+use super::logging::{Logger, Span};
+use super::metrics::{Direction, Metrics, NoMetrics};
+use super::netascii;
+use super::netascii::NetAsciiWriter;
+use super::options::{Extensions, Options, OptionsPolicy};
+use super::pool::BufferPool;
+use super::progress::{Observer, Tracker, TransferStats};
+use super::quota::Quota;
+use super::security::{SecurityEvent, Violation};
+use super::session::{CancellationToken, Registry};
+use super::transport::{Tap, Transport, WireObserver};
+use super::{make_socket, send_error, send_error_via};
+
+
+/// An error receiving an upload.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The sink ran out of space. The peer has been sent a `DiskFull`
+    /// error.
+    DiskFull(io::Error),
+    /// The upload exceeded the configured maximum size. The peer has
+    /// been sent a `DiskFull` error.
+    TooLarge(u64),
+    /// The peer has exceeded its upload quota. The peer has been sent a
+    /// `DiskFull` error.
+    QuotaExceeded,
+    /// The peer already has as many transfers in progress as its
+    /// limits allow. The peer has been sent a `NotDefined` error.
+    TooManyTransfers,
+    /// The memory budget could not afford the transfer, even with
+    /// smaller blocks. The peer has been sent a `NotDefined` error.
+    OutOfMemory,
+    /// The peer aborted the transfer by sending an `ERROR` packet.
+    Aborted(ErrorCode, ErrorMessage),
+    /// The peer stopped sending data, and did not respond to any of the
+    /// retransmitted acknowledgements.
+    TimedOut,
+    /// The transfer was cancelled locally. The peer has been sent a
+    /// `NotDefined` error.
+    Cancelled,
+    /// The transfer mode is not supported. The peer has been sent an
+    /// `IllegalOperation` error.
+    UnsupportedMode(TransferMode),
+    /// A requested extension option had an unacceptable value. The peer
+    /// has been sent a `BadOptions` error.
+    BadOptions(String),
+    /// A packet could not be written.
+    Packet(packet::Error),
+    /// Some other I/O error, e.g. with the socket or the sink.
+    Io(io::Error),
+}
+
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::DiskFull(ref error) =>
+                write!(f, "disk full: {}", error),
+            Error::TooLarge(max_size) =>
+                write!(f, "upload exceeds maximum size of {} bytes",
+                       max_size),
+            Error::Aborted(ref code, ref message) =>
+                write!(f, "peer aborted transfer: {}: {:?}",
+                       code, message.0),
+            Error::QuotaExceeded =>
+                write!(f, "upload quota exceeded"),
+            Error::TooManyTransfers =>
+                write!(f, "too many transfers"),
+            Error::OutOfMemory =>
+                write!(f, "memory budget exhausted"),
+            Error::TimedOut =>
+                write!(f, "too many time-outs"),
+            Error::Cancelled =>
+                write!(f, "transfer cancelled"),
+            Error::UnsupportedMode(txmode) =>
+                write!(f, "transfer mode not supported: {}", txmode),
+            Error::BadOptions(ref message) =>
+                write!(f, "options not acceptable: {}", message),
+            Error::Packet(ref error) =>
+                write!(f, "packet error: {}", error),
+            Error::Io(ref error) =>
+                write!(f, "i/o error: {}", error),
+        }
+    }
+}
+
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::DiskFull(ref error) => Some(error),
+            Error::Packet(ref error) => Some(error),
+            Error::Io(ref error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+
+impl From<packet::Error> for Error {
+    fn from(error: packet::Error) -> Error {
+        Error::Packet(error)
+    }
+}
+
+
+pub type Result<T> = result::Result<T, Error>;
+
+
+/// What to do when a write request targets a file that already exists.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Overwrite {
+    /// Reject the request with a `FileAlreadyExists` error.
+    Reject,
+    /// Replace the existing file once the upload has completed.
+    Replace,
+    /// Keep the existing file and store the upload next to it with a
+    /// numeric suffix, e.g. `config.1`, `config.2`, and so on.
+    Version,
+}
+
+
+/// Configuration for receiving uploads.
+#[derive(Debug)]
+pub struct Config {
+    /// What to do when the target file already exists. This applies
+    /// only to the built-in upload path,
+    /// [`receive_file`](fn.receive_file.html).
+    pub overwrite: Overwrite,
+    /// The largest upload, in bytes, to accept. Uploads that exceed
+    /// this are aborted with a `DiskFull` error.
+    pub max_size: Option<u64>,
+    /// How many times to retransmit the last acknowledgement when no
+    /// data arrives within the negotiated time-out, before giving up.
+    pub retransmits: u8,
+    /// Permission bits, e.g. `0o644`, for files created by
+    /// [`receive_file`](fn.receive_file.html). Unix only; ignored
+    /// elsewhere.
+    pub mode: Option<u32>,
+    /// The user ID to own files created by
+    /// [`receive_file`](fn.receive_file.html). Unix only; ignored
+    /// elsewhere.
+    pub owner: Option<u32>,
+    /// The group ID to own files created by
+    /// [`receive_file`](fn.receive_file.html). Unix only; ignored
+    /// elsewhere.
+    pub group: Option<u32>,
+    /// Per-client upload quota. Requests from clients that have used up
+    /// their quota are rejected with a `DiskFull` error.
+    pub quota: Option<Quota>,
+    /// Per-client limits on simultaneous transfers and their rate.
+    /// Requests from clients with too many transfers in progress are
+    /// rejected with a `NotDefined` error. To keep within the rate,
+    /// acknowledgements are held back, so keep it well above a block
+    /// per time-out lest clients retransmit.
+    pub limits: Option<Arc<ClientLimits>>,
+    /// Memory shared by all transfers. Those that would not fit have
+    /// their block and window sizes reduced or, failing that, are
+    /// rejected with a `NotDefined` error.
+    pub budget: Option<Arc<MemoryBudget>>,
+    /// Notified of each upload's progress and completion.
+    pub observer: Option<Arc<dyn Observer>>,
+    /// Sent an event as each upload starts, retransmits, and ends.
+    pub events: Option<Arc<dyn EventSink>>,
+    /// Shown every datagram of each transfer, as raw bytes.
+    pub wire_observer: Option<Arc<dyn WireObserver>>,
+    /// Counts uploads, retransmissions, and errors.
+    pub metrics: Arc<dyn Metrics>,
+    /// Tells the time for progress reports.
+    pub clock: Arc<dyn Clock>,
+    /// Uploads register here while in progress, so that they can be
+    /// cancelled.
+    pub registry: Option<Arc<Registry>>,
+    /// Limits on the options clients can negotiate. Uploads are
+    /// acknowledged block by block, so `windowsize` is never
+    /// acknowledged, whatever `max_windowsize` allows.
+    pub policy: OptionsPolicy,
+    /// Custom options to negotiate in addition to those defined by the
+    /// RFCs.
+    pub extensions: Extensions,
+    /// How to convert `netascii` uploads to local text.
+    pub netascii: netascii::Policy,
+    /// Where each transfer's buffers come from, and go back to.
+    pub buffers: Arc<BufferPool>,
+}
+
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
+
+impl Config {
+
+    pub fn new() -> Config {
+        Config{
+            overwrite: Overwrite::Replace,
+            max_size: None,
+            retransmits: 8,
+            mode: None,
+            owner: None,
+            group: None,
+            quota: None,
+            limits: None,
+            budget: None,
+            observer: None,
+            events: None,
+            wire_observer: None,
+            metrics: Arc::new(NoMetrics),
+            clock: Arc::new(SystemClock),
+            registry: None,
+            policy: OptionsPolicy::new(),
+            extensions: Extensions::new(),
+            netascii: netascii::Policy::new(),
+            buffers: Arc::new(BufferPool::default()),
+        }
+    }
+
+}
+
+
+/// Receive a file from `peer`, writing it to the filesystem.
+///
+/// This is the built-in upload path. Handlers that want to put the
+/// incoming data somewhere else should use [`receive`](fn.receive.html)
+/// with their own sink.
+///
+/// Data is written to a temporary file in the same directory as
+/// `filename` and renamed into place only once the transfer has
+/// completed and the file has been synced. A dropped or failed transfer
+/// never leaves a truncated file behind.
+///
+/// If the file already exists, `config.overwrite` is consulted before
+/// the request is acknowledged. It's upheld again as the file is put in
+/// place, should another have appeared there meanwhile: unless it says
+/// to replace files, the newcomer is never replaced.
+///
+/// Returns what the transfer did once the file is in place. Failures
+/// are logged as well as returned, so callers that have nothing more
+/// to do with them can ignore them.
+pub fn receive_file(
+    peer: net::SocketAddr,
+    filename: Filename,
+    txmode: TransferMode,
+    options: Options,
+    config: &Config,
+    logger: &Logger,
+)
+    -> Result<TransferStats>
+{
+    info!(
+        logger, "Received WRQ: {:?} {} {}", filename.to_string_lossy(),
+        txmode, options);
+    let logger = logger.new(o!(
+        "filename" => filename.to_string_lossy().into_owned()));
+    let requested = filename.to_path();
+    let path = match target_path(&requested, config.overwrite) {
+        Some(path) => path,
+        None => {
+            warn!(
+                logger, "Rejecting WRQ: file {} already exists",
+                filename.to_string_lossy());
+            if let Err(error) = send_error(
+                peer, ErrorCode::FileAlreadyExists, "file already exists",
+                config.wire_observer.as_ref(), &*config.metrics) {
+                error!(logger, "Could not send error to peer: {}", error);
+            }
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::AlreadyExists, "file already exists")));
+        },
+    };
+    let temppath = temporary_path(&path);
+    // For failures with the file, rather than the transfer, which sends
+    // the peer its own errors.
+    let refuse = |error: io::Error| {
+        if let Err(error) = send_error(
+            peer, ErrorCode::from(&error), &error.to_string(),
+            config.wire_observer.as_ref(), &*config.metrics) {
+            error!(logger, "Could not send error to peer: {}", error);
+        }
+        Error::Io(error)
+    };
+    let result = fs::OpenOptions::new()
+        .write(true).create_new(true).open(&temppath)
+        .and_then(|file| {
+            set_permissions(&file, config)?;
+            Ok(file)
+        })
+        .map_err(&refuse)
+        .and_then(|mut file| {
+            let stats = receive_named(
+                peer, Some(&filename.to_string_lossy()), &mut file, txmode,
+                options, config, &logger)?;
+            file.sync_all()?;
+            Ok(stats)
+        })
+        .and_then(|stats| {
+            publish(&temppath, &requested, &path, config.overwrite)
+                .map(|_| stats)
+                .map_err(|error| match error.kind() {
+                    // Another file was put there during the upload.
+                    io::ErrorKind::AlreadyExists => io::Error::new(
+                        io::ErrorKind::AlreadyExists, "file already exists"),
+                    _ => error,
+                })
+                .map_err(&refuse)
+        });
+    if let Err(ref error) = result {
+        error!(logger, "Problem with file {:?}: {}", &path, error);
+        if temppath.exists() {
+            if let Err(error) = fs::remove_file(&temppath) {
+                warn!(
+                    logger, "Could not remove {:?}: {}", &temppath, error);
+            }
+        }
+    };
+    result
+}
+
+
+/// Where to store an upload destined for `path`, according to the
+/// given overwrite policy, or `None` if the upload must be rejected.
+fn target_path(path: &path::Path, overwrite: Overwrite)
+    -> Option<path::PathBuf>
+{
+    if !path.exists() {
+        return Some(path.to_path_buf());
+    }
+    match overwrite {
+        Overwrite::Reject => None,
+        Overwrite::Replace => Some(path.to_path_buf()),
+        Overwrite::Version => {
+            let mut name = path.as_os_str().to_os_string();
+            name.push(".");
+            (1u32..)
+                .map(|version| {
+                    let mut name = name.clone();
+                    name.push(version.to_string());
+                    path::PathBuf::from(name)
+                })
+                .find(|path| !path.exists())
+        },
+    }
+}
+
+
+/// Move the upload at `temppath` to `path`, as chosen by `target_path`
+/// for `requested`, returning where it ended up.
+///
+/// Only `Overwrite::Replace` ever replaces a file. Another may have been
+/// put at `path` since it was chosen, so otherwise the upload is linked
+/// into place, which fails rather than replace it: `Reject` then fails
+/// with `AlreadyExists`, and `Version` tries the next free suffix.
+fn publish(
+    temppath: &path::Path, requested: &path::Path, path: &path::Path,
+    overwrite: Overwrite)
+    -> io::Result<path::PathBuf>
+{
+    if overwrite == Overwrite::Replace {
+        fs::rename(temppath, path)?;
+        return Ok(path.to_path_buf());
+    }
+    let mut target = path.to_path_buf();
+    loop {
+        match fs::hard_link(temppath, &target) {
+            Ok(()) => {
+                fs::remove_file(temppath)?;
+                return Ok(target);
+            },
+            Err(error) => {
+                if error.kind() != io::ErrorKind::AlreadyExists ||
+                    overwrite != Overwrite::Version {
+                    return Err(error);
+                }
+                target = target_path(requested, overwrite).ok_or(error)?;
+            },
+        }
+    }
+}
+
+
+/// Apply the configured mode and ownership to a newly created file.
+#[cfg(unix)]
+fn set_permissions(file: &fs::File, config: &Config) -> io::Result<()> {
+    use std::os::unix::fs::{fchown, PermissionsExt};
+    if let Some(mode) = config.mode {
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+    }
+    if config.owner.is_some() || config.group.is_some() {
+        fchown(file, config.owner, config.group)?;
+    }
+    Ok(())
+}
+
+
+#[cfg(not(unix))]
+fn set_permissions(_file: &fs::File, _config: &Config) -> io::Result<()> {
+    Ok(())
+}
+
+
+/// A unique name in the same directory as `path` to receive into.
+///
+/// Being in the same directory means it's on the same filesystem, so
+/// it can be renamed atomically into place.
+fn temporary_path(path: &path::Path) -> path::PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => String::new(),
+    };
+    path.with_file_name(format!(
+        ".{}.{}-{}.part", name, process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)))
+}
+
+
+/// Receive data from `peer`, writing it to `sink`.
+///
+/// The sink can be anything that implements `io::Write`: a file, a
+/// pipe, an in-memory buffer, a hash calculator, and so on. It is
+/// flushed once the final block has been written.
+///
+/// In `netascii` mode, line endings are converted according to
+/// `config.netascii`, by default to the native line ending, before
+/// being written to the sink.
+///
+/// Returns an error if the transfer did not complete, in which case
+/// the sink may have received only part of the data. Errors from the
+/// sink indicating that it's full are reported to the peer as
+/// `DiskFull`.
+///
+/// If `config.registry` is set, the upload can be cancelled through it
+/// while in progress, in which case the peer is sent an error. The
+/// cancellation takes effect when the next packet arrives or the
+/// current time-out expires, whichever is sooner.
+pub fn receive(
+    peer: net::SocketAddr,
+    sink: &mut dyn io::Write,
+    txmode: TransferMode,
+    options: Options,
+    config: &Config,
+    logger: &Logger,
+)
+    -> Result<TransferStats>
+{
+    receive_named(peer, None, sink, txmode, options, config, logger)
+}
+
+
+/// `receive`, with the name of the file being received, if known, for
+/// the transfer's span.
+fn receive_named(
+    peer: net::SocketAddr,
+    filename: Option<&str>,
+    sink: &mut dyn io::Write,
+    txmode: TransferMode,
+    options: Options,
+    config: &Config,
+    logger: &Logger,
+)
+    -> Result<TransferStats>
+{
+    let socket = match make_socket(peer) {
+        Ok(socket) => socket,
+        Err(error) => {
+            error!(
+                logger, "Could not open socket: {}", error;
+                "peer" => format!("{}", peer));
+            return Err(Error::Io(error));
+        },
+    };
+    receive_over_named(
+        &socket, peer, filename, sink, txmode, options, config, logger)
+}
+
+
+/// Receive data from `peer` over the given transport, writing it to
+/// `sink`.
+///
+/// This is `receive` for transports other than a UDP socket; see
+/// `Transport`. The transport should be dedicated to this transfer.
+pub fn receive_over(
+    transport: &dyn Transport,
+    peer: net::SocketAddr,
+    sink: &mut dyn io::Write,
+    txmode: TransferMode,
+    options: Options,
+    config: &Config,
+    logger: &Logger,
+)
+    -> Result<TransferStats>
+{
+    receive_over_named(
+        transport, peer, None, sink, txmode, options, config, logger)
+}
+
+
+/// `receive_over`, with the name of the file being received, if known,
+/// for the transfer's span.
+#[allow(clippy::too_many_arguments)]
+fn receive_over_named(
+    transport: &dyn Transport,
+    peer: net::SocketAddr,
+    filename: Option<&str>,
+    sink: &mut dyn io::Write,
+    txmode: TransferMode,
+    options: Options,
+    config: &Config,
+    logger: &Logger,
+)
+    -> Result<TransferStats>
+{
+    let tap;
+    let transport: &dyn Transport = match config.wire_observer {
+        Some(ref observer) => {
+            tap = Tap::new(transport, observer.clone());
+            &tap
+        },
+        None => transport,
+    };
+    let span = Span::transfer("write", peer, filename);
+    let _entered = span.enter();
+    let logger = logger.new(o!("peer" => format!("{}", peer)));
+    debug!(logger, "Receiving with mode {}", txmode);
+    if txmode == TransferMode::Mail {
+        warn!(logger, "Rejecting WRQ: mail mode not supported");
+        send_error_via(
+            transport, peer, ErrorCode::IllegalOperation,
+            "mail transfer mode not supported", &*config.metrics)?;
+        return Err(Error::UnsupportedMode(txmode));
+    }
+    let permit = match config.limits {
+        Some(ref limits) => match limits.admit(peer.ip()) {
+            Some(permit) => Some(permit),
+            None => {
+                warn!(logger, "Rejecting WRQ: too many transfers");
+                let event = SecurityEvent::new(
+                    peer, Violation::QuotaExceeded, "max transfers")
+                    .with_direction(Direction::Write);
+                match filename {
+                    Some(filename) => event.with_filename(filename),
+                    None => event,
+                }.report(config.events.as_deref());
+                send_error_via(
+                    transport, peer, ErrorCode::NotDefined,
+                    "too many transfers", &*config.metrics)?;
+                return Err(Error::TooManyTransfers);
+            },
+        },
+        None => None,
+    };
+    let mut tracker = Tracker::new(
+        peer, config.observer.as_deref(), options.tsize, &*config.clock)
+        .with_metrics(&*config.metrics)
+        .with_events(config.events.as_deref(), Direction::Write)
+        .with_span(span.clone());
+    tracker.started(filename);
+    let session = config.registry.as_ref().map(|r| r.register(peer));
+    let cancel = match session {
+        Some(ref session) => session.token().clone(),
+        None => CancellationToken::new(),
+    };
+    let result = match txmode {
+        TransferMode::Octet => receive_from(
+            sink, transport, peer, options, config, permit.as_ref(),
+            &mut tracker, &cancel, &logger),
+        TransferMode::NetASCII => {
+            let mut decoder = NetAsciiWriter::with_policy(
+                sink, config.netascii);
+            receive_from(
+                &mut decoder, transport, peer, options, config,
+                permit.as_ref(), &mut tracker, &cancel, &logger)
+                .and_then(|_| decoder.finish().map_err(Into::into))
+        },
+        TransferMode::Mail => unreachable!(),
+    };
+    match result {
+        Ok(_) => {
+            info!(logger, "Completed transfer from {:?}", peer);
+            Ok(tracker.completed())
+        },
+        Err(error) => {
+            error!(
+                logger, "Error transferring from {:?}: {}", peer, error);
+            tracker.failed(&error);
+            Err(error)
+        },
+    }
+}
+
+
+#[allow(clippy::too_many_arguments)]
+fn receive_from(
+    sink: &mut dyn io::Write,
+    socket: &dyn Transport,
+    peer: net::SocketAddr,
+    options: Options,
+    config: &Config,
+    permit: Option<&Permit>,
+    tracker: &mut Tracker,
+    cancel: &CancellationToken,
+    logger: &Logger,
+)
+    -> Result<()>
+{
+    // First, connect the socket to the peer so that we're only sending
+    // and receiving traffic to/from the peer.
+    socket.connect(peer)?;
+
+    let mut bufout = config.buffers.acquire(512);
+
+    // If the client has told us how big the file is, we can reject it
+    // up front instead of part way through the transfer.
+    match (options.tsize, config.max_size) {
+        (Some(tsize), Some(max_size)) if tsize > max_size => {
+            send_error_to(
+                socket, &mut bufout, ErrorCode::DiskFull,
+                "file too large", &*config.metrics)?;
+            return Err(Error::TooLarge(max_size));
+        },
+        _ => {},
+    };
+
+    if let Some(ref quota) = config.quota {
+        if quota.exceeded(peer.ip()) {
+            warn!(logger, "Rejecting WRQ: quota exceeded");
+            SecurityEvent::new(peer, Violation::QuotaExceeded, "upload quota")
+                .with_direction(Direction::Write)
+                .report(config.events.as_deref());
+            send_error_to(
+                socket, &mut bufout, ErrorCode::DiskFull,
+                "upload quota exceeded", &*config.metrics)?;
+            return Err(Error::QuotaExceeded);
+        }
+    };
+
+    // Only extensions that the application has registered, and that
+    // accept the requested value, are acknowledged.
+    let negotiated = options.negotiate(&config.policy).and_then(
+        |(mut options_out, negotiated)| {
+            options_out.unknown = config.extensions.negotiate(&options)?;
+            Ok((options_out, negotiated))
+        });
+    let (mut options_out, mut negotiated) = match negotiated {
+        Ok(negotiated) => negotiated,
+        Err(message) => {
+            warn!(logger, "Rejecting WRQ: {}", message);
+            socket.send(&Packet::bad_options(message.clone()).to_vec()?)?;
+            config.metrics.error(ErrorCode::BadOptions);
+            return Err(Error::BadOptions(message));
+        },
+    };
+
+    // Every block is acknowledged, so a window of more than one block
+    // is never acknowledged, whatever the policy allows.
+    options_out.windowsize = None;
+    negotiated.windowsize = 1;
+
+    // Held until the transfer is done.
+    let _commitment = match config.budget {
+        Some(ref budget) => match budget.commit(
+            negotiated.blksize, negotiated.windowsize) {
+            Some(commitment) => {
+                commitment.apply(&mut options_out, &mut negotiated);
+                Some(commitment)
+            },
+            None => {
+                warn!(logger, "Rejecting WRQ: memory budget exhausted");
+                send_error_to(
+                    socket, &mut bufout, ErrorCode::NotDefined,
+                    "server busy", &*config.metrics)?;
+                return Err(Error::OutOfMemory);
+            },
+        },
+        None => None,
+    };
+
+    let blksize = negotiated.blksize;
+    let timeout = Some(negotiated.timeout);
+
+    // opcode + blkno + data
+    let mut bufin = config.buffers.acquire(4 + blksize);
+
+    // Never acknowledge an option the client did not ask for.
+    let options_out = options_out.restricted_to(&options);
+    tracker.negotiated(&negotiated, &options_out);
+
+    // Acknowledge the request, with an OACK if options were accepted,
+    // or an ACK of block zero otherwise.
+    let mut size = if options_out.is_set() {
+        let size = Packet::OAck(options_out.clone()).write(&mut bufout)?;
+        info!(
+            logger, "Sent OACK ({} bytes) to {}.", size, &peer;
+            &options_out);
+        tracker.oack(size);
+        size
+    } else {
+        Packet::Ack(BlockNum(0)).write(&mut bufout)?
+    };
+    socket.send(&bufout[..size])?;
+
+    fn timed_out(error: &io::Error) -> bool {
+        // See the comment in Transport.recv to understand why both
+        // errors are matched.
+        error.kind() == io::ErrorKind::WouldBlock ||
+            error.kind() == io::ErrorKind::TimedOut
+    }
+
+    let mut blkno = BlockNum(1);
+    let mut acked = BlockNum(0);
+    let mut received = 0u64;
+    let mut timeouts = 0u8;
+    'recv: loop {
+        let result = socket.recv(&mut bufin, timeout);
+        if cancel.is_cancelled() {
+            warn!(logger, "Transfer cancelled");
+            send_error_to(
+                socket, &mut bufout, ErrorCode::NotDefined,
+                "transfer cancelled", &*config.metrics)?;
+            return Err(Error::Cancelled);
+        }
+        match result {
+            Ok(amt) => {
+                match Packet::parse(&bufin[..amt]) {
+                    Ok(packet) => match packet {
+                        Packet::Data(blocknum, Data(data)) => {
+                            if blocknum == blkno {
+                                received += data.len() as u64;
+                                match config.max_size {
+                                    Some(max_size) if received > max_size => {
+                                        send_error_to(
+                                            socket, &mut bufout,
+                                            ErrorCode::DiskFull,
+                                            "file too large", &*config.metrics)?;
+                                        return Err(Error::TooLarge(max_size));
+                                    },
+                                    _ => {},
+                                };
+                                if let Err(error) = sink.write_all(data) {
+                                    return Err(sink_error(
+                                        socket, &mut bufout, error,
+                                        &*config.metrics));
+                                };
+                                if let Some(ref quota) = config.quota {
+                                    quota.record(
+                                        peer.ip(), data.len() as u64);
+                                };
+                                // Hold back the ACK to keep within the
+                                // client's rate.
+                                if let Some(permit) = permit {
+                                    config.clock.sleep(
+                                        permit.charge(data.len() as u64));
+                                };
+                                size = Packet::Ack(blkno)
+                                    .write(&mut bufout)?;
+                                acked = blkno;
+                                socket.send(&bufout[..size])?;
+                                tracker.block(data.len());
+                                timeouts = 0;
+                                if data.len() < blksize {
+                                    if let Err(error) = sink.flush() {
+                                        return Err(sink_error(
+                                            socket, &mut bufout, error,
+                                            &*config.metrics));
+                                    };
+                                    break 'recv;
+                                }
+                                blkno = blkno.next_rolling(
+                                    negotiated.rollover);
+                            }
+                            else if blocknum == blkno.prev_rolling(
+                                negotiated.rollover) {
+                                // A duplicate of the last block we
+                                // received, so our ACK was probably
+                                // lost. Acknowledge it again, but don't
+                                // write it to the sink a second time.
+                                socket.send(&bufout[..size])?;
+                                tracker.retransmit(blocknum);
+                                debug!(
+                                    logger, "Re-sent ACK for duplicate \
+                                             DATA block {}.", blocknum);
+                            }
+                            else {
+                                debug!(
+                                    logger, "Ignoring DATA block {}; \
+                                             expected block {}.",
+                                    blocknum, blkno);
+                            };
+                        },
+                        Packet::Error(code, message) => {
+                            error!(logger, "{}: {:?}", code, message.0);
+                            return Err(Error::Aborted(code, message));
+                        },
+                        Packet::Ack(..) => warn!(
+                            logger, "Ignoring unexpected ACK packet."),
+                        Packet::Read(..) => warn!(
+                            logger, "Ignoring unexpected RRQ packet."),
+                        Packet::Write(..) => warn!(
+                            logger, "Ignoring unexpected WRQ packet."),
+                        Packet::OAck(..) => warn!(
+                            logger, "Ignoring unexpected OACK packet."),
+                        Packet::Unknown(..) => warn!(
+                            logger, "Ignoring unknown packet."),
+                    },
+                    Err(error) => {
+                        warn!(
+                            logger, "Ignoring mangled packet ({:?}).",
+                            error);
+                        config.metrics.malformed_packet();
+                    },
+                };
+            },
+            Err(ref error) if timed_out(error) => {
+                match timeouts {
+                    n if n < config.retransmits => {
+                        timeouts += 1;
+                        socket.send(&bufout[..size])?;
+                        tracker.retransmit(acked);
+                        info!(
+                            logger,
+                            "Re-sent ACK to {} (attempt #{}).",
+                            &peer, timeouts + 1);
+                    },
+                    _ => {
+                        error!(logger, "Too many time-outs; aborting");
+                        return Err(Error::TimedOut);
+                    },
+                };
+            },
+            Err(error) => {
+                error!(logger, "Error receiving packet: {}", error);
+                return Err(Error::Io(error));
+            },
+        }
+    };
+    Ok(())
+}
+
+
+/// Report an error writing to the sink to the peer, and convert it
+/// into an `Error`.
+fn sink_error(
+    socket: &dyn Transport, buffer: &mut [u8], error: io::Error,
+    metrics: &dyn Metrics)
+    -> Error
+{
+    let (code, message, error) = match error.kind() {
+        io::ErrorKind::StorageFull | io::ErrorKind::WriteZero => (
+            ErrorCode::DiskFull, "disk full".to_owned(),
+            Error::DiskFull(error)),
+        _ => (
+            ErrorCode::NotDefined, format!("write failed: {}", error),
+            Error::Io(error)),
+    };
+    match send_error_to(socket, buffer, code, &message, metrics) {
+        Ok(_) => error,
+        Err(send_error) => send_error,
+    }
+}
+
+
+/// Send an `ERROR` packet to the peer to which `socket` is connected,
+/// counting it in `metrics`.
+fn send_error_to(
+    socket: &dyn Transport, buffer: &mut [u8], code: ErrorCode,
+    message: &str, metrics: &dyn Metrics)
+    -> Result<()>
+{
+    let packet = Packet::error(code, message);
+    let size = packet.write(buffer)?;
+    socket.send(&buffer[..size])?;
+    metrics.error(code);
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::net;
+    use std::path;
+    use std::process;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time;
+
+    use super::{
+        Config,
+        Error,
+        Overwrite,
+        Result,
+        publish,
+        receive_file,
+        receive_from,
+        receive_over,
+        set_permissions,
+        target_path,
+        temporary_path,
+    };
+    use super::super::budget::MemoryBudget;
+    use super::super::clock::SystemClock;
+    use super::super::limits::ClientLimits;
+    use super::super::logging;
+    use super::super::options::{Options, TypedExtension};
+    use super::super::packet::{
+        BlockNum,
+        Data,
+        ErrorCode,
+        Filename,
+        Packet,
+        TransferMode,
+    };
+    use super::super::progress::Tracker;
+    use super::super::quota::Quota;
+    use super::super::security::{SecurityEvent, Violation};
+    use super::super::session::CancellationToken;
+    use super::super::events::Event;
+    use super::super::metrics::Direction as TransferDirection;
+    use super::super::progress::TransferStats;
+    use super::super::testing::{
+        EventLog, MemoryTransport, MetricsCounts, MetricsLog, MockClock,
+        WireLog};
+    use super::super::transport::{Direction, Transport};
+
+    type Outcome<W> = (Result<()>, W);
+
+    /// A sink that's always full.
+    struct FullSink;
+
+    impl io::Write for FullSink {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::StorageFull, "full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Start receiving in a thread, returning a client socket connected
+    /// to the receiver, and a handle to collect the outcome.
+    fn start(options: Options, config: Config)
+        -> (net::UdpSocket, thread::JoinHandle<Outcome<Vec<u8>>>)
+    {
+        start_with(Vec::new(), options, config, CancellationToken::new())
+    }
+
+    /// Start receiving into `sink` in a thread.
+    fn start_with<W>(
+        mut sink: W, options: Options, config: Config,
+        cancel: CancellationToken)
+        -> (net::UdpSocket, thread::JoinHandle<Outcome<W>>)
+        where W: io::Write + Send + 'static
+    {
+        let logger = logging::discard();
+        let server = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        let peer = client.local_addr().unwrap();
+        let receiver = thread::spawn(move || {
+            let mut tracker = Tracker::new(peer, None, None, &SystemClock);
+            let result = receive_from(
+                &mut sink, &server, peer, options, &config, None,
+                &mut tracker, &cancel, &logger);
+            (result, sink)
+        });
+        (client, receiver)
+    }
+
+    /// Send a `DATA` packet from the client.
+    fn send_data(client: &net::UdpSocket, blkno: u16, data: &[u8]) {
+        let mut buf = [0u8; 516];
+        let size = Packet::Data(BlockNum(blkno), Data(data))
+            .write(&mut buf).unwrap();
+        client.send(&buf[..size]).unwrap();
+    }
+
+    /// Receive an `ACK` at the client, returning its block number.
+    fn recv_ack(client: &net::UdpSocket) -> u16 {
+        let mut buf = [0u8; 516];
+        let size = client.recv(&mut buf).unwrap();
+        match Packet::parse(&buf[..size]).unwrap() {
+            Packet::Ack(BlockNum(blkno)) => blkno,
+            packet => panic!("Unexpected packet: {:?}", packet),
+        }
+    }
+
+    /// Receive an `ERROR` at the client, returning its code.
+    fn recv_error(client: &net::UdpSocket) -> ErrorCode {
+        let mut buf = [0u8; 516];
+        let size = client.recv(&mut buf).unwrap();
+        match Packet::parse(&buf[..size]).unwrap() {
+            Packet::Error(code, _) => code,
+            packet => panic!("Unexpected packet: {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn test_receive_into_sink() {
+        let (client, receiver) = start(Options::new(), Config::new());
+        let payload: Vec<u8> = (0..700).map(|n| n as u8).collect();
+        assert_eq!(0, recv_ack(&client));
+        for (index, chunk) in payload.chunks(512).enumerate() {
+            let blkno = index as u16 + 1;
+            send_data(&client, blkno, chunk);
+            assert_eq!(blkno, recv_ack(&client));
+        }
+        let (result, sink) = receiver.join().unwrap();
+        result.unwrap();
+        assert_eq!(payload, sink);
+    }
+
+    #[test]
+    fn test_receive_acknowledges_duplicate_data_again() {
+        let (client, receiver) = start(Options::new(), Config::new());
+        assert_eq!(0, recv_ack(&client));
+        send_data(&client, 1, &[1u8; 512]);
+        assert_eq!(1, recv_ack(&client));
+        // Pretend that ACK was lost and retransmit.
+        send_data(&client, 1, &[1u8; 512]);
+        assert_eq!(1, recv_ack(&client));
+        send_data(&client, 2, &[2u8; 10]);
+        assert_eq!(2, recv_ack(&client));
+        let (result, sink) = receiver.join().unwrap();
+        result.unwrap();
+        assert_eq!(522, sink.len());
+        assert_eq!(&[1u8; 512][..], &sink[..512]);
+        assert_eq!(&[2u8; 10][..], &sink[512..]);
+    }
+
+    #[test]
+    fn test_receive_ignores_data_beyond_expected_block() {
+        let (client, receiver) = start(Options::new(), Config::new());
+        assert_eq!(0, recv_ack(&client));
+        // Block 1 was lost, or reordered, and block 2 arrives first.
+        send_data(&client, 2, &[2u8; 10]);
+        send_data(&client, 1, &[1u8; 512]);
+        assert_eq!(1, recv_ack(&client));
+        send_data(&client, 2, &[2u8; 10]);
+        assert_eq!(2, recv_ack(&client));
+        let (result, sink) = receiver.join().unwrap();
+        result.unwrap();
+        assert_eq!(&[1u8; 512][..], &sink[..512]);
+        assert_eq!(&[2u8; 10][..], &sink[512..]);
+    }
+
+    #[test]
+    fn test_receive_retransmits_ack_then_times_out() {
+        let mut options = Options::new();
+        options.timeout = Some(1);
+        let mut config = Config::new();
+        config.retransmits = 1;
+        let (client, receiver) = start(options, config);
+        let mut buf = [0u8; 516];
+        for _ in 0..2 {
+            let size = client.recv(&mut buf).unwrap();
+            match Packet::parse(&buf[..size]).unwrap() {
+                Packet::OAck(..) => {},
+                packet => panic!("Unexpected packet: {:?}", packet),
+            };
+        }
+        match receiver.join().unwrap().0 {
+            Err(Error::TimedOut) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        };
+    }
+
+    #[test]
+    fn test_receive_rejects_tsize_over_max_size() {
+        let mut options = Options::new();
+        options.tsize = Some(1000);
+        let mut config = Config::new();
+        config.max_size = Some(999);
+        let (client, receiver) = start(options, config);
+        assert_eq!(ErrorCode::DiskFull, recv_error(&client));
+        let (result, sink) = receiver.join().unwrap();
+        match result {
+            Err(Error::TooLarge(999)) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        assert!(sink.is_empty());
+    }
+
+    #[test]
+    fn test_receive_aborts_when_exceeding_max_size() {
+        let mut config = Config::new();
+        config.max_size = Some(600);
+        let (client, receiver) = start(Options::new(), config);
+        assert_eq!(0, recv_ack(&client));
+        send_data(&client, 1, &[0u8; 512]);
+        assert_eq!(1, recv_ack(&client));
+        send_data(&client, 2, &[0u8; 512]);
+        assert_eq!(ErrorCode::DiskFull, recv_error(&client));
+        let (result, sink) = receiver.join().unwrap();
+        match result {
+            Err(Error::TooLarge(600)) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        assert_eq!(512, sink.len());
+    }
+
+    #[test]
+    fn test_receive_can_be_cancelled() {
+        let cancel = CancellationToken::new();
+        let (client, receiver) = start_with(
+            Vec::new(), Options::new(), Config::new(), cancel.clone());
+        assert_eq!(0, recv_ack(&client));
+        send_data(&client, 1, &[1u8; 512]);
+        assert_eq!(1, recv_ack(&client));
+        cancel.cancel();
+        send_data(&client, 2, &[2u8; 512]);
+        assert_eq!(ErrorCode::NotDefined, recv_error(&client));
+        let (result, sink) = receiver.join().unwrap();
+        match result {
+            Err(Error::Cancelled) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        assert_eq!(512, sink.len());
+    }
+
+    #[test]
+    fn test_receive_rejects_transfer_over_budget() {
+        let mut config = Config::new();
+        config.budget = Some(Arc::new(MemoryBudget::new(1000)));
+        let (client, receiver) = start(Options::new(), config);
+        assert_eq!(ErrorCode::NotDefined, recv_error(&client));
+        match receiver.join().unwrap().0 {
+            Err(Error::OutOfMemory) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        };
+    }
+
+    #[test]
+    fn test_receive_rejects_client_over_quota() {
+        let mut config = Config::new();
+        let quota = Quota::new(100, time::Duration::from_secs(60));
+        quota.record("127.0.0.1".parse().unwrap(), 100);
+        config.quota = Some(quota);
+        let events = Arc::new(EventLog::new());
+        config.events = Some(events.clone());
+        let (client, receiver) = start(Options::new(), config);
+        assert_eq!(ErrorCode::DiskFull, recv_error(&client));
+        match receiver.join().unwrap().0 {
+            Err(Error::QuotaExceeded) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        let peer = client.local_addr().unwrap();
+        assert!(events.events().contains(&Event::Security(
+            SecurityEvent::new(peer, Violation::QuotaExceeded, "upload quota")
+                .with_direction(TransferDirection::Write))));
+    }
+
+    #[test]
+    fn test_receive_acknowledges_registered_extensions() {
+        let mut options = Options::new();
+        options.set_extension("offset", 0);
+        options.set_extension("vendor", "thing");
+        let mut config = Config::new();
+        config.extensions.register(
+            TypedExtension::new("offset", |_: &u64| true));
+        let (client, receiver) = start(options, config);
+        let mut buf = [0u8; 516];
+        let size = client.recv(&mut buf).unwrap();
+        match Packet::parse(&buf[..size]).unwrap() {
+            Packet::OAck(options) => assert_eq!(
+                vec![("offset".to_owned(), "0".to_owned())], options.unknown),
+            packet => panic!("Unexpected packet: {:?}", packet),
+        };
+        send_data(&client, 1, b"data");
+        assert_eq!(1, recv_ack(&client));
+        receiver.join().unwrap().0.unwrap();
+    }
+
+    #[test]
+    fn test_receive_never_acknowledges_windowsize() {
+        let mut options = Options::new();
+        options.blksize = Some(1024);
+        options.windowsize = Some(8);
+        let mut config = Config::new();
+        config.policy.max_windowsize = 16;
+        let (client, receiver) = start(options, config);
+        let mut buf = [0u8; 516];
+        let size = client.recv(&mut buf).unwrap();
+        match Packet::parse(&buf[..size]).unwrap() {
+            Packet::OAck(options) => {
+                assert_eq!(Some(1024), options.blksize);
+                assert_eq!(None, options.windowsize);
+            },
+            packet => panic!("Unexpected packet: {:?}", packet),
+        };
+        send_data(&client, 1, b"data");
+        assert_eq!(1, recv_ack(&client));
+        receiver.join().unwrap().0.unwrap();
+    }
+
+    #[test]
+    fn test_receive_rejects_unacceptable_extension() {
+        let mut options = Options::new();
+        options.set_extension("offset", 100);
+        let mut config = Config::new();
+        config.extensions.register(
+            TypedExtension::new("offset", |&o: &u64| o == 0));
+        let (client, receiver) = start(options, config);
+        assert_eq!(ErrorCode::BadOptions, recv_error(&client));
+        match receiver.join().unwrap().0 {
+            Err(Error::BadOptions(_)) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        };
+    }
+
+    #[test]
+    fn test_receive_reports_full_sink_as_disk_full() {
+        let (client, receiver) = start_with(
+            FullSink, Options::new(), Config::new(),
+            CancellationToken::new());
+        assert_eq!(0, recv_ack(&client));
+        send_data(&client, 1, b"data");
+        assert_eq!(ErrorCode::DiskFull, recv_error(&client));
+        match receiver.join().unwrap().0 {
+            Err(Error::DiskFull(_)) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        };
+    }
+
+    #[test]
+    fn test_receive_file_sends_errors_creating_file() {
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(time::Duration::from_secs(5))).unwrap();
+        let path = env::temp_dir()
+            .join(format!("libtftp-wrq-missing-{}", process::id()))
+            .join("upload");
+        let filename = Filename::new(path.to_str().unwrap());
+        let result = receive_file(
+            client.local_addr().unwrap(), filename, TransferMode::Octet,
+            Options::new(), &Config::new(), &logging::discard());
+        match result {
+            Err(Error::Io(ref error))
+                if error.kind() == io::ErrorKind::NotFound => {},
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        assert_eq!(ErrorCode::FileNotFound, recv_error(&client));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_set_permissions() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        let path = env::temp_dir().join(
+            format!("libtftp-wrq-perms-{}", process::id()));
+        let file = fs::File::create(&path).unwrap();
+        let mut config = Config::new();
+        config.mode = Some(0o640);
+        // Chown to ourselves, which is always permitted.
+        config.owner = Some(file.metadata().unwrap().uid());
+        set_permissions(&file, &config).unwrap();
+        let metadata = file.metadata().unwrap();
+        assert_eq!(0o640, metadata.permissions().mode() & 0o777);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_temporary_path_is_in_same_directory() {
+        let path = path::Path::new("/some/where/pxelinux.0");
+        let temppath = temporary_path(path);
+        assert_eq!(path.parent(), temppath.parent());
+        assert_ne!(path, temppath);
+        assert_ne!(temppath, temporary_path(path));
+    }
+
+    #[test]
+    fn test_target_path_with_existing_file() {
+        let dir = env::temp_dir().join(
+            format!("libtftp-wrq-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let absent = dir.join("absent");
+        let present = dir.join("present");
+        fs::write(&present, b"data").unwrap();
+        fs::write(dir.join("present.1"), b"data").unwrap();
+
+        for overwrite in &[
+            Overwrite::Reject, Overwrite::Replace, Overwrite::Version] {
+            assert_eq!(Some(absent.clone()), target_path(&absent, *overwrite));
+        }
+        assert_eq!(None, target_path(&present, Overwrite::Reject));
+        assert_eq!(
+            Some(present.clone()), target_path(&present, Overwrite::Replace));
+        assert_eq!(
+            Some(dir.join("present.2")),
+            target_path(&present, Overwrite::Version));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_publish_never_replaces_unless_asked() {
+        let dir = env::temp_dir().join(
+            format!("libtftp-wrq-publish-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config");
+        let upload = |data: &[u8]| {
+            let temppath = temporary_path(&path);
+            fs::write(&temppath, data).unwrap();
+            temppath
+        };
+        // Each file turns up after its target was chosen.
+        let temppath = upload(b"rejected");
+        fs::write(&path, b"original").unwrap();
+        let error = publish(&temppath, &path, &path, Overwrite::Reject)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::AlreadyExists, error.kind());
+        assert_eq!(b"original", &fs::read(&path).unwrap()[..]);
+        fs::remove_file(&temppath).unwrap();
+
+        let temppath = upload(b"versioned");
+        let chosen = dir.join("config.1");
+        fs::write(&chosen, b"concurrent").unwrap();
+        assert_eq!(
+            dir.join("config.2"),
+            publish(&temppath, &path, &chosen, Overwrite::Version).unwrap());
+        assert_eq!(b"concurrent", &fs::read(&chosen).unwrap()[..]);
+        assert_eq!(
+            b"versioned", &fs::read(dir.join("config.2")).unwrap()[..]);
+        assert!(!temppath.exists());
+
+        let temppath = upload(b"replaced");
+        assert_eq!(
+            path,
+            publish(&temppath, &path, &path, Overwrite::Replace).unwrap());
+        assert_eq!(b"replaced", &fs::read(&path).unwrap()[..]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_receive_over_times_out_on_clock() {
+        let logger = logging::discard();
+        let (server, client) = MemoryTransport::pair();
+        let clock = MockClock::new();
+        server.set_clock(Arc::new(clock.clone()));
+        let config = Config{clock: Arc::new(clock.clone()), ..Config::new()};
+        let peer = client.local_addr().unwrap();
+        let mut sink = Vec::new();
+        let result = receive_over(
+            &server, peer, &mut sink, TransferMode::Octet, Options::new(),
+            &config, &logger);
+        match result {
+            Err(Error::TimedOut) => {},
+            other => panic!("Unexpected result: {:?}", other),
+        };
+        // The default time-out of 8 seconds expired after the first ACK
+        // and after each of its retransmissions.
+        let waits = config.retransmits as u32 + 1;
+        assert_eq!(time::Duration::from_secs(8) * waits, clock.elapsed());
+        let mut buf = [0u8; 4];
+        for _ in 0..waits {
+            client.recv(&mut buf, None).unwrap();
+            assert_eq!(b"\0\x04\0\x00", &buf);
+        }
+    }
+
+    #[test]
+    fn test_receive_over_rejects_client_with_too_many_transfers() {
+        let logger = logging::discard();
+        let (server, client) = MemoryTransport::pair();
+        let peer = client.local_addr().unwrap();
+        let limits = Arc::new(ClientLimits::new().with_max_transfers(1));
+        let _permit = limits.admit(peer.ip()).unwrap();
+        let config = Config{limits: Some(limits.clone()), ..Config::new()};
+        let result = receive_over(
+            &server, peer, &mut Vec::new(), TransferMode::Octet,
+            Options::new(), &config, &logger);
+        match result {
+            Err(Error::TooManyTransfers) => {},
+            other => panic!("Unexpected result: {:?}", other),
+        };
+        let mut buf = [0u8; 64];
+        let size = client.recv(&mut buf, None).unwrap();
+        assert_eq!(
+            Packet::error(ErrorCode::NotDefined, "too many transfers"),
+            Packet::parse(&buf[..size]).unwrap());
+        assert_eq!(1, limits.transfers(peer.ip()));
+    }
+
+    #[test]
+    fn test_receive_over_memory_transport() {
+        let (server, client) = MemoryTransport::pair();
+        let peer = client.local_addr().unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        // Lose the first ACK of block 1, so it must be retransmitted.
+        let mut lost = false;
+        server.deliver_if(move |datagram| {
+            if !lost && datagram == b"\0\x04\0\x01" {
+                lost = true;
+                false
+            }
+            else {
+                true
+            }
+        });
+        let mut options = Options::new();
+        options.utimeout = Some(10_000);
+        let receiver = thread::spawn(move || {
+            let logger = logging::discard();
+            let mut sink = Vec::new();
+            let result = receive_over(
+                &server, peer, &mut sink, TransferMode::Octet, options,
+                &Config::new(), &logger);
+            (result, sink)
+        });
+        // Fail rather than hang should the receiver stop responding.
+        let timeout = Some(time::Duration::from_secs(5));
+        let mut buf = [0u8; 516];
+        let size = client.recv(&mut buf, timeout).unwrap();
+        match Packet::parse(&buf[..size]).unwrap() {
+            Packet::OAck(options) =>
+                assert_eq!(Some(10_000), options.utimeout),
+            packet => panic!("Unexpected packet: {:?}", packet),
+        };
+        let payload: Vec<u8> = (0..700).map(|n| n as u8).collect();
+        for (index, chunk) in payload.chunks(512).enumerate() {
+            let blkno = index as u16 + 1;
+            let size = Packet::Data(BlockNum(blkno), Data(chunk))
+                .write(&mut buf).unwrap();
+            client.send(&buf[..size]).unwrap();
+            let size = client.recv(&mut buf, timeout).unwrap();
+            assert_eq!(Packet::Ack(BlockNum(blkno)), Packet::parse(
+                &buf[..size]).unwrap());
+        }
+        let (result, sink) = receiver.join().unwrap();
+        result.unwrap();
+        assert_eq!(payload, sink);
+    }
+
+    #[test]
+    fn test_receive_over_shows_wire_observer_every_datagram() {
+        let (server, client) = MemoryTransport::pair();
+        let peer = client.local_addr().unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        let log = Arc::new(WireLog::new());
+        let mut config = Config::new();
+        config.wire_observer = Some(log.clone());
+        let receiver = thread::spawn(move || {
+            let logger = logging::discard();
+            let mut sink = Vec::new();
+            receive_over(
+                &server, peer, &mut sink, TransferMode::Octet,
+                Options::new(), &config, &logger).unwrap();
+        });
+        let timeout = Some(time::Duration::from_secs(5));
+        let mut buf = [0u8; 16];
+        client.recv(&mut buf, timeout).unwrap();
+        client.send(b"\0\x03\0\x01abc").unwrap();
+        client.recv(&mut buf, timeout).unwrap();
+        receiver.join().unwrap();
+        assert_eq!(
+            vec![
+                (Direction::Sent, peer, b"\0\x04\0\x00".to_vec()),
+                (Direction::Received, peer, b"\0\x03\0\x01abc".to_vec()),
+                (Direction::Sent, peer, b"\0\x04\0\x01".to_vec()),
+            ],
+            log.datagrams());
+    }
+
+    #[test]
+    fn test_receive_over_reports_to_metrics() {
+        let (server, client) = MemoryTransport::pair();
+        let peer = client.local_addr().unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        let metrics = Arc::new(MetricsLog::new());
+        let mut config = Config::new();
+        config.metrics = metrics.clone();
+        config.clock = Arc::new(MockClock::new());
+        let receiver = thread::spawn(move || {
+            let logger = logging::discard();
+            let mut sink = Vec::new();
+            receive_over(
+                &server, peer, &mut sink, TransferMode::Octet,
+                Options::new(), &config, &logger).unwrap();
+        });
+        let timeout = Some(time::Duration::from_secs(5));
+        let mut buf = [0u8; 516];
+        client.recv(&mut buf, timeout).unwrap();
+        client.send(b"\0").unwrap();
+        let size = Packet::Data(BlockNum(1), Data(&[0u8; 512]))
+            .write(&mut buf).unwrap();
+        let block = buf[..size].to_vec();
+        client.send(&block).unwrap();
+        client.recv(&mut buf, timeout).unwrap();
+        // Send block 1 again, as if its ACK had been lost.
+        client.send(&block).unwrap();
+        client.recv(&mut buf, timeout).unwrap();
+        client.send(b"\0\x03\0\x02abc").unwrap();
+        client.recv(&mut buf, timeout).unwrap();
+        receiver.join().unwrap();
+        assert_eq!(
+            MetricsCounts{
+                started: 1,
+                completed: vec![(515, time::Duration::from_secs(0))],
+                retransmits: 1,
+                malformed: 1,
+                io_errors: 0,
+                errors: vec![],
+            },
+            metrics.counts());
+    }
+
+    #[test]
+    fn test_receive_over_sends_events() {
+        let (server, client) = MemoryTransport::pair();
+        let peer = client.local_addr().unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        let events = Arc::new(EventLog::new());
+        let mut config = Config::new();
+        config.events = Some(events.clone());
+        config.clock = Arc::new(MockClock::new());
+        let receiver = thread::spawn(move || {
+            let logger = logging::discard();
+            let mut sink = Vec::new();
+            receive_over(
+                &server, peer, &mut sink, TransferMode::Octet,
+                Options::new(), &config, &logger).unwrap();
+        });
+        let timeout = Some(time::Duration::from_secs(5));
+        let mut buf = [0u8; 516];
+        client.recv(&mut buf, timeout).unwrap();
+        let size = Packet::Data(BlockNum(1), Data(&[0u8; 512]))
+            .write(&mut buf).unwrap();
+        let block = buf[..size].to_vec();
+        client.send(&block).unwrap();
+        client.recv(&mut buf, timeout).unwrap();
+        // Send block 1 again, as if its ACK had been lost.
+        client.send(&block).unwrap();
+        client.recv(&mut buf, timeout).unwrap();
+        client.send(b"\0\x03\0\x02abc").unwrap();
+        client.recv(&mut buf, timeout).unwrap();
+        receiver.join().unwrap();
+        let direction = TransferDirection::Write;
+        assert_eq!(
+            vec![
+                Event::TransferStarted{peer, direction, filename: None},
+                Event::BlockRetransmitted{
+                    peer, direction, block: BlockNum(1)},
+                Event::TransferCompleted{
+                    peer, direction, stats: TransferStats{
+                        bytes: 515,
+                        blocks: 2,
+                        retransmits: 1,
+                        duplicate_acks: 0,
+                        duration: time::Duration::from_secs(0),
+                        negotiated: Options::new(),
+                    },
+                },
+            ],
+            events.events());
+    }
+
+    #[test]
+    fn test_receive_over_sends_event_on_failure() {
+        let logger = logging::discard();
+        let (server, client) = MemoryTransport::pair();
+        let clock = MockClock::new();
+        server.set_clock(Arc::new(clock.clone()));
+        let events = Arc::new(EventLog::new());
+        let mut config = Config::new();
+        config.events = Some(events.clone());
+        config.clock = Arc::new(clock);
+        config.retransmits = 0;
+        let peer = client.local_addr().unwrap();
+        let mut sink = Vec::new();
+        receive_over(
+            &server, peer, &mut sink, TransferMode::Octet, Options::new(),
+            &config, &logger).unwrap_err();
+        match events.events().last() {
+            Some(Event::TransferFailed{error, ..}) =>
+                assert_eq!("too many time-outs", error),
+            other => panic!("Unexpected event: {:?}", other),
+        };
+    }
+
+}